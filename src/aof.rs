@@ -1,73 +1,1056 @@
-use std::fs::File;
-use std::io::{self, Write};
-use std::sync::mpsc::{channel, Sender};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
 use std::thread::{sleep, spawn};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use crate::config;
+use crate::handlers::Snapshot;
+use crate::latency;
+use crate::manifest::{Manifest, ManifestEntry};
 use crate::message::Message;
+use crate::metrics::Histogram;
+use crate::rdb::{read_rdb, write_rdb};
 use crate::resp::Resp;
+use crate::zset::format_score;
 
 pub type CB = fn(msg: Message);
 
+// The AOF used by BGREWRITEAOF, set once the server has opened it.
+static AOF: Mutex<Option<Aof>> = Mutex::new(None);
+
+// Writes that found an everysec fsync still running after two seconds.
+pub static AOF_DELAYED_FSYNC: AtomicU64 = AtomicU64::new(0);
+
+// Time spent in each fsync of the file being appended to.
+pub static FSYNC_LATENCY: Histogram = Histogram::new();
+
+const DELAYED_FSYNC_AFTER: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::EverySec),
+            "no" => Ok(AppendFsync::No),
+            _ => Err(format!("invalid appendfsync value '{}'", value)),
+        }
+    }
+}
+
+// Where the multi-part AOF lives: `dir` holds `name.manifest` and the parts.
+#[derive(Clone)]
+struct AofDir {
+    dir: PathBuf,
+    name: String,
+}
+
+impl AofDir {
+    fn path(&self, file: &str) -> PathBuf {
+        self.dir.join(file)
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.path(&format!("{}.manifest", self.name))
+    }
+
+    // Replaces the manifest atomically through a temp file and a rename.
+    fn write_manifest(&self, manifest: &Manifest) -> Result<(), io::Error> {
+        let temp = self.path(&format!("temp-{}.manifest", self.name));
+        let mut file = File::create(&temp)?;
+        file.write_all(manifest.serialize().as_bytes())?;
+        file.sync_data()?;
+        fs::rename(&temp, self.manifest_path())
+    }
+
+    fn create_incr(&self, seq: u64) -> Result<(ManifestEntry, File), io::Error> {
+        let entry = ManifestEntry {
+            name: format!("{}.{}.incr.aof", self.name, seq),
+            seq,
+        };
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(self.path(&entry.name))?;
+        Ok((entry, file))
+    }
+
+    fn size(&self, manifest: &Manifest) -> u64 {
+        manifest
+            .files()
+            .filter_map(|entry| fs::metadata(self.path(&entry.name)).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+}
+
+// `written` and `synced` are logical offsets that keep growing across
+// files; `size` and `base_size` are the total size of all parts now and
+// right after the last rewrite.
+struct SyncState {
+    file: Arc<File>,
+    manifest: Manifest,
+    written: u64,
+    synced: u64,
+    syncing_since: Option<Instant>,
+    size: u64,
+    base_size: u64,
+    rewriting: bool,
+    // Whether the last append reached the file.
+    last_write_ok: bool,
+    // Second of the last `#TS:` annotation written to the current file.
+    last_timestamp: u64,
+}
+
+struct Shared {
+    dir: Option<AofDir>,
+    policy: AppendFsync,
+    // aof-timestamp-enabled: a `#TS:` record before the first write of
+    // each second.
+    timestamps: bool,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+impl Shared {
+    // Returns once everything up to `offset` is on disk. Only one fsync runs
+    // at a time; writers arriving meanwhile wait for it and the next fsync
+    // covers all of them at once.
+    fn sync_to(&self, offset: u64) -> Result<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= offset {
+                return Ok(());
+            }
+            if state.syncing_since.is_none() {
+                break;
+            }
+            state = self.synced.wait(state).unwrap();
+        }
+        let target = state.written;
+        let file = state.file.clone();
+        state.syncing_since = Some(Instant::now());
+        drop(state);
+
+        let start = Instant::now();
+        let result = file.sync_data();
+        FSYNC_LATENCY.record(start.elapsed());
+        latency::sample("aof-fsync", start.elapsed());
+
+        let mut state = self.state.lock().unwrap();
+        state.syncing_since = None;
+        if result.is_ok() {
+            state.synced = state.synced.max(target);
+        }
+        self.synced.notify_all();
+        result
+    }
+}
+
+// Clones share the file; it is fsynced once the last one is dropped.
+#[derive(Clone)]
 pub struct Aof {
-    file: File,
-    sender: Sender<()>,
+    shared: Arc<Shared>,
 }
 
 impl Aof {
-    pub fn new(file: File) -> Self {
-        let (sender, receiver) = channel();
-        let aof = Aof { file, sender };
-        let cloned = aof.file.try_clone().unwrap();
-        spawn(move || loop {
-            if receiver.try_recv().is_ok() {
-                println!("Thread received termination signal. Exiting....");
-                break;
+    // Opens the multi-part AOF in `dir`, creating the directory, the first
+    // incremental file and the manifest when missing. A single-file AOF
+    // named `name` next to the directory becomes the base.
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        name: &str,
+        policy: AppendFsync,
+        timestamps: bool,
+    ) -> Result<Self, io::Error> {
+        let dir = AofDir {
+            dir: dir.as_ref().to_path_buf(),
+            name: name.to_string(),
+        };
+        fs::create_dir_all(&dir.dir)?;
+        let mut manifest = match fs::read_to_string(dir.manifest_path()) {
+            Ok(text) => Manifest::parse(&text)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Manifest::default(),
+            Err(err) => return Err(err),
+        };
+        let legacy = dir.dir.parent().unwrap_or(Path::new("")).join(name);
+        let adopted = format!("{}.1.base.aof", name);
+        if manifest.base.is_none() && manifest.incrs.is_empty() && legacy.is_file() {
+            manifest.base = Some(ManifestEntry {
+                name: adopted.clone(),
+                seq: 1,
+            });
+        }
+        let file = match manifest.incrs.last() {
+            Some(incr) => File::options()
+                .append(true)
+                .read(true)
+                .open(dir.path(&incr.name))?,
+            None => {
+                let (incr, file) = dir.create_incr(1)?;
+                manifest.incrs.push(incr);
+                dir.write_manifest(&manifest)?;
+                file
             }
-            let _ = cloned.sync_all();
-            sleep(Duration::from_secs(1));
+        };
+        // The single file moves once the manifest names it, so a crash in
+        // between leaves it where the next start picks it up again.
+        if manifest
+            .base
+            .as_ref()
+            .is_some_and(|base| base.name == adopted)
+            && legacy.is_file()
+            && !dir.path(&adopted).exists()
+        {
+            fs::rename(&legacy, dir.path(&adopted))?;
+        }
+        let size = dir.size(&manifest);
+        Ok(Aof::new(
+            file,
+            manifest,
+            Some(dir),
+            size,
+            policy,
+            timestamps,
+        ))
+    }
+
+    // An AOF over a single file that cannot be rewritten.
+    #[cfg(test)]
+    pub fn from_file(file: File, policy: AppendFsync) -> Self {
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Aof::new(file, Manifest::default(), None, size, policy, false)
+    }
+
+    fn new(
+        file: File,
+        manifest: Manifest,
+        dir: Option<AofDir>,
+        size: u64,
+        policy: AppendFsync,
+        timestamps: bool,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            dir,
+            policy,
+            timestamps,
+            state: Mutex::new(SyncState {
+                file: Arc::new(file),
+                manifest,
+                written: 0,
+                synced: 0,
+                syncing_since: None,
+                size,
+                base_size: size,
+                rewriting: false,
+                last_write_ok: true,
+                last_timestamp: 0,
+            }),
+            synced: Condvar::new(),
         });
-        aof
+        if policy == AppendFsync::EverySec {
+            let weak = Arc::downgrade(&shared);
+            spawn(move || everysec(weak));
+        }
+        Aof { shared }
     }
 
+    #[cfg(test)]
     pub fn write_message(&mut self, value: &Message) -> Result<usize, io::Error> {
         self.write(value.marshal().as_ref())
     }
 
-    pub fn read(&self, callback: CB) -> Result<(), io::Error> {
-        let mut resp = Resp::new(&self.file);
-        loop {
-            let msg = resp.read();
-            if let Ok(Message::Null) = msg {
-                return Ok(());
-            };
-            callback(msg?);
+    // Appends without waiting for the fsync appendfsync always asks for, and
+    // returns the offset to hand to `commit` for that. Callers append under
+    // their own lock to keep the log in execution order and commit after
+    // releasing it, so concurrent writers still share one fsync.
+    pub fn append_message(&mut self, value: &Message) -> Result<u64, io::Error> {
+        self.append(&value.marshal())
+    }
+
+    pub fn commit(&self, offset: u64) -> Result<(), io::Error> {
+        if self.shared.policy == AppendFsync::Always {
+            self.shared.sync_to(offset)?;
+        }
+        Ok(())
+    }
+
+    // How far the log is on disk, in the offsets `append_message` returns.
+    pub fn synced_offset(&self) -> u64 {
+        self.shared.state.lock().unwrap().synced
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> Result<u64, io::Error> {
+        let mut state = self.shared.state.lock().unwrap();
+        let mut record = Vec::new();
+        if self.shared.timestamps {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs());
+            if now > state.last_timestamp {
+                state.last_timestamp = now;
+                record = Message::timestamp(now).marshal();
+            }
+        }
+        record.extend_from_slice(bytes);
+        let start = Instant::now();
+        let written = (&*state.file).write_all(&record);
+        latency::sample("aof-write", start.elapsed());
+        state.last_write_ok = written.is_ok();
+        written?;
+        state.written += record.len() as u64;
+        state.size += record.len() as u64;
+        if self.shared.policy == AppendFsync::EverySec
+            && matches!(state.syncing_since, Some(since) if since.elapsed() >= DELAYED_FSYNC_AFTER)
+        {
+            AOF_DELAYED_FSYNC.fetch_add(1, Ordering::Relaxed);
+        }
+        let offset = state.written;
+        let rewrite = self.rewrite_due(&state);
+        drop(state);
+
+        if rewrite {
+            if let Err(err) = self.rewrite() {
+                println!("error starting AOF rewrite: {err}");
+            }
+        }
+        Ok(offset)
+    }
+
+    // Replays every part in manifest order. A command cut short at the end
+    // of the last part is dropped, and the file truncated, when
+    // `load_truncated` (aof-load-truncated) is set; anything else malformed
+    // stops the load.
+    pub fn read(&self, callback: CB, load_truncated: bool) -> Result<(), io::Error> {
+        let state = self.shared.state.lock().unwrap();
+        let parts: Vec<(String, Arc<File>)> = match &self.shared.dir {
+            Some(dir) => state
+                .manifest
+                .files()
+                .map(|entry| {
+                    Ok((
+                        entry.name.clone(),
+                        Arc::new(File::open(dir.path(&entry.name))?),
+                    ))
+                })
+                .collect::<Result<_, io::Error>>()?,
+            None => vec![("append only file".to_string(), state.file.clone())],
+        };
+        drop(state);
+        let count = parts.len();
+        for (i, (name, file)) in parts.into_iter().enumerate() {
+            let mut reader = BufReader::new(&*file);
+            // A base written with aof-use-rdb-preamble is an RDB snapshot.
+            if reader.fill_buf()?.starts_with(b"REDIS") {
+                let snapshot = read_rdb(&mut reader).map_err(|err| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Bad RDB preamble in the AOF file {}: {}", name, err),
+                    )
+                })?;
+                snapshot.restore();
+                continue;
+            }
+            let mut resp = Resp::new(reader);
+            loop {
+                let start = resp.offset();
+                let err = match resp.read() {
+                    Ok(msg @ Message::Array(_)) => {
+                        callback(msg);
+                        continue;
+                    }
+                    Ok(Message::Annotation(_)) => continue,
+                    Ok(_) => io::Error::new(io::ErrorKind::InvalidData, "unexpected message type"),
+                    Err(err) => err,
+                };
+                match err.kind() {
+                    io::ErrorKind::InvalidInput if err.to_string().contains("no bytes") => break,
+                    io::ErrorKind::UnexpectedEof if i + 1 == count && load_truncated => {
+                        let discarded = self.truncate(start)?;
+                        println!(
+                            "!!! Warning: short read while loading the AOF file {}. \
+                             Discarded {} bytes after offset {} because aof-load-truncated is enabled",
+                            name, discarded, start
+                        );
+                        break;
+                    }
+                    io::ErrorKind::UnexpectedEof => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Unexpected end of file reading the AOF file {} at offset {}. \
+                                 Run rustis-check-aof --fix on it, or set aof-load-truncated \
+                                 to yes to drop the partial command and start anyway",
+                                name, start
+                            ),
+                        ))
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "Bad file format reading the AOF file {} at offset {}: {}",
+                                name, start, err
+                            ),
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Cuts the file being appended to back to `len` bytes, returning how
+    // many were dropped.
+    fn truncate(&self, len: u64) -> Result<u64, io::Error> {
+        let mut state = self.shared.state.lock().unwrap();
+        let discarded = state.file.metadata()?.len().saturating_sub(len);
+        state.file.set_len(len)?;
+        state.file.sync_data()?;
+        state.size = state.size.saturating_sub(discarded);
+        state.base_size = state.size;
+        Ok(discarded)
+    }
+
+    // Bytes in the base and incremental files.
+    pub fn size(&self) -> u64 {
+        self.shared.state.lock().unwrap().size
+    }
+
+    pub fn rewrite(&self) -> Result<(), String> {
+        self.start_rewrite(config::aof_use_rdb_preamble())
+    }
+
+    // Starts a rewrite. Writes move to a new incremental file right away, and
+    // a new base is built in the background from a copy of the keyspace taken
+    // at the switch, as an RDB snapshot when `preamble` is set. The manifest
+    // then drops the old base and incrementals.
+    fn start_rewrite(&self, preamble: bool) -> Result<(), String> {
+        let Some(dir) = self.shared.dir.clone() else {
+            return Err("ERR no append only file to rewrite".to_string());
+        };
+        let mut state = self.shared.state.lock().unwrap();
+        if state.rewriting {
+            return Err(
+                "ERR Background append only file rewriting already in progress".to_string(),
+            );
+        }
+        let switch = |state: &mut SyncState| -> Result<u64, io::Error> {
+            state.file.sync_data()?;
+            let seq = state.manifest.incrs.last().map_or(0, |incr| incr.seq) + 1;
+            let (incr, file) = dir.create_incr(seq)?;
+            let mut manifest = state.manifest.clone();
+            manifest.incrs.push(incr);
+            dir.write_manifest(&manifest)?;
+            state.manifest = manifest;
+            state.file = Arc::new(file);
+            state.synced = state.written;
+            state.last_timestamp = 0;
+            Ok(seq)
+        };
+        let first_incr = switch(&mut state).map_err(|err| format!("ERR {}", err))?;
+        state.rewriting = true;
+        // Taken under the state lock so it matches the files before the switch.
+        let snapshot = Snapshot::take();
+        let base_seq = state.manifest.base.as_ref().map_or(0, |base| base.seq) + 1;
+        self.shared.synced.notify_all();
+        drop(state);
+
+        let shared = self.shared.clone();
+        spawn(move || {
+            if let Err(err) =
+                finish_rewrite(&shared, &dir, snapshot, base_seq, first_incr, preamble)
+            {
+                println!("AOF rewrite failed: {err}");
+            }
+            shared.state.lock().unwrap().rewriting = false;
+        });
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn wait_for_rewrite(&self) {
+        while self.shared.state.lock().unwrap().rewriting {
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn rewrite_due(&self, state: &SyncState) -> bool {
+        self.shared.dir.is_some()
+            && !state.rewriting
+            && rewrite_due(
+                state.size,
+                state.base_size,
+                config::auto_aof_rewrite_percentage(),
+                config::auto_aof_rewrite_min_size(),
+            )
+    }
+}
+
+// The AOF fields of the persistence section of INFO.
+pub fn info() -> String {
+    let Some(aof) = registered() else {
+        return "aof_enabled:0".to_string();
+    };
+    let state = aof.shared.state.lock().unwrap();
+    let status = |ok| if ok { "ok" } else { "err" };
+    [
+        "aof_enabled:1".to_string(),
+        format!("aof_rewrite_in_progress:{}", state.rewriting as u8),
+        format!("aof_last_write_status:{}", status(state.last_write_ok)),
+        format!("aof_current_size:{}", state.size),
+        format!("aof_base_size:{}", state.base_size),
+        format!(
+            "aof_delayed_fsync:{}",
+            AOF_DELAYED_FSYNC.load(Ordering::Relaxed)
+        ),
+    ]
+    .join("\r\n")
+}
+
+pub fn register(aof: Aof) {
+    *AOF.lock().unwrap() = Some(aof);
+}
+
+pub fn registered() -> Option<Aof> {
+    AOF.lock().unwrap().clone()
+}
+
+pub fn bgrewriteaof(args: Vec<Message>) -> Message {
+    if !args.is_empty() {
+        return Message::error("ERR wrong number of arguments for 'bgrewriteaof' command");
+    }
+    let aof = AOF.lock().unwrap().clone();
+    match aof.map(|aof| aof.rewrite()) {
+        Some(Ok(())) => Message::simple("Background append only file rewriting started"),
+        Some(Err(err)) => Message::error(err),
+        None => Message::error("ERR no append only file to rewrite"),
+    }
+}
+
+// Same rule as Redis: rewrite once the file is at least `min_size` bytes and
+// has grown by `percentage` percent since the last rewrite; 0 disables it.
+fn rewrite_due(size: u64, base_size: u64, percentage: u64, min_size: u64) -> bool {
+    percentage > 0 && size >= min_size && size * 100 / base_size.max(1) >= 100 + percentage
+}
+
+// Writes the shortest command log that rebuilds the snapshot.
+fn write_commands<W: Write>(snapshot: &Snapshot, out: &mut W) -> Result<(), io::Error> {
+    let command = |parts: Vec<&[u8]>| {
+        Message::array(
+            parts
+                .into_iter()
+                .map(|p| Message::bulk(p.to_vec()))
+                .collect(),
+        )
+    };
+    for (key, value) in &snapshot.sets {
+        out.write_all(&command(vec![b"SET", key, value]).marshal())?;
+    }
+    for (key, fields) in &snapshot.hsets {
+        for (field, value) in fields {
+            out.write_all(&command(vec![b"HSET", key, field, value]).marshal())?;
         }
     }
+    for (key, zset) in &snapshot.zsets {
+        let scores: Vec<Vec<u8>> = zset.iter().map(|(score, _)| format_score(score)).collect();
+        let mut parts: Vec<&[u8]> = vec![b"ZADD", key];
+        for ((_, member), score) in zset.iter().zip(&scores) {
+            parts.push(score);
+            parts.push(member);
+        }
+        out.write_all(&command(parts).marshal())?;
+    }
+    for (key, json) in &snapshot.jsons {
+        let value = json.serialize();
+        out.write_all(&command(vec![b"JSON.SET", key, b"$", value.as_bytes()]).marshal())?;
+    }
+    for (key, list) in &snapshot.lists {
+        let mut parts: Vec<&[u8]> = vec![b"RPUSH", key];
+        parts.extend(list.iter().map(Vec::as_slice));
+        out.write_all(&command(parts).marshal())?;
+    }
+    for (key, set) in &snapshot.ssets {
+        let mut parts: Vec<&[u8]> = vec![b"SADD", key];
+        parts.extend(set.iter().map(Vec::as_slice));
+        out.write_all(&command(parts).marshal())?;
+    }
+    for (key, at) in &snapshot.expires {
+        let at = at.to_string();
+        out.write_all(&command(vec![b"PEXPIREAT", key, at.as_bytes()]).marshal())?;
+    }
+    Ok(())
+}
 
-    pub fn close(&mut self) -> Result<(), io::Error> {
-        self.flush()
+// Writes the new base, then swaps it into the manifest together with the
+// incrementals from `first_incr` on and deletes the files it replaced.
+fn finish_rewrite(
+    shared: &Shared,
+    dir: &AofDir,
+    snapshot: Snapshot,
+    base_seq: u64,
+    first_incr: u64,
+    preamble: bool,
+) -> Result<(), io::Error> {
+    let extension = if preamble { "rdb" } else { "aof" };
+    let base = ManifestEntry {
+        name: format!("{}.{}.base.{}", dir.name, base_seq, extension),
+        seq: base_seq,
+    };
+    let base_path = dir.path(&base.name);
+    let temp = dir.path(&format!("temp-{}", base.name));
+    let written = File::create(&temp).and_then(|file| {
+        if preamble {
+            write_rdb(&snapshot, BufWriter::new(&file), true)?;
+        } else {
+            let mut out = BufWriter::new(&file);
+            write_commands(&snapshot, &mut out)?;
+            out.flush()?;
+        }
+        file.sync_data()?;
+        fs::rename(&temp, &base_path)
+    });
+    if let Err(err) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(err);
     }
+
+    let mut state = shared.state.lock().unwrap();
+    let mut manifest = state.manifest.clone();
+    manifest.base = Some(base);
+    manifest.incrs.retain(|incr| incr.seq >= first_incr);
+    if let Err(err) = dir.write_manifest(&manifest) {
+        let _ = fs::remove_file(&base_path);
+        return Err(err);
+    }
+    for old in state.manifest.files() {
+        if !manifest.files().any(|entry| entry.name == old.name) {
+            let _ = fs::remove_file(dir.path(&old.name));
+        }
+    }
+    state.manifest = manifest;
+    state.size = dir.size(&state.manifest);
+    state.base_size = state.size;
+    Ok(())
 }
 
-impl Drop for Aof {
+// Background fsync for `everysec`; exits once the last handle is dropped.
+fn everysec(shared: Weak<Shared>) {
+    loop {
+        sleep(Duration::from_secs(1));
+        let Some(shared) = shared.upgrade() else {
+            break;
+        };
+        let written = shared.state.lock().unwrap().written;
+        let _ = shared.sync_to(written);
+    }
+}
+
+// Handles come and go with clients and INFO calls, so only the last one
+// flushes what is still pending, and never with appendfsync no.
+impl Drop for Shared {
     fn drop(&mut self) {
-        let _ = self.close();
+        if self.policy != AppendFsync::No {
+            let state = self.state.get_mut().unwrap();
+            if state.synced < state.written {
+                let _ = state.file.sync_data();
+            }
+        }
     }
 }
 
 impl Write for Aof {
     fn flush(&mut self) -> Result<(), io::Error> {
-        match self.sender.send(()) {
-            Ok(a) => Ok(a),
-            Err(msg) => Err(io::Error::other(msg)),
-        }
+        let written = self.shared.state.lock().unwrap().written;
+        self.shared.sync_to(written)
     }
 
     fn write(&mut self, bytes: &[u8]) -> Result<usize, io::Error> {
-        self.file.write(bytes)
+        let offset = self.append(bytes)?;
+        self.commit(offset)?;
+        Ok(bytes.len())
     }
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+    use crate::handlers::{LISTS, SETS, ZSETS};
+    use crate::zset::SortedSet;
+    use std::collections::VecDeque;
+    use std::thread;
+
+    fn temp_aof(name: &str, policy: AppendFsync) -> (Aof, PathBuf) {
+        let path = std::env::temp_dir().join(format!("rustis-{}-{}.aof", name, std::process::id()));
+        let file = File::options()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        (Aof::from_file(file, policy), path)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustis-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn commands_for(dir: &Path, name: &str, key: &[u8]) -> Vec<Message> {
+        let text = fs::read_to_string(dir.join(format!("{}.manifest", name))).unwrap();
+        let mut commands = Vec::new();
+        for entry in Manifest::parse(&text).unwrap().files() {
+            let mut resp = Resp::new(File::open(dir.join(&entry.name)).unwrap());
+            loop {
+                match resp.read() {
+                    Err(_) => break,
+                    Ok(Message::Array(parts))
+                        if parts.get(1) == Some(&Message::bulk(key.to_vec())) =>
+                    {
+                        commands.push(Message::Array(parts))
+                    }
+                    Ok(_) => {}
+                }
+            }
+        }
+        commands
+    }
+
+    fn set_command(key: &[u8], value: &[u8]) -> Message {
+        Message::array(vec![
+            Message::bulk(b"SET".to_vec()),
+            Message::bulk(key.to_vec()),
+            Message::bulk(value.to_vec()),
+        ])
+    }
+
+    #[test]
+    fn test_open_creates_manifest_and_incr() {
+        let dir = temp_dir("open");
+        let mut aof = Aof::open(&dir, "db.aof", AppendFsync::No, false).unwrap();
+        aof.write_message(&set_command(b"{open}k", b"v")).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("db.aof.manifest")).unwrap(),
+            "file db.aof.1.incr.aof seq 1 type i\n"
+        );
+        assert_eq!(
+            commands_for(&dir, "db.aof", b"{open}k"),
+            vec![set_command(b"{open}k", b"v")]
+        );
+        drop(aof);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_open_adopts_single_file_aof_as_base() {
+        let parent = temp_dir("legacy");
+        fs::create_dir_all(&parent).unwrap();
+        fs::write(
+            parent.join("db.aof"),
+            set_command(b"{legacy}k", b"v").marshal(),
+        )
+        .unwrap();
+        let dir = parent.join("appendonlydir");
+        let aof = Aof::open(&dir, "db.aof", AppendFsync::No, false).unwrap();
+        assert!(!parent.join("db.aof").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("db.aof.manifest")).unwrap(),
+            "file db.aof.1.base.aof seq 1 type b\nfile db.aof.1.incr.aof seq 1 type i\n"
+        );
+        assert_eq!(
+            commands_for(&dir, "db.aof", b"{legacy}k"),
+            vec![set_command(b"{legacy}k", b"v")]
+        );
+        drop(aof);
+        let _ = fs::remove_dir_all(parent);
+    }
+
+    #[test]
+    fn test_open_finishes_an_interrupted_adoption() {
+        let parent = temp_dir("adopting");
+        let dir = parent.join("appendonlydir");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            parent.join("db.aof"),
+            set_command(b"{adopting}k", b"v").marshal(),
+        )
+        .unwrap();
+        // Stopped after writing the manifest, before moving the file.
+        fs::write(
+            dir.join("db.aof.manifest"),
+            "file db.aof.1.base.aof seq 1 type b\nfile db.aof.1.incr.aof seq 1 type i\n",
+        )
+        .unwrap();
+        fs::write(dir.join("db.aof.1.incr.aof"), b"").unwrap();
+        let aof = Aof::open(&dir, "db.aof", AppendFsync::No, false).unwrap();
+        assert!(!parent.join("db.aof").exists());
+        assert_eq!(
+            commands_for(&dir, "db.aof", b"{adopting}k"),
+            vec![set_command(b"{adopting}k", b"v")]
+        );
+        drop(aof);
+        let _ = fs::remove_dir_all(parent);
+    }
+
+    #[test]
+    fn test_rewrite_compacts_and_keeps_new_writes() {
+        let dir = temp_dir("rewrite");
+        let mut aof = Aof::open(&dir, "db.aof", AppendFsync::No, false).unwrap();
+        for value in [b"1", b"2", b"3"] {
+            aof.write_message(&set_command(b"{rewrite}k", value))
+                .unwrap();
+        }
+        SETS.lock()
+            .unwrap()
+            .insert(b"{rewrite}k".to_vec(), b"3".to_vec());
+        let mut zset = SortedSet::new();
+        zset.insert(b"m".to_vec(), 1.5);
+        ZSETS.lock().unwrap().insert(b"{rewrite}z".to_vec(), zset);
+        LISTS.lock().unwrap().insert(
+            b"{rewrite}l".to_vec(),
+            VecDeque::from([b"a".to_vec(), b"a".to_vec()]),
+        );
+
+        aof.start_rewrite(false).unwrap();
+        aof.write_message(&set_command(b"{rewrite}k", b"4"))
+            .unwrap();
+        aof.wait_for_rewrite();
+        aof.write_message(&set_command(b"{rewrite}k", b"5"))
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("db.aof.manifest")).unwrap(),
+            "file db.aof.1.base.aof seq 1 type b\nfile db.aof.2.incr.aof seq 2 type i\n"
+        );
+        assert!(!dir.join("db.aof.1.incr.aof").exists());
+        assert_eq!(
+            commands_for(&dir, "db.aof", b"{rewrite}k"),
+            vec![
+                set_command(b"{rewrite}k", b"3"),
+                set_command(b"{rewrite}k", b"4"),
+                set_command(b"{rewrite}k", b"5"),
+            ]
+        );
+        assert_eq!(
+            commands_for(&dir, "db.aof", b"{rewrite}z"),
+            vec![Message::command(&["ZADD", "{rewrite}z", "1.5", "m"])]
+        );
+        assert_eq!(
+            commands_for(&dir, "db.aof", b"{rewrite}l"),
+            vec![Message::command(&["RPUSH", "{rewrite}l", "a", "a"])]
+        );
+        SETS.lock().unwrap().remove(&b"{rewrite}k".to_vec());
+        ZSETS.lock().unwrap().remove(&b"{rewrite}z".to_vec());
+        LISTS.lock().unwrap().remove(&b"{rewrite}l".to_vec());
+        drop(aof);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_rewrite_with_rdb_preamble() {
+        let dir = temp_dir("preamble");
+        let mut aof = Aof::open(&dir, "db.aof", AppendFsync::No, false).unwrap();
+        SETS.lock()
+            .unwrap()
+            .insert(b"{preamble}k".to_vec(), b"1".to_vec());
+        aof.start_rewrite(true).unwrap();
+        aof.wait_for_rewrite();
+        aof.write_message(&set_command(b"{preamble}n", b"2"))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("db.aof.manifest")).unwrap(),
+            "file db.aof.1.base.rdb seq 1 type b\nfile db.aof.2.incr.aof seq 2 type i\n"
+        );
+        assert!(fs::read(dir.join("db.aof.1.base.rdb"))
+            .unwrap()
+            .starts_with(b"REDIS0009"));
+
+        SETS.lock().unwrap().remove(&b"{preamble}k".to_vec());
+        aof.read(|_| {}, false).unwrap();
+        assert_eq!(
+            SETS.lock().unwrap().remove(&b"{preamble}k".to_vec()),
+            Some(b"1".to_vec())
+        );
+        assert_eq!(
+            commands_for(&dir, "db.aof", b"{preamble}n"),
+            vec![set_command(b"{preamble}n", b"2")]
+        );
+        drop(aof);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    fn truncated_dir(name: &str) -> (PathBuf, Vec<u8>) {
+        let dir = temp_dir(name);
+        drop(Aof::open(&dir, "db.aof", AppendFsync::No, false).unwrap());
+        let mut bytes = set_command(b"{truncated}k", b"v").marshal();
+        let complete = bytes.len();
+        bytes.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$4\r\n{tru");
+        fs::write(dir.join("db.aof.1.incr.aof"), &bytes).unwrap();
+        (dir, bytes[..complete].to_vec())
+    }
+
+    #[test]
+    fn test_read_truncated_tail() {
+        let (dir, complete) = truncated_dir("truncated");
+        let aof = Aof::open(&dir, "db.aof", AppendFsync::No, false).unwrap();
+
+        let err = aof.read(|_| {}, false).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&format!("at offset {}", complete.len())),
+            "{}",
+            err
+        );
+
+        aof.read(|_| {}, true).unwrap();
+        assert_eq!(fs::read(dir.join("db.aof.1.incr.aof")).unwrap(), complete);
+        drop(aof);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_read_rejects_bad_format() {
+        let dir = temp_dir("badformat");
+        drop(Aof::open(&dir, "db.aof", AppendFsync::No, false).unwrap());
+        let mut bytes = set_command(b"{bad}k", b"v").marshal();
+        bytes.extend_from_slice(b"+OK\r\n");
+        fs::write(dir.join("db.aof.1.incr.aof"), &bytes).unwrap();
+        let aof = Aof::open(&dir, "db.aof", AppendFsync::No, false).unwrap();
+        let err = aof.read(|_| {}, false).unwrap_err();
+        assert!(err.to_string().contains("Bad file format"), "{}", err);
+        assert!(err.to_string().contains("at offset 32"), "{}", err);
+        drop(aof);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_timestamp_annotations() {
+        let dir = temp_dir("timestamps");
+        let mut aof = Aof::open(&dir, "db.aof", AppendFsync::No, true).unwrap();
+        aof.write_message(&set_command(b"{ts}k", b"1")).unwrap();
+        aof.write_message(&set_command(b"{ts}k", b"2")).unwrap();
+
+        let bytes = fs::read(dir.join("db.aof.1.incr.aof")).unwrap();
+        let mut resp = Resp::new(&bytes[..]);
+        let is_timestamp =
+            |msg: &Message| matches!(msg, Message::Annotation(text) if text.starts_with("TS:"));
+        assert!(is_timestamp(&resp.read().unwrap()));
+        assert_eq!(resp.read().unwrap(), set_command(b"{ts}k", b"1"));
+        // Both writes usually share a second; a new one gets its own record.
+        let next = resp.read().unwrap();
+        if is_timestamp(&next) {
+            assert_eq!(resp.read().unwrap(), set_command(b"{ts}k", b"2"));
+        } else {
+            assert_eq!(next, set_command(b"{ts}k", b"2"));
+        }
+        aof.read(|msg| assert!(matches!(msg, Message::Array(_))), false)
+            .unwrap();
+        drop(aof);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_rewrite_needs_a_path() {
+        let aof = Aof::from_file(File::open("/dev/null").unwrap(), AppendFsync::No);
+        assert!(aof.rewrite().is_err());
+    }
+
+    #[test]
+    fn test_rewrite_due() {
+        assert!(!rewrite_due(100, 100, 100, 0));
+        assert!(rewrite_due(200, 100, 100, 0));
+        assert!(!rewrite_due(200, 100, 100, 1000));
+        assert!(!rewrite_due(200, 100, 0, 0));
+        assert!(rewrite_due(2000, 0, 100, 1000));
+    }
+
+    #[test]
+    fn test_parse_appendfsync() {
+        assert_eq!(AppendFsync::parse("always"), Ok(AppendFsync::Always));
+        assert_eq!(AppendFsync::parse("EverySec"), Ok(AppendFsync::EverySec));
+        assert_eq!(AppendFsync::parse("no"), Ok(AppendFsync::No));
+        assert!(AppendFsync::parse("sometimes").is_err());
+    }
+
+    #[test]
+    fn test_always_syncs_before_returning() {
+        let (mut aof, path) = temp_aof("always", AppendFsync::Always);
+        aof.write_message(&Message::bulk(b"x".to_vec())).unwrap();
+        let state = aof.shared.state.lock().unwrap();
+        assert_eq!(state.written, 7);
+        assert_eq!(state.synced, 7);
+        drop(state);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_always_group_commit() {
+        let (aof, path) = temp_aof("group", AppendFsync::Always);
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let mut aof = aof.clone();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        aof.write_message(&Message::bulk(b"x".to_vec())).unwrap();
+                        let state = aof.shared.state.lock().unwrap();
+                        assert!(state.synced <= state.written);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let state = aof.shared.state.lock().unwrap();
+        assert_eq!(state.written, 8 * 20 * 7);
+        assert_eq!(state.synced, state.written);
+        drop(state);
+        assert_eq!(std::fs::read(&path).unwrap().len(), 8 * 20 * 7);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_no_leaves_fsync_to_the_os() {
+        let (mut aof, path) = temp_aof("no", AppendFsync::No);
+        aof.write_message(&Message::bulk(b"x".to_vec())).unwrap();
+        assert_eq!(aof.shared.state.lock().unwrap().synced, 0);
+        aof.flush().unwrap();
+        assert_eq!(aof.shared.state.lock().unwrap().synced, 7);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_dropping_a_clone_does_not_fsync() {
+        let (mut aof, path) = temp_aof("clone", AppendFsync::Always);
+        aof.append_message(&Message::bulk(b"x".to_vec())).unwrap();
+        drop(aof.clone());
+        assert_eq!(aof.shared.state.lock().unwrap().synced, 0);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_everysec_counts_delayed_fsync() {
+        let (mut aof, path) = temp_aof("delayed", AppendFsync::EverySec);
+        // Pretend a slow fsync has been running for a while.
+        aof.shared.state.lock().unwrap().syncing_since = Some(Instant::now() - DELAYED_FSYNC_AFTER);
+        let before = AOF_DELAYED_FSYNC.load(Ordering::Relaxed);
+        aof.write_message(&Message::bulk(b"x".to_vec())).unwrap();
+        assert!(AOF_DELAYED_FSYNC.load(Ordering::Relaxed) > before);
+        aof.shared.state.lock().unwrap().syncing_since = None;
+        aof.shared.synced.notify_all();
+        let _ = std::fs::remove_file(path);
+    }
+}
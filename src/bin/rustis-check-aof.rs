@@ -0,0 +1,330 @@
+// Offline checker for AOF files: reports what a file holds and where it
+// stops parsing. --fix truncates it to the last complete command, and
+// --truncate-to-timestamp cuts it at the first `#TS:` annotation after the
+// given unix time, undoing everything logged from then on. A base written
+// as an RDB preamble is skipped.
+//
+//     rustis-check-aof [--fix | --truncate-to-timestamp <unix-time>] <file>
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+
+use rustis::manifest::Manifest;
+use rustis::message::Message;
+use rustis::resp::Resp;
+
+enum Mode {
+    Check,
+    Fix,
+    TruncateTo(u64),
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Report {
+    commands: u64,
+    counts: BTreeMap<String, u64>,
+    // First and last `#TS:` annotations seen.
+    timestamps: Option<(u64, u64)>,
+    // Offset of the first annotation later than the `until` time.
+    cut: Option<u64>,
+    // Length of the prefix made of complete, well-formed commands.
+    valid_len: u64,
+    // Offset and description of the first malformed entry.
+    error: Option<(u64, String)>,
+}
+
+fn timestamp(msg: &Message) -> Option<u64> {
+    match msg {
+        Message::Annotation(text) => text.strip_prefix("TS:")?.parse().ok(),
+        _ => None,
+    }
+}
+
+fn check<R: Read>(reader: R, until: Option<u64>) -> Report {
+    let mut resp = Resp::new(BufReader::new(reader));
+    let mut report = Report::default();
+    loop {
+        let start = resp.offset();
+        let problem = match resp.read() {
+            Ok(Message::Array(parts)) => match parts.first() {
+                Some(Message::Bulk(name)) => {
+                    let name = String::from_utf8_lossy(name).to_uppercase();
+                    *report.counts.entry(name).or_default() += 1;
+                    report.commands += 1;
+                    report.valid_len = resp.offset();
+                    continue;
+                }
+                _ => "command name is not a bulk string".to_string(),
+            },
+            Ok(msg @ Message::Annotation(_)) => {
+                if let Some(ts) = timestamp(&msg) {
+                    let first = report.timestamps.map_or(ts, |(first, _)| first);
+                    report.timestamps = Some((first, ts));
+                    if report.cut.is_none() && until.is_some_and(|until| ts > until) {
+                        report.cut = Some(start);
+                    }
+                }
+                report.valid_len = resp.offset();
+                continue;
+            }
+            Ok(_) => "expected a command array".to_string(),
+            Err(err) if err.kind() == ErrorKind::InvalidInput && err.to_string() == "no bytes" => {
+                break
+            }
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                "unexpected end of file".to_string()
+            }
+            Err(err) => err.to_string(),
+        };
+        report.error = Some((start, problem));
+        break;
+    }
+    report
+}
+
+fn confirm(question: &str) -> bool {
+    print!("{} Continue? [y/N]: ", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn truncate(part: &Path, len: u64) -> Result<(), String> {
+    let display = |err: io::Error| format!("{}: {}", part.display(), err);
+    let file = File::options().write(true).open(part).map_err(display)?;
+    file.set_len(len)
+        .and_then(|_| file.sync_all())
+        .map_err(display)?;
+    println!("Successfully truncated {}", part.display());
+    Ok(())
+}
+
+fn run(path: &Path, mode: Mode) -> Result<bool, String> {
+    let display = |err: io::Error| format!("{}: {}", path.display(), err);
+    // A manifest stands for its parts, replayed base first.
+    let parts: Vec<_> = if path.extension().is_some_and(|ext| ext == "manifest") {
+        let text = fs::read_to_string(path).map_err(display)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Manifest::parse(&text)?
+            .files()
+            .map(|entry| dir.join(&entry.name))
+            .collect()
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let until = match mode {
+        Mode::TruncateTo(until) => Some(until),
+        _ => None,
+    };
+
+    let mut valid = true;
+    for (i, part) in parts.iter().enumerate() {
+        let last = i + 1 == parts.len();
+        let mut file =
+            BufReader::new(File::open(part).map_err(|err| format!("{}: {}", part.display(), err))?);
+        let len = file.get_ref().metadata().map(|m| m.len()).unwrap_or(0);
+        // RDB preambles are checked when the server loads them.
+        let head = file
+            .fill_buf()
+            .map_err(|err| format!("{}: {}", part.display(), err))?;
+        if head.starts_with(b"REDIS") {
+            println!("{}: RDB preamble of {} bytes, skipped", part.display(), len);
+            continue;
+        }
+        let report = check(file, until);
+        println!(
+            "{}: {} commands in {} bytes",
+            part.display(),
+            report.commands,
+            report.valid_len
+        );
+        for (name, count) in &report.counts {
+            println!("  {:<16} {}", name, count);
+        }
+        if let Some((from, to)) = report.timestamps {
+            println!("  timestamps from {} to {}", from, to);
+        }
+        if let Some((offset, problem)) = report.error {
+            println!(
+                "First malformed entry at offset {} ({}), {} bytes from there to the end",
+                offset,
+                problem,
+                len - offset
+            );
+            if !matches!(mode, Mode::Fix) {
+                valid = false;
+            } else if !last {
+                println!("Only the last file can be fixed by truncating it");
+                valid = false;
+            } else if confirm(&format!(
+                "This will shrink {} from {} bytes to {} bytes.",
+                part.display(),
+                len,
+                offset
+            )) {
+                truncate(part, offset)?;
+            } else {
+                println!("Not fixed");
+                valid = false;
+            }
+            continue;
+        }
+        if let (Some(cut), Some(until)) = (report.cut, until) {
+            if !valid {
+                println!("Fix the AOF before truncating it to a timestamp");
+            } else if !last {
+                println!(
+                    "The first annotation after {} is not in the last file, which is the only one that can be truncated",
+                    until
+                );
+                valid = false;
+            } else if confirm(&format!(
+                "This will drop everything after {} from {}, shrinking it from {} bytes to {} bytes.",
+                until,
+                part.display(),
+                len,
+                cut
+            )) {
+                truncate(part, cut)?;
+            } else {
+                println!("Not truncated");
+            }
+            // Later parts would replay writes from after `until`.
+            break;
+        }
+    }
+    Ok(valid)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (mode, path) = match args.as_slice() {
+        [path] if !path.starts_with("--") => (Mode::Check, path),
+        [flag, path] if flag == "--fix" => (Mode::Fix, path),
+        [flag, until, path] if flag == "--truncate-to-timestamp" => match until.parse() {
+            Ok(until) => (Mode::TruncateTo(until), path),
+            Err(_) => {
+                eprintln!("Invalid unix time '{}'", until);
+                return ExitCode::from(2);
+            }
+        },
+        _ => {
+            eprintln!(
+                "Usage: rustis-check-aof [--fix | --truncate-to-timestamp <unix-time>] \
+                 <file.aof | file.manifest>"
+            );
+            return ExitCode::from(2);
+        }
+    };
+    // What went wrong was printed by run(), this only sums it up.
+    let failure = match mode {
+        Mode::Check => "AOF is not valid. Use the --fix option to try fixing it.",
+        Mode::Fix => "AOF is not valid and was not fixed.",
+        Mode::TruncateTo(_) => {
+            "AOF was not truncated. If it is not valid, use the --fix option first."
+        }
+    };
+    match run(Path::new(path), mode) {
+        Ok(true) => {
+            println!("AOF is valid");
+            ExitCode::SUCCESS
+        }
+        Ok(false) => {
+            println!("{}", failure);
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(parts: &[&str]) -> Vec<u8> {
+        Message::command(parts).marshal()
+    }
+
+    #[test]
+    fn test_check_valid() {
+        let mut input = command(&["SET", "a", "1"]);
+        input.extend(command(&["set", "b", "2"]));
+        input.extend(command(&["HSET", "h", "f", "v"]));
+        let report = check(&input[..], None);
+        assert_eq!(report.commands, 3);
+        assert_eq!(report.counts["SET"], 2);
+        assert_eq!(report.counts["HSET"], 1);
+        assert_eq!(report.valid_len, input.len() as u64);
+        assert_eq!(report.error, None);
+    }
+
+    #[test]
+    fn test_check_truncated() {
+        let mut input = command(&["SET", "a", "1"]);
+        let complete = input.len() as u64;
+        input.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nb");
+        let report = check(&input[..], None);
+        assert_eq!(report.commands, 1);
+        assert_eq!(report.valid_len, complete);
+        assert_eq!(
+            report.error,
+            Some((complete, "unexpected end of file".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_check_malformed() {
+        let mut input = command(&["SET", "a", "1"]);
+        let complete = input.len() as u64;
+        input.extend_from_slice(b"$1\r\nx\r\n");
+        let report = check(&input[..], None);
+        assert_eq!(
+            report.error,
+            Some((complete, "expected a command array".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_check_finds_cut_after_timestamp() {
+        let mut input = Message::timestamp(100).marshal();
+        input.extend(command(&["SET", "a", "1"]));
+        let cut = input.len() as u64;
+        input.extend(Message::timestamp(160).marshal());
+        input.extend(command(&["DEL", "a"]));
+        input.extend(Message::timestamp(200).marshal());
+
+        let report = check(&input[..], Some(150));
+        assert_eq!(report.commands, 2);
+        assert_eq!(report.timestamps, Some((100, 200)));
+        assert_eq!(report.cut, Some(cut));
+        assert_eq!(report.error, None);
+        assert_eq!(check(&input[..], Some(300)).cut, None);
+        assert_eq!(check(&input[..], None).cut, None);
+    }
+
+    #[test]
+    fn test_run_manifest() {
+        let dir = std::env::temp_dir().join(format!("rustis-check-aof-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("db.aof.1.base.aof"), command(&["SET", "a", "1"])).unwrap();
+        fs::write(dir.join("db.aof.1.incr.aof"), command(&["SET", "b", "2"])).unwrap();
+        fs::write(
+            dir.join("db.aof.manifest"),
+            "file db.aof.1.base.aof seq 1 type b\nfile db.aof.1.incr.aof seq 1 type i\n",
+        )
+        .unwrap();
+        assert_eq!(run(&dir.join("db.aof.manifest"), Mode::Check), Ok(true));
+
+        fs::write(dir.join("db.aof.1.base.aof"), b"*1\r\n$3\r\nSE").unwrap();
+        assert_eq!(run(&dir.join("db.aof.manifest"), Mode::Fix), Ok(false));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
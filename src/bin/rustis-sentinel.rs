@@ -0,0 +1,1672 @@
+// Sentinel for rustis: watches primaries and their replicas, agrees with
+// the other sentinels on when a primary is down, elects one of them to
+// fail over to the best replica, and tells clients where the primary is.
+//
+//     rustis-sentinel [--port 26379] --monitor <name> <host> <port> <quorum>
+//         [--down-after-milliseconds <name> <ms>] [--failover-timeout <name> <ms>]
+//         [--known-sentinel <name> <host> <port>]...
+//
+// rustis has no pub/sub, so instead of publishing hello messages on the
+// primary, sentinels send them to each other with SENTINEL HELLO. A hello
+// introduces its sender, so a sentinel only needs --known-sentinel for the
+// peers it should greet first. Epochs and the configuration are kept in
+// memory only.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, BufReader, ErrorKind, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use rustis::message::Message;
+use rustis::message::Message::*;
+use rustis::resp::Resp;
+
+const TICK: Duration = Duration::from_millis(100);
+const PING_PERIOD: Duration = Duration::from_secs(1);
+const INFO_PERIOD: Duration = Duration::from_secs(10);
+const HELLO_PERIOD: Duration = Duration::from_secs(2);
+// While the primary looks down, peers are asked what they think this often,
+// and an answer that it is down counts toward ODOWN for this long.
+const ASK_PERIOD: Duration = Duration::from_secs(1);
+const DOWN_REPLY_VALIDITY: Duration = Duration::from_secs(5);
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(10);
+// Replicas reporting the wrong role or primary are only fixed once nothing
+// changed for this long, leaving time for news of a failover to arrive.
+const RECONFIGURE_AFTER: Duration = Duration::from_secs(8);
+// Sentinels that voted for a peer delay their own attempt by up to this
+// much more, so they do not keep splitting the vote.
+const MAX_DESYNC_MS: u64 = 1000;
+const IO_TIMEOUT: Duration = Duration::from_secs(1);
+
+const DEFAULT_PORT: u16 = 26379;
+const DEFAULT_DOWN_AFTER: Duration = Duration::from_secs(30);
+const DEFAULT_FAILOVER_TIMEOUT: Duration = Duration::from_secs(180);
+
+fn command(parts: &[String]) -> Message {
+    Message::array(
+        parts
+            .iter()
+            .map(|p| Message::bulk(p.as_bytes().to_vec()))
+            .collect(),
+    )
+}
+
+// 40 random hex characters.
+fn new_runid() -> String {
+    let state = RandomState::new();
+    (0..3)
+        .map(|i| format!("{:016x}", state.hash_one(i)))
+        .collect::<String>()[..40]
+        .to_string()
+}
+
+fn jitter() -> Duration {
+    Duration::from_millis(RandomState::new().hash_one(0) % MAX_DESYNC_MS)
+}
+
+// A connection to an instance, kept open between checks.
+struct Link {
+    writer: TcpStream,
+    resp: Resp<BufReader<TcpStream>>,
+}
+
+impl Link {
+    fn connect(host: &str, port: u16) -> io::Result<Link> {
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no address"))?;
+        let stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        Ok(Link {
+            writer: stream.try_clone()?,
+            resp: Resp::new(BufReader::new(stream)),
+        })
+    }
+
+    fn request(&mut self, parts: &[String]) -> io::Result<Message> {
+        self.writer.write_all(&command(parts).marshal())?;
+        self.resp.read()
+    }
+}
+
+// What the replication section of INFO says about an instance.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ReplInfo {
+    role: String,
+    primary: Option<(String, u16)>,
+    link_up: bool,
+    offset: u64,
+    priority: u64,
+    replicas: Vec<(String, u16)>,
+}
+
+fn parse_info(text: &str) -> ReplInfo {
+    let mut info = ReplInfo {
+        priority: 100,
+        ..ReplInfo::default()
+    };
+    let mut host = None;
+    let mut port = None;
+    for line in text.lines() {
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        match field {
+            "role" => info.role = value.to_string(),
+            "master_host" => host = Some(value.to_string()),
+            "master_port" => port = value.parse().ok(),
+            "master_link_status" => info.link_up = value == "up",
+            "slave_repl_offset" => info.offset = value.parse().unwrap_or(0),
+            "slave_priority" | "replica_priority" => info.priority = value.parse().unwrap_or(100),
+            // slave0:ip=127.0.0.1,port=6380,state=online,offset=0,lag=0
+            field if field.starts_with("slave") && field[5..].parse::<u32>().is_ok() => {
+                let fields: HashMap<_, _> = value
+                    .split(',')
+                    .filter_map(|kv| kv.split_once('='))
+                    .collect();
+                if let (Some(ip), Some(Ok(port))) =
+                    (fields.get("ip"), fields.get("port").map(|p| p.parse()))
+                {
+                    info.replicas.push((ip.to_string(), port));
+                }
+            }
+            _ => {}
+        }
+    }
+    info.primary = host.zip(port);
+    info
+}
+
+struct Instance {
+    host: String,
+    port: u16,
+    link: Option<Link>,
+    // Set while a check has taken the link.
+    busy: bool,
+    last_check: Option<Instant>,
+    last_ok_ping: Instant,
+    sdown: bool,
+    info: Option<ReplInfo>,
+    info_refreshed: Option<Instant>,
+    // When INFO last reported a role different from the one before.
+    role_changed: Instant,
+}
+
+impl Instance {
+    fn new(host: &str, port: u16) -> Self {
+        Instance {
+            host: host.to_string(),
+            port,
+            link: None,
+            busy: false,
+            last_check: None,
+            last_ok_ping: Instant::now(),
+            sdown: false,
+            info: None,
+            info_refreshed: None,
+            role_changed: Instant::now(),
+        }
+    }
+
+    fn is(&self, host: &str, port: u16) -> bool {
+        self.host == host && self.port == port
+    }
+
+    fn addr(&self) -> (String, u16) {
+        (self.host.clone(), self.port)
+    }
+
+    fn is_target(&self, target: &Target) -> bool {
+        match target {
+            Target::Sentinel(host, port) | Target::Replica(host, port) => self.is(host, *port),
+            Target::Primary => false,
+        }
+    }
+
+    fn role(&self) -> &str {
+        self.info.as_ref().map_or("", |info| info.role.as_str())
+    }
+
+    fn info_fresh(&self, validity: Duration) -> bool {
+        self.info_refreshed
+            .is_some_and(|refreshed| refreshed.elapsed() < validity)
+    }
+}
+
+// Another sentinel monitoring the same primary.
+struct Peer {
+    instance: Instance,
+    runid: Option<String>,
+    last_hello: Option<Instant>,
+    last_ask: Option<Instant>,
+    // When it last answered that the primary is down.
+    said_down: Option<Instant>,
+    // Whom it voted for as failover leader, and in which epoch.
+    leader: Option<String>,
+    leader_epoch: u64,
+}
+
+impl Peer {
+    fn new(host: &str, port: u16) -> Self {
+        Peer {
+            instance: Instance::new(host, port),
+            runid: None,
+            last_hello: None,
+            last_ask: None,
+            said_down: None,
+            leader: None,
+            leader_epoch: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Failover {
+    None,
+    WaitStart,
+    SelectReplica,
+    PromoteReplica,
+    WaitPromotion,
+    ReconfReplicas,
+}
+
+impl Failover {
+    fn name(self) -> &'static str {
+        match self {
+            Failover::None => "none",
+            Failover::WaitStart => "wait_start",
+            Failover::SelectReplica => "select_slave",
+            Failover::PromoteReplica => "send_slaveof_noone",
+            Failover::WaitPromotion => "wait_promotion",
+            Failover::ReconfReplicas => "reconf_slaves",
+        }
+    }
+}
+
+struct Monitored {
+    name: String,
+    quorum: usize,
+    down_after: Duration,
+    failover_timeout: Duration,
+    primary: Instance,
+    replicas: Vec<Instance>,
+    sentinels: Vec<Peer>,
+    odown: bool,
+    // Epoch of the failover that produced the current primary.
+    config_epoch: u64,
+    config_changed: Instant,
+    // This sentinel's vote for the failover leader, and its epoch.
+    leader: Option<String>,
+    leader_epoch: u64,
+    failover: Failover,
+    failover_epoch: u64,
+    // When the last failover attempt started; voting for a peer pushes it
+    // forward to hold off our own attempt.
+    failover_start: Option<Instant>,
+    failover_state_changed: Instant,
+    forced: bool,
+    promoted: Option<(String, u16)>,
+    promote_sent: bool,
+    reconf_sent: Vec<(String, u16)>,
+}
+
+impl Monitored {
+    fn new(name: &str, host: &str, port: u16, quorum: usize) -> Self {
+        Monitored {
+            name: name.to_string(),
+            quorum,
+            down_after: DEFAULT_DOWN_AFTER,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+            primary: Instance::new(host, port),
+            replicas: Vec::new(),
+            sentinels: Vec::new(),
+            odown: false,
+            config_epoch: 0,
+            config_changed: Instant::now(),
+            leader: None,
+            leader_epoch: 0,
+            failover: Failover::None,
+            failover_epoch: 0,
+            failover_start: None,
+            failover_state_changed: Instant::now(),
+            forced: false,
+            promoted: None,
+            promote_sent: false,
+            reconf_sent: Vec::new(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "master {} {} {}",
+            self.name, self.primary.host, self.primary.port
+        )
+    }
+
+    fn describe_replica(&self, replica: &Instance) -> String {
+        format!(
+            "slave {}:{} {} {} @ {} {} {}",
+            replica.host,
+            replica.port,
+            replica.host,
+            replica.port,
+            self.name,
+            self.primary.host,
+            self.primary.port
+        )
+    }
+
+    // Where clients should go: the promoted replica once the failover has
+    // got as far as reconfiguring the others.
+    fn current_addr(&self) -> (String, u16) {
+        match (&self.promoted, self.failover) {
+            (Some(promoted), Failover::ReconfReplicas) => promoted.clone(),
+            _ => self.primary.addr(),
+        }
+    }
+
+    fn set_failover(&mut self, state: Failover) {
+        self.failover = state;
+        self.failover_state_changed = Instant::now();
+    }
+
+    fn abort_failover(&mut self, reason: &str) {
+        println!("{} {}", reason, self.describe());
+        self.set_failover(Failover::None);
+        self.forced = false;
+        self.promoted = None;
+        self.promote_sent = false;
+        self.reconf_sent.clear();
+    }
+
+    // Healthy enough that replicas disagreeing with it are the ones to fix.
+    fn looks_sane(&self) -> bool {
+        !self.primary.sdown
+            && self.primary.role() == "master"
+            && self.primary.info_fresh(INFO_PERIOD * 3)
+    }
+
+    fn info_period(&self) -> Duration {
+        if self.odown || self.failover != Failover::None {
+            PING_PERIOD
+        } else {
+            INFO_PERIOD
+        }
+    }
+
+    // The replica to promote: lowest priority first, then the one that
+    // got furthest in the replication stream, then the lowest address.
+    // Replicas that are down, silent, or have priority 0 are out.
+    fn select_replica(&self) -> Option<(String, u16)> {
+        let validity = if self.primary.sdown {
+            PING_PERIOD * 5
+        } else {
+            INFO_PERIOD * 3
+        };
+        let mut candidates: Vec<(&Instance, &ReplInfo)> = self
+            .replicas
+            .iter()
+            .filter(|replica| {
+                !replica.sdown
+                    && replica.last_ok_ping.elapsed() < PING_PERIOD * 5
+                    && replica.info_fresh(validity)
+            })
+            .filter_map(|replica| Some((replica, replica.info.as_ref()?)))
+            .filter(|(_, info)| info.role == "slave" && info.priority != 0)
+            .collect();
+        candidates.sort_by(|(a, a_info), (b, b_info)| {
+            a_info
+                .priority
+                .cmp(&b_info.priority)
+                .then(b_info.offset.cmp(&a_info.offset))
+                .then(a.addr().cmp(&b.addr()))
+        });
+        candidates.first().map(|(replica, _)| replica.addr())
+    }
+
+    // Makes `host:port` the primary. The promoted replica keeps its link,
+    // and the old primary is kept as a replica to be reconfigured once it
+    // is back.
+    fn switch_to(&mut self, host: &str, port: u16) {
+        println!(
+            "+switch-master {} {} {} {} {}",
+            self.name, self.primary.host, self.primary.port, host, port
+        );
+        let promoted = match self.replicas.iter().position(|r| r.is(host, port)) {
+            Some(i) => self.replicas.remove(i),
+            None => Instance::new(host, port),
+        };
+        let old = std::mem::replace(&mut self.primary, promoted);
+        if !old.is(host, port) {
+            self.replicas.push(Instance::new(&old.host, old.port));
+        }
+        self.odown = false;
+        self.config_changed = Instant::now();
+        self.set_failover(Failover::None);
+        self.forced = false;
+        self.promoted = None;
+        self.promote_sent = false;
+        self.reconf_sent.clear();
+        for peer in &mut self.sentinels {
+            peer.said_down = None;
+        }
+    }
+}
+
+// Which instance of a monitored primary a check talks to.
+#[derive(Clone, Debug, PartialEq)]
+enum Target {
+    Primary,
+    Replica(String, u16),
+    Sentinel(String, u16),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Request {
+    Ping,
+    Info,
+    // REPLICAOF NO ONE, or REPLICAOF host port.
+    Replicaof(Option<(String, u16)>),
+    // SENTINEL is-master-down-by-addr, also asking for a vote unless
+    // `runid` is "*".
+    AskDown {
+        host: String,
+        port: u16,
+        epoch: u64,
+        runid: String,
+    },
+    Hello(Vec<String>),
+}
+
+impl Request {
+    fn parts(&self) -> Vec<String> {
+        match self {
+            Request::Ping => vec!["PING".into()],
+            Request::Info => vec!["INFO".into(), "replication".into()],
+            Request::Replicaof(None) => vec!["REPLICAOF".into(), "NO".into(), "ONE".into()],
+            Request::Replicaof(Some((host, port))) => {
+                vec!["REPLICAOF".into(), host.clone(), port.to_string()]
+            }
+            Request::AskDown {
+                host,
+                port,
+                epoch,
+                runid,
+            } => vec![
+                "SENTINEL".into(),
+                "is-master-down-by-addr".into(),
+                host.clone(),
+                port.to_string(),
+                epoch.to_string(),
+                runid.clone(),
+            ],
+            Request::Hello(parts) => parts.clone(),
+        }
+    }
+}
+
+// Requests for one instance, run on their own thread over its link.
+struct Check {
+    master: String,
+    target: Target,
+    host: String,
+    port: u16,
+    link: Option<Link>,
+    requests: Vec<Request>,
+}
+
+struct Sentinel {
+    myid: String,
+    port: u16,
+    current_epoch: u64,
+    masters: Vec<Monitored>,
+}
+
+impl Sentinel {
+    fn new(port: u16) -> Self {
+        Sentinel {
+            myid: new_runid(),
+            port,
+            current_epoch: 0,
+            masters: Vec::new(),
+        }
+    }
+
+    fn master(&mut self, name: &str) -> Option<&mut Monitored> {
+        self.masters.iter_mut().find(|m| m.name == name)
+    }
+
+    // Advances every monitored primary and returns the checks now due.
+    fn tick(&mut self) -> Vec<Check> {
+        let mut checks = Vec::new();
+        for i in 0..self.masters.len() {
+            self.update_down_states(i);
+            self.step_failover(i);
+            self.schedule(i, &mut checks);
+        }
+        checks
+    }
+
+    fn update_down_states(&mut self, i: usize) {
+        let m = &mut self.masters[i];
+        let down_after = m.down_after;
+        let sdown = m.primary.last_ok_ping.elapsed() > down_after;
+        if sdown != m.primary.sdown {
+            m.primary.sdown = sdown;
+            println!(
+                "{} {}",
+                if sdown { "+sdown" } else { "-sdown" },
+                m.describe()
+            );
+        }
+        for j in 0..m.replicas.len() {
+            let sdown = m.replicas[j].last_ok_ping.elapsed() > down_after;
+            if sdown != m.replicas[j].sdown {
+                m.replicas[j].sdown = sdown;
+                let event = if sdown { "+sdown" } else { "-sdown" };
+                println!("{} {}", event, m.describe_replica(&m.replicas[j]));
+            }
+        }
+        for peer in &mut m.sentinels {
+            peer.instance.sdown = peer.instance.last_ok_ping.elapsed() > down_after;
+        }
+
+        let agreeing = m
+            .sentinels
+            .iter()
+            .filter(|peer| {
+                peer.said_down
+                    .is_some_and(|t| t.elapsed() < DOWN_REPLY_VALIDITY)
+            })
+            .count();
+        let odown = m.primary.sdown && agreeing + 1 >= m.quorum;
+        if odown != m.odown {
+            m.odown = odown;
+            if odown {
+                println!(
+                    "+odown {} #quorum {}/{}",
+                    m.describe(),
+                    agreeing + 1,
+                    m.quorum
+                );
+            } else {
+                println!("-odown {}", m.describe());
+            }
+        }
+    }
+
+    fn start_failover(&mut self, i: usize) {
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        let m = &mut self.masters[i];
+        m.failover_epoch = epoch;
+        m.failover_start = Some(Instant::now());
+        m.set_failover(Failover::WaitStart);
+        println!("+new-epoch {}", epoch);
+        println!("+try-failover {}", m.describe());
+    }
+
+    fn step_failover(&mut self, i: usize) {
+        let myid = self.myid.clone();
+        let m = &self.masters[i];
+        let timeout = m.failover_timeout;
+        let in_state = m.failover_state_changed.elapsed();
+        match m.failover {
+            Failover::None => {
+                let retry_due = m
+                    .failover_start
+                    .is_none_or(|start| Instant::now() >= start + timeout * 2);
+                if m.odown && retry_due {
+                    self.start_failover(i);
+                }
+            }
+            Failover::WaitStart => {
+                let epoch = m.failover_epoch;
+                let leader = if m.forced {
+                    Some(myid.clone())
+                } else {
+                    self.leader(i, epoch)
+                };
+                let m = &mut self.masters[i];
+                if leader.as_deref() == Some(myid.as_str()) {
+                    println!("+elected-leader {}", m.describe());
+                    m.set_failover(Failover::SelectReplica);
+                } else if in_state > ELECTION_TIMEOUT.min(timeout) {
+                    m.abort_failover("-failover-abort-not-elected");
+                }
+            }
+            Failover::SelectReplica => {
+                let m = &mut self.masters[i];
+                match m.select_replica() {
+                    None => m.abort_failover("-failover-abort-no-good-slave"),
+                    Some((host, port)) => {
+                        println!(
+                            "+selected-slave slave {host}:{port} {host} {port} @ {} {} {}",
+                            m.name, m.primary.host, m.primary.port
+                        );
+                        m.promoted = Some((host, port));
+                        m.promote_sent = false;
+                        m.set_failover(Failover::PromoteReplica);
+                    }
+                }
+            }
+            Failover::PromoteReplica | Failover::WaitPromotion if in_state > timeout => {
+                self.masters[i].abort_failover("-failover-abort-slave-timeout");
+            }
+            Failover::PromoteReplica => {}
+            Failover::WaitPromotion => {
+                let m = &mut self.masters[i];
+                let promoted = m.promoted.clone().unwrap();
+                let is_primary = m
+                    .replicas
+                    .iter()
+                    .any(|r| r.is(&promoted.0, promoted.1) && r.role() == "master");
+                if is_primary {
+                    m.config_epoch = m.failover_epoch;
+                    m.config_changed = Instant::now();
+                    println!(
+                        "+promoted-slave slave {}:{} @ {}",
+                        promoted.0,
+                        promoted.1,
+                        m.describe()
+                    );
+                    println!("+failover-state-reconf-slaves {}", m.describe());
+                    m.set_failover(Failover::ReconfReplicas);
+                }
+            }
+            Failover::ReconfReplicas => {
+                let m = &mut self.masters[i];
+                let promoted = m.promoted.clone().unwrap();
+                let done = m
+                    .replicas
+                    .iter()
+                    .filter(|r| !r.is(&promoted.0, promoted.1) && !r.sdown)
+                    .all(|r| {
+                        r.info
+                            .as_ref()
+                            .is_some_and(|info| info.primary.as_ref() == Some(&promoted))
+                    });
+                if done || in_state > timeout {
+                    if !done {
+                        println!("-failover-end-for-timeout {}", m.describe());
+                    }
+                    println!("+failover-end {}", m.describe());
+                    m.switch_to(&promoted.0, promoted.1);
+                }
+            }
+        }
+    }
+
+    // Records this sentinel's vote for the leader of the failover in
+    // `epoch`: the first candidate to ask in a new epoch gets it. Returns
+    // whom it voted for last, and in which epoch.
+    fn vote(&mut self, i: usize, epoch: u64, runid: &str) -> (Option<String>, u64) {
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            println!("+new-epoch {}", epoch);
+        }
+        let current_epoch = self.current_epoch;
+        let m = &mut self.masters[i];
+        if m.leader_epoch < epoch && current_epoch <= epoch {
+            m.leader = Some(runid.to_string());
+            m.leader_epoch = current_epoch;
+            println!("+vote-for-leader {} {}", runid, current_epoch);
+            if runid != self.myid {
+                m.failover_start = Some(Instant::now() + jitter());
+            }
+        }
+        (m.leader.clone(), m.leader_epoch)
+    }
+
+    // The sentinel holding a majority of the votes for `epoch`, and at
+    // least the quorum. This sentinel votes for the peers' favourite, or
+    // itself when they have none.
+    fn leader(&mut self, i: usize, epoch: u64) -> Option<String> {
+        let mut votes: HashMap<String, usize> = HashMap::new();
+        for peer in &self.masters[i].sentinels {
+            if let (Some(leader), true) = (&peer.leader, peer.leader_epoch == epoch) {
+                *votes.entry(leader.clone()).or_default() += 1;
+            }
+        }
+        let favourite = votes
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(runid, _)| runid.clone())
+            .unwrap_or_else(|| self.myid.clone());
+        if let (Some(mine), my_epoch) = self.vote(i, epoch, &favourite) {
+            if my_epoch == epoch {
+                *votes.entry(mine).or_default() += 1;
+            }
+        }
+        let m = &self.masters[i];
+        let voters = m.sentinels.len() + 1;
+        votes
+            .into_iter()
+            .filter(|(_, count)| *count > voters / 2 && *count >= m.quorum)
+            .map(|(runid, _)| runid)
+            .next()
+    }
+
+    fn schedule(&mut self, i: usize, checks: &mut Vec<Check>) {
+        let hello = self.hello_parts(i);
+        let current_epoch = self.current_epoch;
+        let myid = self.myid.clone();
+        let m = &mut self.masters[i];
+        let info_period = m.info_period();
+        let name = m.name.clone();
+        let now = Instant::now();
+        let due = |instance: &Instance| {
+            !instance.busy
+                && instance
+                    .last_check
+                    .is_none_or(|last| now.duration_since(last) >= PING_PERIOD)
+        };
+
+        if due(&m.primary) {
+            let mut requests = vec![Request::Ping];
+            if !m.primary.info_fresh(info_period) {
+                requests.push(Request::Info);
+            }
+            checks.push(take_check(&name, Target::Primary, &mut m.primary, requests));
+        }
+
+        let sane = m.failover == Failover::None
+            && m.looks_sane()
+            && m.config_changed.elapsed() >= RECONFIGURE_AFTER;
+        let primary_addr = m.primary.addr();
+        for j in 0..m.replicas.len() {
+            if !due(&m.replicas[j]) {
+                continue;
+            }
+            let replica = &m.replicas[j];
+            let addr = replica.addr();
+            let info_due = !replica.info_fresh(info_period);
+            let mut requests = vec![Request::Ping];
+            match m.failover {
+                Failover::PromoteReplica
+                    if m.promoted.as_ref() == Some(&addr) && !m.promote_sent =>
+                {
+                    requests.push(Request::Replicaof(None));
+                    m.promote_sent = true;
+                }
+                Failover::ReconfReplicas
+                    if m.promoted.as_ref() != Some(&addr) && !m.reconf_sent.contains(&addr) =>
+                {
+                    let promoted = m.promoted.clone().unwrap();
+                    println!("+slave-reconf-sent {}", m.describe_replica(replica));
+                    requests.push(Request::Replicaof(Some(promoted)));
+                    m.reconf_sent.push(addr.clone());
+                }
+                Failover::None if sane && info_due && !replica.sdown => {
+                    if let Some(info) = &replica.info {
+                        let wrong = if info.role == "master" {
+                            Some("+convert-to-slave")
+                        } else if info.primary.as_ref() != Some(&primary_addr) {
+                            Some("+fix-slave-config")
+                        } else {
+                            None
+                        };
+                        if let Some(event) = wrong {
+                            if replica.role_changed.elapsed() >= RECONFIGURE_AFTER {
+                                println!("{} {}", event, m.describe_replica(replica));
+                                requests.push(Request::Replicaof(Some(primary_addr.clone())));
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+            if info_due || requests.len() > 1 {
+                requests.push(Request::Info);
+            }
+            let target = Target::Replica(addr.0, addr.1);
+            checks.push(take_check(&name, target, &mut m.replicas[j], requests));
+        }
+
+        let primary_sdown = m.primary.sdown;
+        let asking_runid = if m.failover == Failover::WaitStart {
+            myid
+        } else {
+            "*".to_string()
+        };
+        for peer in &mut m.sentinels {
+            if !due(&peer.instance) {
+                continue;
+            }
+            let mut requests = vec![Request::Ping];
+            if peer
+                .last_hello
+                .is_none_or(|last| now.duration_since(last) >= HELLO_PERIOD)
+            {
+                requests.push(Request::Hello(hello.clone()));
+                peer.last_hello = Some(now);
+            }
+            if primary_sdown
+                && peer
+                    .last_ask
+                    .is_none_or(|last| now.duration_since(last) >= ASK_PERIOD)
+            {
+                requests.push(Request::AskDown {
+                    host: primary_addr.0.clone(),
+                    port: primary_addr.1,
+                    epoch: current_epoch,
+                    runid: asking_runid.clone(),
+                });
+                peer.last_ask = Some(now);
+            }
+            let target = Target::Sentinel(peer.instance.host.clone(), peer.instance.port);
+            checks.push(take_check(&name, target, &mut peer.instance, requests));
+        }
+    }
+
+    // SENTINEL HELLO with this sentinel's address and the configuration
+    // it believes in for a primary. The epoch is bumped on promotion, so the
+    // address already is the promoted replica's.
+    fn hello_parts(&self, i: usize) -> Vec<String> {
+        let m = &self.masters[i];
+        let (host, port) = m.current_addr();
+        vec![
+            "SENTINEL".into(),
+            "HELLO".into(),
+            "127.0.0.1".into(),
+            self.port.to_string(),
+            self.myid.clone(),
+            self.current_epoch.to_string(),
+            m.name.clone(),
+            host,
+            port.to_string(),
+            m.config_epoch.to_string(),
+        ]
+    }
+
+    // Takes the results of a check back, along with its link.
+    fn finish(
+        &mut self,
+        master: &str,
+        target: &Target,
+        link: Option<Link>,
+        results: Vec<(Request, Option<Message>)>,
+    ) {
+        let Some(i) = self.masters.iter().position(|m| m.name == master) else {
+            return;
+        };
+        let m = &mut self.masters[i];
+        let instance = match target {
+            Target::Primary => Some(&mut m.primary),
+            Target::Replica(host, port) => m.replicas.iter_mut().find(|r| r.is(host, *port)),
+            Target::Sentinel(host, port) => m
+                .sentinels
+                .iter_mut()
+                .map(|peer| &mut peer.instance)
+                .find(|instance| instance.is(host, *port)),
+        };
+        // The instance went away meanwhile, or now plays another part.
+        let Some(instance) = instance.filter(|instance| instance.busy) else {
+            return;
+        };
+        instance.busy = false;
+        instance.link = link;
+
+        let mut discovered = Vec::new();
+        let mut promotion_failed = false;
+        let mut reconf_failed = Vec::new();
+        let mut asked = None;
+        for (request, reply) in results {
+            match (&request, reply) {
+                (Request::Ping, Some(Simple(_))) => instance.last_ok_ping = Instant::now(),
+                (Request::Ping, Some(Error(err)))
+                    if err.starts_with("LOADING") || err.starts_with("MASTERDOWN") =>
+                {
+                    instance.last_ok_ping = Instant::now()
+                }
+                (Request::Info, Some(Bulk(text))) => {
+                    let info = parse_info(&String::from_utf8_lossy(&text));
+                    if instance.role() != info.role {
+                        instance.role_changed = Instant::now();
+                    }
+                    if *target == Target::Primary {
+                        discovered = info.replicas.clone();
+                    }
+                    instance.info = Some(info);
+                    instance.info_refreshed = Some(Instant::now());
+                }
+                (Request::Replicaof(None), reply) => {
+                    promotion_failed = !matches!(reply, Some(Simple(_)));
+                }
+                (Request::Replicaof(Some(_)), reply) if !matches!(reply, Some(Simple(_))) => {
+                    reconf_failed.push(instance.addr());
+                }
+                (Request::AskDown { .. }, Some(Array(reply))) => asked = Some(reply),
+                _ => {}
+            }
+        }
+
+        if let Some(reply) = asked {
+            if let Some(peer) = m
+                .sentinels
+                .iter_mut()
+                .find(|p| p.instance.is_target(target))
+            {
+                if let [Integer(down), Bulk(leader), Integer(epoch)] = reply.as_slice() {
+                    peer.said_down = (*down == 1).then(Instant::now);
+                    if leader.as_slice() != b"*" {
+                        peer.leader = Some(String::from_utf8_lossy(leader).into_owned());
+                        peer.leader_epoch = *epoch as u64;
+                    }
+                }
+            }
+        }
+        if m.failover == Failover::PromoteReplica {
+            if promotion_failed {
+                // Try again on the next check, until the failover times out.
+                m.promote_sent = false;
+            } else if m.promote_sent
+                && matches!(target, Target::Replica(host, port) if m.promoted == Some((host.clone(), *port)))
+            {
+                println!("+failover-state-wait-promotion {}", m.describe());
+                m.set_failover(Failover::WaitPromotion);
+            }
+        }
+        m.reconf_sent.retain(|addr| !reconf_failed.contains(addr));
+        for (host, port) in discovered {
+            if !m.replicas.iter().any(|r| r.is(&host, port)) && !m.primary.is(&host, port) {
+                let replica = Instance::new(&host, port);
+                println!("+slave {}", m.describe_replica(&replica));
+                m.replicas.push(replica);
+            }
+        }
+    }
+
+    // SENTINEL HELLO <ip> <port> <runid> <current-epoch> <master-name>
+    // <master-ip> <master-port> <master-config-epoch>
+    fn hello(&mut self, args: &[String]) -> Message {
+        let [ip, port, runid, epoch, name, master_ip, master_port, config_epoch] = args else {
+            return Message::error("ERR wrong number of arguments for 'sentinel|hello' command");
+        };
+        let (Ok(port), Ok(epoch), Ok(master_port), Ok(config_epoch)) = (
+            port.parse::<u16>(),
+            epoch.parse::<u64>(),
+            master_port.parse::<u16>(),
+            config_epoch.parse::<u64>(),
+        ) else {
+            return Message::error("ERR invalid hello message");
+        };
+        if *runid == self.myid {
+            return Message::simple("OK");
+        }
+        if epoch > self.current_epoch {
+            self.current_epoch = epoch;
+            println!("+new-epoch {}", epoch);
+        }
+        let Some(m) = self.master(name) else {
+            return Message::simple("OK");
+        };
+        let peer = match m.sentinels.iter().position(|p| p.instance.is(ip, port)) {
+            Some(j) => &mut m.sentinels[j],
+            None => {
+                println!(
+                    "+sentinel sentinel {} {} {} @ {}",
+                    runid,
+                    ip,
+                    port,
+                    m.describe().trim_start_matches("master ")
+                );
+                m.sentinels.push(Peer::new(ip, port));
+                m.sentinels.last_mut().unwrap()
+            }
+        };
+        peer.runid = Some(runid.clone());
+        peer.instance.last_ok_ping = Instant::now();
+        if config_epoch > m.config_epoch {
+            m.config_epoch = config_epoch;
+            if !m.primary.is(master_ip, master_port) {
+                println!(
+                    "+config-update-from sentinel {} {} {} @ {}",
+                    runid,
+                    ip,
+                    port,
+                    m.describe().trim_start_matches("master ")
+                );
+                m.switch_to(master_ip, master_port);
+            }
+        }
+        Message::simple("OK")
+    }
+}
+
+fn take_check(
+    master: &str,
+    target: Target,
+    instance: &mut Instance,
+    requests: Vec<Request>,
+) -> Check {
+    instance.busy = true;
+    instance.last_check = Some(Instant::now());
+    Check {
+        master: master.to_string(),
+        target,
+        host: instance.host.clone(),
+        port: instance.port,
+        link: instance.link.take(),
+        requests,
+    }
+}
+
+fn run_check(shared: &Mutex<Sentinel>, mut check: Check) {
+    let mut link = check
+        .link
+        .take()
+        .or_else(|| Link::connect(&check.host, check.port).ok());
+    let mut results = Vec::new();
+    // Requests after a failed one are not sent; the link is reopened on
+    // the next check.
+    for request in check.requests {
+        let reply = link.as_mut().and_then(|l| l.request(&request.parts()).ok());
+        if reply.is_none() {
+            link = None;
+        }
+        results.push((request, reply));
+    }
+    shared
+        .lock()
+        .unwrap()
+        .finish(&check.master, &check.target, link, results);
+}
+
+fn fields(pairs: Vec<(&str, String)>) -> Message {
+    Message::array(
+        pairs
+            .into_iter()
+            .flat_map(|(name, value)| {
+                [
+                    Message::bulk(name.as_bytes().to_vec()),
+                    Message::bulk(value.into_bytes()),
+                ]
+            })
+            .collect(),
+    )
+}
+
+fn flags(base: &str, instance: &Instance, odown: bool, failover: bool) -> String {
+    let mut flags = vec![base];
+    if instance.sdown {
+        flags.push("s_down");
+    }
+    if odown {
+        flags.push("o_down");
+    }
+    if failover {
+        flags.push("failover_in_progress");
+    }
+    flags.join(",")
+}
+
+fn master_fields(m: &Monitored) -> Message {
+    fields(vec![
+        ("name", m.name.clone()),
+        ("ip", m.primary.host.clone()),
+        ("port", m.primary.port.to_string()),
+        (
+            "flags",
+            flags("master", &m.primary, m.odown, m.failover != Failover::None),
+        ),
+        (
+            "last-ok-ping-reply",
+            m.primary.last_ok_ping.elapsed().as_millis().to_string(),
+        ),
+        ("num-slaves", m.replicas.len().to_string()),
+        ("num-other-sentinels", m.sentinels.len().to_string()),
+        ("quorum", m.quorum.to_string()),
+        (
+            "down-after-milliseconds",
+            m.down_after.as_millis().to_string(),
+        ),
+        (
+            "failover-timeout",
+            m.failover_timeout.as_millis().to_string(),
+        ),
+        ("config-epoch", m.config_epoch.to_string()),
+        ("failover-state", m.failover.name().to_string()),
+    ])
+}
+
+fn replica_fields(replica: &Instance) -> Message {
+    let info = replica.info.clone().unwrap_or_default();
+    let (master_host, master_port) = info
+        .primary
+        .map_or(("?".to_string(), 0), |(host, port)| (host, port));
+    fields(vec![
+        ("name", format!("{}:{}", replica.host, replica.port)),
+        ("ip", replica.host.clone()),
+        ("port", replica.port.to_string()),
+        ("flags", flags("slave", replica, false, false)),
+        (
+            "last-ok-ping-reply",
+            replica.last_ok_ping.elapsed().as_millis().to_string(),
+        ),
+        ("role-reported", info.role.clone()),
+        (
+            "master-link-status",
+            if info.link_up { "ok" } else { "err" }.to_string(),
+        ),
+        ("master-host", master_host),
+        ("master-port", master_port.to_string()),
+        ("slave-priority", info.priority.to_string()),
+        ("slave-repl-offset", info.offset.to_string()),
+    ])
+}
+
+fn sentinel_fields(peer: &Peer) -> Message {
+    let runid = peer.runid.clone().unwrap_or_default();
+    fields(vec![
+        ("name", runid.clone()),
+        ("ip", peer.instance.host.clone()),
+        ("port", peer.instance.port.to_string()),
+        ("runid", runid),
+        ("flags", flags("sentinel", &peer.instance, false, false)),
+        (
+            "last-ok-ping-reply",
+            peer.instance.last_ok_ping.elapsed().as_millis().to_string(),
+        ),
+        ("voted-leader", peer.leader.clone().unwrap_or("?".into())),
+        ("voted-leader-epoch", peer.leader_epoch.to_string()),
+    ])
+}
+
+fn no_such_master() -> Message {
+    Message::error("ERR No such master with that name")
+}
+
+impl Sentinel {
+    fn dispatch(&mut self, parts: &[String]) -> Message {
+        let Some(name) = parts.first() else {
+            return Message::error("ERR empty command");
+        };
+        match name.to_uppercase().as_str() {
+            "PING" => Message::simple("PONG"),
+            "ROLE" => Message::array(vec![
+                Message::bulk(b"sentinel".to_vec()),
+                Message::array(
+                    self.masters
+                        .iter()
+                        .map(|m| Message::bulk(m.name.clone().into_bytes()))
+                        .collect(),
+                ),
+            ]),
+            "INFO" => Message::bulk(self.info().into_bytes()),
+            "SENTINEL" if parts.len() > 1 => self.sentinel_command(&parts[1..]),
+            "SENTINEL" => Message::error("ERR wrong number of arguments for 'sentinel' command"),
+            _ => Message::error(format!(
+                "ERR unknown command '{}', with args beginning with: {}",
+                name,
+                parts[1..]
+                    .iter()
+                    .map(|arg| format!("'{}' ", arg))
+                    .collect::<String>()
+            )),
+        }
+    }
+
+    fn info(&self) -> String {
+        let mut lines = vec![
+            "# Sentinel".to_string(),
+            format!("sentinel_masters:{}", self.masters.len()),
+            format!("sentinel_current_epoch:{}", self.current_epoch),
+        ];
+        for (i, m) in self.masters.iter().enumerate() {
+            lines.push(format!(
+                "master{}:name={},status={},address={}:{},slaves={},sentinels={}",
+                i,
+                m.name,
+                if m.odown { "odown" } else { "ok" },
+                m.primary.host,
+                m.primary.port,
+                m.replicas.len(),
+                m.sentinels.len() + 1
+            ));
+        }
+        lines.join("\r\n") + "\r\n"
+    }
+
+    fn sentinel_command(&mut self, args: &[String]) -> Message {
+        let subcommand = args[0].to_lowercase();
+        match (subcommand.as_str(), &args[1..]) {
+            ("myid", []) => Message::bulk(self.myid.clone().into_bytes()),
+            ("masters", []) => Message::array(self.masters.iter().map(master_fields).collect()),
+            ("master", [name]) => self
+                .master(name)
+                .map_or_else(no_such_master, |m| master_fields(m)),
+            ("replicas" | "slaves", [name]) => self.master(name).map_or_else(no_such_master, |m| {
+                Message::array(m.replicas.iter().map(replica_fields).collect())
+            }),
+            ("sentinels", [name]) => self.master(name).map_or_else(no_such_master, |m| {
+                Message::array(m.sentinels.iter().map(sentinel_fields).collect())
+            }),
+            ("get-master-addr-by-name", [name]) => match self.master(name) {
+                Some(m) => {
+                    let (host, port) = m.current_addr();
+                    Message::array(vec![
+                        Message::bulk(host.into_bytes()),
+                        Message::bulk(port.to_string().into_bytes()),
+                    ])
+                }
+                None => Null,
+            },
+            ("is-master-down-by-addr", [ip, port, epoch, runid]) => {
+                let (Ok(port), Ok(epoch)) = (port.parse::<u16>(), epoch.parse::<u64>()) else {
+                    return Message::error("ERR value is not an integer or out of range");
+                };
+                let i = self.masters.iter().position(|m| m.primary.is(ip, port));
+                let down = i.is_some_and(|i| self.masters[i].primary.sdown);
+                let (leader, leader_epoch) = match i {
+                    Some(i) if runid != "*" => self.vote(i, epoch, runid),
+                    _ => (None, 0),
+                };
+                Message::array(vec![
+                    Message::integer(down as i64),
+                    Message::bulk(leader.unwrap_or("*".into()).into_bytes()),
+                    Message::integer(leader_epoch as i64),
+                ])
+            }
+            ("failover", [name]) => {
+                let Some(i) = self.masters.iter().position(|m| m.name == *name) else {
+                    return no_such_master();
+                };
+                let m = &mut self.masters[i];
+                if m.failover != Failover::None {
+                    return Message::error("INPROG Failover already in progress");
+                }
+                if m.select_replica().is_none() {
+                    return Message::error("NOGOODSLAVE No suitable replica to promote");
+                }
+                m.forced = true;
+                self.start_failover(i);
+                Message::simple("OK")
+            }
+            ("ckquorum", [name]) => {
+                let Some(m) = self.master(name) else {
+                    return no_such_master();
+                };
+                let voters = m.sentinels.len() + 1;
+                let usable = 1 + m.sentinels.iter().filter(|p| !p.instance.sdown).count();
+                if usable < m.quorum {
+                    Message::error(format!(
+                        "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the specified quorum for this master",
+                        usable
+                    ))
+                } else if usable <= voters / 2 {
+                    Message::error(format!(
+                        "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the majority and authorize a failover",
+                        usable
+                    ))
+                } else {
+                    Message::simple(format!(
+                        "OK {} usable Sentinels. Quorum and failover authorization can be reached",
+                        usable
+                    ))
+                }
+            }
+            ("hello", args) => self.hello(args),
+            _ => Message::error(format!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'",
+                args[0]
+            )),
+        }
+    }
+}
+
+fn handle_client(shared: Arc<Mutex<Sentinel>>, stream: TcpStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut resp = Resp::new(BufReader::new(stream));
+    loop {
+        let reply = match resp.read() {
+            Ok(Array(parts)) => {
+                let parts: Vec<String> = parts
+                    .iter()
+                    .map(|part| match part {
+                        Bulk(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+                        _ => String::new(),
+                    })
+                    .collect();
+                shared.lock().unwrap().dispatch(&parts)
+            }
+            Ok(_) => Message::error("Protocol error: expected '*'"),
+            Err(err) if err.kind() == ErrorKind::InvalidInput => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&reply.marshal())?;
+    }
+}
+
+// Parses the command line, whose options mirror sentinel.conf directives.
+fn parse_args(args: &[String]) -> Result<Sentinel, String> {
+    let mut sentinel = Sentinel::new(DEFAULT_PORT);
+    let mut rest = args;
+    while let Some((option, tail)) = rest.split_first() {
+        let values = |n: usize| {
+            tail.get(..n)
+                .ok_or_else(|| format!("missing value for '{}'", option))
+        };
+        let port = |value: &str| {
+            value
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port '{}'", value))
+        };
+        let millis = |value: &str| {
+            value
+                .parse::<u64>()
+                .map(Duration::from_millis)
+                .map_err(|_| format!("invalid milliseconds '{}'", value))
+        };
+        let used = match option.as_str() {
+            "--port" => {
+                sentinel.port = port(&values(1)?[0])?;
+                1
+            }
+            "--monitor" => {
+                let [name, host, p, quorum] = values(4)? else {
+                    unreachable!()
+                };
+                let quorum = quorum
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&quorum| quorum > 0)
+                    .ok_or_else(|| "Quorum must be 1 or greater.".to_string())?;
+                if sentinel.master(name).is_some() {
+                    return Err(format!("Duplicated master name '{}'", name));
+                }
+                sentinel
+                    .masters
+                    .push(Monitored::new(name, host, port(p)?, quorum));
+                4
+            }
+            "--down-after-milliseconds" | "--failover-timeout" => {
+                let [name, ms] = values(2)? else {
+                    unreachable!()
+                };
+                let ms = millis(ms)?;
+                let m = sentinel
+                    .master(name)
+                    .ok_or("No such master with specified name.")?;
+                if option == "--down-after-milliseconds" {
+                    m.down_after = ms;
+                } else {
+                    m.failover_timeout = ms;
+                }
+                2
+            }
+            "--known-sentinel" => {
+                let [name, host, p] = values(3)? else {
+                    unreachable!()
+                };
+                let p = port(p)?;
+                let m = sentinel
+                    .master(name)
+                    .ok_or("No such master with specified name.")?;
+                m.sentinels.push(Peer::new(host, p));
+                3
+            }
+            _ => return Err(format!("unknown option '{}'", option)),
+        };
+        rest = &tail[used..];
+    }
+    Ok(sentinel)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let sentinel = match parse_args(&args) {
+        Ok(sentinel) => sentinel,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!(
+                "Usage: rustis-sentinel [--port <port>] --monitor <name> <host> <port> <quorum> \
+                 [--down-after-milliseconds <name> <ms>] [--failover-timeout <name> <ms>] \
+                 [--known-sentinel <name> <host> <port>]..."
+            );
+            return ExitCode::from(2);
+        }
+    };
+    let listener = match TcpListener::bind(("127.0.0.1", sentinel.port)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Could not listen on port {}: {}", sentinel.port, err);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("Sentinel ID is {}", sentinel.myid);
+    for m in &sentinel.masters {
+        println!("+monitor {} quorum {}", m.describe(), m.quorum);
+    }
+
+    let shared = Arc::new(Mutex::new(sentinel));
+    let timer = shared.clone();
+    spawn(move || loop {
+        sleep(TICK);
+        let checks = timer.lock().unwrap().tick();
+        for check in checks {
+            let shared = timer.clone();
+            spawn(move || run_check(&shared, check));
+        }
+    });
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let shared = shared.clone();
+        spawn(move || {
+            let _ = handle_client(shared, stream);
+        });
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(parts: &[&str]) -> Vec<String> {
+        parts.iter().map(|p| p.to_string()).collect()
+    }
+
+    fn replica(port: u16, priority: u64, offset: u64) -> Instance {
+        let mut replica = Instance::new("127.0.0.1", port);
+        replica.info = Some(ReplInfo {
+            role: "slave".into(),
+            primary: Some(("127.0.0.1".into(), 6379)),
+            link_up: true,
+            offset,
+            priority,
+            replicas: vec![],
+        });
+        replica.info_refreshed = Some(Instant::now());
+        replica
+    }
+
+    fn sentinel() -> Sentinel {
+        parse_args(&args(&[
+            "--monitor",
+            "m",
+            "127.0.0.1",
+            "6379",
+            "2",
+            "--known-sentinel",
+            "m",
+            "127.0.0.1",
+            "26380",
+            "--known-sentinel",
+            "m",
+            "127.0.0.1",
+            "26381",
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_info() {
+        let primary = parse_info(
+            "# Replication\r\nrole:master\r\nconnected_slaves:2\r\n\
+             slave0:ip=127.0.0.1,port=6380,state=online,offset=10,lag=0\r\n\
+             slave1:ip=127.0.0.1,port=6381,state=online,offset=10,lag=1\r\n\
+             master_replid:abc\r\n",
+        );
+        assert_eq!(primary.role, "master");
+        assert_eq!(
+            primary.replicas,
+            vec![("127.0.0.1".into(), 6380), ("127.0.0.1".into(), 6381)]
+        );
+
+        let replica = parse_info(
+            "role:slave\r\nmaster_host:127.0.0.1\r\nmaster_port:6379\r\n\
+             master_link_status:up\r\nslave_repl_offset:42\r\nslave_priority:10\r\n",
+        );
+        assert_eq!(replica.primary, Some(("127.0.0.1".into(), 6379)));
+        assert!(replica.link_up);
+        assert_eq!((replica.offset, replica.priority), (42, 10));
+        assert_eq!(parse_info("role:slave").priority, 100);
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let sentinel = parse_args(&args(&[
+            "--port",
+            "26380",
+            "--monitor",
+            "m",
+            "127.0.0.1",
+            "6379",
+            "2",
+            "--down-after-milliseconds",
+            "m",
+            "500",
+            "--failover-timeout",
+            "m",
+            "1000",
+        ]))
+        .unwrap();
+        assert_eq!(sentinel.port, 26380);
+        let m = &sentinel.masters[0];
+        assert_eq!((m.name.as_str(), m.primary.port, m.quorum), ("m", 6379, 2));
+        assert_eq!(m.down_after, Duration::from_millis(500));
+        assert_eq!(m.failover_timeout, Duration::from_millis(1000));
+
+        assert!(parse_args(&args(&["--monitor", "m", "h", "1", "0"])).is_err());
+        assert!(parse_args(&args(&["--monitor", "m", "h"])).is_err());
+        assert!(parse_args(&args(&["--failover-timeout", "x", "1"])).is_err());
+    }
+
+    #[test]
+    fn test_select_replica() {
+        let mut m = Monitored::new("m", "127.0.0.1", 6379, 1);
+        m.replicas = vec![replica(6380, 100, 10), replica(6381, 100, 20)];
+        // The one furthest along wins among equal priorities...
+        assert_eq!(m.select_replica(), Some(("127.0.0.1".into(), 6381)));
+        // ...but a lower priority comes first, and 0 means never.
+        m.replicas.push(replica(6382, 50, 0));
+        assert_eq!(m.select_replica(), Some(("127.0.0.1".into(), 6382)));
+        m.replicas[2].info.as_mut().unwrap().priority = 0;
+        m.replicas[1].sdown = true;
+        assert_eq!(m.select_replica(), Some(("127.0.0.1".into(), 6380)));
+        m.replicas[0].info_refreshed = None;
+        assert_eq!(m.select_replica(), None);
+    }
+
+    #[test]
+    fn test_vote_once_per_epoch() {
+        let mut sentinel = sentinel();
+        assert_eq!(sentinel.vote(0, 1, "a"), (Some("a".into()), 1));
+        assert_eq!(sentinel.current_epoch, 1);
+        // Later candidates in the same epoch learn whom the vote went to.
+        assert_eq!(sentinel.vote(0, 1, "b"), (Some("a".into()), 1));
+        assert_eq!(sentinel.vote(0, 2, "b"), (Some("b".into()), 2));
+        // Having voted for a peer holds off our own attempt.
+        assert!(sentinel.masters[0].failover_start.unwrap() > Instant::now());
+    }
+
+    #[test]
+    fn test_leader_needs_majority_and_quorum() {
+        let mut sentinel = sentinel();
+        let myid = sentinel.myid.clone();
+        sentinel.current_epoch = 1;
+        // Nobody else voted: our own vote is 1 of 3.
+        assert_eq!(sentinel.leader(0, 1), None);
+        sentinel.masters[0].sentinels[0].leader = Some(myid.clone());
+        sentinel.masters[0].sentinels[0].leader_epoch = 1;
+        assert_eq!(sentinel.leader(0, 1), Some(myid));
+
+        // A peer that got there first gets our vote too.
+        let mut sentinel = self::sentinel();
+        for peer in &mut sentinel.masters[0].sentinels[..1] {
+            peer.leader = Some("other".into());
+            peer.leader_epoch = 1;
+        }
+        assert_eq!(sentinel.leader(0, 1), Some("other".into()));
+    }
+
+    #[test]
+    fn test_is_master_down_by_addr() {
+        let mut sentinel = sentinel();
+        let ask = |sentinel: &mut Sentinel, runid: &str| {
+            sentinel.dispatch(&args(&[
+                "SENTINEL",
+                "is-master-down-by-addr",
+                "127.0.0.1",
+                "6379",
+                "3",
+                runid,
+            ]))
+        };
+        assert_eq!(
+            ask(&mut sentinel, "*"),
+            Message::array(vec![
+                Message::integer(0),
+                Message::bulk(b"*".to_vec()),
+                Message::integer(0)
+            ])
+        );
+        sentinel.masters[0].primary.sdown = true;
+        assert_eq!(
+            ask(&mut sentinel, "peer"),
+            Message::array(vec![
+                Message::integer(1),
+                Message::bulk(b"peer".to_vec()),
+                Message::integer(3)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_hello_with_newer_config_switches_primary() {
+        let mut sentinel = sentinel();
+        sentinel.masters[0].replicas = vec![replica(6380, 100, 0), replica(6381, 100, 0)];
+        let hello = |epoch: &str, port: &str| {
+            args(&[
+                "SENTINEL",
+                "HELLO",
+                "127.0.0.1",
+                "26390",
+                "peer",
+                "5",
+                "m",
+                "127.0.0.1",
+                port,
+                epoch,
+            ])
+        };
+        assert_eq!(
+            sentinel.dispatch(&hello("0", "6380")),
+            Message::simple("OK")
+        );
+        // An unknown sender is added as a peer; an old config changes nothing.
+        assert_eq!(sentinel.masters[0].sentinels.len(), 3);
+        assert_eq!(sentinel.current_epoch, 5);
+        assert_eq!(sentinel.masters[0].primary.port, 6379);
+
+        sentinel.dispatch(&hello("4", "6380"));
+        let m = &sentinel.masters[0];
+        assert_eq!((m.primary.port, m.config_epoch), (6380, 4));
+        let mut replicas: Vec<u16> = m.replicas.iter().map(|r| r.port).collect();
+        replicas.sort();
+        assert_eq!(replicas, vec![6379, 6381]);
+        assert_eq!(
+            sentinel.dispatch(&args(&["SENTINEL", "get-master-addr-by-name", "m"])),
+            Message::array(vec![
+                Message::bulk(b"127.0.0.1".to_vec()),
+                Message::bulk(b"6380".to_vec())
+            ])
+        );
+        assert_eq!(
+            sentinel.dispatch(&args(&["SENTINEL", "get-master-addr-by-name", "x"])),
+            Null
+        );
+    }
+
+    #[test]
+    fn test_forced_failover_reaches_promotion() {
+        let mut sentinel = sentinel();
+        assert_eq!(
+            sentinel.dispatch(&args(&["SENTINEL", "failover", "m"])),
+            Message::error("NOGOODSLAVE No suitable replica to promote")
+        );
+        sentinel.masters[0].replicas = vec![replica(6380, 100, 0)];
+        assert_eq!(
+            sentinel.dispatch(&args(&["SENTINEL", "failover", "m"])),
+            Message::simple("OK")
+        );
+        sentinel.step_failover(0);
+        sentinel.step_failover(0);
+        let m = &sentinel.masters[0];
+        assert_eq!(m.failover, Failover::PromoteReplica);
+        assert_eq!(m.promoted, Some(("127.0.0.1".into(), 6380)));
+        assert_eq!(
+            sentinel.dispatch(&args(&["SENTINEL", "failover", "m"])),
+            Message::error("INPROG Failover already in progress")
+        );
+    }
+}
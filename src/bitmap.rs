@@ -0,0 +1,893 @@
+use crate::handlers::{parse_int, SetMap};
+use crate::message::Message;
+use crate::message::Message::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Unit {
+    Byte,
+    Bit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Encoding {
+    signed: bool,
+    bits: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FieldOp {
+    Get,
+    Set(i64),
+    IncrBy(i64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FieldCommand {
+    op: FieldOp,
+    encoding: Encoding,
+    offset: usize,
+    overflow: Overflow,
+}
+
+pub fn setbit(args: Vec<Message>, sets: &SetMap, max_bulk_len: usize) -> Message {
+    match args.as_slice() {
+        [Bulk(key), Bulk(offset), Bulk(bit)] => {
+            let offset = match parse_int(offset) {
+                Some(o) if o >= 0 && ((o as u64) >> 3) < max_bulk_len as u64 => o as usize,
+                _ => return Message::error("ERR bit offset is not an integer or out of range"),
+            };
+            let bit = match bit.as_slice() {
+                b"0" => false,
+                b"1" => true,
+                _ => return Message::error("ERR bit is not an integer or out of range"),
+            };
+            let mut sets = sets.lock().unwrap();
+            let value = sets.entry(key.clone()).or_default();
+            let byte = offset >> 3;
+            if value.len() <= byte {
+                value.resize(byte + 1, 0);
+            }
+            let mask = 1u8 << (7 - (offset & 7));
+            let previous = value[byte] & mask != 0;
+            if bit {
+                value[byte] |= mask;
+            } else {
+                value[byte] &= !mask;
+            }
+            Message::integer(previous as i64)
+        }
+        _ => Message::error("ERR wrong number of arguments for 'setbit' command"),
+    }
+}
+
+pub fn getbit(args: Vec<Message>, sets: &SetMap) -> Message {
+    match args.as_slice() {
+        [Bulk(key), Bulk(offset)] => {
+            let offset = match parse_int(offset) {
+                Some(o) if o >= 0 => o as usize,
+                _ => return Message::error("ERR bit offset is not an integer or out of range"),
+            };
+            let sets = sets.lock().unwrap();
+            let bit = sets
+                .get(key)
+                .and_then(|value| value.get(offset >> 3))
+                .map(|byte| (byte >> (7 - (offset & 7))) & 1)
+                .unwrap_or(0);
+            Message::integer(bit as i64)
+        }
+        _ => Message::error("ERR wrong number of arguments for 'getbit' command"),
+    }
+}
+
+pub fn bitcount(args: Vec<Message>, sets: &SetMap) -> Message {
+    let (key, range) = match args.as_slice() {
+        [Bulk(key)] => (key, None),
+        [Bulk(key), Bulk(start), Bulk(end), rest @ ..] if rest.len() <= 1 => {
+            let unit = match parse_unit(rest.first()) {
+                Some(unit) => unit,
+                None => return Message::error("ERR syntax error"),
+            };
+            match (parse_int(start), parse_int(end)) {
+                (Some(start), Some(end)) => (key, Some((start, end, unit))),
+                _ => return Message::error("ERR value is not an integer or out of range"),
+            }
+        }
+        [Bulk(_), _, ..] => return Message::error("ERR syntax error"),
+        _ => return Message::error("ERR wrong number of arguments for 'bitcount' command"),
+    };
+    let sets = sets.lock().unwrap();
+    let value = match sets.get(key) {
+        Some(value) => value,
+        None => return Message::integer(0),
+    };
+    let (start, end, unit) = range.unwrap_or((0, -1, Unit::Byte));
+    let count = match bit_range(value.len(), start, end, unit) {
+        Some((first, last)) => count_bits(value, first, last),
+        None => 0,
+    };
+    Message::integer(count as i64)
+}
+
+pub fn bitpos(args: Vec<Message>, sets: &SetMap) -> Message {
+    let (key, bit, rest) = match args.as_slice() {
+        [Bulk(key), Bulk(bit), rest @ ..] if rest.len() <= 3 => (key, bit, rest),
+        _ => return Message::error("ERR wrong number of arguments for 'bitpos' command"),
+    };
+    let bit = match bit.as_slice() {
+        b"0" => false,
+        b"1" => true,
+        _ => return Message::error("ERR The bit argument must be 1 or 0."),
+    };
+    let mut bounds = Vec::with_capacity(2);
+    for arg in rest.iter().take(2) {
+        match arg {
+            Bulk(n) => match parse_int(n) {
+                Some(n) => bounds.push(n),
+                None => return Message::error("ERR value is not an integer or out of range"),
+            },
+            _ => return Message::error("ERR syntax error"),
+        }
+    }
+    let unit = match parse_unit(rest.get(2)) {
+        Some(unit) => unit,
+        None => return Message::error("ERR syntax error"),
+    };
+    let end_given = bounds.len() == 2;
+
+    let sets = sets.lock().unwrap();
+    let value = match sets.get(key) {
+        Some(value) => value,
+        None => return Message::integer(if bit { -1 } else { 0 }),
+    };
+    let start = bounds.first().copied().unwrap_or(0);
+    let end = bounds.get(1).copied().unwrap_or(-1);
+    let (first, last) = match bit_range(value.len(), start, end, unit) {
+        Some(range) => range,
+        None => return Message::integer(-1),
+    };
+    match find_bit(value, bit, first, last) {
+        Some(pos) => Message::integer(pos as i64),
+        // Without an explicit end the string is treated as padded with zeros on the right.
+        None if !bit && !end_given => Message::integer(value.len() as i64 * 8),
+        None => Message::integer(-1),
+    }
+}
+
+pub fn bitop(args: Vec<Message>, sets: &SetMap) -> Message {
+    let (op, dest, keys) = match args.as_slice() {
+        [Bulk(op), Bulk(dest), keys @ ..] if !keys.is_empty() => (op, dest, keys),
+        _ => return Message::error("ERR wrong number of arguments for 'bitop' command"),
+    };
+    let op = String::from_utf8_lossy(op).to_uppercase();
+    match op.as_str() {
+        "AND" | "OR" | "XOR" => {}
+        "NOT" if keys.len() != 1 => {
+            return Message::error("ERR BITOP NOT must be called with a single source key.")
+        }
+        "DIFF" if keys.len() < 2 => {
+            return Message::error("ERR BITOP DIFF must be called with at least two source keys.")
+        }
+        "NOT" | "DIFF" => {}
+        _ => return Message::error("ERR syntax error"),
+    }
+
+    let mut sets = sets.lock().unwrap();
+    let mut sources = Vec::with_capacity(keys.len());
+    for key in keys {
+        match key {
+            Bulk(key) => sources.push(sets.get(key).cloned().unwrap_or_default()),
+            _ => return Message::error("ERR syntax error"),
+        }
+    }
+    let len = sources.iter().map(Vec::len).max().unwrap_or(0);
+    // Shorter sources behave as if they were padded with zero bytes.
+    let byte = |src: &Vec<u8>, i: usize| src.get(i).copied().unwrap_or(0);
+    let result: Vec<u8> = (0..len)
+        .map(|i| {
+            let first = byte(&sources[0], i);
+            let others = sources[1..].iter().map(|src| byte(src, i));
+            match op.as_str() {
+                "AND" => others.fold(first, |acc, b| acc & b),
+                "OR" => others.fold(first, |acc, b| acc | b),
+                "XOR" => others.fold(first, |acc, b| acc ^ b),
+                "NOT" => !first,
+                // Bits set in the first key and in none of the others.
+                _ => first & !others.fold(0, |acc, b| acc | b),
+            }
+        })
+        .collect();
+
+    if result.is_empty() {
+        sets.remove(dest);
+    } else {
+        sets.insert(dest.clone(), result);
+    }
+    Message::integer(len as i64)
+}
+
+pub fn bitfield(args: Vec<Message>, sets: &SetMap, max_bulk_len: usize) -> Message {
+    let (key, commands) = match args.as_slice() {
+        [Bulk(key), rest @ ..] => match parse_field_commands(rest, max_bulk_len) {
+            Ok(commands) => (key, commands),
+            Err(err) => return err,
+        },
+        _ => return Message::error("ERR wrong number of arguments for 'bitfield' command"),
+    };
+    let mut sets = sets.lock().unwrap();
+    let writes = commands.iter().any(|c| c.op != FieldOp::Get);
+    if !writes {
+        let value = sets.get(key).map(Vec::as_slice).unwrap_or_default();
+        return Message::array(commands.iter().map(|c| read_field(value, c)).collect());
+    }
+
+    // A missing key only comes into being when a field is written, not
+    // when every write failed the OVERFLOW FAIL check.
+    let existed = sets.contains_key(key);
+    let mut wrote = false;
+    let value = sets.entry(key.clone()).or_default();
+    let mut replies = Vec::with_capacity(commands.len());
+    for command in commands {
+        let reply = match command.op {
+            FieldOp::Get => read_field(value, &command),
+            FieldOp::Set(new) => {
+                let old = get_field(value, command.offset, command.encoding);
+                match overflow(new as i128, command.encoding, command.overflow) {
+                    Some(new) => {
+                        set_field(value, command.offset, command.encoding, new);
+                        wrote = true;
+                        Message::integer(old)
+                    }
+                    None => Message::Null,
+                }
+            }
+            FieldOp::IncrBy(incr) => {
+                let old = get_field(value, command.offset, command.encoding);
+                match overflow(
+                    old as i128 + incr as i128,
+                    command.encoding,
+                    command.overflow,
+                ) {
+                    Some(new) => {
+                        set_field(value, command.offset, command.encoding, new);
+                        wrote = true;
+                        Message::integer(new)
+                    }
+                    None => Message::Null,
+                }
+            }
+        };
+        replies.push(reply);
+    }
+    if !existed && !wrote {
+        sets.remove(key);
+    }
+    Message::array(replies)
+}
+
+pub fn bitfield_ro(args: Vec<Message>, sets: &SetMap, max_bulk_len: usize) -> Message {
+    let (key, commands) = match args.as_slice() {
+        [Bulk(key), rest @ ..] => match parse_field_commands(rest, max_bulk_len) {
+            Ok(commands) => (key, commands),
+            Err(err) => return err,
+        },
+        _ => return Message::error("ERR wrong number of arguments for 'bitfield_ro' command"),
+    };
+    if commands.iter().any(|c| c.op != FieldOp::Get) {
+        return Message::error("ERR BITFIELD_RO only supports the GET subcommand");
+    }
+    let sets = sets.lock().unwrap();
+    let value = sets.get(key).map(Vec::as_slice).unwrap_or_default();
+    Message::array(commands.iter().map(|c| read_field(value, c)).collect())
+}
+
+// Validates every subcommand up front so that a bad argument late in the
+// list leaves the value untouched.
+fn parse_field_commands(
+    args: &[Message],
+    max_bulk_len: usize,
+) -> Result<Vec<FieldCommand>, Message> {
+    let mut commands = Vec::new();
+    let mut overflow_mode = Overflow::Wrap;
+    let mut i = 0;
+    while i < args.len() {
+        let name = match &args[i] {
+            Bulk(name) => name.to_ascii_uppercase(),
+            _ => return Err(Message::error("ERR syntax error")),
+        };
+        let arity = match name.as_slice() {
+            b"OVERFLOW" if i + 1 < args.len() => 1,
+            b"GET" if i + 2 < args.len() => 2,
+            b"SET" | b"INCRBY" if i + 3 < args.len() => 3,
+            _ => return Err(Message::error("ERR syntax error")),
+        };
+        let params: Vec<&[u8]> = args[i + 1..=i + arity]
+            .iter()
+            .map(|arg| match arg {
+                Bulk(b) => b.as_slice(),
+                _ => b"",
+            })
+            .collect();
+        i += arity + 1;
+
+        if name == b"OVERFLOW" {
+            overflow_mode = match params[0].to_ascii_uppercase().as_slice() {
+                b"WRAP" => Overflow::Wrap,
+                b"SAT" => Overflow::Sat,
+                b"FAIL" => Overflow::Fail,
+                _ => return Err(Message::error("ERR Invalid OVERFLOW type specified")),
+            };
+            continue;
+        }
+        let encoding = parse_encoding(params[0]).ok_or_else(|| {
+            Message::error(
+                "ERR Invalid bitfield type. Use something like i16 u8. \
+                 Note that u64 is not supported but i64 is.",
+            )
+        })?;
+        let offset = parse_field_offset(params[1], encoding, max_bulk_len)
+            .ok_or_else(|| Message::error("ERR bit offset is not an integer or out of range"))?;
+        let op = match name.as_slice() {
+            b"GET" => FieldOp::Get,
+            _ => {
+                let n = parse_int(params[2])
+                    .ok_or_else(|| Message::error("ERR value is not an integer or out of range"))?;
+                if name == b"SET" {
+                    FieldOp::Set(n)
+                } else {
+                    FieldOp::IncrBy(n)
+                }
+            }
+        };
+        commands.push(FieldCommand {
+            op,
+            encoding,
+            offset,
+            overflow: overflow_mode,
+        });
+    }
+    Ok(commands)
+}
+
+fn parse_encoding(arg: &[u8]) -> Option<Encoding> {
+    let (signed, bits) = match arg.split_first()? {
+        (b'i' | b'I', bits) => (true, bits),
+        (b'u' | b'U', bits) => (false, bits),
+        _ => return None,
+    };
+    let bits = str::from_utf8(bits).ok()?.parse::<u32>().ok()?;
+    match (signed, bits) {
+        (true, 1..=64) | (false, 1..=63) => Some(Encoding { signed, bits }),
+        _ => None,
+    }
+}
+
+// Offsets prefixed with `#` are multiplied by the width of the field.
+fn parse_field_offset(arg: &[u8], encoding: Encoding, max_bulk_len: usize) -> Option<usize> {
+    let offset = match arg.strip_prefix(b"#") {
+        Some(index) => parse_int(index)?.checked_mul(encoding.bits as i64)?,
+        None => parse_int(arg)?,
+    };
+    let last_bit = offset.checked_add(encoding.bits as i64 - 1)?;
+    if offset < 0 || (last_bit as u64 >> 3) >= max_bulk_len as u64 {
+        return None;
+    }
+    Some(offset as usize)
+}
+
+fn read_field(value: &[u8], command: &FieldCommand) -> Message {
+    Message::integer(get_field(value, command.offset, command.encoding))
+}
+
+fn get_field(value: &[u8], offset: usize, encoding: Encoding) -> i64 {
+    let mut raw: u64 = 0;
+    for pos in offset..offset + encoding.bits as usize {
+        let bit = value
+            .get(pos >> 3)
+            .map(|byte| (byte >> (7 - (pos & 7))) & 1)
+            .unwrap_or(0);
+        raw = (raw << 1) | bit as u64;
+    }
+    if encoding.signed && encoding.bits < 64 && raw >> (encoding.bits - 1) & 1 == 1 {
+        (raw as i64) - (1i64 << encoding.bits)
+    } else {
+        raw as i64
+    }
+}
+
+fn set_field(value: &mut Vec<u8>, offset: usize, encoding: Encoding, field: i64) {
+    let last_byte = (offset + encoding.bits as usize - 1) >> 3;
+    if value.len() <= last_byte {
+        value.resize(last_byte + 1, 0);
+    }
+    let raw = field as u64;
+    for (i, pos) in (offset..offset + encoding.bits as usize).enumerate() {
+        let mask = 1u8 << (7 - (pos & 7));
+        if (raw >> (encoding.bits as usize - 1 - i)) & 1 == 1 {
+            value[pos >> 3] |= mask;
+        } else {
+            value[pos >> 3] &= !mask;
+        }
+    }
+}
+
+// Applies the overflow policy to a result that may not fit the encoding,
+// returning None when FAIL rejects it.
+fn overflow(value: i128, encoding: Encoding, mode: Overflow) -> Option<i64> {
+    let bits = encoding.bits;
+    let (min, max) = if encoding.signed {
+        (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1)
+    } else {
+        (0, (1i128 << bits) - 1)
+    };
+    if (min..=max).contains(&value) {
+        return Some(value as i64);
+    }
+    match mode {
+        Overflow::Fail => None,
+        Overflow::Sat => Some(value.clamp(min, max) as i64),
+        Overflow::Wrap => {
+            let wrapped = value.rem_euclid(1i128 << bits);
+            if encoding.signed && wrapped > max {
+                Some((wrapped - (1i128 << bits)) as i64)
+            } else {
+                Some(wrapped as i64)
+            }
+        }
+    }
+}
+
+fn parse_unit(arg: Option<&Message>) -> Option<Unit> {
+    match arg {
+        None => Some(Unit::Byte),
+        Some(Bulk(unit)) => match unit.to_ascii_uppercase().as_slice() {
+            b"BYTE" => Some(Unit::Byte),
+            b"BIT" => Some(Unit::Bit),
+            _ => None,
+        },
+        Some(_) => None,
+    }
+}
+
+// Resolves a possibly negative start/end pair against a value of `len` bytes
+// into an inclusive range of bit positions.
+fn bit_range(len: usize, start: i64, end: i64, unit: Unit) -> Option<(usize, usize)> {
+    let total = match unit {
+        Unit::Byte => len as i64,
+        Unit::Bit => len as i64 * 8,
+    };
+    let start = if start < 0 { total + start } else { start }.max(0);
+    let end = if end < 0 { total + end } else { end }
+        .max(0)
+        .min(total - 1);
+    if total == 0 || start > end {
+        return None;
+    }
+    match unit {
+        Unit::Byte => Some((start as usize * 8, end as usize * 8 + 7)),
+        Unit::Bit => Some((start as usize, end as usize)),
+    }
+}
+
+fn count_bits(bytes: &[u8], first: usize, last: usize) -> usize {
+    let (first_byte, last_byte) = (first / 8, last / 8);
+    let head_mask = 0xFFu8 >> (first % 8);
+    let tail_mask = 0xFFu8 << (7 - last % 8);
+    if first_byte == last_byte {
+        return (bytes[first_byte] & head_mask & tail_mask).count_ones() as usize;
+    }
+    let middle: usize = bytes[first_byte + 1..last_byte]
+        .iter()
+        .map(|b| b.count_ones() as usize)
+        .sum();
+    (bytes[first_byte] & head_mask).count_ones() as usize
+        + middle
+        + (bytes[last_byte] & tail_mask).count_ones() as usize
+}
+
+fn find_bit(bytes: &[u8], bit: bool, first: usize, last: usize) -> Option<usize> {
+    let skip = if bit { 0x00 } else { 0xFF };
+    let mut pos = first;
+    while pos <= last {
+        let byte = bytes[pos / 8];
+        if pos.is_multiple_of(8) && pos + 7 <= last && byte == skip {
+            pos += 8;
+            continue;
+        }
+        if ((byte >> (7 - pos % 8)) & 1 == 1) == bit {
+            return Some(pos);
+        }
+        pos += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    fn with_value(key: &str, value: &[u8]) -> SetMap {
+        let sets = Mutex::new(HashMap::new());
+        sets.lock()
+            .unwrap()
+            .insert(key.as_bytes().to_vec(), value.to_vec());
+        sets
+    }
+
+    #[test]
+    fn test_setbit_grows_value() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        let result = setbit(Message::bulks(&["foo", "17", "1"]), &sets, 1024);
+        assert_eq!(result, Message::integer(0));
+        assert_eq!(sets.lock().unwrap()[b"foo".as_slice()], vec![0, 0, 0x40]);
+    }
+
+    #[test]
+    fn test_setbit_returns_previous_bit() {
+        let sets = with_value("foo", &[0x80]);
+        assert_eq!(
+            setbit(Message::bulks(&["foo", "0", "0"]), &sets, 1024),
+            Message::integer(1)
+        );
+        assert_eq!(sets.lock().unwrap()[b"foo".as_slice()], vec![0]);
+    }
+
+    #[test]
+    fn test_setbit_offset_over_cap() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        let result = setbit(Message::bulks(&["foo", "8", "1"]), &sets, 1);
+        assert_eq!(
+            result,
+            Message::error("ERR bit offset is not an integer or out of range")
+        );
+        assert!(sets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_setbit_invalid_bit() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        let result = setbit(Message::bulks(&["foo", "0", "2"]), &sets, 1024);
+        assert_eq!(
+            result,
+            Message::error("ERR bit is not an integer or out of range")
+        );
+    }
+
+    #[test]
+    fn test_getbit() {
+        let sets = with_value("foo", &[0x40]);
+        assert_eq!(
+            getbit(Message::bulks(&["foo", "1"]), &sets),
+            Message::integer(1)
+        );
+        assert_eq!(
+            getbit(Message::bulks(&["foo", "2"]), &sets),
+            Message::integer(0)
+        );
+        assert_eq!(
+            getbit(Message::bulks(&["foo", "100"]), &sets),
+            Message::integer(0)
+        );
+        assert_eq!(
+            getbit(Message::bulks(&["bar", "1"]), &sets),
+            Message::integer(0)
+        );
+    }
+
+    #[test]
+    fn test_bitcount() {
+        let sets = with_value("foo", b"foobar");
+        assert_eq!(
+            bitcount(Message::bulks(&["foo"]), &sets),
+            Message::integer(26)
+        );
+        assert_eq!(
+            bitcount(Message::bulks(&["foo", "0", "0"]), &sets),
+            Message::integer(4)
+        );
+        assert_eq!(
+            bitcount(Message::bulks(&["foo", "1", "1"]), &sets),
+            Message::integer(6)
+        );
+        assert_eq!(
+            bitcount(Message::bulks(&["foo", "1", "1", "BYTE"]), &sets),
+            Message::integer(6)
+        );
+        assert_eq!(
+            bitcount(Message::bulks(&["foo", "5", "30", "BIT"]), &sets),
+            Message::integer(17)
+        );
+        assert_eq!(
+            bitcount(Message::bulks(&["foo", "-2", "-1"]), &sets),
+            Message::integer(7)
+        );
+        assert_eq!(
+            bitcount(Message::bulks(&["foo", "3", "1"]), &sets),
+            Message::integer(0)
+        );
+        assert_eq!(
+            bitcount(Message::bulks(&["bar"]), &sets),
+            Message::integer(0)
+        );
+    }
+
+    #[test]
+    fn test_bitcount_syntax_error() {
+        let sets = with_value("foo", b"foobar");
+        assert_eq!(
+            bitcount(Message::bulks(&["foo", "1"]), &sets),
+            Message::error("ERR syntax error")
+        );
+        assert_eq!(
+            bitcount(Message::bulks(&["foo", "1", "2", "WORD"]), &sets),
+            Message::error("ERR syntax error")
+        );
+    }
+
+    #[test]
+    fn test_bitpos() {
+        let sets = with_value("foo", &[0xFF, 0xF0, 0x00]);
+        assert_eq!(
+            bitpos(Message::bulks(&["foo", "0"]), &sets),
+            Message::integer(12)
+        );
+        assert_eq!(
+            bitpos(Message::bulks(&["foo", "1", "2"]), &sets),
+            Message::integer(-1)
+        );
+        assert_eq!(
+            bitpos(Message::bulks(&["foo", "1", "7", "15", "BIT"]), &sets),
+            Message::integer(7)
+        );
+        assert_eq!(
+            bitpos(Message::bulks(&["foo", "0", "0", "0"]), &sets),
+            Message::integer(-1)
+        );
+    }
+
+    #[test]
+    fn test_bitpos_clear_bit_past_end() {
+        let sets = with_value("foo", &[0xFF, 0xFF]);
+        assert_eq!(
+            bitpos(Message::bulks(&["foo", "0"]), &sets),
+            Message::integer(16)
+        );
+        assert_eq!(
+            bitpos(Message::bulks(&["foo", "0", "0", "-1"]), &sets),
+            Message::integer(-1)
+        );
+    }
+
+    #[test]
+    fn test_bitpos_missing_key() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        assert_eq!(
+            bitpos(Message::bulks(&["foo", "0"]), &sets),
+            Message::integer(0)
+        );
+        assert_eq!(
+            bitpos(Message::bulks(&["foo", "1"]), &sets),
+            Message::integer(-1)
+        );
+    }
+
+    #[test]
+    fn test_bitop() {
+        let sets = with_value("a", &[0b1100, 0xFF]);
+        sets.lock().unwrap().insert(b"b".to_vec(), vec![0b1010]);
+        let get = |key: &str| sets.lock().unwrap().get(key.as_bytes()).cloned();
+
+        assert_eq!(
+            bitop(Message::bulks(&["AND", "d", "a", "b"]), &sets),
+            Message::integer(2)
+        );
+        assert_eq!(get("d"), Some(vec![0b1000, 0]));
+        bitop(Message::bulks(&["or", "d", "a", "b"]), &sets);
+        assert_eq!(get("d"), Some(vec![0b1110, 0xFF]));
+        bitop(Message::bulks(&["XOR", "d", "a", "b"]), &sets);
+        assert_eq!(get("d"), Some(vec![0b0110, 0xFF]));
+        bitop(Message::bulks(&["NOT", "d", "b"]), &sets);
+        assert_eq!(get("d"), Some(vec![!0b1010]));
+        bitop(Message::bulks(&["DIFF", "d", "a", "b"]), &sets);
+        assert_eq!(get("d"), Some(vec![0b0100, 0xFF]));
+    }
+
+    #[test]
+    fn test_bitop_missing_sources_delete_dest() {
+        let sets = with_value("d", b"x");
+        assert_eq!(
+            bitop(Message::bulks(&["OR", "d", "a", "b"]), &sets),
+            Message::integer(0)
+        );
+        assert!(sets.lock().unwrap().get(b"d".as_slice()).is_none());
+    }
+
+    #[test]
+    fn test_bitfield_set_and_get() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        let result = bitfield(
+            Message::bulks(&[
+                "foo", "SET", "u8", "0", "200", "GET", "u8", "0", "GET", "i8", "0",
+            ]),
+            &sets,
+            1024,
+        );
+        assert_eq!(
+            result,
+            Message::array(vec![
+                Message::integer(0),
+                Message::integer(200),
+                Message::integer(-56),
+            ])
+        );
+        assert_eq!(sets.lock().unwrap()[b"foo".as_slice()], vec![200]);
+    }
+
+    #[test]
+    fn test_bitfield_unaligned_and_relative_offsets() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        bitfield(
+            Message::bulks(&["foo", "SET", "u4", "#1", "15", "SET", "i5", "3", "-1"]),
+            &sets,
+            1024,
+        );
+        assert_eq!(sets.lock().unwrap()[b"foo".as_slice()], vec![0x1F]);
+        assert_eq!(
+            bitfield_ro(
+                Message::bulks(&["foo", "GET", "u4", "#1", "GET", "i64", "0"]),
+                &sets,
+                1024
+            ),
+            Message::array(vec![
+                Message::integer(15),
+                Message::integer(0x1F00_0000_0000_0000),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_bitfield_incrby_overflow() {
+        let sets = with_value("foo", &[250]);
+        let result = bitfield(
+            Message::bulks(&[
+                "foo", "INCRBY", "u8", "0", "10", "OVERFLOW", "SAT", "INCRBY", "u8", "0", "300",
+                "OVERFLOW", "FAIL", "INCRBY", "u8", "0", "1",
+            ]),
+            &sets,
+            1024,
+        );
+        assert_eq!(
+            result,
+            Message::array(vec![
+                Message::integer(4),
+                Message::integer(255),
+                Message::Null,
+            ])
+        );
+        assert_eq!(sets.lock().unwrap()[b"foo".as_slice()], vec![255]);
+    }
+
+    #[test]
+    fn test_bitfield_signed_wrap_and_sat() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        let result = bitfield(
+            Message::bulks(&[
+                "foo", "SET", "i8", "0", "127", "INCRBY", "i8", "0", "1", "OVERFLOW", "SAT",
+                "INCRBY", "i8", "0", "-10",
+            ]),
+            &sets,
+            1024,
+        );
+        assert_eq!(
+            result,
+            Message::array(vec![
+                Message::integer(0),
+                Message::integer(-128),
+                Message::integer(-128),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_bitfield_i64() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        let result = bitfield(
+            Message::bulks(&["foo", "SET", "i64", "3", "-2", "INCRBY", "i64", "3", "1"]),
+            &sets,
+            1024,
+        );
+        assert_eq!(
+            result,
+            Message::array(vec![Message::integer(0), Message::integer(-1)])
+        );
+    }
+
+    #[test]
+    fn test_bitfield_get_missing_key_does_not_create() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        assert_eq!(
+            bitfield(Message::bulks(&["foo", "GET", "u8", "0"]), &sets, 1024),
+            Message::array(vec![Message::integer(0)])
+        );
+        assert!(sets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bitfield_failed_write_does_not_create() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        let args = ["foo", "OVERFLOW", "FAIL", "INCRBY", "u8", "0", "300"];
+        assert_eq!(
+            bitfield(Message::bulks(&args), &sets, 1024),
+            Message::array(vec![Message::Null])
+        );
+        assert!(sets.lock().unwrap().is_empty());
+        let args = ["foo", "OVERFLOW", "FAIL", "SET", "u8", "0", "0"];
+        assert_eq!(
+            bitfield(Message::bulks(&args), &sets, 1024),
+            Message::array(vec![Message::integer(0)])
+        );
+        assert_eq!(sets.lock().unwrap()[&b"foo".to_vec()], vec![0]);
+    }
+
+    #[test]
+    fn test_bitfield_errors() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        assert_eq!(
+            bitfield(Message::bulks(&["foo", "GET", "u64", "0"]), &sets, 1024),
+            Message::error(
+                "ERR Invalid bitfield type. Use something like i16 u8. \
+                 Note that u64 is not supported but i64 is."
+            )
+        );
+        assert_eq!(
+            bitfield(
+                Message::bulks(&["foo", "SET", "u8", "8192", "1"]),
+                &sets,
+                1024
+            ),
+            Message::error("ERR bit offset is not an integer or out of range")
+        );
+        assert_eq!(
+            bitfield(Message::bulks(&["foo", "OVERFLOW", "NOPE"]), &sets, 1024),
+            Message::error("ERR Invalid OVERFLOW type specified")
+        );
+        assert_eq!(
+            bitfield(Message::bulks(&["foo", "SET", "u8", "0"]), &sets, 1024),
+            Message::error("ERR syntax error")
+        );
+        assert_eq!(
+            bitfield_ro(
+                Message::bulks(&["foo", "INCRBY", "u8", "0", "1"]),
+                &sets,
+                1024
+            ),
+            Message::error("ERR BITFIELD_RO only supports the GET subcommand")
+        );
+        assert!(sets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_bitop_arity_errors() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        assert_eq!(
+            bitop(Message::bulks(&["NOT", "d", "a", "b"]), &sets),
+            Message::error("ERR BITOP NOT must be called with a single source key.")
+        );
+        assert_eq!(
+            bitop(Message::bulks(&["DIFF", "d", "a"]), &sets),
+            Message::error("ERR BITOP DIFF must be called with at least two source keys.")
+        );
+        assert_eq!(
+            bitop(Message::bulks(&["NAND", "d", "a"]), &sets),
+            Message::error("ERR syntax error")
+        );
+    }
+}
@@ -0,0 +1,1622 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::handlers::{exists, keys, remove, Command, EXPIRES};
+use crate::message::Message;
+use crate::message::Message::*;
+use crate::rdb::dump_payload;
+use crate::replication;
+use crate::resp::Resp;
+
+pub const SLOTS: usize = 16384;
+
+// CRC16/XMODEM, the checksum keys are hashed with.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Only the part between the first `{` and the next `}` is hashed when it
+// is not empty, so related keys can be kept in one slot.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tagged = key.iter().position(|&b| b == b'{').and_then(|open| {
+        let tag = &key[open + 1..];
+        let close = tag.iter().position(|&b| b == b'}')?;
+        (close > 0).then(|| &tag[..close])
+    });
+    crc16(tagged.unwrap_or(key)) % SLOTS as u16
+}
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    id: String,
+    host: String,
+    port: u16,
+    // Port of the node-to-node bus.
+    cport: u16,
+    // Id of the primary it replicates, for replicas.
+    primary: Option<String>,
+    config_epoch: u64,
+    // Suspected down after not answering pings for the node timeout.
+    pfail: bool,
+    // Down, as agreed by a majority of the primaries.
+    fail: bool,
+    // Met with CLUSTER MEET but not answered yet, so the id is made up.
+    handshake: bool,
+    // Unix ms of the oldest unanswered ping (0 if none), of the last ping
+    // and of the last pong.
+    ping_sent: u64,
+    last_ping: u64,
+    pong_received: u64,
+    // Primaries currently gossiping that it is down, and when they last did.
+    fail_reports: HashMap<String, u64>,
+}
+
+impl Node {
+    fn new(id: String, host: String, port: u16, cport: u16) -> Self {
+        Node {
+            id,
+            host,
+            port,
+            cport,
+            ..Node::default()
+        }
+    }
+}
+
+// A node as a line of CLUSTER NODES or nodes.conf describes it.
+struct NodeLine {
+    node: Node,
+    myself: bool,
+    slots: Vec<usize>,
+    // `[slot->-id]` and `[slot-<-id]` entries, only listed for myself.
+    migrating: Vec<(usize, String)>,
+    importing: Vec<(usize, String)>,
+}
+
+// <id> <ip:port@cport> <flags> <primary> <ping-sent> <pong-recv>
+// <config-epoch> <link-state> <slot> ...
+fn parse_line(line: &str) -> Result<NodeLine, String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let invalid = || format!("invalid node line '{}'", line);
+    if fields.len() < 8 {
+        return Err(invalid());
+    }
+    let addr = fields[1].split(',').next().unwrap_or_default();
+    let (host_port, cport) = addr.split_once('@').ok_or_else(invalid)?;
+    let (host, port) = host_port.rsplit_once(':').ok_or_else(invalid)?;
+    let flags: Vec<&str> = fields[2].split(',').collect();
+    let mut node = Node::new(
+        fields[0].to_string(),
+        host.to_string(),
+        port.parse().map_err(|_| invalid())?,
+        cport.parse().map_err(|_| invalid())?,
+    );
+    node.primary = (fields[3] != "-").then(|| fields[3].to_string());
+    node.config_epoch = fields[6].parse().map_err(|_| invalid())?;
+    node.pfail = flags.contains(&"fail?");
+    node.fail = flags.contains(&"fail");
+    node.handshake = flags.contains(&"handshake");
+    let mut parsed = NodeLine {
+        node,
+        myself: flags.contains(&"myself"),
+        slots: Vec::new(),
+        migrating: Vec::new(),
+        importing: Vec::new(),
+    };
+    for entry in &fields[8..] {
+        if let Some(entry) = entry.strip_prefix('[').and_then(|e| e.strip_suffix(']')) {
+            let (slot, other, list) = match (entry.split_once("->-"), entry.split_once("-<-")) {
+                (Some((slot, to)), _) => (slot, to, &mut parsed.migrating),
+                (_, Some((slot, from))) => (slot, from, &mut parsed.importing),
+                _ => return Err(invalid()),
+            };
+            let slot = slot
+                .parse()
+                .ok()
+                .filter(|&slot| slot < SLOTS)
+                .ok_or_else(invalid)?;
+            list.push((slot, other.to_string()));
+            continue;
+        }
+        let (start, end) = entry.split_once('-').unwrap_or((entry, entry));
+        let (Ok(start), Ok(end)) = (start.parse::<usize>(), end.parse::<usize>()) else {
+            return Err(invalid());
+        };
+        if start > end || end >= SLOTS {
+            return Err(invalid());
+        }
+        parsed.slots.extend(start..=end);
+    }
+    Ok(parsed)
+}
+
+struct Cluster {
+    myself: String,
+    current_epoch: u64,
+    nodes: Vec<Node>,
+    // Id of the node serving each slot.
+    slots: Vec<Option<String>>,
+    assigned: usize,
+    // Slots this node is handing over to, or taking over from, another.
+    migrating: BTreeMap<usize, String>,
+    importing: BTreeMap<usize, String>,
+    // Whether keyed commands are served, kept by `update`.
+    ok: bool,
+    // Ids removed with CLUSTER FORGET, and until when gossip may not add
+    // them back.
+    forgotten: HashMap<String, u64>,
+    path: PathBuf,
+    // What `path` was last written with.
+    saved: String,
+}
+
+// Set once cluster mode is enabled at startup.
+static CLUSTER: Mutex<Option<Cluster>> = Mutex::new(None);
+
+// Every node is pinged this often.
+const PING_INTERVAL: u64 = 1000;
+// How long a forgotten node is kept out of the cluster.
+const FORGET_TTL: u64 = 60_000;
+// Connect and read timeout for bus messages.
+const BUS_TIMEOUT: Duration = Duration::from_millis(1000);
+const BUS_TICK: Duration = Duration::from_millis(100);
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+impl Cluster {
+    fn new(path: &Path) -> Self {
+        Cluster {
+            myself: String::new(),
+            current_epoch: 0,
+            nodes: Vec::new(),
+            slots: vec![None; SLOTS],
+            assigned: 0,
+            migrating: BTreeMap::new(),
+            importing: BTreeMap::new(),
+            ok: false,
+            forgotten: HashMap::new(),
+            path: path.to_path_buf(),
+            saved: String::new(),
+        }
+    }
+
+    fn node(&self, id: &str) -> Option<&Node> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+
+    fn node_mut(&mut self, id: &str) -> Option<&mut Node> {
+        self.nodes.iter_mut().find(|node| node.id == id)
+    }
+
+    fn me(&self) -> &Node {
+        self.node(&self.myself).expect("myself is a known node")
+    }
+
+    fn is_forgotten(&self, id: &str, now: u64) -> bool {
+        self.forgotten.get(id).is_some_and(|&until| until > now)
+    }
+
+    fn set_slot(&mut self, slot: usize, owner: Option<String>) {
+        match (&self.slots[slot], &owner) {
+            (None, Some(_)) => self.assigned += 1,
+            (Some(_), None) => self.assigned -= 1,
+            _ => {}
+        }
+        self.slots[slot] = owner;
+    }
+
+    // Slots whose owner is flagged with `pfail` or `fail`.
+    fn slots_flagged(&self, flagged: impl Fn(&Node) -> bool) -> usize {
+        let ids: Vec<&str> = self
+            .nodes
+            .iter()
+            .filter(|node| flagged(node))
+            .map(|node| node.id.as_str())
+            .collect();
+        if ids.is_empty() {
+            return 0;
+        }
+        self.slots
+            .iter()
+            .filter(|owner| owner.as_deref().is_some_and(|owner| ids.contains(&owner)))
+            .count()
+    }
+
+    // Contiguous ranges of the slots `id` serves.
+    fn ranges(&self, id: &str) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for (slot, owner) in self.slots.iter().enumerate() {
+            if owner.as_deref() != Some(id) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    fn flags(&self, node: &Node) -> String {
+        let mut flags = Vec::new();
+        if node.id == self.myself {
+            flags.push("myself");
+        }
+        flags.push(if node.primary.is_some() {
+            "slave"
+        } else {
+            "master"
+        });
+        if node.fail {
+            flags.push("fail");
+        } else if node.pfail {
+            flags.push("fail?");
+        }
+        if node.handshake {
+            flags.push("handshake");
+        }
+        flags.join(",")
+    }
+
+    // One line of CLUSTER NODES, which is also the nodes.conf format. The
+    // file and the bus leave out the ping times, which change all the time.
+    fn describe(&self, node: &Node, times: bool) -> String {
+        let (ping_sent, pong_received) = match times {
+            true => (node.ping_sent, node.pong_received),
+            false => (0, 0),
+        };
+        let link = match node.pfail || node.fail {
+            true => "disconnected",
+            false => "connected",
+        };
+        let mut line = format!(
+            "{} {}:{}@{} {} {} {} {} {} {}",
+            node.id,
+            node.host,
+            node.port,
+            node.cport,
+            self.flags(node),
+            node.primary.as_deref().unwrap_or("-"),
+            ping_sent,
+            pong_received,
+            node.config_epoch,
+            link
+        );
+        for (start, end) in self.ranges(&node.id) {
+            if start == end {
+                line.push_str(&format!(" {}", start));
+            } else {
+                line.push_str(&format!(" {}-{}", start, end));
+            }
+        }
+        if node.id == self.myself {
+            for (slot, to) in &self.migrating {
+                line.push_str(&format!(" [{}->-{}]", slot, to));
+            }
+            for (slot, from) in &self.importing {
+                line.push_str(&format!(" [{}-<-{}]", slot, from));
+            }
+        }
+        line
+    }
+
+    fn nodes_text(&self, times: bool) -> String {
+        self.nodes
+            .iter()
+            .map(|node| self.describe(node, times) + "\n")
+            .collect()
+    }
+
+    fn parse(text: &str, path: &Path) -> Result<Cluster, String> {
+        let mut cluster = Cluster::new(path);
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields[0] == "vars" {
+                for pair in fields[1..].chunks(2) {
+                    if let ["currentEpoch", epoch] = pair {
+                        cluster.current_epoch = epoch
+                            .parse()
+                            .map_err(|_| format!("invalid epoch in '{}'", line))?;
+                    }
+                }
+                continue;
+            }
+            let parsed = parse_line(line)?;
+            if parsed.myself {
+                cluster.myself = parsed.node.id.clone();
+                cluster.migrating.extend(parsed.migrating);
+                cluster.importing.extend(parsed.importing);
+            }
+            for slot in parsed.slots {
+                cluster.set_slot(slot, Some(parsed.node.id.clone()));
+            }
+            cluster.nodes.push(parsed.node);
+        }
+        if cluster.myself.is_empty() {
+            return Err("no node is flagged myself".to_string());
+        }
+        Ok(cluster)
+    }
+
+    // Recomputes whether the cluster is up, and saves the configuration
+    // if it changed.
+    fn update(&mut self) -> io::Result<()> {
+        self.ok = !config::cluster_require_full_coverage()
+            || (self.assigned == SLOTS && self.slots_flagged(|node| node.fail) == 0);
+        let text = format!(
+            "{}vars currentEpoch {} lastVoteEpoch 0\n",
+            self.nodes_text(false),
+            self.current_epoch
+        );
+        if text == self.saved {
+            return Ok(());
+        }
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, &text)?;
+        fs::rename(temp, &self.path)?;
+        self.saved = text;
+        Ok(())
+    }
+
+    // Takes a new config epoch unless this node already has the highest
+    // one, so its slot claims win over older ones.
+    fn bump_epoch(&mut self) -> bool {
+        let max = self
+            .nodes
+            .iter()
+            .map(|node| node.config_epoch)
+            .max()
+            .unwrap_or(0)
+            .max(self.current_epoch);
+        let mine = self.me().config_epoch;
+        if mine != 0 && mine == max {
+            return false;
+        }
+        self.current_epoch += 1;
+        let epoch = self.current_epoch;
+        let myself = self.myself.clone();
+        if let Some(me) = self.node_mut(&myself) {
+            me.config_epoch = epoch;
+        }
+        true
+    }
+
+    // A bus message: its type, the current epoch and the sender's own
+    // line, then a line per other node, or the failed node's id for FAIL.
+    fn message(&self, kind: &str, failed: Option<&str>) -> Message {
+        let mut parts = vec![
+            kind.to_string(),
+            self.current_epoch.to_string(),
+            self.describe(self.me(), false),
+        ];
+        match failed {
+            Some(id) => parts.push(id.to_string()),
+            None => parts.extend(
+                self.nodes
+                    .iter()
+                    .filter(|node| node.id != self.myself && !node.handshake)
+                    .map(|node| self.describe(node, false)),
+            ),
+        }
+        Message::array(parts.into_iter().map(bulk).collect())
+    }
+
+    // Handles a bus message from another node.
+    fn receive(&mut self, parts: &[String], now: u64) -> Result<(), String> {
+        let [kind, epoch, sender, rest @ ..] = parts else {
+            return Err("truncated bus message".to_string());
+        };
+        let epoch: u64 = epoch
+            .parse()
+            .map_err(|_| format!("invalid epoch '{}'", epoch))?;
+        self.current_epoch = self.current_epoch.max(epoch);
+        let sender = parse_line(sender)?;
+        let id = sender.node.id.clone();
+        if id == self.myself {
+            return Ok(());
+        }
+        // Only a MEET introduces the sender itself; otherwise nodes join
+        // through gossip from the ones that already know them.
+        if self.node(&id).is_none() {
+            if kind != "MEET" || self.is_forgotten(&id, now) {
+                return Ok(());
+            }
+            let node = &sender.node;
+            let mut added = Node::new(id.clone(), node.host.clone(), node.port, node.cport);
+            added.pong_received = now;
+            self.nodes.push(added);
+        }
+        self.learn(sender);
+        let from_primary = self.node(&id).is_some_and(|node| node.primary.is_none());
+        if kind == "FAIL" {
+            for failed in rest {
+                if let Some(node) = self.node_mut(failed).filter(|node| !node.fail) {
+                    println!("FAIL message received from {} about {}", id, failed);
+                    node.fail = true;
+                    node.pfail = false;
+                }
+            }
+            return Ok(());
+        }
+        for line in rest {
+            self.gossip(&id, from_primary, parse_line(line)?.node, now);
+        }
+        Ok(())
+    }
+
+    // Takes what a node says about itself: address, role, epoch and slots.
+    fn learn(&mut self, sender: NodeLine) {
+        let claim = sender.node;
+        let Some(node) = self.node_mut(&claim.id) else {
+            return;
+        };
+        node.host = claim.host;
+        node.port = claim.port;
+        node.cport = claim.cport;
+        node.primary = claim.primary.clone();
+        node.config_epoch = claim.config_epoch;
+        if claim.primary.is_some() {
+            return;
+        }
+        // A slot goes to whichever primary claims it with the newer epoch.
+        for slot in sender.slots {
+            if self.importing.contains_key(&slot) {
+                continue;
+            }
+            let owner_epoch = match &self.slots[slot] {
+                Some(owner) if *owner == claim.id => continue,
+                Some(owner) => self.node(owner).map_or(0, |node| node.config_epoch),
+                None => 0,
+            };
+            if self.slots[slot].is_none() || owner_epoch < claim.config_epoch {
+                self.set_slot(slot, Some(claim.id.clone()));
+            }
+        }
+        // Two primaries must not share an epoch, or neither claim would
+        // win; the one with the smaller id moves on.
+        let me = self.me();
+        if me.primary.is_none() && me.config_epoch == claim.config_epoch && self.myself < claim.id {
+            self.bump_epoch();
+            println!(
+                "configEpoch collision with node {}. configEpoch set to {}",
+                claim.id,
+                self.me().config_epoch
+            );
+        }
+    }
+
+    // Takes what `sender` says about another node.
+    fn gossip(&mut self, sender: &str, from_primary: bool, about: Node, now: u64) {
+        if about.id == self.myself {
+            return;
+        }
+        if let Some(node) = self.node_mut(&about.id) {
+            // Only primaries take part in failure detection.
+            if from_primary && (about.pfail || about.fail) {
+                node.fail_reports.insert(sender.to_string(), now);
+            } else {
+                node.fail_reports.remove(sender);
+            }
+            return;
+        }
+        if about.fail || about.handshake || self.is_forgotten(&about.id, now) {
+            return;
+        }
+        let mut added = Node::new(about.id, about.host, about.port, about.cport);
+        added.primary = about.primary;
+        added.config_epoch = about.config_epoch;
+        self.nodes.push(added);
+    }
+
+    // Handles the reply to a bus message sent to the node `pinged`.
+    fn pong(&mut self, pinged: &str, reply: Message, now: u64) -> Result<(), String> {
+        let parts = texts(reply).ok_or("unexpected bus reply")?;
+        let sender = parse_line(parts.get(2).ok_or("truncated bus reply")?)?;
+        let id = sender.node.id;
+        let Some(i) = self.nodes.iter().position(|node| node.id == pinged) else {
+            return Ok(());
+        };
+        if self.nodes[i].handshake {
+            // The node answering a MEET tells its real id, unless it was
+            // known already under that id.
+            if self.node(&id).is_some() {
+                self.nodes.remove(i);
+            } else {
+                self.nodes[i].id = id.clone();
+                self.nodes[i].handshake = false;
+            }
+        } else if pinged != id {
+            return Ok(());
+        }
+        let Some(node) = self.node_mut(&id) else {
+            return Ok(());
+        };
+        node.ping_sent = 0;
+        node.pong_received = now;
+        node.pfail = false;
+        if node.fail {
+            println!("Clear FAIL state for node {}: is reachable again.", id);
+            node.fail = false;
+        }
+        self.receive(&parts, now)
+    }
+
+    // Runs every BUS_TICK: flags nodes that stopped answering, and
+    // returns the messages to send as (node id, host, bus port, message).
+    fn tick(&mut self, now: u64) -> Vec<Outgoing> {
+        let timeout = config::cluster_node_timeout();
+        // A MEET that is never answered is dropped.
+        self.nodes.retain(|node| {
+            !(node.handshake && node.ping_sent != 0 && now - node.ping_sent > timeout.max(1000))
+        });
+        self.forgotten.retain(|_, until| *until > now);
+
+        let mut pings = Vec::new();
+        for node in self.nodes.iter_mut().filter(|node| node.id != self.myself) {
+            if node.ping_sent != 0 && now - node.ping_sent > timeout && !node.pfail && !node.fail {
+                println!(
+                    "*** Marking node {} as failing (quorum not reached yet)",
+                    node.id
+                );
+                node.pfail = true;
+            }
+            // A node that stopped answering is retried at the same pace.
+            if now - node.last_ping >= PING_INTERVAL {
+                node.last_ping = now;
+                if node.ping_sent == 0 {
+                    node.ping_sent = now;
+                }
+                let kind = if node.handshake { "MEET" } else { "PING" };
+                pings.push((node.id.clone(), node.host.clone(), node.cport, kind));
+            }
+        }
+
+        // Reports count for twice the node timeout; a node is down once a
+        // majority of the primaries serving slots flagged it.
+        let primaries = self
+            .nodes
+            .iter()
+            .filter(|node| self.slots.contains(&Some(node.id.clone())))
+            .count();
+        let needed = primaries / 2 + 1;
+        let me_primary = self.me().primary.is_none() as usize;
+        let mut failed = Vec::new();
+        for node in &mut self.nodes {
+            node.fail_reports
+                .retain(|_, reported| now - *reported <= timeout * 2);
+            if node.pfail && !node.fail && node.fail_reports.len() + me_primary >= needed {
+                println!("Marking node {} as failing (quorum reached).", node.id);
+                node.fail = true;
+                node.pfail = false;
+                failed.push(node.id.clone());
+            }
+        }
+
+        let mut out: Vec<Outgoing> = pings
+            .into_iter()
+            .map(|(id, host, cport, kind)| (id, host, cport, self.message(kind, None)))
+            .collect();
+        for id in &failed {
+            let message = self.message("FAIL", Some(id));
+            out.extend(
+                self.nodes
+                    .iter()
+                    .filter(|node| node.id != self.myself && node.id != *id && !node.handshake)
+                    .map(|node| {
+                        (
+                            node.id.clone(),
+                            node.host.clone(),
+                            node.cport,
+                            message.clone(),
+                        )
+                    }),
+            );
+        }
+        out
+    }
+}
+
+type Outgoing = (String, String, u16, Message);
+
+fn texts(message: Message) -> Option<Vec<String>> {
+    let Array(parts) = message else {
+        return None;
+    };
+    parts
+        .into_iter()
+        .map(|part| match part {
+            Bulk(bytes) => String::from_utf8(bytes).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+// Turns cluster mode on, loading the layout from `path` or starting as a
+// lone primary with a new id and no slots.
+pub fn init(path: &Path) -> Result<(), String> {
+    let port = config::port();
+    let mut cluster = match fs::read_to_string(path) {
+        Ok(text) => Cluster::parse(&text, path)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let mut cluster = Cluster::new(path);
+            cluster.myself = replication::new_replid();
+            cluster.nodes.push(Node::new(
+                cluster.myself.clone(),
+                "127.0.0.1".to_string(),
+                port,
+                port.wrapping_add(10000),
+            ));
+            cluster
+        }
+        Err(err) => return Err(err.to_string()),
+    };
+    // The port may have changed since the file was written.
+    let myself = cluster.myself.clone();
+    if let Some(node) = cluster.node_mut(&myself) {
+        node.port = port;
+        node.cport = port.wrapping_add(10000);
+    }
+    cluster.update().map_err(|err| err.to_string())?;
+    println!("Cluster node {}", cluster.myself);
+    *CLUSTER.lock().unwrap() = Some(cluster);
+    Ok(())
+}
+
+// Listens on the cluster bus port and starts pinging the other nodes.
+pub fn spawn_bus() -> io::Result<()> {
+    let Some(cport) = CLUSTER.lock().unwrap().as_ref().map(|c| c.me().cport) else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(("127.0.0.1", cport))?;
+    spawn(move || {
+        for stream in listener.incoming().flatten() {
+            spawn(move || serve_bus(stream));
+        }
+    });
+    spawn(|| loop {
+        sleep(BUS_TICK);
+        let out = {
+            let mut guard = CLUSTER.lock().unwrap();
+            let Some(cluster) = guard.as_mut() else {
+                return;
+            };
+            let out = cluster.tick(now_ms());
+            if let Err(err) = cluster.update() {
+                println!("Error saving the cluster config: {}", err);
+            }
+            out
+        };
+        for (id, host, cport, message) in out {
+            spawn(move || send(&id, &host, cport, message));
+        }
+    });
+    Ok(())
+}
+
+// Answers every message from another node with a PONG.
+fn serve_bus(stream: TcpStream) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    let mut resp = Resp::new(BufReader::new(stream));
+    while let Ok(message) = resp.read() {
+        let reply = {
+            let mut guard = CLUSTER.lock().unwrap();
+            let Some(cluster) = guard.as_mut() else {
+                return;
+            };
+            let parts = texts(message).unwrap_or_default();
+            if let Err(err) = cluster.receive(&parts, now_ms()) {
+                println!("Bad cluster bus message: {}", err);
+            }
+            if let Err(err) = cluster.update() {
+                println!("Error saving the cluster config: {}", err);
+            }
+            cluster.message("PONG", None)
+        };
+        if writer.write_all(&reply.marshal()).is_err() {
+            return;
+        }
+    }
+}
+
+// Sends one message to the node `id` and hands its reply to the cluster.
+fn send(id: &str, host: &str, cport: u16, message: Message) {
+    let reply = (|| {
+        let addr = (host, cport)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other("no address"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, BUS_TIMEOUT)?;
+        stream.set_read_timeout(Some(BUS_TIMEOUT))?;
+        stream.write_all(&message.marshal())?;
+        Resp::new(BufReader::new(stream)).read()
+    })();
+    let Ok(reply) = reply else {
+        return;
+    };
+    let mut guard = CLUSTER.lock().unwrap();
+    let Some(cluster) = guard.as_mut() else {
+        return;
+    };
+    if let Err(err) = cluster.pong(id, reply, now_ms()) {
+        println!("Bad cluster bus reply: {}", err);
+    }
+    if let Err(err) = cluster.update() {
+        println!("Error saving the cluster config: {}", err);
+    }
+}
+
+// In cluster mode, the error sending a client elsewhere when this node
+// does not serve the keys of a call, or None to run it here. Calls without
+// keys always run here. `asking` is set right after an ASKING.
+pub fn redirect(command: &Command, argv: &[Message], asking: bool) -> Option<Message> {
+    let guard = CLUSTER.lock().unwrap();
+    let cluster = guard.as_ref()?;
+    let mut slot = None;
+    let mut keys = Vec::new();
+    for i in command.key_indexes(argv.len()) {
+        let Some(Bulk(key)) = argv.get(i) else {
+            continue;
+        };
+        let key_slot = key_slot(key) as usize;
+        match slot {
+            Some(slot) if slot != key_slot => {
+                return Some(Message::error(
+                    "CROSSSLOT Keys in request don't hash to the same slot",
+                ))
+            }
+            _ => slot = Some(key_slot),
+        }
+        keys.push(key);
+    }
+    let slot = slot?;
+    if !cluster.ok {
+        return Some(Message::error("CLUSTERDOWN The cluster is down"));
+    }
+    let asking = asking || command.name == "RESTORE-ASKING";
+    match &cluster.slots[slot] {
+        // Keys already moved away are looked up on the target.
+        Some(owner) if *owner == cluster.myself => {
+            let target = cluster.node(cluster.migrating.get(&slot)?)?;
+            let missing = keys.iter().filter(|key| !exists(key)).count();
+            if missing == 0 {
+                None
+            } else if missing == keys.len() {
+                Some(Message::error(format!(
+                    "ASK {} {}:{}",
+                    slot, target.host, target.port
+                )))
+            } else {
+                Some(Message::error(
+                    "TRYAGAIN Multiple keys request during rehashing of slot",
+                ))
+            }
+        }
+        _ if asking && cluster.importing.contains_key(&slot) => None,
+        None => Some(Message::error("CLUSTERDOWN Hash slot not served")),
+        Some(owner) => {
+            let node = cluster.node(owner)?;
+            Some(Message::error(format!(
+                "MOVED {} {}:{}",
+                slot, node.host, node.port
+            )))
+        }
+    }
+}
+
+// ASKING: lets the next command run against a slot being imported.
+pub fn asking() -> Message {
+    match CLUSTER.lock().unwrap().is_some() {
+        true => Message::simple("OK"),
+        false => Message::error("ERR This instance has cluster support disabled"),
+    }
+}
+
+// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+// [KEYS key ...]: moves keys to another instance with RESTORE-ASKING.
+// Returns the reply, and the DEL to propagate for keys moved away.
+pub fn migrate(args: &[Message]) -> (Message, Option<Message>) {
+    match migrate_keys(args) {
+        Ok((reply, moved)) if moved.is_empty() => (reply, None),
+        Ok((reply, moved)) => {
+            for key in &moved {
+                remove(key);
+            }
+            let mut del = vec![Message::bulk(b"DEL".to_vec())];
+            del.extend(moved.into_iter().map(Message::bulk));
+            (reply, Some(Message::array(del)))
+        }
+        Err(err) => (err, None),
+    }
+}
+
+fn migrate_keys(args: &[Message]) -> Result<(Message, Vec<Vec<u8>>), Message> {
+    let mut parts = Vec::new();
+    for arg in args {
+        let Bulk(bytes) = arg else {
+            return Err(Message::error("ERR syntax error"));
+        };
+        parts.push(bytes.clone());
+    }
+    let [host, port, key, db, timeout, options @ ..] = parts.as_slice() else {
+        return Err(Message::error(
+            "ERR wrong number of arguments for 'migrate' command",
+        ));
+    };
+    let (mut copy, mut replace, mut keys) = (false, false, vec![key.clone()]);
+    for (i, option) in options.iter().enumerate() {
+        match String::from_utf8_lossy(option).to_uppercase().as_str() {
+            "COPY" => copy = true,
+            "REPLACE" => replace = true,
+            "KEYS" if key.is_empty() => {
+                keys = options[i + 1..].to_vec();
+                break;
+            }
+            "KEYS" => {
+                return Err(Message::error(
+                    "ERR When using MIGRATE KEYS option, the key argument must be set to the empty string",
+                ))
+            }
+            _ => return Err(Message::error("ERR syntax error")),
+        }
+    }
+    let port = config::parse_port(&String::from_utf8_lossy(port))
+        .map_err(|_| Message::error("ERR Invalid port"))?;
+    if db.as_slice() != b"0" {
+        return Err(Message::error("ERR DB index is out of range"));
+    }
+    let timeout = match String::from_utf8_lossy(timeout).parse::<i64>() {
+        Ok(ms) if ms > 0 => Duration::from_millis(ms as u64),
+        Ok(_) => Duration::from_millis(1000),
+        Err(_) => {
+            return Err(Message::error(
+                "ERR value is not an integer or out of range",
+            ))
+        }
+    };
+
+    let mut payloads = Vec::new();
+    for key in keys {
+        match dump_payload(&key) {
+            Ok(Some(payload)) => payloads.push((key, payload)),
+            Ok(None) => {}
+            Err(err) => return Err(Message::error(format!("ERR {}", err))),
+        }
+    }
+    if payloads.is_empty() {
+        return Ok((Message::simple("NOKEY"), Vec::new()));
+    }
+
+    let host = String::from_utf8_lossy(host).to_string();
+    let io_error = |what: &str| {
+        Message::error(format!(
+            "IOERR error or timeout {} to target instance",
+            what
+        ))
+    };
+    let addr = (host.as_str(), port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| io_error("connecting"))?;
+    let mut stream =
+        TcpStream::connect_timeout(&addr, timeout).map_err(|_| io_error("connecting"))?;
+    _ = stream.set_read_timeout(Some(timeout));
+    _ = stream.set_write_timeout(Some(timeout));
+    let mut request = Vec::new();
+    for (key, payload) in &payloads {
+        // The key keeps its expire time, sent as the time left.
+        let ttl = EXPIRES
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, |&at| at.saturating_sub(now_ms()).max(1));
+        let mut restore = vec![
+            Message::bulk(b"RESTORE-ASKING".to_vec()),
+            Message::bulk(key.clone()),
+            Message::bulk(ttl.to_string().into_bytes()),
+            Message::bulk(payload.clone()),
+        ];
+        if replace {
+            restore.push(Message::bulk(b"REPLACE".to_vec()));
+        }
+        request.extend(Message::array(restore).marshal());
+    }
+    stream
+        .write_all(&request)
+        .map_err(|_| io_error("writing"))?;
+
+    // Keys the target took are moved even when others failed, or the
+    // connection broke before the rest were answered.
+    let mut resp = Resp::new(BufReader::new(stream));
+    let mut moved = Vec::new();
+    let mut reply = Message::simple("OK");
+    for (key, _) in payloads {
+        let Ok(answer) = resp.read() else {
+            reply = io_error("reading");
+            break;
+        };
+        match answer {
+            Error(err) => {
+                reply = Message::error(format!("ERR Target instance replied with error: {}", err))
+            }
+            _ if copy => {}
+            _ => moved.push(key),
+        }
+    }
+    Ok((reply, moved))
+}
+
+// The cluster section of INFO.
+pub fn info() -> String {
+    format!(
+        "cluster_enabled:{}",
+        CLUSTER.lock().unwrap().is_some() as u8
+    )
+}
+
+fn parse_slot(arg: &[u8]) -> Result<usize, Message> {
+    String::from_utf8_lossy(arg)
+        .parse::<usize>()
+        .ok()
+        .filter(|&slot| slot < SLOTS)
+        .ok_or_else(|| Message::error("ERR Invalid or out of range slot"))
+}
+
+// Slots named by ADDSLOTS/DELSLOTS, or the ranges of ADDSLOTSRANGE and
+// DELSLOTSRANGE.
+fn parse_slots(args: &[Vec<u8>], ranges: bool) -> Result<Vec<usize>, Message> {
+    if args.is_empty() || (ranges && !args.len().is_multiple_of(2)) {
+        return Err(Message::error(
+            "ERR wrong number of arguments for 'cluster' command",
+        ));
+    }
+    let mut slots = Vec::new();
+    if !ranges {
+        for arg in args {
+            slots.push(parse_slot(arg)?);
+        }
+        return Ok(slots);
+    }
+    for pair in args.chunks(2) {
+        let (start, end) = (parse_slot(&pair[0])?, parse_slot(&pair[1])?);
+        if start > end {
+            return Err(Message::error(format!(
+                "ERR start slot number {} is greater than end slot number {}",
+                start, end
+            )));
+        }
+        slots.extend(start..=end);
+    }
+    Ok(slots)
+}
+
+fn bulk<S: Into<String>>(s: S) -> Message {
+    Message::bulk(s.into().into_bytes())
+}
+
+fn node_entry(node: &Node) -> Message {
+    Message::array(vec![
+        bulk(node.host.clone()),
+        Message::integer(node.port as i64),
+        bulk(node.id.clone()),
+    ])
+}
+
+fn slots_reply(cluster: &Cluster) -> Message {
+    let mut entries = Vec::new();
+    for node in cluster.nodes.iter().filter(|node| node.primary.is_none()) {
+        for (start, end) in cluster.ranges(&node.id) {
+            let mut entry = vec![
+                Message::integer(start as i64),
+                Message::integer(end as i64),
+                node_entry(node),
+            ];
+            entry.extend(
+                cluster
+                    .nodes
+                    .iter()
+                    .filter(|replica| replica.primary.as_deref() == Some(node.id.as_str()))
+                    .map(node_entry),
+            );
+            entries.push((start, Message::array(entry)));
+        }
+    }
+    entries.sort_by_key(|(start, _)| *start);
+    Message::array(entries.into_iter().map(|(_, entry)| entry).collect())
+}
+
+fn shards_reply(cluster: &Cluster) -> Message {
+    let shard_node = |node: &Node| {
+        let offset = if node.id == cluster.myself {
+            replication::offset()
+        } else {
+            0
+        };
+        let role = if node.primary.is_some() {
+            "replica"
+        } else {
+            "master"
+        };
+        Message::array(vec![
+            bulk("id"),
+            bulk(node.id.clone()),
+            bulk("port"),
+            Message::integer(node.port as i64),
+            bulk("ip"),
+            bulk(node.host.clone()),
+            bulk("endpoint"),
+            bulk(node.host.clone()),
+            bulk("role"),
+            bulk(role),
+            bulk("replication-offset"),
+            Message::integer(offset as i64),
+            bulk("health"),
+            bulk(if node.fail { "fail" } else { "online" }),
+        ])
+    };
+    let shards = cluster
+        .nodes
+        .iter()
+        .filter(|node| node.primary.is_none())
+        .map(|primary| {
+            let slots = cluster
+                .ranges(&primary.id)
+                .into_iter()
+                .flat_map(|(start, end)| {
+                    [Message::integer(start as i64), Message::integer(end as i64)]
+                })
+                .collect();
+            let mut nodes = vec![shard_node(primary)];
+            nodes.extend(
+                cluster
+                    .nodes
+                    .iter()
+                    .filter(|node| node.primary.as_deref() == Some(primary.id.as_str()))
+                    .map(shard_node),
+            );
+            Message::array(vec![
+                bulk("slots"),
+                Message::array(slots),
+                bulk("nodes"),
+                Message::array(nodes),
+            ])
+        })
+        .collect();
+    Message::array(shards)
+}
+
+fn info_reply(cluster: &Cluster) -> Message {
+    let myself = cluster.node(&cluster.myself);
+    let size = cluster
+        .nodes
+        .iter()
+        .filter(|node| cluster.slots.iter().any(|o| o.as_ref() == Some(&node.id)))
+        .count();
+    let pfail = cluster.slots_flagged(|node| node.pfail);
+    let fail = cluster.slots_flagged(|node| node.fail);
+    let lines = [
+        format!("cluster_state:{}", if cluster.ok { "ok" } else { "fail" }),
+        format!("cluster_slots_assigned:{}", cluster.assigned),
+        format!("cluster_slots_ok:{}", cluster.assigned - pfail - fail),
+        format!("cluster_slots_pfail:{}", pfail),
+        format!("cluster_slots_fail:{}", fail),
+        format!("cluster_known_nodes:{}", cluster.nodes.len()),
+        format!("cluster_size:{}", size),
+        format!("cluster_current_epoch:{}", cluster.current_epoch),
+        format!(
+            "cluster_my_epoch:{}",
+            myself.map_or(0, |node| node.config_epoch)
+        ),
+    ];
+    bulk(lines.join("\r\n") + "\r\n")
+}
+
+pub fn cluster(args: Vec<Message>) -> Message {
+    let mut parts = Vec::new();
+    for arg in args {
+        let Bulk(bytes) = arg else {
+            return Message::error("ERR syntax error");
+        };
+        parts.push(bytes);
+    }
+    let Some((subcommand, rest)) = parts.split_first() else {
+        return Message::error("ERR wrong number of arguments for 'cluster' command");
+    };
+    let subcommand = String::from_utf8_lossy(subcommand).to_lowercase();
+    let wrong_arity = || {
+        Message::error(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'",
+            subcommand
+        ))
+    };
+    // Pure computation, so it works without cluster mode too.
+    if subcommand == "keyslot" {
+        return match rest {
+            [key] => Message::integer(key_slot(key) as i64),
+            _ => wrong_arity(),
+        };
+    }
+
+    let mut guard = CLUSTER.lock().unwrap();
+    let Some(cluster) = guard.as_mut() else {
+        return Message::error("ERR This instance has cluster support disabled");
+    };
+    match (subcommand.as_str(), rest) {
+        ("myid", []) => bulk(cluster.myself.clone()),
+        ("info", []) => info_reply(cluster),
+        ("nodes", []) => bulk(cluster.nodes_text(true)),
+        ("slots", []) => slots_reply(cluster),
+        ("shards", []) => shards_reply(cluster),
+        ("countkeysinslot", [slot]) => match parse_slot(slot) {
+            Ok(slot) => Message::integer(keys_in_slot(slot).count() as i64),
+            Err(_) => Message::error("ERR Invalid slot"),
+        },
+        ("getkeysinslot", [slot, count]) => {
+            let Ok(slot) = parse_slot(slot) else {
+                return Message::error("ERR Invalid slot");
+            };
+            let Ok(count) = String::from_utf8_lossy(count).parse::<usize>() else {
+                return Message::error("ERR Invalid number of keys");
+            };
+            Message::array(keys_in_slot(slot).take(count).map(Message::bulk).collect())
+        }
+        ("addslots" | "addslotsrange" | "delslots" | "delslotsrange", rest) => {
+            let slots = match parse_slots(rest, subcommand.ends_with("range")) {
+                Ok(slots) => slots,
+                Err(err) => return err,
+            };
+            let adding = subcommand.starts_with("add");
+            for (i, &slot) in slots.iter().enumerate() {
+                if slots[..i].contains(&slot) {
+                    return Message::error(format!("ERR Slot {} specified multiple times", slot));
+                }
+                match (&cluster.slots[slot], adding) {
+                    (Some(_), true) => {
+                        return Message::error(format!("ERR Slot {} is already busy", slot))
+                    }
+                    (None, false) => {
+                        return Message::error(format!("ERR Slot {} is already unassigned", slot))
+                    }
+                    _ => {}
+                }
+            }
+            let owner = adding.then(|| cluster.myself.clone());
+            for slot in slots {
+                cluster.set_slot(slot, owner.clone());
+            }
+            updated(cluster)
+        }
+        ("flushslots", []) => {
+            if !keys().is_empty() {
+                return Message::error("ERR DB must be empty to perform CLUSTER FLUSHSLOTS.");
+            }
+            for slot in 0..SLOTS {
+                if cluster.slots[slot].as_deref() == Some(cluster.myself.as_str()) {
+                    cluster.set_slot(slot, None);
+                }
+            }
+            updated(cluster)
+        }
+        ("saveconfig", []) => updated(cluster),
+        ("meet", [host, port, cport @ ..]) if cport.len() <= 1 => {
+            let host = String::from_utf8_lossy(host).to_string();
+            let parse = |port: &[u8]| config::parse_port(&String::from_utf8_lossy(port)).ok();
+            let (Some(port), Some(cport)) = (
+                parse(port),
+                cport
+                    .first()
+                    .map_or(parse(port).map(|p| p.wrapping_add(10000)), |c| parse(c)),
+            ) else {
+                return Message::error(format!(
+                    "ERR Invalid node address specified: {}:{}",
+                    host,
+                    String::from_utf8_lossy(port)
+                ));
+            };
+            // The bus swaps the made up id for the real one on the answer.
+            let mut node = Node::new(replication::new_replid(), host, port, cport);
+            node.handshake = true;
+            cluster.nodes.push(node);
+            Message::simple("OK")
+        }
+        ("forget", [id]) => {
+            let id = String::from_utf8_lossy(id).to_string();
+            if id == cluster.myself {
+                return Message::error("ERR I tried hard but I can't forget myself...");
+            }
+            if cluster.me().primary.as_deref() == Some(id.as_str()) {
+                return Message::error("ERR Can't forget my master!");
+            }
+            let Some(i) = cluster.nodes.iter().position(|node| node.id == id) else {
+                return Message::error(format!("ERR Unknown node {}", id));
+            };
+            cluster.nodes.remove(i);
+            for slot in 0..SLOTS {
+                if cluster.slots[slot].as_deref() == Some(id.as_str()) {
+                    cluster.set_slot(slot, None);
+                }
+            }
+            cluster.forgotten.insert(id, now_ms() + FORGET_TTL);
+            updated(cluster)
+        }
+        ("count-failure-reports", [id]) => match cluster.node(&String::from_utf8_lossy(id)) {
+            Some(node) => Message::integer(node.fail_reports.len() as i64),
+            None => Message::error(format!("ERR Unknown node {}", String::from_utf8_lossy(id))),
+        },
+        ("bumpepoch", []) => {
+            let bumped = cluster.bump_epoch();
+            let epoch = cluster.me().config_epoch;
+            match updated(cluster) {
+                Simple(_) if bumped => Message::simple(format!("BUMPED {}", epoch)),
+                Simple(_) => Message::simple(format!("STILL {}", epoch)),
+                err => err,
+            }
+        }
+        ("setslot", [slot, action, rest @ ..]) => {
+            let slot = match parse_slot(slot) {
+                Ok(slot) => slot,
+                Err(err) => return err,
+            };
+            let action = String::from_utf8_lossy(action).to_lowercase();
+            match setslot(cluster, slot, &action, rest) {
+                Ok(()) => updated(cluster),
+                Err(err) => err,
+            }
+        }
+        _ => wrong_arity(),
+    }
+}
+
+fn keys_in_slot(slot: usize) -> impl Iterator<Item = Vec<u8>> {
+    keys()
+        .into_iter()
+        .filter(move |key| key_slot(key) as usize == slot)
+}
+
+// CLUSTER SETSLOT slot IMPORTING|MIGRATING|NODE node-id and
+// CLUSTER SETSLOT slot STABLE, the steps of moving a slot: the target
+// imports it, the source migrates it, MIGRATE moves the keys and NODE
+// hands it over, first on the target and then on the source.
+fn setslot(
+    cluster: &mut Cluster,
+    slot: usize,
+    action: &str,
+    rest: &[Vec<u8>],
+) -> Result<(), Message> {
+    let id = match rest {
+        [] => None,
+        [id] => {
+            let id = String::from_utf8_lossy(id).to_string();
+            if cluster.node(&id).is_none() {
+                return Err(Message::error(format!(
+                    "ERR I don't know about node {}",
+                    id
+                )));
+            }
+            Some(id)
+        }
+        _ => return Err(Message::error("ERR syntax error")),
+    };
+    let mine = cluster.slots[slot].as_deref() == Some(cluster.myself.as_str());
+    match (action, id) {
+        ("migrating", Some(id)) => {
+            if !mine {
+                return Err(Message::error(format!(
+                    "ERR I'm not the owner of hash slot {}",
+                    slot
+                )));
+            }
+            cluster.migrating.insert(slot, id);
+        }
+        ("importing", Some(id)) => {
+            if mine {
+                return Err(Message::error(format!(
+                    "ERR I'm already the owner of hash slot {}",
+                    slot
+                )));
+            }
+            cluster.importing.insert(slot, id);
+        }
+        ("stable", None) => {
+            cluster.migrating.remove(&slot);
+            cluster.importing.remove(&slot);
+        }
+        ("node", Some(id)) => {
+            let keys = keys_in_slot(slot).count();
+            if mine && id != cluster.myself && keys > 0 {
+                return Err(Message::error(format!(
+                    "ERR Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                    slot
+                )));
+            }
+            if keys == 0 {
+                cluster.migrating.remove(&slot);
+            }
+            // The new owner's claim has to beat the old one's epoch, which
+            // this takes without asking the other nodes.
+            if id == cluster.myself && cluster.importing.remove(&slot).is_some() {
+                cluster.bump_epoch();
+            }
+            cluster.set_slot(slot, Some(id));
+        }
+        _ => {
+            return Err(Message::error(
+                "ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP",
+            ))
+        }
+    }
+    Ok(())
+}
+
+fn updated(cluster: &mut Cluster) -> Message {
+    match cluster.update() {
+        Ok(()) => Message::simple("OK"),
+        Err(err) => Message::error(format!("ERR error saving the cluster node config: {}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_keeps_keys_moved_before_a_read_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let target = spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut resp = Resp::new(BufReader::new(stream.try_clone().unwrap()));
+            resp.read().unwrap();
+            resp.read().unwrap();
+            // Takes the first key and hangs up before answering the second.
+            (&stream).write_all(b"+OK\r\n").unwrap();
+        });
+        let mut snapshot = crate::handlers::Snapshot::default();
+        snapshot.sets.insert(b"migrate-a".to_vec(), b"1".to_vec());
+        snapshot.sets.insert(b"migrate-b".to_vec(), b"2".to_vec());
+        snapshot.restore();
+        let args = Message::bulks(&[
+            "127.0.0.1",
+            &port.to_string(),
+            "",
+            "0",
+            "1000",
+            "KEYS",
+            "migrate-a",
+            "migrate-b",
+        ]);
+        let (reply, del) = migrate(&args);
+        target.join().unwrap();
+        assert_eq!(
+            reply,
+            Message::error("IOERR error or timeout reading to target instance")
+        );
+        assert_eq!(
+            del,
+            Some(Message::array(vec![
+                Message::bulk(b"DEL".to_vec()),
+                Message::bulk(b"migrate-a".to_vec()),
+            ]))
+        );
+        assert!(!exists(b"migrate-a"));
+        assert!(exists(b"migrate-b"));
+    }
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"{user1000}.followers"), key_slot(b"user1000"));
+        // An empty tag, or a missing `}`, hashes the whole key.
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % 16384);
+        assert_eq!(key_slot(b"foo{bar"), crc16(b"foo{bar") % 16384);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+    }
+
+    fn two_nodes() -> Cluster {
+        let text = "\
+aaaa 127.0.0.1:7000@17000 myself,master - 0 0 1 connected 0-8191
+bbbb 127.0.0.1:7001@17001 master - 0 0 2 connected 8192-16382 16383 [5->-cccc]
+cccc 127.0.0.1:7002@17002 slave bbbb 0 0 2 connected
+vars currentEpoch 2 lastVoteEpoch 0
+";
+        Cluster::parse(text, Path::new("nodes.conf")).unwrap()
+    }
+
+    #[test]
+    fn test_nodes_conf_round_trip() {
+        let cluster = two_nodes();
+        assert_eq!(cluster.myself, "aaaa");
+        assert_eq!(cluster.current_epoch, 2);
+        assert_eq!(cluster.assigned, SLOTS);
+        assert_eq!(cluster.ranges("bbbb"), vec![(8192, 16383)]);
+        // Migrations are only kept for myself.
+        assert!(cluster.migrating.is_empty());
+        assert_eq!(
+            cluster.nodes_text(false),
+            "\
+aaaa 127.0.0.1:7000@17000 myself,master - 0 0 1 connected 0-8191
+bbbb 127.0.0.1:7001@17001 master - 0 0 2 connected 8192-16383
+cccc 127.0.0.1:7002@17002 slave bbbb 0 0 2 connected
+"
+        );
+        assert!(Cluster::parse(
+            "bbbb 127.0.0.1:7001@17001 master - 0 0 2 connected",
+            Path::new("x")
+        )
+        .is_err());
+        assert!(Cluster::parse(
+            "aaaa 127.0.0.1:7000@17000 myself,master - 0 0 1 connected 0-16384",
+            Path::new("x")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_slots_reply() {
+        let cluster = two_nodes();
+        let node = |port: i64, id: &str| {
+            Message::array(vec![bulk("127.0.0.1"), Message::integer(port), bulk(id)])
+        };
+        assert_eq!(
+            slots_reply(&cluster),
+            Message::array(vec![
+                Message::array(vec![
+                    Message::integer(0),
+                    Message::integer(8191),
+                    node(7000, "aaaa"),
+                ]),
+                Message::array(vec![
+                    Message::integer(8192),
+                    Message::integer(16383),
+                    node(7001, "bbbb"),
+                    node(7002, "cccc"),
+                ]),
+            ])
+        );
+    }
+
+    fn lone_node(id: &str, port: u16, slots: &str) -> Cluster {
+        let line = format!(
+            "{} 127.0.0.1:{}@{} myself,master - 0 0 0 connected {}",
+            id,
+            port,
+            port + 10000,
+            slots
+        );
+        Cluster::parse(&line, Path::new("nodes.conf")).unwrap()
+    }
+
+    // Delivers `message` to `to` and its PONG back to `from`, as the bus
+    // would for a message sent to the node `pinged`.
+    fn exchange(from: &mut Cluster, pinged: &str, message: Message, to: &mut Cluster, now: u64) {
+        to.receive(&texts(message).unwrap(), now).unwrap();
+        from.pong(pinged, to.message("PONG", None), now).unwrap();
+    }
+
+    fn meet(a: &mut Cluster, b: &mut Cluster, now: u64) {
+        let mut node = Node::new("handshake".to_string(), "127.0.0.1".to_string(), 0, 0);
+        node.handshake = true;
+        a.nodes.push(node);
+        let out = a.tick(now);
+        let (id, _, _, message) = out.into_iter().find(|(id, ..)| id == "handshake").unwrap();
+        exchange(a, &id, message, b, now);
+    }
+
+    #[test]
+    fn test_meet_and_gossip() {
+        let mut a = lone_node("aaaa", 7000, "0-8191");
+        let mut b = lone_node("bbbb", 7001, "8192-16383");
+        let mut c = lone_node("cccc", 7002, "");
+        meet(&mut a, &mut b, 1000);
+        assert_eq!(a.node("bbbb").unwrap().port, 7001);
+        assert_eq!(b.node("aaaa").unwrap().port, 7000);
+        assert!(a.node("handshake").is_none());
+        assert_eq!(a.assigned, SLOTS);
+        assert_eq!(b.assigned, SLOTS);
+        // Both started at epoch 0, so the smaller id took a new one.
+        assert_eq!(a.me().config_epoch, 1);
+        assert_eq!(a.current_epoch, 1);
+
+        // c only meets b, and reaches a through b's gossip.
+        meet(&mut c, &mut b, 2000);
+        let ping = b.message("PING", None);
+        exchange(&mut b, "aaaa", ping, &mut a, 3000);
+        assert_eq!(a.node("cccc").unwrap().port, 7002);
+        assert_eq!(a.nodes.len(), 3);
+    }
+
+    #[test]
+    fn test_failure_detection() {
+        let mut a = lone_node("aaaa", 7000, "0-5000");
+        let mut b = lone_node("bbbb", 7001, "5001-10000");
+        let mut c = lone_node("cccc", 7002, "10001-16383");
+        meet(&mut a, &mut b, 1000);
+        meet(&mut a, &mut c, 1000);
+        meet(&mut b, &mut c, 1000);
+
+        // b stops answering: a suspects it, but one primary is no majority.
+        let timeout = config::cluster_node_timeout();
+        let now = 1000 + timeout + 1;
+        a.node_mut("bbbb").unwrap().ping_sent = 1000;
+        a.tick(now);
+        assert!(a.node("bbbb").unwrap().pfail);
+        assert!(!a.node("bbbb").unwrap().fail);
+
+        // c agrees, so a flags it down and tells c.
+        c.node_mut("bbbb").unwrap().pfail = true;
+        let ping = c.message("PING", None);
+        exchange(&mut c, "aaaa", ping, &mut a, now);
+        assert_eq!(a.node("bbbb").unwrap().fail_reports.len(), 1);
+        let out = a.tick(now);
+        assert!(a.node("bbbb").unwrap().fail);
+        assert_eq!(a.slots_flagged(|node| node.fail), 5000);
+        let (_, _, _, fail) = out
+            .into_iter()
+            .find(|(id, _, _, message)| {
+                id == "cccc" && texts(message.clone()).unwrap()[0] == "FAIL"
+            })
+            .unwrap();
+        exchange(&mut a, "cccc", fail, &mut c, now);
+        assert!(c.node("bbbb").unwrap().fail);
+
+        // Answering again clears it.
+        let ping = a.message("PING", None);
+        exchange(&mut a, "bbbb", ping, &mut b, now + 1);
+        assert!(!a.node("bbbb").unwrap().fail);
+    }
+
+    #[test]
+    fn test_setslot_hands_over_with_a_newer_epoch() {
+        let mut a = lone_node("aaaa", 7000, "0-16383");
+        let mut b = lone_node("bbbb", 7001, "");
+        meet(&mut a, &mut b, 1000);
+        let (a_id, b_id) = (b"aaaa".to_vec(), b"bbbb".to_vec());
+        assert!(setslot(&mut a, 7, "importing", std::slice::from_ref(&b_id)).is_err());
+        assert!(setslot(&mut b, 7, "migrating", std::slice::from_ref(&a_id)).is_err());
+        assert!(setslot(&mut b, 7, "importing", &[b"nosuch".to_vec()]).is_err());
+        setslot(&mut a, 7, "migrating", std::slice::from_ref(&b_id)).unwrap();
+        setslot(&mut b, 7, "importing", std::slice::from_ref(&a_id)).unwrap();
+        assert!(a.describe(a.me(), false).ends_with(" 0-16383 [7->-bbbb]"));
+        assert!(b.describe(b.me(), false).ends_with(" [7-<-aaaa]"));
+
+        let ping = a.message("PING", None);
+        exchange(&mut a, "bbbb", ping, &mut b, 1500);
+        setslot(&mut b, 7, "node", std::slice::from_ref(&b_id)).unwrap();
+        assert!(b.importing.is_empty());
+        assert!(b.me().config_epoch > a.me().config_epoch);
+        // a gives the slot up on b's next ping.
+        let ping = b.message("PING", None);
+        exchange(&mut b, "aaaa", ping, &mut a, 2000);
+        assert_eq!(a.slots[7].as_deref(), Some("bbbb"));
+        assert_eq!(a.ranges("aaaa"), vec![(0, 6), (8, 16383)]);
+        setslot(&mut a, 7, "stable", &[]).unwrap();
+        assert!(a.migrating.is_empty());
+    }
+}
@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+
+use crate::handlers::{Command, FLAG_NAMES, WRITE};
+use crate::message::Message;
+use crate::message::Message::*;
+
+pub type CommandTable = HashMap<&'static str, Command>;
+
+// (name, summary, since, group) for COMMAND DOCS.
+const DOCS: &[(&str, &str, &str, &str)] = &[
+    ("BGREWRITEAOF", "Asynchronously rewrites the append-only file to disk.", "1.0.0", "server"),
+    ("SAVE", "Synchronously saves the database(s) to disk.", "1.0.0", "server"),
+    ("BGSAVE", "Asynchronously saves the database(s) to disk.", "1.0.0", "server"),
+    ("LASTSAVE", "Returns the Unix timestamp of the last successful save to disk.", "1.0.0", "server"),
+    ("REPLICAOF", "Configures a server as replica of another, or promotes it to a master.", "5.0.0", "server"),
+    ("ROLE", "Returns the replication role.", "2.8.12", "server"),
+    ("INFO", "Returns information and statistics about the server.", "1.0.0", "server"),
+    ("SLOWLOG", "A container for slow log commands.", "2.2.12", "server"),
+    ("LATENCY", "A container for latency diagnostics commands.", "2.8.13", "server"),
+    ("CLUSTER", "A container for Redis Cluster commands.", "3.0.0", "cluster"),
+    ("BITCOUNT", "Counts the number of set bits (population counting) in a string.", "2.6.0", "bitmap"),
+    ("BITFIELD", "Performs arbitrary bitfield integer operations on strings.", "3.2.0", "bitmap"),
+    ("BITFIELD_RO", "Performs arbitrary read-only bitfield integer operations on strings.", "6.0.0", "bitmap"),
+    ("BITOP", "Performs bitwise operations on multiple strings, and stores the result.", "2.6.0", "bitmap"),
+    ("BITPOS", "Finds the first set (1) or clear (0) bit in a string.", "2.8.7", "bitmap"),
+    ("COMMAND", "Returns detailed information about all commands.", "2.8.13", "server"),
+    ("DEL", "Deletes one or more keys.", "1.0.0", "generic"),
+    ("DUMP", "Returns a serialized representation of the value stored at a key.", "2.6.0", "generic"),
+    ("GEOADD", "Adds one or more members to a geospatial index. The key is created if it doesn't exist.", "3.2.0", "geo"),
+    ("GEODIST", "Returns the distance between two members of a geospatial index.", "3.2.0", "geo"),
+    ("GEOHASH", "Returns members from a geospatial index as geohash strings.", "3.2.0", "geo"),
+    ("GEOPOS", "Returns the longitude and latitude of members from a geospatial index.", "3.2.0", "geo"),
+    ("GEOSEARCH", "Queries a geospatial index for members inside an area of a box or a circle.", "6.2.0", "geo"),
+    ("GEOSEARCHSTORE", "Queries a geospatial index for members inside an area of a box or a circle, optionally stores the result.", "6.2.0", "geo"),
+    ("GET", "Returns the string value of a key.", "1.0.0", "string"),
+    ("GETBIT", "Returns a bit value by offset.", "2.2.0", "bitmap"),
+    ("HGET", "Returns the value of a field in a hash.", "2.0.0", "hash"),
+    ("HGETALL", "Returns all fields and values in a hash.", "2.0.0", "hash"),
+    ("HSET", "Creates or modifies the value of a field in a hash.", "2.0.0", "hash"),
+    ("JSON.ARRAPPEND", "Appends one or more JSON values to the arrays at the path.", "1.0.0", "json"),
+    ("JSON.ARRLEN", "Returns the length of the arrays at the path.", "1.0.0", "json"),
+    ("JSON.DEL", "Deletes the values at the path.", "1.0.0", "json"),
+    ("JSON.GET", "Returns the values at the paths, serialized as JSON.", "1.0.0", "json"),
+    ("JSON.MGET", "Returns the values at the path from several keys.", "1.0.0", "json"),
+    ("JSON.NUMINCRBY", "Increments the numbers at the path by a value.", "1.0.0", "json"),
+    ("JSON.OBJKEYS", "Returns the keys of the objects at the path.", "1.0.0", "json"),
+    ("JSON.SET", "Sets or updates the JSON value at the path.", "1.0.0", "json"),
+    ("JSON.TYPE", "Returns the types of the values at the path.", "1.0.0", "json"),
+    ("LLEN", "Returns the length of a list.", "1.0.0", "list"),
+    ("LRANGE", "Returns a range of elements from a list.", "1.0.0", "list"),
+    ("PERSIST", "Removes the expiration time of a key.", "2.2.0", "generic"),
+    ("PEXPIREAT", "Sets the expiration time of a key to a Unix milliseconds timestamp.", "2.6.0", "generic"),
+    ("PFADD", "Adds elements to a HyperLogLog key. Creates the key if it doesn't exist.", "2.8.9", "hyperloglog"),
+    ("PFCOUNT", "Returns the approximated cardinality of the set(s) observed by the HyperLogLog key(s).", "2.8.9", "hyperloglog"),
+    ("PFMERGE", "Merges one or more HyperLogLog values into a single key.", "2.8.9", "hyperloglog"),
+    ("PING", "Returns the server's liveliness response.", "1.0.0", "connection"),
+    ("PTTL", "Returns the expiration time in milliseconds of a key.", "2.6.0", "generic"),
+    ("RESTORE", "Creates a key from the serialized representation of a value.", "2.6.0", "generic"),
+    ("RESTORE-ASKING", "An internal command for migrating keys in a cluster.", "3.0.0", "server"),
+    ("RPUSH", "Appends one or more elements to a list. Creates the key if it doesn't exist.", "1.0.0", "list"),
+    ("SADD", "Adds one or more members to a set. Creates the key if it doesn't exist.", "1.0.0", "set"),
+    ("SCARD", "Returns the number of members in a set.", "1.0.0", "set"),
+    ("SET", "Sets the string value of a key, ignoring its type. The key is created if it doesn't exist.", "1.0.0", "string"),
+    ("SETBIT", "Sets or clears the bit at offset of the string value. Creates the key if it doesn't exist.", "2.2.0", "bitmap"),
+    ("SMEMBERS", "Returns all members of a set.", "1.0.0", "set"),
+    ("TTL", "Returns the expiration time in seconds of a key.", "1.0.0", "generic"),
+    ("ZADD", "Adds one or more members to a sorted set, or updates their scores. Creates the key if it doesn't exist.", "1.2.0", "sorted-set"),
+    ("ZCARD", "Returns the number of members in a sorted set.", "1.2.0", "sorted-set"),
+    ("ZREM", "Removes one or more members from a sorted set. Deletes the sorted set if all members were removed.", "1.2.0", "sorted-set"),
+    ("ZSCORE", "Returns the score of a member in a sorted set.", "1.2.0", "sorted-set"),
+];
+
+pub fn command(args: Vec<Message>, table: &CommandTable) -> Message {
+    let (sub, rest) = match args.split_first() {
+        None => return Message::array(sorted(table).into_iter().map(info).collect()),
+        Some((Bulk(sub), rest)) => (String::from_utf8_lossy(sub).to_uppercase(), rest),
+        Some(_) => return Message::error("ERR syntax error"),
+    };
+    match sub.as_str() {
+        "COUNT" if rest.is_empty() => Message::integer(table.len() as i64),
+        "INFO" if rest.is_empty() => Message::array(sorted(table).into_iter().map(info).collect()),
+        "INFO" => Message::array(
+            rest.iter()
+                .map(|name| lookup(table, name).map(info).unwrap_or(Null))
+                .collect(),
+        ),
+        "DOCS" => {
+            let commands = match rest {
+                [] => sorted(table),
+                _ => rest.iter().filter_map(|name| lookup(table, name)).collect(),
+            };
+            let mut reply = Vec::new();
+            for command in commands {
+                reply.push(Message::bulk(command.name.to_lowercase().into_bytes()));
+                reply.push(docs(command));
+            }
+            Message::array(reply)
+        }
+        "GETKEYS" if !rest.is_empty() => getkeys(rest, table),
+        "LIST" => list(rest, table),
+        _ => Message::error(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try COMMAND HELP.",
+            sub.to_lowercase()
+        )),
+    }
+}
+
+fn sorted(table: &CommandTable) -> Vec<&Command> {
+    let mut commands: Vec<&Command> = table.values().collect();
+    commands.sort_by_key(|command| command.name);
+    commands
+}
+
+fn lookup<'a>(table: &'a CommandTable, name: &Message) -> Option<&'a Command> {
+    match name {
+        Bulk(name) => table.get(String::from_utf8_lossy(name).to_uppercase().as_str()),
+        _ => None,
+    }
+}
+
+fn simple_array<'a>(items: impl IntoIterator<Item = &'a str>) -> Message {
+    Message::array(items.into_iter().map(Message::simple).collect())
+}
+
+fn bulk_str(s: &str) -> Message {
+    Message::bulk(s.as_bytes().to_vec())
+}
+
+// The ten element reply of COMMAND INFO: name, arity, flags, first key, last
+// key, step, ACL categories, tips, key specs and subcommands.
+fn info(command: &Command) -> Message {
+    let flags = FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| command.flags & flag != 0)
+        .map(|(_, name)| *name);
+    Message::array(vec![
+        Message::bulk(command.name.to_lowercase().into_bytes()),
+        Message::integer(command.arity as i64),
+        simple_array(flags),
+        Message::integer(command.first_key as i64),
+        Message::integer(command.last_key as i64),
+        Message::integer(command.step as i64),
+        simple_array(command.acl_categories.iter().copied()),
+        Message::array(vec![]),
+        key_specs(command),
+        Message::array(vec![]),
+    ])
+}
+
+// A single range spec equivalent to the legacy first/last/step triple.
+fn key_specs(command: &Command) -> Message {
+    if command.first_key == 0 {
+        return Message::array(vec![]);
+    }
+    let flags = if command.flags & WRITE != 0 {
+        ["RW", "UPDATE"]
+    } else {
+        ["RO", "ACCESS"]
+    };
+    let last_key = match command.last_key {
+        n if n < 0 => n,
+        n => n - command.first_key,
+    };
+    Message::array(vec![Message::array(vec![
+        bulk_str("flags"),
+        simple_array(flags),
+        bulk_str("begin_search"),
+        Message::array(vec![
+            bulk_str("type"),
+            bulk_str("index"),
+            bulk_str("spec"),
+            Message::array(vec![
+                bulk_str("index"),
+                Message::integer(command.first_key as i64),
+            ]),
+        ]),
+        bulk_str("find_keys"),
+        Message::array(vec![
+            bulk_str("type"),
+            bulk_str("range"),
+            bulk_str("spec"),
+            Message::array(vec![
+                bulk_str("lastkey"),
+                Message::integer(last_key as i64),
+                bulk_str("keystep"),
+                Message::integer(command.step as i64),
+                bulk_str("limit"),
+                Message::integer(0),
+            ]),
+        ]),
+    ])])
+}
+
+fn docs(command: &Command) -> Message {
+    let mut reply = Vec::new();
+    if let Some((_, summary, since, group)) = DOCS.iter().find(|doc| doc.0 == command.name) {
+        reply.extend([
+            bulk_str("summary"),
+            bulk_str(summary),
+            bulk_str("since"),
+            bulk_str(since),
+            bulk_str("group"),
+            bulk_str(group),
+        ]);
+    }
+    Message::array(reply)
+}
+
+fn getkeys(argv: &[Message], table: &CommandTable) -> Message {
+    let command = match lookup(table, &argv[0]) {
+        Some(command) => command,
+        None => return Message::error("ERR Invalid command specified"),
+    };
+    if !command.check_arity(argv.len()) {
+        return Message::error("ERR Invalid number of arguments specified for command");
+    }
+    let keys = command.key_indexes(argv.len());
+    if keys.is_empty() {
+        return Message::error("ERR The command has no key arguments");
+    }
+    Message::array(keys.into_iter().map(|i| argv[i].clone()).collect())
+}
+
+fn list(args: &[Message], table: &CommandTable) -> Message {
+    let filter: Box<dyn Fn(&Command) -> bool> = match args {
+        [] => Box::new(|_| true),
+        [Bulk(filterby), Bulk(kind), Bulk(value)] if filterby.eq_ignore_ascii_case(b"FILTERBY") => {
+            match String::from_utf8_lossy(kind).to_uppercase().as_str() {
+                // No modules can be loaded, so nothing belongs to one.
+                "MODULE" => Box::new(|_| false),
+                "ACLCAT" => Box::new(move |command| {
+                    command
+                        .acl_categories
+                        .iter()
+                        .any(|category| category.as_bytes()[1..].eq_ignore_ascii_case(value))
+                }),
+                "PATTERN" => Box::new(move |command| {
+                    glob_match(value, command.name.to_lowercase().as_bytes())
+                }),
+                _ => return Message::error("ERR syntax error"),
+            }
+        }
+        _ => return Message::error("ERR syntax error"),
+    };
+    Message::array(
+        sorted(table)
+            .into_iter()
+            .filter(|command| filter(command))
+            .map(|command| Message::bulk(command.name.to_lowercase().into_bytes()))
+            .collect(),
+    )
+}
+
+// Redis style glob: `*`, `?`, `[...]` classes with `^` and ranges, and `\`
+// escapes. Matching is case-insensitive like COMMAND LIST.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => string.is_empty(),
+        Some((b'*', rest)) => (0..=string.len()).any(|i| glob_match(rest, &string[i..])),
+        Some((b'?', rest)) => !string.is_empty() && glob_match(rest, &string[1..]),
+        Some((b'[', rest)) => {
+            let Some((&c, tail)) = string.split_first() else {
+                return false;
+            };
+            let (negate, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match class {
+                    [] => break,
+                    [b']', after @ ..] => {
+                        class = after;
+                        break;
+                    }
+                    [b'\\', x, after @ ..] => {
+                        matched |= x.eq_ignore_ascii_case(&c);
+                        class = after;
+                    }
+                    [lo, b'-', hi, after @ ..] if *hi != b']' => {
+                        let (lo, hi) = (
+                            lo.min(hi).to_ascii_lowercase(),
+                            lo.max(hi).to_ascii_lowercase(),
+                        );
+                        matched |= (lo..=hi).contains(&c.to_ascii_lowercase());
+                        class = after;
+                    }
+                    [x, after @ ..] => {
+                        matched |= x.eq_ignore_ascii_case(&c);
+                        class = after;
+                    }
+                }
+            }
+            matched != negate && glob_match(class, tail)
+        }
+        Some((b'\\', [x, rest @ ..])) => match string.split_first() {
+            Some((c, tail)) => x.eq_ignore_ascii_case(c) && glob_match(rest, tail),
+            None => false,
+        },
+        Some((x, rest)) => match string.split_first() {
+            Some((c, tail)) => x.eq_ignore_ascii_case(c) && glob_match(rest, tail),
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::HANDLERS;
+
+    #[test]
+    fn test_every_command_has_docs() {
+        for name in HANDLERS.keys() {
+            assert!(
+                DOCS.iter().any(|doc| doc.0 == *name),
+                "{} has no docs",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_command_count() {
+        assert_eq!(
+            command(Message::bulks(&["COUNT"]), &HANDLERS),
+            Message::integer(HANDLERS.len() as i64)
+        );
+    }
+
+    #[test]
+    fn test_command_info_shape() {
+        let reply = command(Message::bulks(&["INFO", "get", "nosuch"]), &HANDLERS);
+        let Array(entries) = reply else {
+            panic!("expected array");
+        };
+        assert_eq!(entries[1], Null);
+        let Array(get) = &entries[0] else {
+            panic!("expected array");
+        };
+        assert_eq!(get.len(), 10);
+        assert_eq!(get[0], Message::bulk(b"get".to_vec()));
+        assert_eq!(get[1], Message::integer(2));
+        assert_eq!(
+            get[2],
+            Message::array(vec![Message::simple("readonly"), Message::simple("fast")])
+        );
+        assert_eq!(
+            &get[3..6],
+            &[
+                Message::integer(1),
+                Message::integer(1),
+                Message::integer(1)
+            ]
+        );
+        assert_eq!(
+            get[6],
+            Message::array(vec![
+                Message::simple("@read"),
+                Message::simple("@string"),
+                Message::simple("@fast")
+            ])
+        );
+    }
+
+    #[test]
+    fn test_command_docs() {
+        let reply = command(Message::bulks(&["DOCS", "zcard"]), &HANDLERS);
+        assert_eq!(
+            reply,
+            Message::array(vec![
+                Message::bulk(b"zcard".to_vec()),
+                Message::array(Message::bulks(&[
+                    "summary",
+                    "Returns the number of members in a sorted set.",
+                    "since",
+                    "1.2.0",
+                    "group",
+                    "sorted-set"
+                ])),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_command_getkeys() {
+        assert_eq!(
+            command(
+                Message::bulks(&["GETKEYS", "BITOP", "AND", "dest", "a", "b"]),
+                &HANDLERS
+            ),
+            Message::array(Message::bulks(&["dest", "a", "b"]))
+        );
+        assert_eq!(
+            command(
+                Message::bulks(&["GETKEYS", "JSON.MGET", "a", "b", "$"]),
+                &HANDLERS
+            ),
+            Message::array(Message::bulks(&["a", "b"]))
+        );
+        assert_eq!(
+            command(Message::bulks(&["GETKEYS", "PING"]), &HANDLERS),
+            Message::error("ERR The command has no key arguments")
+        );
+        assert_eq!(
+            command(Message::bulks(&["GETKEYS", "GET"]), &HANDLERS),
+            Message::error("ERR Invalid number of arguments specified for command")
+        );
+        assert_eq!(
+            command(Message::bulks(&["GETKEYS", "NOSUCH"]), &HANDLERS),
+            Message::error("ERR Invalid command specified")
+        );
+    }
+
+    #[test]
+    fn test_command_list_filterby() {
+        assert_eq!(
+            command(
+                Message::bulks(&["LIST", "FILTERBY", "ACLCAT", "hyperloglog"]),
+                &HANDLERS
+            ),
+            Message::array(Message::bulks(&["pfadd", "pfcount", "pfmerge"]))
+        );
+        assert_eq!(
+            command(
+                Message::bulks(&["LIST", "FILTERBY", "PATTERN", "geo*store"]),
+                &HANDLERS
+            ),
+            Message::array(Message::bulks(&["geosearchstore"]))
+        );
+        assert_eq!(
+            command(
+                Message::bulks(&["LIST", "FILTERBY", "MODULE", "json"]),
+                &HANDLERS
+            ),
+            Message::array(vec![])
+        );
+        assert_eq!(
+            command(
+                Message::bulks(&["LIST", "FILTERBY", "NAME", "x"]),
+                &HANDLERS
+            ),
+            Message::error("ERR syntax error")
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"h?l[a-z]o*", b"hello world"));
+        assert!(glob_match(b"[^x]*", b"abc"));
+        assert!(!glob_match(b"[^a]*", b"abc"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+    }
+}
@@ -0,0 +1,502 @@
+use std::sync::{LazyLock, Mutex};
+
+use crate::aof::AppendFsync;
+
+pub static CONFIG: LazyLock<Mutex<Config>> = LazyLock::new(|| Mutex::new(Config::default()));
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    // Largest string value in bytes; also caps how far SETBIT may grow a value.
+    pub proto_max_bulk_len: usize,
+    // HyperLogLog values switch from the sparse to the dense encoding above this size.
+    pub hll_sparse_max_bytes: usize,
+    // When the AOF is fsynced: on every write, once a second, or never.
+    pub appendfsync: AppendFsync,
+    // Whether a command cut short at the end of the AOF is dropped at startup
+    // instead of refusing to start.
+    pub aof_load_truncated: bool,
+    // Whether an RDB file holding lists, sets, functions or keys with an
+    // expire time loads without them instead of refusing to start.
+    pub rdb_load_lossy: bool,
+    // Whether the AOF gets `#TS:` annotations for point-in-time recovery.
+    pub aof_timestamp_enabled: bool,
+    // The AOF manifest and its base and incremental files live in
+    // `appenddirname`, all named after `appendfilename`.
+    pub appenddirname: String,
+    pub appendfilename: String,
+    // The AOF is rewritten once it has grown this many percent since the
+    // last rewrite and is at least `auto_aof_rewrite_min_size` bytes.
+    pub auto_aof_rewrite_percentage: u64,
+    pub auto_aof_rewrite_min_size: u64,
+    // Whether writes are logged to the AOF at all; without it the dataset
+    // is loaded from and saved to the RDB file only.
+    pub appendonly: bool,
+    // Whether a rewritten AOF starts with an RDB snapshot instead of commands.
+    pub aof_use_rdb_preamble: bool,
+    // `save <seconds> <changes>` rules; a snapshot is taken once any of them
+    // matches. Empty disables automatic snapshots.
+    pub save: Vec<(u64, u64)>,
+    pub dbfilename: String,
+    pub port: u16,
+    // "host port" of the primary to replicate from at startup.
+    pub replicaof: Option<(String, u16)>,
+    // Bytes of the replication stream kept for partial resyncs.
+    pub repl_backlog_size: usize,
+    // Whether a replica refuses writes from its own clients.
+    pub replica_read_only: bool,
+    // Sentinels promote replicas with a lower priority first, and never
+    // one with priority 0.
+    pub replica_priority: u64,
+    // Whether keys are sharded over 16384 slots, with the layout kept in
+    // `cluster_config_file`.
+    pub cluster_enabled: bool,
+    pub cluster_config_file: String,
+    // Whether keyed commands are refused while some slot has no node.
+    pub cluster_require_full_coverage: bool,
+    // Milliseconds a node may go without answering pings before it is
+    // suspected to have failed.
+    pub cluster_node_timeout: u64,
+    // Port of the HTTP listener serving Prometheus metrics; 0 disables it.
+    pub metrics_port: u16,
+    // Commands running at least this many microseconds go to the slow log;
+    // a negative value disables it.
+    pub slowlog_log_slower_than: i64,
+    pub slowlog_max_len: usize,
+    // Events taking at least this many milliseconds are recorded for the
+    // LATENCY command; 0 disables it.
+    pub latency_monitor_threshold: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            proto_max_bulk_len: 512 * 1024 * 1024,
+            hll_sparse_max_bytes: 3000,
+            appendfsync: AppendFsync::EverySec,
+            aof_load_truncated: true,
+            rdb_load_lossy: false,
+            aof_timestamp_enabled: false,
+            appenddirname: "appendonlydir".to_string(),
+            appendfilename: "database.aof".to_string(),
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            appendonly: true,
+            aof_use_rdb_preamble: true,
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            dbfilename: "dump.rdb".to_string(),
+            port: 6379,
+            replicaof: None,
+            repl_backlog_size: 1024 * 1024,
+            replica_read_only: true,
+            replica_priority: 100,
+            cluster_enabled: false,
+            cluster_config_file: "nodes.conf".to_string(),
+            cluster_require_full_coverage: true,
+            cluster_node_timeout: 15000,
+            metrics_port: 0,
+            slowlog_log_slower_than: 10000,
+            slowlog_max_len: 128,
+            latency_monitor_threshold: 0,
+        }
+    }
+}
+
+impl Config {
+    // Parses `--name value` pairs the same way redis-server does.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let name = match arg.strip_prefix("--") {
+                Some(name) => name.to_string(),
+                None => return Err(format!("unexpected argument '{}'", arg)),
+            };
+            match args.next() {
+                Some(value) => config.set(&name, &value)?,
+                None => return Err(format!("missing value for '--{}'", name)),
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name.to_lowercase().as_str() {
+            "proto-max-bulk-len" => self.proto_max_bulk_len = parse_memory(value)?,
+            "hll-sparse-max-bytes" => self.hll_sparse_max_bytes = parse_memory(value)?,
+            "appendfsync" => self.appendfsync = AppendFsync::parse(value)?,
+            "aof-load-truncated" => self.aof_load_truncated = parse_bool(value)?,
+            "rdb-load-lossy" => self.rdb_load_lossy = parse_bool(value)?,
+            "aof-timestamp-enabled" => self.aof_timestamp_enabled = parse_bool(value)?,
+            "appenddirname" => self.appenddirname = parse_file_name(value)?,
+            "appendfilename" => self.appendfilename = parse_file_name(value)?,
+            "auto-aof-rewrite-percentage" => {
+                self.auto_aof_rewrite_percentage = value
+                    .parse()
+                    .map_err(|_| format!("invalid percentage '{}'", value))?
+            }
+            "auto-aof-rewrite-min-size" => {
+                self.auto_aof_rewrite_min_size = parse_memory(value)? as u64
+            }
+            "appendonly" => self.appendonly = parse_bool(value)?,
+            "aof-use-rdb-preamble" => self.aof_use_rdb_preamble = parse_bool(value)?,
+            "save" => self.save = parse_save_rules(value)?,
+            "dbfilename" => self.dbfilename = parse_file_name(value)?,
+            "port" => self.port = parse_port(value)?,
+            "replicaof" => self.replicaof = parse_replicaof(value)?,
+            "repl-backlog-size" => self.repl_backlog_size = parse_memory(value)?.max(1),
+            "replica-read-only" => self.replica_read_only = parse_bool(value)?,
+            "replica-priority" => {
+                self.replica_priority = value
+                    .parse()
+                    .map_err(|_| format!("invalid priority '{}'", value))?
+            }
+            "cluster-enabled" => self.cluster_enabled = parse_bool(value)?,
+            "cluster-config-file" => self.cluster_config_file = parse_file_name(value)?,
+            "cluster-require-full-coverage" => {
+                self.cluster_require_full_coverage = parse_bool(value)?
+            }
+            "cluster-node-timeout" => {
+                self.cluster_node_timeout = value
+                    .parse()
+                    .ok()
+                    .filter(|&ms| ms > 0)
+                    .ok_or_else(|| format!("invalid timeout '{}'", value))?
+            }
+            "metrics-port" => self.metrics_port = parse_port(value)?,
+            "slowlog-log-slower-than" => {
+                self.slowlog_log_slower_than = value
+                    .parse()
+                    .map_err(|_| format!("invalid threshold '{}'", value))?
+            }
+            "latency-monitor-threshold" => {
+                self.latency_monitor_threshold = value
+                    .parse()
+                    .map_err(|_| format!("invalid threshold '{}'", value))?
+            }
+            "slowlog-max-len" => {
+                self.slowlog_max_len = value
+                    .parse()
+                    .map_err(|_| format!("invalid length '{}'", value))?
+            }
+            _ => return Err(format!("unknown option '{}'", name)),
+        }
+        Ok(())
+    }
+}
+
+pub fn proto_max_bulk_len() -> usize {
+    CONFIG.lock().unwrap().proto_max_bulk_len
+}
+
+pub fn hll_sparse_max_bytes() -> usize {
+    CONFIG.lock().unwrap().hll_sparse_max_bytes
+}
+
+pub fn auto_aof_rewrite_percentage() -> u64 {
+    CONFIG.lock().unwrap().auto_aof_rewrite_percentage
+}
+
+pub fn auto_aof_rewrite_min_size() -> u64 {
+    CONFIG.lock().unwrap().auto_aof_rewrite_min_size
+}
+
+pub fn rdb_load_lossy() -> bool {
+    CONFIG.lock().unwrap().rdb_load_lossy
+}
+
+pub fn aof_use_rdb_preamble() -> bool {
+    CONFIG.lock().unwrap().aof_use_rdb_preamble
+}
+
+pub fn save_rules() -> Vec<(u64, u64)> {
+    CONFIG.lock().unwrap().save.clone()
+}
+
+pub fn dbfilename() -> String {
+    CONFIG.lock().unwrap().dbfilename.clone()
+}
+
+pub fn port() -> u16 {
+    CONFIG.lock().unwrap().port
+}
+
+pub fn repl_backlog_size() -> usize {
+    CONFIG.lock().unwrap().repl_backlog_size
+}
+
+pub fn replica_read_only() -> bool {
+    CONFIG.lock().unwrap().replica_read_only
+}
+
+pub fn replica_priority() -> u64 {
+    CONFIG.lock().unwrap().replica_priority
+}
+
+pub fn cluster_require_full_coverage() -> bool {
+    CONFIG.lock().unwrap().cluster_require_full_coverage
+}
+
+pub fn cluster_node_timeout() -> u64 {
+    CONFIG.lock().unwrap().cluster_node_timeout
+}
+
+pub fn slowlog_log_slower_than() -> i64 {
+    CONFIG.lock().unwrap().slowlog_log_slower_than
+}
+
+pub fn slowlog_max_len() -> usize {
+    CONFIG.lock().unwrap().slowlog_max_len
+}
+
+pub fn latency_monitor_threshold() -> u64 {
+    CONFIG.lock().unwrap().latency_monitor_threshold
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("argument must be 'yes' or 'no', got '{}'", value)),
+    }
+}
+
+// Plain names only, so the AOF stays inside the working directory.
+fn parse_file_name(value: &str) -> Result<String, String> {
+    if value.is_empty() || value.contains('/') || value == "." || value == ".." {
+        return Err(format!("invalid file name '{}'", value));
+    }
+    Ok(value.to_string())
+}
+
+pub fn parse_port(value: &str) -> Result<u16, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid port '{}'", value))
+}
+
+// "host port", or "no one" for none.
+fn parse_replicaof(value: &str) -> Result<Option<(String, u16)>, String> {
+    match value.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => Ok(None),
+        [host, port] => Ok(Some((host.to_string(), parse_port(port)?))),
+        _ => Err(format!(
+            "invalid replicaof '{}', expected <host> <port>",
+            value
+        )),
+    }
+}
+
+// Pairs of seconds and changes, e.g. "3600 1 300 100".
+fn parse_save_rules(value: &str) -> Result<Vec<(u64, u64)>, String> {
+    let numbers = value
+        .split_whitespace()
+        .map(|n| n.parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("invalid save rules '{}'", value))?;
+    if !numbers.len().is_multiple_of(2) {
+        return Err(format!("invalid save rules '{}'", value));
+    }
+    Ok(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+// Accepts plain byte counts as well as the k/kb/m/mb/g/gb suffixes.
+pub fn parse_memory(value: &str) -> Result<usize, String> {
+    let lower = value.to_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid memory value '{}'", value)),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid memory value '{}'", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_default_proto_max_bulk_len() {
+        assert_eq!(Config::default().proto_max_bulk_len, 536870912);
+    }
+
+    #[test]
+    fn test_from_args() {
+        let config = Config::from_args(args(&["--proto-max-bulk-len", "1mb"])).unwrap();
+        assert_eq!(config.proto_max_bulk_len, 1024 * 1024);
+    }
+
+    #[test]
+    fn test_from_args_appendfsync() {
+        let config = Config::from_args(args(&["--appendfsync", "always"])).unwrap();
+        assert_eq!(config.appendfsync, AppendFsync::Always);
+        assert_eq!(Config::default().appendfsync, AppendFsync::EverySec);
+        assert!(Config::from_args(args(&["--appendfsync", "often"])).is_err());
+    }
+
+    #[test]
+    fn test_from_args_auto_aof_rewrite() {
+        let config = Config::from_args(args(&[
+            "--auto-aof-rewrite-percentage",
+            "50",
+            "--auto-aof-rewrite-min-size",
+            "1mb",
+        ]))
+        .unwrap();
+        assert_eq!(config.auto_aof_rewrite_percentage, 50);
+        assert_eq!(config.auto_aof_rewrite_min_size, 1024 * 1024);
+        assert!(Config::from_args(args(&["--auto-aof-rewrite-percentage", "-1"])).is_err());
+    }
+
+    #[test]
+    fn test_from_args_append_files() {
+        let config = Config::from_args(args(&[
+            "--appenddirname",
+            "aofs",
+            "--appendfilename",
+            "rustis.aof",
+        ]))
+        .unwrap();
+        assert_eq!(config.appenddirname, "aofs");
+        assert_eq!(config.appendfilename, "rustis.aof");
+        assert!(Config::from_args(args(&["--appenddirname", "../x"])).is_err());
+    }
+
+    #[test]
+    fn test_from_args_aof_load_truncated() {
+        assert!(Config::default().aof_load_truncated);
+        let config = Config::from_args(args(&["--aof-load-truncated", "no"])).unwrap();
+        assert!(!config.aof_load_truncated);
+        assert!(Config::from_args(args(&["--aof-load-truncated", "maybe"])).is_err());
+        let config = Config::from_args(args(&["--aof-timestamp-enabled", "yes"])).unwrap();
+        assert!(config.aof_timestamp_enabled);
+    }
+
+    #[test]
+    fn test_from_args_rdb_load_lossy() {
+        assert!(!Config::default().rdb_load_lossy);
+        let config = Config::from_args(args(&["--rdb-load-lossy", "yes"])).unwrap();
+        assert!(config.rdb_load_lossy);
+    }
+
+    #[test]
+    fn test_from_args_save() {
+        let config = Config::from_args(args(&["--save", "900 1 60 500"])).unwrap();
+        assert_eq!(config.save, vec![(900, 1), (60, 500)]);
+        let config = Config::from_args(args(&["--save", ""])).unwrap();
+        assert!(config.save.is_empty());
+        assert!(Config::from_args(args(&["--save", "900"])).is_err());
+        assert!(Config::from_args(args(&["--dbfilename", "a/b.rdb"])).is_err());
+    }
+
+    #[test]
+    fn test_from_args_replication() {
+        let config = Config::from_args(args(&[
+            "--port",
+            "6380",
+            "--replicaof",
+            "127.0.0.1 6379",
+            "--replica-read-only",
+            "no",
+            "--replica-priority",
+            "10",
+        ]))
+        .unwrap();
+        assert_eq!(config.port, 6380);
+        assert_eq!(config.replicaof, Some(("127.0.0.1".to_string(), 6379)));
+        assert!(!config.replica_read_only);
+        assert_eq!(config.replica_priority, 10);
+        assert!(Config::from_args(args(&["--replicaof", "localhost"])).is_err());
+        assert!(Config::from_args(args(&["--port", "70000"])).is_err());
+    }
+
+    #[test]
+    fn test_from_args_cluster() {
+        assert!(!Config::default().cluster_enabled);
+        let config = Config::from_args(args(&[
+            "--cluster-enabled",
+            "yes",
+            "--cluster-config-file",
+            "nodes-7000.conf",
+            "--cluster-require-full-coverage",
+            "no",
+            "--cluster-node-timeout",
+            "2000",
+        ]))
+        .unwrap();
+        assert!(config.cluster_enabled);
+        assert_eq!(config.cluster_config_file, "nodes-7000.conf");
+        assert!(!config.cluster_require_full_coverage);
+        assert_eq!(config.cluster_node_timeout, 2000);
+        assert!(Config::from_args(args(&["--cluster-node-timeout", "0"])).is_err());
+    }
+
+    #[test]
+    fn test_from_args_metrics_port() {
+        assert_eq!(Config::default().metrics_port, 0);
+        let config = Config::from_args(args(&["--metrics-port", "9121"])).unwrap();
+        assert_eq!(config.metrics_port, 9121);
+        assert!(Config::from_args(args(&["--metrics-port", "http"])).is_err());
+    }
+
+    #[test]
+    fn test_from_args_slowlog() {
+        let config = Config::default();
+        assert_eq!(config.slowlog_log_slower_than, 10000);
+        assert_eq!(config.slowlog_max_len, 128);
+        let config = Config::from_args(args(&[
+            "--slowlog-log-slower-than",
+            "-1",
+            "--slowlog-max-len",
+            "16",
+        ]))
+        .unwrap();
+        assert_eq!(config.slowlog_log_slower_than, -1);
+        assert_eq!(config.slowlog_max_len, 16);
+        assert!(Config::from_args(args(&["--slowlog-max-len", "-1"])).is_err());
+    }
+
+    #[test]
+    fn test_from_args_latency_monitor_threshold() {
+        assert_eq!(Config::default().latency_monitor_threshold, 0);
+        let config = Config::from_args(args(&["--latency-monitor-threshold", "100"])).unwrap();
+        assert_eq!(config.latency_monitor_threshold, 100);
+        assert!(Config::from_args(args(&["--latency-monitor-threshold", "-1"])).is_err());
+    }
+
+    #[test]
+    fn test_from_args_unknown_option() {
+        assert!(Config::from_args(args(&["--nope", "1"])).is_err());
+    }
+
+    #[test]
+    fn test_from_args_missing_value() {
+        assert!(Config::from_args(args(&["--proto-max-bulk-len"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("100"), Ok(100));
+        assert_eq!(parse_memory("2k"), Ok(2000));
+        assert_eq!(parse_memory("2KB"), Ok(2048));
+        assert_eq!(parse_memory("1gb"), Ok(1024 * 1024 * 1024));
+        assert!(parse_memory("12xb").is_err());
+        assert!(parse_memory("").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::atomic::Ordering;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::aof;
+use crate::handlers::{self, parse_int, EXECUTION, EXPIRES};
+use crate::info;
+use crate::message::Message;
+use crate::message::Message::*;
+use crate::replication;
+use crate::tcp_handler::{commit, propagate, Client};
+
+// How often expired keys nobody asks for are looked for, like Redis'
+// active expire cycle.
+const EXPIRE_PERIOD: Duration = Duration::from_millis(100);
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+// Deletes the keys among `keys` whose expire time has passed, returning
+// the DEL that carries the deletion to the AOF and replicas. A replica
+// keeps them until that DEL arrives from its primary, as in Redis. Callers
+// hold EXECUTION.
+pub fn expire_due(keys: impl IntoIterator<Item = Vec<u8>>) -> Option<Message> {
+    let now = now_ms();
+    let due: Vec<Vec<u8>> = {
+        let expires = EXPIRES.lock().unwrap();
+        if expires.is_empty() {
+            return None;
+        }
+        keys.into_iter()
+            .filter(|key| expires.get(key).is_some_and(|&at| at <= now))
+            .collect()
+    };
+    if due.is_empty() || replication::is_replica() {
+        return None;
+    }
+    for key in &due {
+        handlers::remove(key);
+    }
+    info::EXPIRED_KEYS.fetch_add(due.len() as u64, Ordering::Relaxed);
+    let mut del = vec![Message::bulk(b"DEL".to_vec())];
+    del.extend(due.into_iter().map(Message::bulk));
+    Some(Message::array(del))
+}
+
+// Deletes expired keys no command touched, ten times a second.
+pub fn spawn_expire_cron() {
+    spawn(|| loop {
+        sleep(EXPIRE_PERIOD);
+        let execution = EXECUTION.lock().unwrap();
+        let now = now_ms();
+        let due: Vec<Vec<u8>> = EXPIRES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, &at)| at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let Some(del) = expire_due(due) else {
+            continue;
+        };
+        let mut aof = aof::registered();
+        let appended = propagate(&del, &mut Client::default(), aof.as_mut());
+        drop(execution);
+        if let Err(err) = appended.and_then(|offset| commit(aof.as_ref(), offset)) {
+            println!("Propagating expired keys failed: {err:?}");
+        }
+    });
+}
+
+// PEXPIREAT key unix-time-milliseconds. A time already past deletes the
+// key, which replaying the command does again.
+pub fn pexpireat(args: Vec<Message>) -> Message {
+    let [Bulk(key), Bulk(at)] = args.as_slice() else {
+        return Message::error("ERR wrong number of arguments for 'pexpireat' command");
+    };
+    let Some(at) = parse_int(at) else {
+        return Message::error("ERR value is not an integer or out of range");
+    };
+    if !handlers::exists(key) {
+        return Message::integer(0);
+    }
+    match u64::try_from(at) {
+        Ok(at) if at > now_ms() => _ = EXPIRES.lock().unwrap().insert(key.clone(), at),
+        _ => _ = handlers::remove(key),
+    }
+    Message::integer(1)
+}
+
+pub fn pttl(args: Vec<Message>) -> Message {
+    remaining(args, "pttl", 1)
+}
+
+pub fn ttl(args: Vec<Message>) -> Message {
+    remaining(args, "ttl", 1000)
+}
+
+// The time to live in `unit` milliseconds, rounded like Redis; -2 for a
+// missing key and -1 for one that does not expire.
+fn remaining(args: Vec<Message>, name: &str, unit: u64) -> Message {
+    let [Bulk(key)] = args.as_slice() else {
+        return Message::error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ));
+    };
+    if !handlers::exists(key) {
+        return Message::integer(-2);
+    }
+    match EXPIRES.lock().unwrap().get(key) {
+        Some(&at) => Message::integer(((at.saturating_sub(now_ms()) + unit / 2) / unit) as i64),
+        None => Message::integer(-1),
+    }
+}
+
+pub fn persist(args: Vec<Message>) -> Message {
+    let [Bulk(key)] = args.as_slice() else {
+        return Message::error("ERR wrong number of arguments for 'persist' command");
+    };
+    Message::integer(EXPIRES.lock().unwrap().remove(key).is_some() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::SETS;
+
+    fn set(key: &str) {
+        SETS.lock()
+            .unwrap()
+            .insert(key.as_bytes().to_vec(), b"v".to_vec());
+    }
+
+    #[test]
+    fn test_pexpireat_and_ttl() {
+        let later = (now_ms() + 100_000).to_string();
+        assert_eq!(
+            pexpireat(Message::bulks(&["{expire}missing", &later])),
+            Message::integer(0)
+        );
+        set("{expire}k");
+        assert_eq!(ttl(Message::bulks(&["{expire}k"])), Message::integer(-1));
+        assert_eq!(
+            pexpireat(Message::bulks(&["{expire}k", &later])),
+            Message::integer(1)
+        );
+        assert_eq!(ttl(Message::bulks(&["{expire}k"])), Message::integer(100));
+        let Integer(ms) = pttl(Message::bulks(&["{expire}k"])) else {
+            panic!("expected an integer");
+        };
+        assert!((99_000..=100_000).contains(&ms), "{}", ms);
+        assert_eq!(persist(Message::bulks(&["{expire}k"])), Message::integer(1));
+        assert_eq!(persist(Message::bulks(&["{expire}k"])), Message::integer(0));
+        assert_eq!(ttl(Message::bulks(&["{expire}k"])), Message::integer(-1));
+        assert_eq!(
+            ttl(Message::bulks(&["{expire}missing"])),
+            Message::integer(-2)
+        );
+        handlers::remove(b"{expire}k");
+    }
+
+    #[test]
+    fn test_pexpireat_in_the_past_deletes() {
+        set("{expire}past");
+        assert_eq!(
+            pexpireat(Message::bulks(&["{expire}past", "1000"])),
+            Message::integer(1)
+        );
+        assert!(!handlers::exists(b"{expire}past"));
+    }
+
+    #[test]
+    fn test_expire_due() {
+        let _execution = EXECUTION.lock().unwrap();
+        set("{expire}due");
+        set("{expire}later");
+        EXPIRES
+            .lock()
+            .unwrap()
+            .insert(b"{expire}due".to_vec(), now_ms() - 1);
+        EXPIRES
+            .lock()
+            .unwrap()
+            .insert(b"{expire}later".to_vec(), now_ms() + 100_000);
+        let keys = [b"{expire}due".to_vec(), b"{expire}later".to_vec()];
+        assert_eq!(
+            expire_due(keys.clone()),
+            Some(Message::command(&["DEL", "{expire}due"]))
+        );
+        assert!(!handlers::exists(b"{expire}due"));
+        assert!(!EXPIRES.lock().unwrap().contains_key(&keys[0]));
+        assert_eq!(expire_due(keys), None);
+        handlers::remove(b"{expire}later");
+    }
+}
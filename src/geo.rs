@@ -0,0 +1,1070 @@
+use std::collections::HashSet;
+
+use crate::handlers::{parse_float, parse_int, ZSetMap};
+use crate::message::Message;
+use crate::message::Message::*;
+use crate::zset::SortedSet;
+
+// Members live in a sorted set whose scores are 52-bit interleaved geohashes,
+// so nearby points sit next to each other in score order.
+const GEO_STEP_MAX: u32 = 26;
+const GEO_LAT_MIN: f64 = -85.05112878;
+const GEO_LAT_MAX: f64 = 85.05112878;
+const GEO_LONG_MIN: f64 = -180.0;
+const GEO_LONG_MAX: f64 = 180.0;
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+const GEO_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Area {
+    long_min: f64,
+    long_max: f64,
+    lat_min: f64,
+    lat_max: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Shape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Origin {
+    Member(Vec<u8>),
+    LonLat(f64, f64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Sort {
+    None,
+    Asc,
+    Desc,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Search {
+    origin: Origin,
+    shape: Shape,
+    // Meters per requested unit; shapes are kept in meters.
+    unit: f64,
+    sort: Sort,
+    count: Option<usize>,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store_dist: bool,
+}
+
+struct Hit {
+    member: Vec<u8>,
+    score: f64,
+    dist: f64,
+    lon: f64,
+    lat: f64,
+}
+
+pub fn geoadd(args: Vec<Message>, zsets: &ZSetMap) -> Message {
+    let (key, rest) = match args.as_slice() {
+        [Bulk(key), rest @ ..] => (key, rest),
+        _ => return Message::error("ERR wrong number of arguments for 'geoadd' command"),
+    };
+    let (mut nx, mut xx, mut ch) = (false, false, false);
+    let mut i = 0;
+    while let Some(Bulk(option)) = rest.get(i) {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" => nx = true,
+            b"XX" => xx = true,
+            b"CH" => ch = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    if nx && xx {
+        return Message::error("ERR XX and NX options at the same time are not compatible");
+    }
+    let triples = &rest[i..];
+    if triples.is_empty() || triples.len() % 3 != 0 {
+        return Message::error(
+            "ERR syntax error. Try GEOADD key [x1] [y1] [name1] [x2] [y2] [name2] ... ",
+        );
+    }
+
+    let mut entries = Vec::with_capacity(triples.len() / 3);
+    for triple in triples.chunks(3) {
+        let (lon, lat, member) = match triple {
+            [Bulk(lon), Bulk(lat), Bulk(member)] => match (parse_float(lon), parse_float(lat)) {
+                (Some(lon), Some(lat)) => (lon, lat, member),
+                _ => return Message::error("ERR value is not a valid float"),
+            },
+            _ => return Message::error("ERR syntax error"),
+        };
+        if !valid_lon_lat(lon, lat) {
+            return Message::error(format!(
+                "ERR invalid longitude,latitude pair {:.6},{:.6}",
+                lon, lat
+            ));
+        }
+        entries.push((
+            member.clone(),
+            encode(lon, lat, GEO_LAT_MIN, GEO_LAT_MAX, GEO_STEP_MAX),
+        ));
+    }
+
+    let mut zsets = zsets.lock().unwrap();
+    let zset = zsets.entry(key.clone()).or_default();
+    let mut changed = 0;
+    for (member, hash) in entries {
+        let score = hash as f64;
+        match zset.score(&member) {
+            Some(_) if nx => {}
+            None if xx => {}
+            Some(old) => {
+                if old != score {
+                    zset.insert(member, score);
+                    changed += ch as i64;
+                }
+            }
+            None => {
+                zset.insert(member, score);
+                changed += 1;
+            }
+        }
+    }
+    if zset.is_empty() {
+        zsets.remove(key);
+    }
+    Message::integer(changed)
+}
+
+pub fn geopos(args: Vec<Message>, zsets: &ZSetMap) -> Message {
+    let (key, members) = match args.as_slice() {
+        [Bulk(key), members @ ..] => (key, members),
+        _ => return Message::error("ERR wrong number of arguments for 'geopos' command"),
+    };
+    let zsets = zsets.lock().unwrap();
+    let zset = zsets.get(key);
+    let positions = members
+        .iter()
+        .map(|member| match member_score(zset, member) {
+            Some(score) => {
+                let (lon, lat) = decode_score(score);
+                coordinates(lon, lat)
+            }
+            None => Message::Null,
+        })
+        .collect();
+    Message::array(positions)
+}
+
+pub fn geodist(args: Vec<Message>, zsets: &ZSetMap) -> Message {
+    let (key, first, second, unit) = match args.as_slice() {
+        [Bulk(key), first, second, rest @ ..] if rest.len() <= 1 => {
+            (key, first, second, rest.first())
+        }
+        _ => return Message::error("ERR wrong number of arguments for 'geodist' command"),
+    };
+    let unit = match unit {
+        Some(Bulk(unit)) => match parse_unit(unit) {
+            Some(unit) => unit,
+            None => return unsupported_unit(),
+        },
+        Some(_) => return unsupported_unit(),
+        None => 1.0,
+    };
+    let zsets = zsets.lock().unwrap();
+    let zset = zsets.get(key);
+    match (member_score(zset, first), member_score(zset, second)) {
+        (Some(a), Some(b)) => {
+            let (lon1, lat1) = decode_score(a);
+            let (lon2, lat2) = decode_score(b);
+            Message::bulk(format_distance(distance(lon1, lat1, lon2, lat2) / unit))
+        }
+        _ => Message::Null,
+    }
+}
+
+pub fn geohash(args: Vec<Message>, zsets: &ZSetMap) -> Message {
+    let (key, members) = match args.as_slice() {
+        [Bulk(key), members @ ..] => (key, members),
+        _ => return Message::error("ERR wrong number of arguments for 'geohash' command"),
+    };
+    let zsets = zsets.lock().unwrap();
+    let zset = zsets.get(key);
+    let hashes = members
+        .iter()
+        .map(|member| match member_score(zset, member) {
+            Some(score) => Message::bulk(standard_geohash(score)),
+            None => Message::Null,
+        })
+        .collect();
+    Message::array(hashes)
+}
+
+pub fn geosearch(args: Vec<Message>, zsets: &ZSetMap) -> Message {
+    let (key, rest) = match args.as_slice() {
+        [Bulk(key), rest @ ..] if !rest.is_empty() => (key, rest),
+        _ => return Message::error("ERR wrong number of arguments for 'geosearch' command"),
+    };
+    let search = match parse_search(rest, false) {
+        Ok(search) => search,
+        Err(err) => return err,
+    };
+    let zsets = zsets.lock().unwrap();
+    let zset = match zsets.get(key) {
+        Some(zset) => zset,
+        None => return Message::array(vec![]),
+    };
+    let hits = match run_search(zset, &search) {
+        Ok(hits) => hits,
+        Err(err) => return err,
+    };
+    let plain = !(search.with_coord || search.with_dist || search.with_hash);
+    let replies = hits
+        .into_iter()
+        .map(|hit| {
+            if plain {
+                return Message::bulk(hit.member);
+            }
+            let mut item = vec![Message::bulk(hit.member)];
+            if search.with_dist {
+                item.push(Message::bulk(format_distance(hit.dist / search.unit)));
+            }
+            if search.with_hash {
+                item.push(Message::integer(hit.score as i64));
+            }
+            if search.with_coord {
+                item.push(coordinates(hit.lon, hit.lat));
+            }
+            Message::array(item)
+        })
+        .collect();
+    Message::array(replies)
+}
+
+pub fn geosearchstore(args: Vec<Message>, zsets: &ZSetMap) -> Message {
+    let (dest, source, rest) = match args.as_slice() {
+        [Bulk(dest), Bulk(source), rest @ ..] if !rest.is_empty() => (dest, source, rest),
+        _ => return Message::error("ERR wrong number of arguments for 'geosearchstore' command"),
+    };
+    let search = match parse_search(rest, true) {
+        Ok(search) => search,
+        Err(err) => return err,
+    };
+    let mut zsets = zsets.lock().unwrap();
+    let hits = match zsets.get(source) {
+        Some(zset) => match run_search(zset, &search) {
+            Ok(hits) => hits,
+            Err(err) => return err,
+        },
+        None => vec![],
+    };
+    if hits.is_empty() {
+        zsets.remove(dest);
+        return Message::integer(0);
+    }
+    let mut stored = SortedSet::new();
+    for hit in hits {
+        let score = if search.store_dist {
+            hit.dist / search.unit
+        } else {
+            hit.score
+        };
+        stored.insert(hit.member, score);
+    }
+    let len = stored.len();
+    zsets.insert(dest.clone(), stored);
+    Message::integer(len as i64)
+}
+
+fn parse_search(args: &[Message], store: bool) -> Result<Search, Message> {
+    let mut origin = None;
+    let mut shape = None;
+    let mut search = Search {
+        origin: Origin::LonLat(0.0, 0.0),
+        shape: Shape::Radius(0.0),
+        unit: 1.0,
+        sort: Sort::None,
+        count: None,
+        any: false,
+        with_coord: false,
+        with_dist: false,
+        with_hash: false,
+        store_dist: false,
+    };
+    let mut origins = 0;
+    let mut shapes = 0;
+    let mut i = 0;
+    let arg = |i: usize| match args.get(i) {
+        Some(Bulk(arg)) => Ok(arg.as_slice()),
+        _ => Err(Message::error("ERR syntax error")),
+    };
+    let float = |i: usize| {
+        arg(i).and_then(|a| {
+            parse_float(a).ok_or_else(|| Message::error("ERR value is not a valid float"))
+        })
+    };
+    let unit = |i: usize| arg(i).and_then(|a| parse_unit(a).ok_or_else(unsupported_unit));
+
+    while i < args.len() {
+        let option = arg(i)?.to_ascii_uppercase();
+        match option.as_slice() {
+            b"FROMMEMBER" => {
+                origin = Some(Origin::Member(arg(i + 1)?.to_vec()));
+                origins += 1;
+                i += 2;
+            }
+            b"FROMLONLAT" => {
+                let (lon, lat) = (float(i + 1)?, float(i + 2)?);
+                if !valid_lon_lat(lon, lat) {
+                    return Err(Message::error(format!(
+                        "ERR invalid longitude,latitude pair {:.6},{:.6}",
+                        lon, lat
+                    )));
+                }
+                origin = Some(Origin::LonLat(lon, lat));
+                origins += 1;
+                i += 3;
+            }
+            b"BYRADIUS" => {
+                let (radius, unit) = (float(i + 1)?, unit(i + 2)?);
+                if radius < 0.0 {
+                    return Err(Message::error("ERR radius cannot be negative"));
+                }
+                shape = Some(Shape::Radius(radius * unit));
+                search.unit = unit;
+                shapes += 1;
+                i += 3;
+            }
+            b"BYBOX" => {
+                let (width, height, unit) = (float(i + 1)?, float(i + 2)?, unit(i + 3)?);
+                if width < 0.0 || height < 0.0 {
+                    return Err(Message::error("ERR height or width cannot be negative"));
+                }
+                shape = Some(Shape::Box(width * unit, height * unit));
+                search.unit = unit;
+                shapes += 1;
+                i += 4;
+            }
+            b"ASC" => {
+                search.sort = Sort::Asc;
+                i += 1;
+            }
+            b"DESC" => {
+                search.sort = Sort::Desc;
+                i += 1;
+            }
+            b"COUNT" => {
+                let count = arg(i + 1).and_then(|a| {
+                    parse_int(a).ok_or_else(|| {
+                        Message::error("ERR value is not an integer or out of range")
+                    })
+                })?;
+                if count <= 0 {
+                    return Err(Message::error("ERR COUNT must be > 0"));
+                }
+                search.count = Some(count as usize);
+                i += 2;
+                if let Ok(any) = arg(i) {
+                    if any.eq_ignore_ascii_case(b"ANY") {
+                        search.any = true;
+                        i += 1;
+                    }
+                }
+            }
+            b"WITHCOORD" if !store => {
+                search.with_coord = true;
+                i += 1;
+            }
+            b"WITHDIST" if !store => {
+                search.with_dist = true;
+                i += 1;
+            }
+            b"WITHHASH" if !store => {
+                search.with_hash = true;
+                i += 1;
+            }
+            b"STOREDIST" if store => {
+                search.store_dist = true;
+                i += 1;
+            }
+            _ => return Err(Message::error("ERR syntax error")),
+        }
+    }
+
+    let command = if store { "GEOSEARCHSTORE" } else { "GEOSEARCH" };
+    search.origin = match (origin, origins) {
+        (Some(origin), 1) => origin,
+        _ => {
+            return Err(Message::error(format!(
+                "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for {}",
+                command
+            )))
+        }
+    };
+    search.shape = match (shape, shapes) {
+        (Some(shape), 1) => shape,
+        _ => {
+            return Err(Message::error(format!(
+                "ERR exactly one of BYRADIUS and BYBOX can be specified for {}",
+                command
+            )))
+        }
+    };
+    Ok(search)
+}
+
+// Scans the geohash cell containing the origin and its eight neighbours at a
+// precision coarse enough for the 3x3 block to cover the search area, then
+// filters candidates by their exact distance.
+fn run_search(zset: &SortedSet, search: &Search) -> Result<Vec<Hit>, Message> {
+    let (lon, lat) = match &search.origin {
+        Origin::LonLat(lon, lat) => (*lon, *lat),
+        Origin::Member(member) => match zset.score(member) {
+            Some(score) => decode_score(score),
+            None => return Err(Message::error("ERR could not decode requested zset member")),
+        },
+    };
+    let (half_width, half_height) = match search.shape {
+        Shape::Radius(r) => (r, r),
+        Shape::Box(w, h) => (w / 2.0, h / 2.0),
+    };
+    let bounds = bounding_box(lon, lat, half_width, half_height);
+    let radius = match search.shape {
+        Shape::Radius(r) => r,
+        Shape::Box(..) => half_width.hypot(half_height),
+    };
+
+    let mut step = estimate_steps(radius, lat);
+    let mut area = cell_area(encode(lon, lat, GEO_LAT_MIN, GEO_LAT_MAX, step), step);
+    while step > 1 && !covers(&area, &bounds) {
+        step -= 1;
+        area = cell_area(encode(lon, lat, GEO_LAT_MIN, GEO_LAT_MAX, step), step);
+    }
+
+    let cell_height = area.lat_max - area.lat_min;
+    let cell_width = area.long_max - area.long_min;
+    let center_lat = (area.lat_min + area.lat_max) / 2.0;
+    let center_lon = (area.long_min + area.long_max) / 2.0;
+    let mut cells = Vec::with_capacity(9);
+    for dy in [-1.0, 0.0, 1.0] {
+        let cell_lat = center_lat + dy * cell_height;
+        if !(GEO_LAT_MIN..=GEO_LAT_MAX).contains(&cell_lat) {
+            continue;
+        }
+        for dx in [-1.0, 0.0, 1.0] {
+            let mut cell_lon = center_lon + dx * cell_width;
+            if cell_lon < GEO_LONG_MIN {
+                cell_lon += 360.0;
+            } else if cell_lon > GEO_LONG_MAX {
+                cell_lon -= 360.0;
+            }
+            let hash = encode(cell_lon, cell_lat, GEO_LAT_MIN, GEO_LAT_MAX, step);
+            if !cells.contains(&hash) {
+                cells.push(hash);
+            }
+        }
+    }
+
+    let shift = 2 * (GEO_STEP_MAX - step);
+    let limit = if search.any { search.count } else { None };
+    let mut seen = HashSet::new();
+    let mut hits = Vec::new();
+    'cells: for hash in cells {
+        let min = (hash << shift) as f64;
+        let max = ((hash + 1) << shift) as f64;
+        for (score, member) in zset.range(min, max) {
+            let (point_lon, point_lat) = decode_score(score);
+            let dist = match search.shape {
+                Shape::Radius(r) => {
+                    let dist = distance(lon, lat, point_lon, point_lat);
+                    if dist > r {
+                        continue;
+                    }
+                    dist
+                }
+                Shape::Box(w, h) => {
+                    if lat_distance(lat, point_lat) > h / 2.0
+                        || distance(lon, point_lat, point_lon, point_lat) > w / 2.0
+                    {
+                        continue;
+                    }
+                    distance(lon, lat, point_lon, point_lat)
+                }
+            };
+            if !seen.insert(member.clone()) {
+                continue;
+            }
+            hits.push(Hit {
+                member: member.clone(),
+                score,
+                dist,
+                lon: point_lon,
+                lat: point_lat,
+            });
+            if limit == Some(hits.len()) {
+                break 'cells;
+            }
+        }
+    }
+
+    // COUNT without ANY implies the closest matches.
+    let sort = match search.sort {
+        Sort::None if search.count.is_some() && !search.any => Sort::Asc,
+        sort => sort,
+    };
+    match sort {
+        Sort::Asc => hits.sort_by(|a, b| a.dist.total_cmp(&b.dist)),
+        Sort::Desc => hits.sort_by(|a, b| b.dist.total_cmp(&a.dist)),
+        Sort::None => {}
+    }
+    if let Some(count) = search.count {
+        hits.truncate(count);
+    }
+    Ok(hits)
+}
+
+fn member_score(zset: Option<&SortedSet>, member: &Message) -> Option<f64> {
+    match member {
+        Bulk(member) => zset?.score(member),
+        _ => None,
+    }
+}
+
+fn valid_lon_lat(lon: f64, lat: f64) -> bool {
+    (GEO_LONG_MIN..=GEO_LONG_MAX).contains(&lon) && (GEO_LAT_MIN..=GEO_LAT_MAX).contains(&lat)
+}
+
+fn parse_unit(unit: &[u8]) -> Option<f64> {
+    match unit.to_ascii_lowercase().as_slice() {
+        b"m" => Some(1.0),
+        b"km" => Some(1000.0),
+        b"ft" => Some(0.3048),
+        b"mi" => Some(1609.34),
+        _ => None,
+    }
+}
+
+fn unsupported_unit() -> Message {
+    Message::error("ERR unsupported unit provided. please use M, KM, FT, MI")
+}
+
+fn format_distance(dist: f64) -> Vec<u8> {
+    format!("{:.4}", dist).into_bytes()
+}
+
+fn coordinates(lon: f64, lat: f64) -> Message {
+    Message::array(vec![
+        Message::bulk(format_coordinate(lon)),
+        Message::bulk(format_coordinate(lat)),
+    ])
+}
+
+// Seventeen decimals with trailing zeros trimmed, like Redis' human doubles.
+fn format_coordinate(value: f64) -> Vec<u8> {
+    let formatted = format!("{:.17}", value);
+    let trimmed = formatted.trim_end_matches('0').trim_end_matches('.');
+    trimmed.as_bytes().to_vec()
+}
+
+fn interleave(x: u32, y: u32) -> u64 {
+    let mut bits = 0u64;
+    for i in 0..32 {
+        bits |= (((x >> i) & 1) as u64) << (2 * i);
+        bits |= (((y >> i) & 1) as u64) << (2 * i + 1);
+    }
+    bits
+}
+
+fn deinterleave(bits: u64) -> (u32, u32) {
+    let (mut x, mut y) = (0u32, 0u32);
+    for i in 0..32 {
+        x |= (((bits >> (2 * i)) & 1) as u32) << i;
+        y |= (((bits >> (2 * i + 1)) & 1) as u32) << i;
+    }
+    (x, y)
+}
+
+// Latitude bits land on even positions and longitude bits on odd ones.
+fn encode(lon: f64, lat: f64, lat_min: f64, lat_max: f64, step: u32) -> u64 {
+    let cells = (1u64 << step) as f64;
+    let max_cell = (1u32 << step) - 1;
+    let lat_offset = ((lat - lat_min) / (lat_max - lat_min) * cells) as u32;
+    let lon_offset = ((lon - GEO_LONG_MIN) / (GEO_LONG_MAX - GEO_LONG_MIN) * cells) as u32;
+    interleave(lat_offset.min(max_cell), lon_offset.min(max_cell))
+}
+
+fn cell_area(hash: u64, step: u32) -> Area {
+    let (lat_cell, lon_cell) = deinterleave(hash);
+    let cells = (1u64 << step) as f64;
+    let lat_scale = GEO_LAT_MAX - GEO_LAT_MIN;
+    let lon_scale = GEO_LONG_MAX - GEO_LONG_MIN;
+    Area {
+        lat_min: GEO_LAT_MIN + (lat_cell as f64 / cells) * lat_scale,
+        lat_max: GEO_LAT_MIN + ((lat_cell as f64 + 1.0) / cells) * lat_scale,
+        long_min: GEO_LONG_MIN + (lon_cell as f64 / cells) * lon_scale,
+        long_max: GEO_LONG_MIN + ((lon_cell as f64 + 1.0) / cells) * lon_scale,
+    }
+}
+
+fn decode_score(score: f64) -> (f64, f64) {
+    let area = cell_area(score as u64, GEO_STEP_MAX);
+    let lon = ((area.long_min + area.long_max) / 2.0).clamp(GEO_LONG_MIN, GEO_LONG_MAX);
+    let lat = ((area.lat_min + area.lat_max) / 2.0).clamp(GEO_LAT_MIN, GEO_LAT_MAX);
+    (lon, lat)
+}
+
+// GEOHASH replies use the standard -90..90 latitude range rather than the
+// Mercator-limited one used for scores.
+fn standard_geohash(score: f64) -> Vec<u8> {
+    let (lon, lat) = decode_score(score);
+    let bits = encode(lon, lat, -90.0, 90.0, GEO_STEP_MAX);
+    (0..11)
+        .map(|i| {
+            // Only 52 bits are available, so the eleventh character is always '0'.
+            let index = if i == 10 {
+                0
+            } else {
+                (bits >> (52 - (i + 1) * 5)) & 0x1f
+            };
+            GEO_ALPHABET[index as usize]
+        })
+        .collect()
+}
+
+fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    2.0 * EARTH_RADIUS_IN_METERS * (u * u + lat1.cos() * lat2.cos() * v * v).sqrt().asin()
+}
+
+fn lat_distance(lat1: f64, lat2: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (lat2.to_radians() - lat1.to_radians()).abs()
+}
+
+fn estimate_steps(radius: f64, lat: f64) -> u32 {
+    if radius == 0.0 {
+        return GEO_STEP_MAX;
+    }
+    let mut range = radius;
+    let mut step: i32 = 1;
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    step -= 2;
+    // Cells get narrower towards the poles.
+    if !(-66.0..=66.0).contains(&lat) {
+        step -= 1;
+        if !(-80.0..=80.0).contains(&lat) {
+            step -= 1;
+        }
+    }
+    step.clamp(1, GEO_STEP_MAX as i32) as u32
+}
+
+fn bounding_box(lon: f64, lat: f64, half_width: f64, half_height: f64) -> Area {
+    let lat_delta = (half_height / EARTH_RADIUS_IN_METERS).to_degrees();
+    let lon_delta_top =
+        (half_width / EARTH_RADIUS_IN_METERS / (lat + lat_delta).to_radians().cos()).to_degrees();
+    let lon_delta_bottom =
+        (half_width / EARTH_RADIUS_IN_METERS / (lat - lat_delta).to_radians().cos()).to_degrees();
+    let lon_delta = if lat < 0.0 {
+        lon_delta_bottom
+    } else {
+        lon_delta_top
+    };
+    Area {
+        long_min: lon - lon_delta,
+        long_max: lon + lon_delta,
+        lat_min: lat - lat_delta,
+        lat_max: lat + lat_delta,
+    }
+}
+
+fn covers(cell: &Area, bounds: &Area) -> bool {
+    let height = cell.lat_max - cell.lat_min;
+    let width = cell.long_max - cell.long_min;
+    cell.lat_min - height <= bounds.lat_min
+        && cell.lat_max + height >= bounds.lat_max
+        && cell.long_min - width <= bounds.long_min
+        && cell.long_max + width >= bounds.long_max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    fn sicily() -> ZSetMap {
+        let zsets = Mutex::new(HashMap::new());
+        geoadd(
+            Message::bulks(&[
+                "Sicily",
+                "13.361389",
+                "38.115556",
+                "Palermo",
+                "15.087269",
+                "37.502669",
+                "Catania",
+            ]),
+            &zsets,
+        );
+        zsets
+    }
+
+    fn names(reply: Message) -> Vec<Message> {
+        match reply {
+            Message::Array(items) => items,
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_geoadd_scores_match_redis() {
+        let zsets = sicily();
+        let zsets = zsets.lock().unwrap();
+        let zset = &zsets[b"Sicily".as_slice()];
+        assert_eq!(zset.score(b"Palermo"), Some(3479099956230698.0));
+        assert_eq!(zset.score(b"Catania"), Some(3479447370796909.0));
+    }
+
+    #[test]
+    fn test_geoadd_options() {
+        let zsets = sicily();
+        assert_eq!(
+            geoadd(
+                Message::bulks(&["Sicily", "NX", "13", "38", "Palermo"]),
+                &zsets
+            ),
+            Message::integer(0)
+        );
+        assert_eq!(
+            geoadd(
+                Message::bulks(&["Sicily", "XX", "13", "38", "Rome"]),
+                &zsets
+            ),
+            Message::integer(0)
+        );
+        assert_eq!(
+            geoadd(
+                Message::bulks(&["Sicily", "CH", "13", "38", "Palermo"]),
+                &zsets
+            ),
+            Message::integer(1)
+        );
+        assert_eq!(
+            geoadd(
+                Message::bulks(&["Sicily", "NX", "XX", "13", "38", "Palermo"]),
+                &zsets
+            ),
+            Message::error("ERR XX and NX options at the same time are not compatible")
+        );
+    }
+
+    #[test]
+    fn test_geoadd_invalid_pair() {
+        let zsets = Mutex::new(HashMap::new());
+        assert_eq!(
+            geoadd(Message::bulks(&["k", "181", "10", "m"]), &zsets),
+            Message::error("ERR invalid longitude,latitude pair 181.000000,10.000000")
+        );
+        assert_eq!(
+            geoadd(Message::bulks(&["k", "10", "10"]), &zsets),
+            Message::error(
+                "ERR syntax error. Try GEOADD key [x1] [y1] [name1] [x2] [y2] [name2] ... "
+            )
+        );
+        assert!(zsets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_geopos() {
+        let zsets = sicily();
+        let reply = geopos(Message::bulks(&["Sicily", "Palermo", "Nowhere"]), &zsets);
+        assert_eq!(
+            reply,
+            Message::array(vec![
+                Message::array(vec![
+                    Message::bulk(b"13.36138933897018433".to_vec()),
+                    Message::bulk(b"38.11555639549629859".to_vec()),
+                ]),
+                Message::Null,
+            ])
+        );
+    }
+
+    #[test]
+    fn test_geodist() {
+        let zsets = sicily();
+        assert_eq!(
+            geodist(Message::bulks(&["Sicily", "Palermo", "Catania"]), &zsets),
+            Message::bulk(b"166274.1516".to_vec())
+        );
+        assert_eq!(
+            geodist(
+                Message::bulks(&["Sicily", "Palermo", "Catania", "km"]),
+                &zsets
+            ),
+            Message::bulk(b"166.2742".to_vec())
+        );
+        assert_eq!(
+            geodist(Message::bulks(&["Sicily", "Palermo", "Nowhere"]), &zsets),
+            Message::Null
+        );
+        assert_eq!(
+            geodist(
+                Message::bulks(&["Sicily", "Palermo", "Catania", "yd"]),
+                &zsets
+            ),
+            unsupported_unit()
+        );
+    }
+
+    #[test]
+    fn test_geohash() {
+        let zsets = sicily();
+        assert_eq!(
+            geohash(Message::bulks(&["Sicily", "Palermo", "Catania"]), &zsets),
+            Message::array(vec![
+                Message::bulk(b"sqc8b49rny0".to_vec()),
+                Message::bulk(b"sqdtr74hyu0".to_vec()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_geosearch_by_radius() {
+        let zsets = sicily();
+        geoadd(
+            Message::bulks(&[
+                "Sicily",
+                "12.758489",
+                "38.788135",
+                "edge1",
+                "17.241510",
+                "38.788135",
+                "edge2",
+            ]),
+            &zsets,
+        );
+        let reply = geosearch(
+            Message::bulks(&[
+                "Sicily",
+                "FROMLONLAT",
+                "15",
+                "37",
+                "BYRADIUS",
+                "200",
+                "km",
+                "ASC",
+            ]),
+            &zsets,
+        );
+        assert_eq!(
+            names(reply),
+            vec![
+                Message::bulk(b"Catania".to_vec()),
+                Message::bulk(b"Palermo".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_geosearch_by_box_with_options() {
+        let zsets = sicily();
+        geoadd(
+            Message::bulks(&[
+                "Sicily",
+                "12.758489",
+                "38.788135",
+                "edge1",
+                "17.241510",
+                "38.788135",
+                "edge2",
+            ]),
+            &zsets,
+        );
+        let reply = geosearch(
+            Message::bulks(&[
+                "Sicily",
+                "FROMLONLAT",
+                "15",
+                "37",
+                "BYBOX",
+                "400",
+                "400",
+                "km",
+                "ASC",
+                "WITHCOORD",
+                "WITHDIST",
+            ]),
+            &zsets,
+        );
+        let items = names(reply);
+        assert_eq!(items.len(), 4);
+        assert_eq!(
+            items[0],
+            Message::array(vec![
+                Message::bulk(b"Catania".to_vec()),
+                Message::bulk(b"56.4413".to_vec()),
+                Message::array(vec![
+                    Message::bulk(b"15.08726745843887329".to_vec()),
+                    Message::bulk(b"37.50266842333162032".to_vec()),
+                ]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_geosearch_from_member_with_count() {
+        let zsets = sicily();
+        let reply = geosearch(
+            Message::bulks(&[
+                "Sicily",
+                "FROMMEMBER",
+                "Palermo",
+                "BYRADIUS",
+                "500",
+                "km",
+                "COUNT",
+                "1",
+                "WITHHASH",
+            ]),
+            &zsets,
+        );
+        assert_eq!(
+            reply,
+            Message::array(vec![Message::array(vec![
+                Message::bulk(b"Palermo".to_vec()),
+                Message::integer(3479099956230698),
+            ])])
+        );
+    }
+
+    #[test]
+    fn test_geosearch_errors() {
+        let zsets = sicily();
+        assert_eq!(
+            geosearch(Message::bulks(&["Sicily", "BYRADIUS", "1", "km"]), &zsets),
+            Message::error(
+                "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH"
+            )
+        );
+        assert_eq!(
+            geosearch(
+                Message::bulks(&["Sicily", "FROMLONLAT", "15", "37"]),
+                &zsets
+            ),
+            Message::error("ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH")
+        );
+        assert_eq!(
+            geosearch(
+                Message::bulks(&["Sicily", "FROMMEMBER", "Rome", "BYRADIUS", "1", "km"]),
+                &zsets
+            ),
+            Message::error("ERR could not decode requested zset member")
+        );
+        assert_eq!(
+            geosearch(
+                Message::bulks(&[
+                    "Sicily",
+                    "FROMLONLAT",
+                    "15",
+                    "37",
+                    "BYRADIUS",
+                    "1",
+                    "km",
+                    "ANY"
+                ]),
+                &zsets
+            ),
+            Message::error("ERR syntax error")
+        );
+        assert_eq!(
+            geosearch(
+                Message::bulks(&["Missing", "FROMLONLAT", "15", "37", "BYRADIUS", "1", "km"]),
+                &zsets
+            ),
+            Message::array(vec![])
+        );
+    }
+
+    #[test]
+    fn test_geosearchstore() {
+        let zsets = sicily();
+        let reply = geosearchstore(
+            Message::bulks(&[
+                "dest",
+                "Sicily",
+                "FROMLONLAT",
+                "15",
+                "37",
+                "BYRADIUS",
+                "200",
+                "km",
+                "STOREDIST",
+            ]),
+            &zsets,
+        );
+        assert_eq!(reply, Message::integer(2));
+        let zsets = zsets.lock().unwrap();
+        let score = zsets[b"dest".as_slice()].score(b"Catania").unwrap();
+        assert!((score - 56.4413).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_geosearchstore_rejects_with_options() {
+        let zsets = sicily();
+        assert_eq!(
+            geosearchstore(
+                Message::bulks(&[
+                    "dest",
+                    "Sicily",
+                    "FROMLONLAT",
+                    "15",
+                    "37",
+                    "BYRADIUS",
+                    "200",
+                    "km",
+                    "WITHDIST",
+                ]),
+                &zsets,
+            ),
+            Message::error("ERR syntax error")
+        );
+    }
+
+    #[test]
+    fn test_search_across_antimeridian() {
+        let zsets = Mutex::new(HashMap::new());
+        geoadd(
+            Message::bulks(&["k", "179.99", "0", "east", "-179.99", "0", "west"]),
+            &zsets,
+        );
+        let reply = geosearch(
+            Message::bulks(&[
+                "k",
+                "FROMLONLAT",
+                "179.999",
+                "0",
+                "BYRADIUS",
+                "10",
+                "km",
+                "ASC",
+            ]),
+            &zsets,
+        );
+        assert_eq!(
+            names(reply),
+            vec![
+                Message::bulk(b"east".to_vec()),
+                Message::bulk(b"west".to_vec()),
+            ]
+        );
+    }
+}
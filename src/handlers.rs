@@ -1,16 +1,58 @@
 use std::boxed::Box;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
 
+use crate::aof::bgrewriteaof;
+use crate::bitmap::{bitcount, bitfield, bitfield_ro, bitop, bitpos, getbit, setbit};
+use crate::cluster::cluster;
+use crate::command::command;
+use crate::config;
+use crate::expire::{persist, pexpireat, pttl, ttl};
+use crate::geo::{geoadd, geodist, geohash, geopos, geosearch, geosearchstore};
+use crate::hyperloglog::{pfadd, pfcount, pfmerge};
+use crate::info::info;
+use crate::json::{
+    json_arrappend, json_arrlen, json_del, json_get, json_mget, json_numincrby, json_objkeys,
+    json_set, json_type, Json,
+};
+use crate::latency::{self, latency};
+use crate::list::{llen, lrange, rpush};
 use crate::message::Message;
 use crate::message::Message::*;
+use crate::metrics::Histogram;
+use crate::rdb::{bgsave, dump, lastsave, restore, save};
+use crate::replication::{replicaof, role};
+use crate::set::{sadd, scard, smembers};
+use crate::slowlog::slowlog;
+use crate::zset::{zadd, zcard, zrem, zscore, SortedSet};
 
 pub type HandlerFunc = Box<dyn Handler + Sync + Send>;
 
-type MapMutex<K, V> = Mutex<HashMap<K, V>>;
-type SetMap = MapMutex<Vec<u8>, Vec<u8>>;
-type HSetMap = MapMutex<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>;
-type HandlerMap = LazyLock<HashMap<&'static str, HandlerFunc>>;
+pub type MapMutex<K, V> = Mutex<HashMap<K, V>>;
+pub type SetMap = MapMutex<Vec<u8>, Vec<u8>>;
+pub type HSetMap = MapMutex<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>;
+pub type ZSetMap = MapMutex<Vec<u8>, SortedSet>;
+pub type JsonMap = MapMutex<Vec<u8>, Json>;
+pub type ListMap = MapMutex<Vec<u8>, VecDeque<Vec<u8>>>;
+pub type SSetMap = MapMutex<Vec<u8>, HashSet<Vec<u8>>>;
+pub type ExpireMap = MapMutex<Vec<u8>, u64>;
+type HandlerMap = LazyLock<HashMap<&'static str, Command>>;
+
+// Command flags, named after their Redis counterparts.
+pub const WRITE: u32 = 1 << 0;
+pub const READONLY: u32 = 1 << 1;
+pub const DENYOOM: u32 = 1 << 2;
+pub const ADMIN: u32 = 1 << 3;
+pub const FAST: u32 = 1 << 4;
+
+pub const FLAG_NAMES: [(u32, &str); 5] = [
+    (WRITE, "write"),
+    (READONLY, "readonly"),
+    (DENYOOM, "denyoom"),
+    (ADMIN, "admin"),
+    (FAST, "fast"),
+];
 
 pub trait Handler {
     fn call(&self, args: Vec<Message>) -> Message;
@@ -25,14 +67,588 @@ where
     }
 }
 
+// An entry of the command table. `arity` counts the command name and is
+// negative for "at least"; key positions are argv indexes where a negative
+// `last_key` counts from the end.
+pub struct Command {
+    pub name: &'static str,
+    pub arity: i32,
+    pub flags: u32,
+    pub first_key: i32,
+    pub last_key: i32,
+    pub step: i32,
+    pub acl_categories: &'static [&'static str],
+    // Time spent in `handler`, one sample per call from a client.
+    pub latency: Histogram,
+    handler: HandlerFunc,
+}
+
+impl Command {
+    pub fn new(
+        name: &'static str,
+        arity: i32,
+        flags: u32,
+        (first_key, last_key, step): (i32, i32, i32),
+        acl_categories: &'static [&'static str],
+        handler: HandlerFunc,
+    ) -> Self {
+        Command {
+            name,
+            arity,
+            flags,
+            first_key,
+            last_key,
+            step,
+            acl_categories,
+            latency: Histogram::new(),
+            handler,
+        }
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.flags & WRITE != 0
+    }
+
+    // `argc` includes the command name, like `arity`.
+    pub fn check_arity(&self, argc: usize) -> bool {
+        match self.arity {
+            n if n >= 0 => argc == n as usize,
+            n => argc >= n.unsigned_abs() as usize,
+        }
+    }
+
+    // Argv indexes of the keys in a call with `argc` arguments.
+    pub fn key_indexes(&self, argc: usize) -> Vec<usize> {
+        if self.first_key <= 0 {
+            return Vec::new();
+        }
+        let last = match self.last_key {
+            n if n < 0 => argc as i32 + n,
+            n => n.min(argc as i32 - 1),
+        };
+        (self.first_key..=last)
+            .step_by(self.step.max(1) as usize)
+            .map(|i| i as usize)
+            .collect()
+    }
+
+    pub fn arity_error(&self) -> Message {
+        Message::error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            self.name.to_lowercase()
+        ))
+    }
+
+    // A write that deletes a key also drops its expire time, so the name
+    // does not inherit it when reused.
+    pub fn call(&self, args: Vec<Message>) -> Message {
+        if !self.is_write() || EXPIRES.lock().unwrap().is_empty() {
+            return self.handler.call(args);
+        }
+        let keys: Vec<Vec<u8>> = self
+            .key_indexes(args.len() + 1)
+            .into_iter()
+            .filter_map(|i| match args.get(i - 1) {
+                Some(Bulk(key)) => Some(key.clone()),
+                _ => None,
+            })
+            .collect();
+        let reply = self.handler.call(args);
+        for key in keys {
+            if !exists(&key) {
+                EXPIRES.lock().unwrap().remove(&key);
+            }
+        }
+        reply
+    }
+}
+
 pub static HANDLERS: HandlerMap = LazyLock::new(|| {
-    let mut m: HashMap<&'static str, HandlerFunc> = HashMap::new();
-    m.insert("GET", Box::new(|args| get(args, &SETS)));
-    m.insert("HGET", Box::new(|args| hget(args, &HSETS)));
-    m.insert("HGETALL", Box::new(|args| hgetall(args, &HSETS)));
-    m.insert("PING", Box::new(|args| ping(args, &SETS)));
-    m.insert("SET", Box::new(|args| set(args, &SETS)));
-    m.insert("HSET", Box::new(|args| hset(args, &HSETS)));
+    let mut m: HashMap<&'static str, Command> = HashMap::new();
+    let mut add = |command: Command| {
+        m.insert(command.name, command);
+    };
+    add(Command::new(
+        "GET",
+        2,
+        READONLY | FAST,
+        (1, 1, 1),
+        &["@read", "@string", "@fast"],
+        Box::new(|args| get(args, &SETS)),
+    ));
+    add(Command::new(
+        "HGET",
+        3,
+        READONLY | FAST,
+        (1, 1, 1),
+        &["@read", "@hash", "@fast"],
+        Box::new(|args| hget(args, &HSETS)),
+    ));
+    add(Command::new(
+        "HGETALL",
+        2,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@hash", "@slow"],
+        Box::new(|args| hgetall(args, &HSETS)),
+    ));
+    add(Command::new(
+        "PING",
+        -1,
+        FAST,
+        (0, 0, 0),
+        &["@fast", "@connection"],
+        Box::new(|args| ping(args, &SETS)),
+    ));
+    add(Command::new(
+        "COMMAND",
+        -1,
+        0,
+        (0, 0, 0),
+        &["@slow", "@connection"],
+        Box::new(|args| command(args, &HANDLERS)),
+    ));
+    add(Command::new(
+        "BGREWRITEAOF",
+        1,
+        ADMIN,
+        (0, 0, 0),
+        &["@admin", "@slow", "@dangerous"],
+        Box::new(bgrewriteaof),
+    ));
+    add(Command::new(
+        "SAVE",
+        1,
+        ADMIN,
+        (0, 0, 0),
+        &["@admin", "@slow", "@dangerous"],
+        Box::new(save),
+    ));
+    add(Command::new(
+        "BGSAVE",
+        1,
+        ADMIN,
+        (0, 0, 0),
+        &["@admin", "@slow", "@dangerous"],
+        Box::new(bgsave),
+    ));
+    add(Command::new(
+        "LASTSAVE",
+        1,
+        READONLY | FAST,
+        (0, 0, 0),
+        &["@admin", "@fast", "@dangerous"],
+        Box::new(lastsave),
+    ));
+    add(Command::new(
+        "REPLICAOF",
+        3,
+        ADMIN,
+        (0, 0, 0),
+        &["@admin", "@slow", "@dangerous"],
+        Box::new(replicaof),
+    ));
+    add(Command::new(
+        "ROLE",
+        1,
+        FAST,
+        (0, 0, 0),
+        &["@admin", "@fast", "@dangerous"],
+        Box::new(role),
+    ));
+    add(Command::new(
+        "INFO",
+        -1,
+        0,
+        (0, 0, 0),
+        &["@slow", "@dangerous"],
+        Box::new(info),
+    ));
+    add(Command::new(
+        "SLOWLOG",
+        -2,
+        0,
+        (0, 0, 0),
+        &["@admin", "@slow", "@dangerous"],
+        Box::new(slowlog),
+    ));
+    add(Command::new(
+        "LATENCY",
+        -2,
+        0,
+        (0, 0, 0),
+        &["@admin", "@slow", "@dangerous"],
+        Box::new(latency),
+    ));
+    add(Command::new(
+        "CLUSTER",
+        -2,
+        0,
+        (0, 0, 0),
+        &["@slow"],
+        Box::new(cluster),
+    ));
+    add(Command::new(
+        "DEL",
+        -2,
+        WRITE,
+        (1, -1, 1),
+        &["@keyspace", "@write", "@slow"],
+        Box::new(del),
+    ));
+    add(Command::new(
+        "DUMP",
+        2,
+        READONLY,
+        (1, 1, 1),
+        &["@keyspace", "@read", "@slow"],
+        Box::new(dump),
+    ));
+    add(Command::new(
+        "RESTORE",
+        -4,
+        WRITE | DENYOOM,
+        (1, 1, 1),
+        &["@keyspace", "@write", "@slow", "@dangerous"],
+        Box::new(restore),
+    ));
+    // Sent by MIGRATE, and served for a slot that is still being imported.
+    add(Command::new(
+        "RESTORE-ASKING",
+        -4,
+        WRITE | DENYOOM,
+        (1, 1, 1),
+        &["@keyspace", "@write", "@slow", "@dangerous"],
+        Box::new(restore),
+    ));
+    add(Command::new(
+        "SET",
+        3,
+        WRITE | DENYOOM,
+        (1, 1, 1),
+        &["@write", "@string", "@slow"],
+        Box::new(|args| set(args, &SETS)),
+    ));
+    add(Command::new(
+        "HSET",
+        4,
+        WRITE | DENYOOM | FAST,
+        (1, 1, 1),
+        &["@write", "@hash", "@fast"],
+        Box::new(|args| hset(args, &HSETS)),
+    ));
+    add(Command::new(
+        "SETBIT",
+        4,
+        WRITE | DENYOOM,
+        (1, 1, 1),
+        &["@write", "@bitmap", "@slow"],
+        Box::new(|args| setbit(args, &SETS, config::proto_max_bulk_len())),
+    ));
+    add(Command::new(
+        "GETBIT",
+        3,
+        READONLY | FAST,
+        (1, 1, 1),
+        &["@read", "@bitmap", "@fast"],
+        Box::new(|args| getbit(args, &SETS)),
+    ));
+    add(Command::new(
+        "BITCOUNT",
+        -2,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@bitmap", "@slow"],
+        Box::new(|args| bitcount(args, &SETS)),
+    ));
+    add(Command::new(
+        "BITPOS",
+        -3,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@bitmap", "@slow"],
+        Box::new(|args| bitpos(args, &SETS)),
+    ));
+    add(Command::new(
+        "BITOP",
+        -4,
+        WRITE | DENYOOM,
+        (2, -1, 1),
+        &["@write", "@bitmap", "@slow"],
+        Box::new(|args| bitop(args, &SETS)),
+    ));
+    add(Command::new(
+        "BITFIELD",
+        -2,
+        WRITE | DENYOOM,
+        (1, 1, 1),
+        &["@write", "@bitmap", "@slow"],
+        Box::new(|args| bitfield(args, &SETS, config::proto_max_bulk_len())),
+    ));
+    add(Command::new(
+        "BITFIELD_RO",
+        -2,
+        READONLY | FAST,
+        (1, 1, 1),
+        &["@read", "@bitmap", "@fast"],
+        Box::new(|args| bitfield_ro(args, &SETS, config::proto_max_bulk_len())),
+    ));
+    add(Command::new(
+        "PFADD",
+        -2,
+        WRITE | DENYOOM | FAST,
+        (1, 1, 1),
+        &["@write", "@hyperloglog", "@fast"],
+        Box::new(|args| pfadd(args, &SETS, config::hll_sparse_max_bytes())),
+    ));
+    add(Command::new(
+        "PFCOUNT",
+        -2,
+        READONLY,
+        (1, -1, 1),
+        &["@read", "@hyperloglog", "@slow"],
+        Box::new(|args| pfcount(args, &SETS)),
+    ));
+    add(Command::new(
+        "PFMERGE",
+        -2,
+        WRITE | DENYOOM,
+        (1, -1, 1),
+        &["@write", "@hyperloglog", "@slow"],
+        Box::new(|args| pfmerge(args, &SETS, config::hll_sparse_max_bytes())),
+    ));
+    add(Command::new(
+        "ZSCORE",
+        3,
+        READONLY | FAST,
+        (1, 1, 1),
+        &["@read", "@sortedset", "@fast"],
+        Box::new(|args| zscore(args, &ZSETS)),
+    ));
+    add(Command::new(
+        "ZCARD",
+        2,
+        READONLY | FAST,
+        (1, 1, 1),
+        &["@read", "@sortedset", "@fast"],
+        Box::new(|args| zcard(args, &ZSETS)),
+    ));
+    add(Command::new(
+        "ZADD",
+        -4,
+        WRITE | DENYOOM | FAST,
+        (1, 1, 1),
+        &["@write", "@sortedset", "@fast"],
+        Box::new(|args| zadd(args, &ZSETS)),
+    ));
+    add(Command::new(
+        "ZREM",
+        -3,
+        WRITE | FAST,
+        (1, 1, 1),
+        &["@write", "@sortedset", "@fast"],
+        Box::new(|args| zrem(args, &ZSETS)),
+    ));
+    add(Command::new(
+        "GEOADD",
+        -5,
+        WRITE | DENYOOM,
+        (1, 1, 1),
+        &["@write", "@geo", "@slow"],
+        Box::new(|args| geoadd(args, &ZSETS)),
+    ));
+    add(Command::new(
+        "GEOPOS",
+        -2,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@geo", "@slow"],
+        Box::new(|args| geopos(args, &ZSETS)),
+    ));
+    add(Command::new(
+        "GEODIST",
+        -4,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@geo", "@slow"],
+        Box::new(|args| geodist(args, &ZSETS)),
+    ));
+    add(Command::new(
+        "GEOHASH",
+        -2,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@geo", "@slow"],
+        Box::new(|args| geohash(args, &ZSETS)),
+    ));
+    add(Command::new(
+        "GEOSEARCH",
+        -7,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@geo", "@slow"],
+        Box::new(|args| geosearch(args, &ZSETS)),
+    ));
+    add(Command::new(
+        "GEOSEARCHSTORE",
+        -8,
+        WRITE | DENYOOM,
+        (1, 2, 1),
+        &["@write", "@geo", "@slow"],
+        Box::new(|args| geosearchstore(args, &ZSETS)),
+    ));
+    add(Command::new(
+        "JSON.SET",
+        -4,
+        WRITE | DENYOOM,
+        (1, 1, 1),
+        &["@write", "@json", "@slow"],
+        Box::new(|args| json_set(args, &JSONS)),
+    ));
+    add(Command::new(
+        "JSON.GET",
+        -2,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@json", "@slow"],
+        Box::new(|args| json_get(args, &JSONS)),
+    ));
+    add(Command::new(
+        "JSON.MGET",
+        -3,
+        READONLY,
+        (1, -2, 1),
+        &["@read", "@json", "@slow"],
+        Box::new(|args| json_mget(args, &JSONS)),
+    ));
+    add(Command::new(
+        "JSON.DEL",
+        -2,
+        WRITE,
+        (1, 1, 1),
+        &["@write", "@json", "@slow"],
+        Box::new(|args| json_del(args, &JSONS)),
+    ));
+    add(Command::new(
+        "JSON.TYPE",
+        -2,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@json", "@slow"],
+        Box::new(|args| json_type(args, &JSONS)),
+    ));
+    add(Command::new(
+        "JSON.NUMINCRBY",
+        4,
+        WRITE,
+        (1, 1, 1),
+        &["@write", "@json", "@slow"],
+        Box::new(|args| json_numincrby(args, &JSONS)),
+    ));
+    add(Command::new(
+        "JSON.ARRAPPEND",
+        -4,
+        WRITE | DENYOOM,
+        (1, 1, 1),
+        &["@write", "@json", "@slow"],
+        Box::new(|args| json_arrappend(args, &JSONS)),
+    ));
+    add(Command::new(
+        "JSON.ARRLEN",
+        -2,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@json", "@slow"],
+        Box::new(|args| json_arrlen(args, &JSONS)),
+    ));
+    add(Command::new(
+        "JSON.OBJKEYS",
+        -2,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@json", "@slow"],
+        Box::new(|args| json_objkeys(args, &JSONS)),
+    ));
+    add(Command::new(
+        "RPUSH",
+        -3,
+        WRITE | DENYOOM | FAST,
+        (1, 1, 1),
+        &["@write", "@list", "@fast"],
+        Box::new(|args| rpush(args, &LISTS)),
+    ));
+    add(Command::new(
+        "LLEN",
+        2,
+        READONLY | FAST,
+        (1, 1, 1),
+        &["@read", "@list", "@fast"],
+        Box::new(|args| llen(args, &LISTS)),
+    ));
+    add(Command::new(
+        "LRANGE",
+        4,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@list", "@slow"],
+        Box::new(|args| lrange(args, &LISTS)),
+    ));
+    add(Command::new(
+        "SADD",
+        -3,
+        WRITE | DENYOOM | FAST,
+        (1, 1, 1),
+        &["@write", "@set", "@fast"],
+        Box::new(|args| sadd(args, &SSETS)),
+    ));
+    add(Command::new(
+        "SCARD",
+        2,
+        READONLY | FAST,
+        (1, 1, 1),
+        &["@read", "@set", "@fast"],
+        Box::new(|args| scard(args, &SSETS)),
+    ));
+    add(Command::new(
+        "SMEMBERS",
+        2,
+        READONLY,
+        (1, 1, 1),
+        &["@read", "@set", "@slow"],
+        Box::new(|args| smembers(args, &SSETS)),
+    ));
+    add(Command::new(
+        "PEXPIREAT",
+        3,
+        WRITE | FAST,
+        (1, 1, 1),
+        &["@keyspace", "@write", "@fast"],
+        Box::new(pexpireat),
+    ));
+    add(Command::new(
+        "PERSIST",
+        2,
+        WRITE | FAST,
+        (1, 1, 1),
+        &["@keyspace", "@write", "@fast"],
+        Box::new(persist),
+    ));
+    add(Command::new(
+        "TTL",
+        2,
+        READONLY | FAST,
+        (1, 1, 1),
+        &["@keyspace", "@read", "@fast"],
+        Box::new(ttl),
+    ));
+    add(Command::new(
+        "PTTL",
+        2,
+        READONLY | FAST,
+        (1, 1, 1),
+        &["@keyspace", "@read", "@fast"],
+        Box::new(pttl),
+    ));
     m
 });
 
@@ -40,6 +656,205 @@ pub static SETS: LazyLock<SetMap> = LazyLock::new(|| Mutex::new(HashMap::new()))
 
 pub static HSETS: LazyLock<HSetMap> = LazyLock::new(|| Mutex::new(HashMap::new()));
 
+pub static ZSETS: LazyLock<ZSetMap> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub static JSONS: LazyLock<JsonMap> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub static LISTS: LazyLock<ListMap> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Set-typed keys; SETS holds strings.
+pub static SSETS: LazyLock<SSetMap> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Unix time in milliseconds at which each expiring key is deleted.
+pub static EXPIRES: LazyLock<ExpireMap> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Held while a command runs, so that clients, the AOF and the replication
+// stream all see commands in one order.
+pub static EXECUTION: Mutex<()> = Mutex::new(());
+
+// A point-in-time copy of every store.
+#[derive(Default)]
+pub struct Snapshot {
+    pub sets: HashMap<Vec<u8>, Vec<u8>>,
+    pub hsets: HashMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>,
+    pub zsets: HashMap<Vec<u8>, SortedSet>,
+    pub jsons: HashMap<Vec<u8>, Json>,
+    pub lists: HashMap<Vec<u8>, VecDeque<Vec<u8>>>,
+    pub ssets: HashMap<Vec<u8>, HashSet<Vec<u8>>>,
+    pub expires: HashMap<Vec<u8>, u64>,
+}
+
+impl Snapshot {
+    // Reported as the "fork" latency event, as this copy is what stands in
+    // for Redis forking a child.
+    pub fn take() -> Self {
+        let start = Instant::now();
+        let snapshot = Snapshot {
+            sets: SETS.lock().unwrap().clone(),
+            hsets: HSETS.lock().unwrap().clone(),
+            zsets: ZSETS.lock().unwrap().clone(),
+            jsons: JSONS.lock().unwrap().clone(),
+            lists: LISTS.lock().unwrap().clone(),
+            ssets: SSETS.lock().unwrap().clone(),
+            expires: EXPIRES.lock().unwrap().clone(),
+        };
+        latency::sample("fork", start.elapsed());
+        snapshot
+    }
+
+    // Adds every key to the stores, replacing what is there.
+    pub fn restore(self) {
+        SETS.lock().unwrap().extend(self.sets);
+        HSETS.lock().unwrap().extend(self.hsets);
+        ZSETS.lock().unwrap().extend(self.zsets);
+        JSONS.lock().unwrap().extend(self.jsons);
+        LISTS.lock().unwrap().extend(self.lists);
+        SSETS.lock().unwrap().extend(self.ssets);
+        EXPIRES.lock().unwrap().extend(self.expires);
+    }
+
+    // Replaces the whole keyspace, as a replica does after a full sync.
+    pub fn replace_all(self) {
+        *SETS.lock().unwrap() = self.sets;
+        *HSETS.lock().unwrap() = self.hsets;
+        *ZSETS.lock().unwrap() = self.zsets;
+        *JSONS.lock().unwrap() = self.jsons;
+        *LISTS.lock().unwrap() = self.lists;
+        *SSETS.lock().unwrap() = self.ssets;
+        *EXPIRES.lock().unwrap() = self.expires;
+    }
+
+    // Just the given keys, for DUMP and MIGRATE.
+    pub fn of(keys: &[Vec<u8>]) -> Self {
+        fn pick<V: Clone>(
+            map: &Mutex<HashMap<Vec<u8>, V>>,
+            keys: &[Vec<u8>],
+        ) -> HashMap<Vec<u8>, V> {
+            let map = map.lock().unwrap();
+            keys.iter()
+                .filter_map(|key| Some((key.clone(), map.get(key)?.clone())))
+                .collect()
+        }
+        Snapshot {
+            sets: pick(&SETS, keys),
+            hsets: pick(&HSETS, keys),
+            zsets: pick(&ZSETS, keys),
+            jsons: pick(&JSONS, keys),
+            lists: pick(&LISTS, keys),
+            ssets: pick(&SSETS, keys),
+            expires: pick(&EXPIRES, keys),
+        }
+    }
+
+    // Keys, not counting expire times.
+    pub fn len(&self) -> usize {
+        self.sets.len()
+            + self.hsets.len()
+            + self.zsets.len()
+            + self.jsons.len()
+            + self.lists.len()
+            + self.ssets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Whether `key` is in any store.
+pub fn exists(key: &[u8]) -> bool {
+    SETS.lock().unwrap().contains_key(key)
+        || HSETS.lock().unwrap().contains_key(key)
+        || ZSETS.lock().unwrap().contains_key(key)
+        || JSONS.lock().unwrap().contains_key(key)
+        || LISTS.lock().unwrap().contains_key(key)
+        || SSETS.lock().unwrap().contains_key(key)
+}
+
+// Removes `key` from every store and forgets its expire time, returning
+// whether it was in any.
+pub fn remove(key: &[u8]) -> bool {
+    EXPIRES.lock().unwrap().remove(key);
+    let removed = [
+        SETS.lock().unwrap().remove(key).is_some(),
+        HSETS.lock().unwrap().remove(key).is_some(),
+        ZSETS.lock().unwrap().remove(key).is_some(),
+        JSONS.lock().unwrap().remove(key).is_some(),
+        LISTS.lock().unwrap().remove(key).is_some(),
+        SSETS.lock().unwrap().remove(key).is_some(),
+    ];
+    removed.contains(&true)
+}
+
+pub fn del(args: Vec<Message>) -> Message {
+    let mut removed = 0;
+    for arg in &args {
+        let Bulk(key) = arg else {
+            return Message::error("ERR syntax error");
+        };
+        removed += remove(key) as i64;
+    }
+    Message::integer(removed)
+}
+
+// Every key in any store, in order.
+pub fn keys() -> BTreeSet<Vec<u8>> {
+    let mut keys = BTreeSet::new();
+    keys.extend(SETS.lock().unwrap().keys().cloned());
+    keys.extend(HSETS.lock().unwrap().keys().cloned());
+    keys.extend(ZSETS.lock().unwrap().keys().cloned());
+    keys.extend(JSONS.lock().unwrap().keys().cloned());
+    keys.extend(LISTS.lock().unwrap().keys().cloned());
+    keys.extend(SSETS.lock().unwrap().keys().cloned());
+    keys
+}
+
+// The number of distinct keys, as keys() would list, without copying them
+// out of the stores. A name used in several stores counts in the first.
+pub fn key_count() -> usize {
+    let sets = SETS.lock().unwrap();
+    let hsets = HSETS.lock().unwrap();
+    let zsets = ZSETS.lock().unwrap();
+    let jsons = JSONS.lock().unwrap();
+    let lists = LISTS.lock().unwrap();
+    let ssets = SSETS.lock().unwrap();
+    type Contains<'a> = &'a dyn Fn(&Vec<u8>) -> bool;
+    let contains: [Contains; 6] = [
+        &|key| sets.contains_key(key),
+        &|key| hsets.contains_key(key),
+        &|key| zsets.contains_key(key),
+        &|key| jsons.contains_key(key),
+        &|key| lists.contains_key(key),
+        &|key| ssets.contains_key(key),
+    ];
+    let names: [Box<dyn Iterator<Item = &Vec<u8>>>; 6] = [
+        Box::new(sets.keys()),
+        Box::new(hsets.keys()),
+        Box::new(zsets.keys()),
+        Box::new(jsons.keys()),
+        Box::new(lists.keys()),
+        Box::new(ssets.keys()),
+    ];
+    names
+        .into_iter()
+        .enumerate()
+        .map(|(i, names)| {
+            names
+                .filter(|key| !contains[..i].iter().any(|contains| contains(key)))
+                .count()
+        })
+        .sum()
+}
+
+pub fn parse_int(arg: &[u8]) -> Option<i64> {
+    str::from_utf8(arg).ok()?.parse::<i64>().ok()
+}
+
+pub fn parse_float(arg: &[u8]) -> Option<f64> {
+    let value = str::from_utf8(arg).ok()?.parse::<f64>().ok()?;
+    (!value.is_nan()).then_some(value)
+}
+
 pub fn ping(args: Vec<Message>, _sets: &SetMap) -> Message {
     match args.as_slice() {
         [] => Message::simple("PONG"),
@@ -53,6 +868,8 @@ pub fn set(args: Vec<Message>, sets: &SetMap) -> Message {
         [Bulk(key), Bulk(value)] => {
             let mut sets = sets.lock().unwrap();
             sets.insert(key.clone(), value.clone());
+            // Like Redis, SET discards the key's expire time.
+            EXPIRES.lock().unwrap().remove(key);
             Message::simple("OK")
         }
         _ => Message::error("ERR wrong number of arguments for 'set' command"),
@@ -123,8 +940,202 @@ pub fn hgetall(args: Vec<Message>, hsets: &HSetMap) -> Message {
 }
 
 #[cfg(test)]
+// The hash tests spell out the HSETS type in full.
+#[allow(clippy::type_complexity)]
 mod tests {
     use super::*;
+    use std::collections::BTreeMap;
+
+    type Snapshot = (
+        BTreeMap<Vec<u8>, Vec<u8>>,
+        BTreeMap<Vec<u8>, HashMap<Vec<u8>, Vec<u8>>>,
+        BTreeMap<Vec<u8>, SortedSet>,
+        BTreeMap<Vec<u8>, Json>,
+        BTreeMap<Vec<u8>, VecDeque<Vec<u8>>>,
+        BTreeMap<Vec<u8>, HashSet<Vec<u8>>>,
+        BTreeMap<Vec<u8>, u64>,
+    );
+
+    fn snapshot(prefix: &[u8]) -> Snapshot {
+        fn keep<V: Clone>(map: &HashMap<Vec<u8>, V>, prefix: &[u8]) -> BTreeMap<Vec<u8>, V> {
+            map.iter()
+                .filter(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()
+        }
+        (
+            keep(&SETS.lock().unwrap(), prefix),
+            keep(&HSETS.lock().unwrap(), prefix),
+            keep(&ZSETS.lock().unwrap(), prefix),
+            keep(&JSONS.lock().unwrap(), prefix),
+            keep(&LISTS.lock().unwrap(), prefix),
+            keep(&SSETS.lock().unwrap(), prefix),
+            keep(&EXPIRES.lock().unwrap(), prefix),
+        )
+    }
+
+    // Runs every command against the shared stores and checks that exactly
+    // the ones that changed the keyspace carry the write flag, so the AOF
+    // cannot silently miss a new write command.
+    #[test]
+    fn test_write_flag_matches_keyspace_changes() {
+        let script: &[(&str, &[&str])] = &[
+            ("PING", &[]),
+            ("COMMAND", &["COUNT"]),
+            ("LASTSAVE", &[]),
+            ("REPLICAOF", &["NO", "ONE"]),
+            ("ROLE", &[]),
+            ("INFO", &[]),
+            ("SLOWLOG", &["LEN"]),
+            ("LATENCY", &["LATEST"]),
+            ("CLUSTER", &["KEYSLOT", "{flags}s"]),
+            ("SET", &["{flags}s", "v"]),
+            ("GET", &["{flags}s"]),
+            ("HSET", &["{flags}h", "f", "v"]),
+            ("HGET", &["{flags}h", "f"]),
+            ("HGETALL", &["{flags}h"]),
+            ("SETBIT", &["{flags}b", "7", "1"]),
+            ("GETBIT", &["{flags}b", "7"]),
+            ("BITCOUNT", &["{flags}b"]),
+            ("BITPOS", &["{flags}b", "1"]),
+            ("BITOP", &["NOT", "{flags}b2", "{flags}b"]),
+            ("BITFIELD", &["{flags}b", "INCRBY", "u8", "0", "1"]),
+            ("BITFIELD_RO", &["{flags}b", "GET", "u8", "0"]),
+            // Without elements the cached cardinality stays valid, so
+            // PFCOUNT has nothing to refresh. The stale case is
+            // test_pfcount_refreshes_a_stale_cache.
+            ("PFADD", &["{flags}p"]),
+            ("PFCOUNT", &["{flags}p"]),
+            ("PFMERGE", &["{flags}p2", "{flags}p"]),
+            (
+                "GEOADD",
+                &["{flags}g", "13.36", "38.11", "a", "15.08", "37.5", "b"],
+            ),
+            ("GEOPOS", &["{flags}g", "a"]),
+            ("GEODIST", &["{flags}g", "a", "b"]),
+            ("GEOHASH", &["{flags}g", "a"]),
+            (
+                "GEOSEARCH",
+                &["{flags}g", "FROMMEMBER", "a", "BYRADIUS", "500", "km"],
+            ),
+            (
+                "GEOSEARCHSTORE",
+                &[
+                    "{flags}g2",
+                    "{flags}g",
+                    "FROMMEMBER",
+                    "a",
+                    "BYRADIUS",
+                    "500",
+                    "km",
+                ],
+            ),
+            ("ZSCORE", &["{flags}g", "a"]),
+            ("ZCARD", &["{flags}g"]),
+            ("ZREM", &["{flags}g", "b"]),
+            ("ZADD", &["{flags}z", "1", "a"]),
+            ("JSON.SET", &["{flags}j", "$", r#"{"a":[1],"n":1}"#]),
+            ("JSON.GET", &["{flags}j"]),
+            ("JSON.MGET", &["{flags}j", "$.a"]),
+            ("JSON.TYPE", &["{flags}j"]),
+            ("JSON.NUMINCRBY", &["{flags}j", "$.n", "1"]),
+            ("JSON.ARRAPPEND", &["{flags}j", "$.a", "2"]),
+            ("JSON.ARRLEN", &["{flags}j", "$.a"]),
+            ("JSON.OBJKEYS", &["{flags}j"]),
+            ("JSON.DEL", &["{flags}j", "$.a"]),
+            ("RPUSH", &["{flags}l", "a", "b"]),
+            ("LLEN", &["{flags}l"]),
+            ("LRANGE", &["{flags}l", "0", "-1"]),
+            ("SADD", &["{flags}m", "a", "b"]),
+            ("SCARD", &["{flags}m"]),
+            ("SMEMBERS", &["{flags}m"]),
+            ("PEXPIREAT", &["{flags}s", "4102444800000"]),
+            ("TTL", &["{flags}s"]),
+            ("PTTL", &["{flags}s"]),
+            ("PERSIST", &["{flags}s"]),
+            ("DUMP", &["{flags}j"]),
+            // "{dump}" stands for the payload DUMP returned.
+            ("RESTORE", &["{flags}r", "0", "{dump}"]),
+            ("RESTORE-ASKING", &["{flags}r2", "0", "{dump}"]),
+            ("DEL", &["{flags}r", "{flags}r2", "{flags}nosuch"]),
+        ];
+        // These write files but leave the keyspace alone, so they are only
+        // checked against the table rather than run.
+        let persistence = ["BGREWRITEAOF", "SAVE", "BGSAVE"];
+        for name in persistence {
+            assert!(!HANDLERS[name].is_write(), "{} is flagged write", name);
+        }
+        for name in HANDLERS.keys() {
+            assert!(
+                persistence.contains(name) || script.iter().any(|(n, _)| n == name),
+                "{} has no entry in the write flag script",
+                name
+            );
+        }
+        let mut dump = Vec::new();
+        for (name, args) in script {
+            let command = &HANDLERS[name];
+            let args: Vec<Message> = args
+                .iter()
+                .map(|&a| match a {
+                    "{dump}" => Message::bulk(dump.clone()),
+                    a => Message::bulk(a.as_bytes().to_vec()),
+                })
+                .collect();
+            assert!(command.check_arity(args.len() + 1), "{} arity", name);
+            let before = snapshot(b"{flags}");
+            let reply = command.call(args);
+            let changed = snapshot(b"{flags}") != before;
+            if let (&"DUMP", Bulk(payload)) = (name, &reply) {
+                dump = payload.clone();
+            }
+            assert!(!matches!(reply, Error(_)), "{} failed: {:?}", name, reply);
+            assert_eq!(
+                changed,
+                command.is_write(),
+                "{} changed the keyspace: {}, write flag: {}",
+                name,
+                changed,
+                command.is_write()
+            );
+        }
+    }
+
+    // PFCOUNT is READONLY but rewrites a stale cache, which is the one place
+    // a read changes the keyspace. Only the cached cardinality may change.
+    #[test]
+    fn test_pfcount_refreshes_a_stale_cache() {
+        let key = b"{pfcache}p".to_vec();
+        HANDLERS["PFADD"].call(Message::bulks(&["{pfcache}p", "a", "b"]));
+        let stale = SETS.lock().unwrap()[&key].clone();
+        let count = HANDLERS["PFCOUNT"].call(Message::bulks(&["{pfcache}p"]));
+        assert_eq!(count, Message::integer(2));
+        let fresh = SETS.lock().unwrap()[&key].clone();
+        assert_ne!(stale, fresh);
+        assert_eq!(stale[..8], fresh[..8]);
+        assert_eq!(stale[16..], fresh[16..]);
+        assert!(!HANDLERS["PFCOUNT"].is_write());
+        // Counting the stale copy a replica keeps gives the same answer.
+        SETS.lock().unwrap().insert(key.clone(), stale);
+        assert_eq!(
+            HANDLERS["PFCOUNT"].call(Message::bulks(&["{pfcache}p"])),
+            count
+        );
+        SETS.lock().unwrap().remove(&key);
+    }
+
+    #[test]
+    fn test_check_arity() {
+        assert!(HANDLERS["GET"].check_arity(2));
+        assert!(!HANDLERS["GET"].check_arity(3));
+        assert!(HANDLERS["PING"].check_arity(1));
+        assert!(HANDLERS["PING"].check_arity(2));
+        assert!(!HANDLERS["BITOP"].check_arity(3));
+        assert_eq!(
+            HANDLERS["JSON.GET"].arity_error(),
+            Message::error("ERR wrong number of arguments for 'json.get' command")
+        );
+    }
 
     #[test]
     fn test_init_handler_funcs_contains_ping() {
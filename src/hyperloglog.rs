@@ -0,0 +1,545 @@
+use crate::handlers::SetMap;
+use crate::message::Message;
+use crate::message::Message::*;
+
+// The layout below mirrors Redis so values can be copied between servers:
+// a 16 byte header ("HYLL", encoding, 3 unused bytes, little endian cached
+// cardinality whose most significant bit flags it as stale) followed by
+// either 16384 packed 6-bit registers or the sparse run-length opcodes.
+const HLL_P: u32 = 14;
+const HLL_Q: u32 = 64 - HLL_P;
+const HLL_REGISTERS: usize = 1 << HLL_P;
+const HLL_BITS: usize = 6;
+const HLL_REGISTER_MAX: u8 = (1 << HLL_BITS) - 1;
+const HLL_HDR_SIZE: usize = 16;
+const HLL_DENSE_SIZE: usize = HLL_HDR_SIZE + (HLL_REGISTERS * HLL_BITS).div_ceil(8);
+const HLL_DENSE: u8 = 0;
+const HLL_SPARSE: u8 = 1;
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+const HLL_SEED: u64 = 0xadc83b19;
+
+const SPARSE_VAL_MAX_VALUE: u8 = 32;
+const SPARSE_VAL_MAX_LEN: usize = 4;
+const SPARSE_ZERO_MAX_LEN: usize = 64;
+const SPARSE_XZERO_MAX_LEN: usize = 16384;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Hll {
+    registers: Vec<u8>,
+    dense: bool,
+    cached: Option<u64>,
+}
+
+impl Hll {
+    fn new() -> Self {
+        Hll {
+            registers: vec![0; HLL_REGISTERS],
+            dense: false,
+            cached: Some(0),
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Hll, Message> {
+        if bytes.len() < HLL_HDR_SIZE || &bytes[..4] != b"HYLL" {
+            return Err(wrong_type());
+        }
+        let mut card = [0u8; 8];
+        card.copy_from_slice(&bytes[8..16]);
+        let cached = match card[7] & 0x80 {
+            0 => Some(u64::from_le_bytes(card)),
+            _ => None,
+        };
+        let body = &bytes[HLL_HDR_SIZE..];
+        let registers = match bytes[4] {
+            HLL_DENSE if bytes.len() == HLL_DENSE_SIZE => {
+                (0..HLL_REGISTERS).map(|i| dense_get(body, i)).collect()
+            }
+            HLL_SPARSE => sparse_decode(body)?,
+            _ => return Err(wrong_type()),
+        };
+        Ok(Hll {
+            registers,
+            dense: bytes[4] == HLL_DENSE,
+            cached,
+        })
+    }
+
+    // Keeps the sparse encoding while it is both representable and smaller
+    // than `sparse_max_bytes`; once dense a value never goes back.
+    fn encode(&mut self, sparse_max_bytes: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HLL_DENSE_SIZE);
+        bytes.extend_from_slice(b"HYLL");
+        bytes.extend_from_slice(&[HLL_SPARSE, 0, 0, 0]);
+        match self.cached {
+            Some(card) => bytes.extend_from_slice(&card.to_le_bytes()),
+            None => bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0x80]),
+        }
+        if !self.dense {
+            if let Some(sparse) = sparse_encode(&self.registers) {
+                if HLL_HDR_SIZE + sparse.len() <= sparse_max_bytes {
+                    bytes.extend(sparse);
+                    return bytes;
+                }
+            }
+            self.dense = true;
+        }
+        bytes[4] = HLL_DENSE;
+        bytes.resize(HLL_DENSE_SIZE, 0);
+        for (i, register) in self.registers.iter().enumerate() {
+            dense_set(&mut bytes[HLL_HDR_SIZE..], i, *register);
+        }
+        bytes
+    }
+
+    fn add(&mut self, element: &[u8]) -> bool {
+        let hash = murmurhash64a(element, HLL_SEED);
+        let index = (hash as usize) & (HLL_REGISTERS - 1);
+        // The sentinel bit bounds the run length at HLL_Q + 1.
+        let count = ((hash >> HLL_P) | (1 << HLL_Q)).trailing_zeros() as u8 + 1;
+        if self.registers[index] < count {
+            self.registers[index] = count;
+            self.cached = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn merge(&mut self, other: &Hll) {
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            *mine = (*mine).max(*theirs);
+        }
+        self.dense |= other.dense;
+        self.cached = None;
+    }
+
+    // Cardinality estimator from Otmar Ertl, "New cardinality estimation
+    // algorithms for HyperLogLog sketches" (arXiv:1702.01284), as used by Redis.
+    fn count(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let mut histogram = [0u32; 64];
+        for register in &self.registers {
+            histogram[*register as usize] += 1;
+        }
+        let q = HLL_Q as usize;
+        let mut z = m * tau((m - histogram[q + 1] as f64) / m);
+        for j in (1..=q).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        (HLL_ALPHA_INF * m * m / z).round() as u64
+    }
+}
+
+pub fn pfadd(args: Vec<Message>, sets: &SetMap, sparse_max_bytes: usize) -> Message {
+    let (key, elements) = match args.as_slice() {
+        [Bulk(key), elements @ ..] => (key, elements),
+        _ => return Message::error("ERR wrong number of arguments for 'pfadd' command"),
+    };
+    let mut sets = sets.lock().unwrap();
+    let (mut hll, mut updated) = match sets.get(key) {
+        Some(value) => match Hll::decode(value) {
+            Ok(hll) => (hll, false),
+            Err(err) => return err,
+        },
+        None => (Hll::new(), true),
+    };
+    for element in elements {
+        if let Bulk(element) = element {
+            updated |= hll.add(element);
+        }
+    }
+    if updated {
+        sets.insert(key.clone(), hll.encode(sparse_max_bytes));
+    }
+    Message::integer(updated as i64)
+}
+
+// A single key with a stale cached cardinality gets the fresh one written
+// back. PFCOUNT stays READONLY anyway, as in Redis, so the refresh reaches
+// neither the AOF nor replicas: their copy keeps the stale mark until they
+// count it themselves. Only the cache bytes differ, so every count agrees,
+// but GET and DUMP can show different bytes on each side.
+pub fn pfcount(args: Vec<Message>, sets: &SetMap) -> Message {
+    let keys = match args.as_slice() {
+        [] => return Message::error("ERR wrong number of arguments for 'pfcount' command"),
+        keys => keys,
+    };
+    let mut sets = sets.lock().unwrap();
+    if let [Bulk(key)] = keys {
+        let value = match sets.get_mut(key) {
+            Some(value) => value,
+            None => return Message::integer(0),
+        };
+        let hll = match Hll::decode(value) {
+            Ok(hll) => hll,
+            Err(err) => return err,
+        };
+        let count = match hll.cached {
+            Some(count) => count,
+            None => {
+                // Refresh the cache in place so the registers keep their encoding.
+                let count = hll.count();
+                value[8..HLL_HDR_SIZE].copy_from_slice(&count.to_le_bytes());
+                count
+            }
+        };
+        return Message::integer(count as i64);
+    }
+
+    let mut union = Hll::new();
+    for key in keys {
+        if let Some(value) = key_bytes(key).and_then(|key| sets.get(key)) {
+            match Hll::decode(value) {
+                Ok(hll) => union.merge(&hll),
+                Err(err) => return err,
+            }
+        }
+    }
+    Message::integer(union.count() as i64)
+}
+
+pub fn pfmerge(args: Vec<Message>, sets: &SetMap, sparse_max_bytes: usize) -> Message {
+    let (dest, sources) = match args.as_slice() {
+        [Bulk(dest), sources @ ..] => (dest, sources),
+        _ => return Message::error("ERR wrong number of arguments for 'pfmerge' command"),
+    };
+    let mut sets = sets.lock().unwrap();
+    let mut merged = Hll::new();
+    let dest_value = sets.get(dest);
+    let inputs = dest_value.into_iter().chain(
+        sources
+            .iter()
+            .filter_map(|key| key_bytes(key).and_then(|k| sets.get(k))),
+    );
+    for value in inputs {
+        match Hll::decode(value) {
+            Ok(hll) => merged.merge(&hll),
+            Err(err) => return err,
+        }
+    }
+    sets.insert(dest.clone(), merged.encode(sparse_max_bytes));
+    Message::simple("OK")
+}
+
+fn key_bytes(key: &Message) -> Option<&Vec<u8>> {
+    match key {
+        Bulk(key) => Some(key),
+        _ => None,
+    }
+}
+
+fn wrong_type() -> Message {
+    Message::error("WRONGTYPE Key is not a valid HyperLogLog string value.")
+}
+
+fn corrupted() -> Message {
+    Message::error("INVALIDOBJ Corrupted HLL object detected")
+}
+
+fn dense_get(body: &[u8], index: usize) -> u8 {
+    let bit = index * HLL_BITS;
+    let (byte, shift) = (bit / 8, bit % 8);
+    let b0 = body[byte] as u16;
+    let b1 = body.get(byte + 1).copied().unwrap_or(0) as u16;
+    (((b0 >> shift) | (b1 << (8 - shift))) as u8) & HLL_REGISTER_MAX
+}
+
+fn dense_set(body: &mut [u8], index: usize, value: u8) {
+    let bit = index * HLL_BITS;
+    let (byte, shift) = (bit / 8, bit % 8);
+    let value = value as u16;
+    let max = HLL_REGISTER_MAX as u16;
+    body[byte] &= !((max << shift) as u8);
+    body[byte] |= (value << shift) as u8;
+    if let Some(next) = body.get_mut(byte + 1) {
+        *next &= !((max >> (8 - shift)) as u8);
+        *next |= (value >> (8 - shift)) as u8;
+    }
+}
+
+// Opcodes: ZERO 00xxxxxx, XZERO 01xxxxxx yyyyyyyy and VAL 1vvvvvxx.
+fn sparse_decode(body: &[u8]) -> Result<Vec<u8>, Message> {
+    let mut registers = Vec::with_capacity(HLL_REGISTERS);
+    let mut i = 0;
+    while i < body.len() {
+        let op = body[i];
+        let (value, len) = if op & 0x80 != 0 {
+            i += 1;
+            (((op >> 2) & 0x1f) + 1, (op & 0x03) as usize + 1)
+        } else if op & 0x40 != 0 {
+            let low = *body.get(i + 1).ok_or_else(corrupted)?;
+            i += 2;
+            (0, ((((op & 0x3f) as usize) << 8) | low as usize) + 1)
+        } else {
+            i += 1;
+            (0, (op & 0x3f) as usize + 1)
+        };
+        if registers.len() + len > HLL_REGISTERS {
+            return Err(corrupted());
+        }
+        registers.resize(registers.len() + len, value);
+    }
+    if registers.len() != HLL_REGISTERS {
+        return Err(corrupted());
+    }
+    Ok(registers)
+}
+
+// Returns None when a register is too large for a VAL opcode.
+fn sparse_encode(registers: &[u8]) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < registers.len() {
+        let value = registers[i];
+        if value > SPARSE_VAL_MAX_VALUE {
+            return None;
+        }
+        let run = registers[i..].iter().take_while(|r| **r == value).count();
+        let mut left = run;
+        while left > 0 {
+            if value == 0 && left > SPARSE_ZERO_MAX_LEN {
+                let len = left.min(SPARSE_XZERO_MAX_LEN);
+                bytes.push(0x40 | ((len - 1) >> 8) as u8);
+                bytes.push(((len - 1) & 0xff) as u8);
+                left -= len;
+            } else if value == 0 {
+                bytes.push((left - 1) as u8);
+                left = 0;
+            } else {
+                let len = left.min(SPARSE_VAL_MAX_LEN);
+                bytes.push(0x80 | ((value - 1) << 2) | (len - 1) as u8);
+                left -= len;
+            }
+        }
+        i += run;
+    }
+    Some(bytes)
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if previous == z {
+            return z / 3.0;
+        }
+    }
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if previous == z {
+            return z;
+        }
+    }
+}
+
+// MurmurHash64A as used by Redis, reading blocks little endian.
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    fn add_range(sets: &SetMap, key: &str, range: std::ops::Range<usize>, sparse_max: usize) {
+        let mut args = vec![Message::bulk(key.as_bytes().to_vec())];
+        args.extend(range.map(|i| Message::bulk(format!("element:{}", i).into_bytes())));
+        pfadd(args, sets, sparse_max);
+    }
+
+    #[test]
+    fn test_new_hll_layout() {
+        let bytes = Hll::new().encode(3000);
+        let mut expected = b"HYLL\x01\0\0\0".to_vec();
+        expected.extend_from_slice(&[0; 8]);
+        expected.extend_from_slice(&[0x7f, 0xff]);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn test_pfadd_reports_changes() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        assert_eq!(
+            pfadd(Message::bulks(&["hll", "a", "b", "c"]), &sets, 3000),
+            Message::integer(1)
+        );
+        assert_eq!(
+            pfadd(Message::bulks(&["hll", "a", "b"]), &sets, 3000),
+            Message::integer(0)
+        );
+        assert_eq!(
+            pfcount(Message::bulks(&["hll"]), &sets),
+            Message::integer(3)
+        );
+    }
+
+    #[test]
+    fn test_pfadd_without_elements_creates_key() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        assert_eq!(
+            pfadd(Message::bulks(&["hll"]), &sets, 3000),
+            Message::integer(1)
+        );
+        assert_eq!(
+            pfadd(Message::bulks(&["hll"]), &sets, 3000),
+            Message::integer(0)
+        );
+        assert_eq!(
+            pfcount(Message::bulks(&["hll"]), &sets),
+            Message::integer(0)
+        );
+    }
+
+    #[test]
+    fn test_pfcount_caches_cardinality() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        pfadd(Message::bulks(&["hll", "a", "b"]), &sets, 3000);
+        let stale = sets.lock().unwrap()[b"hll".as_slice()][15];
+        assert_eq!(stale & 0x80, 0x80);
+        pfcount(Message::bulks(&["hll"]), &sets);
+        let value = sets.lock().unwrap()[b"hll".as_slice()].clone();
+        assert_eq!(&value[8..16], &2u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_estimate_accuracy() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        add_range(&sets, "hll", 0..20000, 3000);
+        let count = match pfcount(Message::bulks(&["hll"]), &sets) {
+            Message::Integer(count) => count,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert!(
+            (count - 20000).abs() < 20000 * 3 / 100,
+            "estimate {}",
+            count
+        );
+    }
+
+    #[test]
+    fn test_promotes_to_dense() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        add_range(&sets, "small", 0..10, 3000);
+        assert_eq!(sets.lock().unwrap()[b"small".as_slice()][4], HLL_SPARSE);
+        add_range(&sets, "big", 0..5000, 3000);
+        let value = sets.lock().unwrap()[b"big".as_slice()].clone();
+        assert_eq!(value[4], HLL_DENSE);
+        assert_eq!(value.len(), HLL_DENSE_SIZE);
+    }
+
+    #[test]
+    fn test_large_register_forces_dense() {
+        let mut hll = Hll::new();
+        hll.registers[5] = SPARSE_VAL_MAX_VALUE + 1;
+        let bytes = hll.encode(3000);
+        assert_eq!(bytes[4], HLL_DENSE);
+        assert_eq!(Hll::decode(&bytes).unwrap().registers[5], 33);
+    }
+
+    #[test]
+    fn test_sparse_and_dense_round_trip() {
+        let mut hll = Hll::new();
+        for i in 0..200 {
+            hll.add(format!("{}", i).as_bytes());
+        }
+        let sparse = hll.clone().encode(3000);
+        let dense = hll.clone().encode(0);
+        assert_eq!(sparse[4], HLL_SPARSE);
+        assert_eq!(dense[4], HLL_DENSE);
+        assert_eq!(Hll::decode(&sparse).unwrap().registers, hll.registers);
+        assert_eq!(Hll::decode(&dense).unwrap().registers, hll.registers);
+    }
+
+    #[test]
+    fn test_pfcount_union_and_pfmerge() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        add_range(&sets, "a", 0..100, 3000);
+        add_range(&sets, "b", 50..150, 3000);
+        let union = pfcount(Message::bulks(&["a", "b", "missing"]), &sets);
+        assert_eq!(
+            pfmerge(Message::bulks(&["c", "a", "b"]), &sets, 3000),
+            Message::simple("OK")
+        );
+        assert_eq!(pfcount(Message::bulks(&["c"]), &sets), union);
+        match union {
+            Message::Integer(count) => assert!((145..=155).contains(&count)),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_pfmerge_includes_dest() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        pfadd(Message::bulks(&["dest", "x"]), &sets, 3000);
+        pfadd(Message::bulks(&["src", "y"]), &sets, 3000);
+        pfmerge(Message::bulks(&["dest", "src"]), &sets, 3000);
+        assert_eq!(
+            pfcount(Message::bulks(&["dest"]), &sets),
+            Message::integer(2)
+        );
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let sets: SetMap = Mutex::new(HashMap::new());
+        sets.lock()
+            .unwrap()
+            .insert(b"str".to_vec(), b"hello".to_vec());
+        assert_eq!(
+            pfadd(Message::bulks(&["str", "a"]), &sets, 3000),
+            wrong_type()
+        );
+        assert_eq!(pfcount(Message::bulks(&["str"]), &sets), wrong_type());
+    }
+
+    #[test]
+    fn test_corrupted_sparse() {
+        let mut bytes = Hll::new().encode(3000);
+        bytes.push(0x00);
+        assert_eq!(Hll::decode(&bytes), Err(corrupted()));
+    }
+}
@@ -0,0 +1,277 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::LazyLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::aof;
+use crate::cluster;
+use crate::config;
+use crate::handlers;
+use crate::message::Message;
+use crate::message::Message::*;
+use crate::rdb;
+use crate::replication;
+
+// Counters kept by handle_client.
+pub static CONNECTED_CLIENTS: AtomicU64 = AtomicU64::new(0);
+pub static TOTAL_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+pub static TOTAL_COMMANDS: AtomicU64 = AtomicU64::new(0);
+// Keys found, or not, by read commands.
+pub static KEYSPACE_HITS: AtomicU64 = AtomicU64::new(0);
+pub static KEYSPACE_MISSES: AtomicU64 = AtomicU64::new(0);
+pub static EXPIRED_KEYS: AtomicU64 = AtomicU64::new(0);
+
+// Set by `init` when the server starts.
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+static RUN_ID: LazyLock<String> = LazyLock::new(replication::new_replid);
+
+// Bytes allocated now and at most, for used_memory. Like Redis, the peak
+// is sampled after each command instead of on every allocation.
+static USED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+static PEAK_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+// The system allocator, counting what is in use.
+struct Counting;
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn allocated(size: usize) {
+    USED_MEMORY.fetch_add(size, Ordering::Relaxed);
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        USED_MEMORY.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            USED_MEMORY.fetch_sub(layout.size(), Ordering::Relaxed);
+            allocated(new_size);
+        }
+        new
+    }
+}
+
+pub fn init() {
+    LazyLock::force(&START);
+}
+
+pub fn used_memory() -> usize {
+    USED_MEMORY.load(Ordering::Relaxed)
+}
+
+pub fn sample_peak_memory() {
+    PEAK_MEMORY.fetch_max(used_memory(), Ordering::Relaxed);
+}
+
+// Like Redis' bytesToHuman: 1023B, 1.50K, 12.00M.
+fn human(bytes: usize) -> String {
+    let mut value = bytes as f64;
+    for unit in ["B", "K", "M", "G", "T"] {
+        if value < 1024.0 {
+            return match unit {
+                "B" => format!("{}B", bytes),
+                unit => format!("{:.2}{}", value, unit),
+            };
+        }
+        value /= 1024.0;
+    }
+    format!("{:.2}P", value)
+}
+
+fn server() -> String {
+    let uptime = START.elapsed().as_secs();
+    let mode = if config::CONFIG.lock().unwrap().cluster_enabled {
+        "cluster"
+    } else {
+        "standalone"
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros());
+    [
+        "redis_version:7.4.0".to_string(),
+        format!("rustis_version:{}", env!("CARGO_PKG_VERSION")),
+        format!("redis_mode:{}", mode),
+        format!("os:{} {}", std::env::consts::OS, std::env::consts::ARCH),
+        format!("arch_bits:{}", usize::BITS),
+        format!("process_id:{}", std::process::id()),
+        format!("run_id:{}", *RUN_ID),
+        format!("tcp_port:{}", config::port()),
+        format!("server_time_usec:{}", now),
+        format!("uptime_in_seconds:{}", uptime),
+        format!("uptime_in_days:{}", uptime / 86400),
+    ]
+    .join("\r\n")
+}
+
+fn clients() -> String {
+    format!(
+        "connected_clients:{}",
+        CONNECTED_CLIENTS.load(Ordering::Relaxed)
+    )
+}
+
+fn memory() -> String {
+    sample_peak_memory();
+    let (used, peak) = (used_memory(), PEAK_MEMORY.load(Ordering::Relaxed));
+    [
+        format!("used_memory:{}", used),
+        format!("used_memory_human:{}", human(used)),
+        format!("used_memory_peak:{}", peak),
+        format!("used_memory_peak_human:{}", human(peak)),
+    ]
+    .join("\r\n")
+}
+
+fn persistence() -> String {
+    format!("loading:0\r\n{}\r\n{}", rdb::info(), aof::info())
+}
+
+fn stats() -> String {
+    let counter =
+        |name: &str, counter: &AtomicU64| format!("{}:{}", name, counter.load(Ordering::Relaxed));
+    [
+        counter("total_connections_received", &TOTAL_CONNECTIONS),
+        counter("total_commands_processed", &TOTAL_COMMANDS),
+        counter("keyspace_hits", &KEYSPACE_HITS),
+        counter("keyspace_misses", &KEYSPACE_MISSES),
+        counter("expired_keys", &EXPIRED_KEYS),
+    ]
+    .join("\r\n")
+}
+
+// Only databases holding keys are listed; rustis keys do not expire.
+fn keyspace() -> String {
+    match handlers::key_count() {
+        0 => String::new(),
+        keys => format!(
+            "db0:keys={},expires={},avg_ttl=0",
+            keys,
+            handlers::EXPIRES.lock().unwrap().len()
+        ),
+    }
+}
+
+// Renders a section's `field:value` lines.
+type Section = fn() -> String;
+
+// Sections in the order INFO lists them.
+const SECTIONS: [(&str, Section); 8] = [
+    ("server", server),
+    ("clients", clients),
+    ("memory", memory),
+    ("persistence", persistence),
+    ("stats", stats),
+    ("replication", replication::info),
+    ("cluster", cluster::info),
+    ("keyspace", keyspace),
+];
+
+// INFO [section ...]: every section by default, or only the named ones.
+pub fn info(args: Vec<Message>) -> Message {
+    let mut wanted = Vec::new();
+    for arg in &args {
+        let Bulk(name) = arg else {
+            return Message::error("ERR syntax error");
+        };
+        wanted.push(String::from_utf8_lossy(name).to_lowercase());
+    }
+    let all = wanted.is_empty()
+        || wanted
+            .iter()
+            .any(|name| matches!(name.as_str(), "default" | "all" | "everything"));
+    let text = SECTIONS
+        .iter()
+        .filter(|(name, _)| all || wanted.iter().any(|wanted| wanted == name))
+        .map(|(name, render)| {
+            let mut title = name.to_string();
+            title[..1].make_ascii_uppercase();
+            match render() {
+                lines if lines.is_empty() => format!("# {}\r\n", title),
+                lines => format!("# {}\r\n{}\r\n", title, lines),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n");
+    Message::bulk(text.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(msg: Message) -> String {
+        match msg {
+            Bulk(bytes) => String::from_utf8(bytes).unwrap(),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_info_sections() {
+        let all = text(info(vec![]));
+        assert!(all.starts_with("# Server\r\nredis_version:"));
+        for field in [
+            "\r\nuptime_in_seconds:",
+            "\r\n\r\n# Clients\r\nconnected_clients:",
+            "\r\nused_memory:",
+            "\r\naof_enabled:",
+            "\r\nrdb_last_bgsave_status:ok\r\n",
+            "\r\ntotal_commands_processed:",
+            "\r\nkeyspace_misses:",
+            "\r\n# Replication\r\nrole:master\r\n",
+            "\r\nmaster_repl_offset:",
+            "\r\n# Cluster\r\ncluster_enabled:0\r\n",
+            "\r\n# Keyspace\r\n",
+        ] {
+            assert!(all.contains(field), "{} missing from {}", field, all);
+        }
+        let replication = text(info(vec![Message::bulk(b"REPLICATION".to_vec())]));
+        assert!(replication.starts_with("# Replication\r\nrole:master\r\n"));
+        let two = text(info(vec![
+            Message::bulk(b"clients".to_vec()),
+            Message::bulk(b"Stats".to_vec()),
+        ]));
+        assert!(two.starts_with("# Clients\r\n"));
+        assert!(two.contains("\r\n\r\n# Stats\r\ntotal_connections_received:"));
+        assert_eq!(text(info(vec![Message::bulk(b"nosuch".to_vec())])), "");
+    }
+
+    #[test]
+    fn test_human() {
+        assert_eq!(human(1023), "1023B");
+        assert_eq!(human(1536), "1.50K");
+        assert_eq!(human(12 * 1024 * 1024), "12.00M");
+        assert_eq!(human(3 << 30), "3.00G");
+    }
+
+    #[test]
+    fn test_used_memory_follows_allocations() {
+        let before = used_memory();
+        let block = vec![0u8; 64 << 20];
+        assert!(used_memory() >= before + block.len() / 2);
+        sample_peak_memory();
+        assert!(PEAK_MEMORY.load(Ordering::Relaxed) >= block.len());
+    }
+}
@@ -0,0 +1,1563 @@
+use std::collections::HashMap;
+use std::slice;
+
+use crate::handlers::{parse_int, JsonMap};
+use crate::message::Message;
+use crate::message::Message::*;
+
+const MAX_DEPTH: usize = 128;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Object),
+}
+
+// Objects keep insertion order, like RedisJSON, and index their keys so
+// lookups and duplicate checks don't scan every field.
+#[derive(Clone, Debug, Default)]
+pub struct Object {
+    fields: Vec<(String, Json)>,
+    index: HashMap<String, usize>,
+}
+
+impl PartialEq for Object {
+    fn eq(&self, other: &Object) -> bool {
+        self.fields == other.fields
+    }
+}
+
+impl Object {
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, (String, Json)> {
+        self.fields.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Json> {
+        self.index.get(name).map(|&i| &self.fields[i].1)
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut Json> {
+        self.index.get(name).map(|&i| &mut self.fields[i].1)
+    }
+
+    // An existing field keeps its position and takes the new value.
+    pub fn insert(&mut self, name: String, value: Json) {
+        match self.index.get(&name) {
+            Some(&i) => self.fields[i].1 = value,
+            None => {
+                self.index.insert(name.clone(), self.fields.len());
+                self.fields.push((name, value));
+            }
+        }
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        let Some(i) = self.index.remove(name) else {
+            return false;
+        };
+        self.fields.remove(i);
+        for (name, _) in &self.fields[i..] {
+            *self.index.get_mut(name).unwrap() -= 1;
+        }
+        true
+    }
+}
+
+impl FromIterator<(String, Json)> for Object {
+    fn from_iter<I: IntoIterator<Item = (String, Json)>>(iter: I) -> Object {
+        let mut object = Object::default();
+        for (name, value) in iter {
+            object.insert(name, value);
+        }
+        object
+    }
+}
+
+impl Json {
+    pub fn parse(input: &[u8]) -> Result<Json, String> {
+        let mut parser = Parser { input, pos: 0 };
+        parser.skip_whitespace();
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.pos != input.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::Bool(_) => "boolean",
+            Json::Int(_) => "integer",
+            Json::Float(_) => "number",
+            Json::Str(_) => "string",
+            Json::Array(_) => "array",
+            Json::Object(_) => "object",
+        }
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out, &Format::default(), 0);
+        out
+    }
+
+    fn write(&self, out: &mut String, format: &Format, level: usize) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Int(i) => out.push_str(&i.to_string()),
+            Json::Float(f) => out.push_str(&format_float(*f)),
+            Json::Str(s) => write_string(out, s),
+            Json::Array(items) if items.is_empty() => out.push_str("[]"),
+            Json::Object(fields) if fields.is_empty() => out.push_str("{}"),
+            Json::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    format.break_line(out, level + 1);
+                    item.write(out, format, level + 1);
+                }
+                format.break_line(out, level);
+                out.push(']');
+            }
+            Json::Object(fields) => {
+                out.push('{');
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    format.break_line(out, level + 1);
+                    write_string(out, name);
+                    out.push(':');
+                    out.push_str(&format.space);
+                    value.write(out, format, level + 1);
+                }
+                format.break_line(out, level);
+                out.push('}');
+            }
+        }
+    }
+
+    fn field(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.get(name),
+            _ => None,
+        }
+    }
+
+    fn field_mut(&mut self, name: &str) -> Option<&mut Json> {
+        match self {
+            Json::Object(fields) => fields.get_mut(name),
+            _ => None,
+        }
+    }
+
+    // How many containers deep the value nests, 0 for a scalar.
+    fn depth(&self) -> usize {
+        let children = match self {
+            Json::Array(items) => items.iter().map(Json::depth).max(),
+            Json::Object(fields) => fields.iter().map(|(_, value)| value.depth()).max(),
+            _ => return 0,
+        };
+        children.map_or(0, |depth| depth + 1)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Format {
+    indent: String,
+    newline: String,
+    space: String,
+}
+
+impl Format {
+    fn break_line(&self, out: &mut String, level: usize) {
+        out.push_str(&self.newline);
+        for _ in 0..level {
+            out.push_str(&self.indent);
+        }
+    }
+}
+
+fn format_float(f: f64) -> String {
+    if f.fract() == 0.0 && f.abs() < 1e16 {
+        format!("{:.1}", f)
+    } else {
+        f.to_string()
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{08}' => out.push_str("\\b"),
+            '\u{0c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, reason: &str) -> String {
+        format!("{} at offset {}", reason, self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &[u8], value: Json) -> Result<Json, String> {
+        if self.input[self.pos..].starts_with(literal) {
+            self.pos += literal.len();
+            Ok(value)
+        } else {
+            Err(self.error("expected value"))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nesting too deep"));
+        }
+        match self.peek() {
+            Some(b'n') => self.expect(b"null", Json::Null),
+            Some(b't') => self.expect(b"true", Json::Bool(true)),
+            Some(b'f') => self.expect(b"false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::Str),
+            Some(b'[') => self.array(depth),
+            Some(b'{') => self.object(depth),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(self.error("expected value")),
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Json, String> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            self.skip_whitespace();
+            items.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Json, String> {
+        self.pos += 1;
+        let mut fields = Object::default();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected string key"));
+            }
+            let name = self.string()?;
+            self.skip_whitespace();
+            if self.peek() != Some(b':') {
+                return Err(self.error("expected ':'"));
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let value = self.value(depth + 1)?;
+            // Later duplicates win, as in most parsers.
+            fields.insert(name, value);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        let digits = |p: &mut Self| {
+            let from = p.pos;
+            while matches!(p.peek(), Some(b'0'..=b'9')) {
+                p.pos += 1;
+            }
+            p.pos - from
+        };
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        let leading_zero = self.peek() == Some(b'0');
+        let integer_digits = digits(self);
+        if integer_digits == 0 || (leading_zero && integer_digits > 1) {
+            return Err(self.error("invalid number"));
+        }
+        let mut float = false;
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            float = true;
+            if digits(self) == 0 {
+                return Err(self.error("invalid number"));
+            }
+        }
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            float = true;
+            if matches!(self.peek(), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if digits(self) == 0 {
+                return Err(self.error("invalid number"));
+            }
+        }
+        let text = str::from_utf8(&self.input[start..self.pos]).unwrap();
+        if !float {
+            if let Ok(i) = text.parse::<i64>() {
+                return Ok(Json::Int(i));
+            }
+        }
+        match text.parse::<f64>() {
+            Ok(f) if f.is_finite() => Ok(Json::Float(f)),
+            _ => Err(self.error("number out of range")),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let c = self
+                .peek()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.peek().ok_or_else(|| self.error("invalid escape"))?;
+                    self.pos += 1;
+                    let decoded = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{08}',
+                        b'f' => '\u{0c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut buf = [0u8; 4];
+                    bytes.extend_from_slice(decoded.encode_utf8(&mut buf).as_bytes());
+                }
+                c if c < 0x20 => return Err(self.error("control character in string")),
+                c => bytes.push(c),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("invalid UTF-8 in string"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let hex = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .and_then(|h| str::from_utf8(h).ok())
+            .and_then(|h| u32::from_str_radix(h, 16).ok())
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(hex)
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if !self.input[self.pos..].starts_with(b"\\u") {
+                return Err(self.error("unpaired surrogate"));
+            }
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Selector {
+    Name(String),
+    Index(i64),
+    Slice(Option<i64>, Option<i64>),
+    Wildcard,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Step {
+    recursive: bool,
+    selectors: Vec<Selector>,
+}
+
+// Legacy paths (".a.b", "a[0]") reply with a single value, while JSONPath
+// ("$.a[*]") replies with every match.
+#[derive(Clone, Debug, PartialEq)]
+struct Path {
+    text: String,
+    legacy: bool,
+    steps: Vec<Step>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Index(usize),
+    Field(String),
+}
+
+type Location = Vec<Key>;
+
+impl Path {
+    fn parse(text: &[u8]) -> Result<Path, Message> {
+        let text = str::from_utf8(text).map_err(|_| invalid_path())?;
+        let (legacy, rest) = match text.strip_prefix('$') {
+            Some(rest) => (false, rest.to_string()),
+            None if text == "." => (true, String::new()),
+            None if text.starts_with('.') || text.starts_with('[') => (true, text.to_string()),
+            None => (true, format!(".{}", text)),
+        };
+        let chars: Vec<char> = rest.chars().collect();
+        let mut steps = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let mut recursive = false;
+            match chars[i] {
+                '.' if chars.get(i + 1) == Some(&'.') => {
+                    recursive = true;
+                    i += 2;
+                }
+                '.' => i += 1,
+                '[' => {}
+                _ => return Err(invalid_path()),
+            }
+            if chars.get(i) == Some(&'[') {
+                let end = i + chars[i..]
+                    .iter()
+                    .position(|c| *c == ']')
+                    .ok_or_else(invalid_path)?;
+                let inner: String = chars[i + 1..end].iter().collect();
+                steps.push(Step {
+                    recursive,
+                    selectors: parse_bracket(&inner)?,
+                });
+                i = end + 1;
+            } else {
+                let end = chars[i..]
+                    .iter()
+                    .position(|c| *c == '.' || *c == '[')
+                    .map(|p| i + p)
+                    .unwrap_or(chars.len());
+                let name: String = chars[i..end].iter().collect();
+                let selector = match name.as_str() {
+                    "" => return Err(invalid_path()),
+                    "*" => Selector::Wildcard,
+                    _ => Selector::Name(name),
+                };
+                steps.push(Step {
+                    recursive,
+                    selectors: vec![selector],
+                });
+                i = end;
+            }
+        }
+        Ok(Path {
+            text: text.to_string(),
+            legacy,
+            steps,
+        })
+    }
+
+    fn is_root(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    fn resolve(&self, root: &Json) -> Vec<Location> {
+        resolve_steps(root, &self.steps)
+    }
+}
+
+fn parse_bracket(inner: &str) -> Result<Vec<Selector>, Message> {
+    // Quoted names may themselves contain commas, so split by hand.
+    let mut selectors = Vec::new();
+    let chars: Vec<char> = inner.trim().chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let selector = match chars[i] {
+            quote @ ('\'' | '"') => {
+                let end = i
+                    + 1
+                    + chars[i + 1..]
+                        .iter()
+                        .position(|c| *c == quote)
+                        .ok_or_else(invalid_path)?;
+                let name: String = chars[i + 1..end].iter().collect();
+                i = end + 1;
+                Selector::Name(name)
+            }
+            _ => {
+                let end = chars[i..]
+                    .iter()
+                    .position(|c| *c == ',')
+                    .map(|p| i + p)
+                    .unwrap_or(chars.len());
+                let token: String = chars[i..end].iter().collect::<String>();
+                i = end;
+                parse_index_selector(token.trim())?
+            }
+        };
+        selectors.push(selector);
+        while chars.get(i) == Some(&' ') {
+            i += 1;
+        }
+        match chars.get(i) {
+            Some(',') => i += 1,
+            None => {}
+            _ => return Err(invalid_path()),
+        }
+        while chars.get(i) == Some(&' ') {
+            i += 1;
+        }
+    }
+    if selectors.is_empty() {
+        return Err(invalid_path());
+    }
+    Ok(selectors)
+}
+
+fn parse_index_selector(token: &str) -> Result<Selector, Message> {
+    if token == "*" {
+        return Ok(Selector::Wildcard);
+    }
+    let bound = |s: &str| match s.trim() {
+        "" => Ok(None),
+        n => parse_int(n.as_bytes()).map(Some).ok_or_else(invalid_path),
+    };
+    match token.split_once(':') {
+        Some((start, end)) => Ok(Selector::Slice(bound(start)?, bound(end)?)),
+        None => parse_int(token.as_bytes())
+            .map(Selector::Index)
+            .ok_or_else(invalid_path),
+    }
+}
+
+fn resolve_steps(root: &Json, steps: &[Step]) -> Vec<Location> {
+    let mut current: Vec<Location> = vec![vec![]];
+    for step in steps {
+        let mut next = Vec::new();
+        for location in current {
+            let bases = if step.recursive {
+                let mut all = Vec::new();
+                descendants(get(root, &location).unwrap(), location, &mut all);
+                all
+            } else {
+                vec![location]
+            };
+            for base in bases {
+                let node = get(root, &base).unwrap();
+                for selector in &step.selectors {
+                    select(node, &base, selector, &mut next);
+                }
+            }
+        }
+        current = next;
+    }
+    current
+}
+
+fn descendants(node: &Json, location: Location, out: &mut Vec<Location>) {
+    out.push(location.clone());
+    match node {
+        Json::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                let mut child = location.clone();
+                child.push(Key::Index(i));
+                descendants(item, child, out);
+            }
+        }
+        Json::Object(fields) => {
+            for (name, value) in fields.iter() {
+                let mut child = location.clone();
+                child.push(Key::Field(name.clone()));
+                descendants(value, child, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn select(node: &Json, base: &Location, selector: &Selector, out: &mut Vec<Location>) {
+    let child = |key: Key| {
+        let mut location = base.clone();
+        location.push(key);
+        location
+    };
+    match (selector, node) {
+        (Selector::Name(name), Json::Object(_)) if node.field(name).is_some() => {
+            out.push(child(Key::Field(name.clone())));
+        }
+        (Selector::Index(i), Json::Array(items)) => {
+            let len = items.len() as i64;
+            let i = if *i < 0 { len + i } else { *i };
+            if (0..len).contains(&i) {
+                out.push(child(Key::Index(i as usize)));
+            }
+        }
+        (Selector::Slice(start, end), Json::Array(items)) => {
+            let len = items.len() as i64;
+            let clamp = |n: i64| if n < 0 { (len + n).max(0) } else { n.min(len) };
+            let start = clamp(start.unwrap_or(0));
+            let end = clamp(end.unwrap_or(len));
+            for i in start..end {
+                out.push(child(Key::Index(i as usize)));
+            }
+        }
+        (Selector::Wildcard, Json::Array(items)) => {
+            for i in 0..items.len() {
+                out.push(child(Key::Index(i)));
+            }
+        }
+        (Selector::Wildcard, Json::Object(fields)) => {
+            for (name, _) in fields.iter() {
+                out.push(child(Key::Field(name.clone())));
+            }
+        }
+        _ => {}
+    }
+}
+
+fn get<'a>(root: &'a Json, location: &[Key]) -> Option<&'a Json> {
+    location
+        .iter()
+        .try_fold(root, |node, key| match (key, node) {
+            (Key::Index(i), Json::Array(items)) => items.get(*i),
+            (Key::Field(name), _) => node.field(name),
+            _ => None,
+        })
+}
+
+fn get_mut<'a>(root: &'a mut Json, location: &[Key]) -> Option<&'a mut Json> {
+    location
+        .iter()
+        .try_fold(root, |node, key| match (key, node) {
+            (Key::Index(i), Json::Array(items)) => items.get_mut(*i),
+            (Key::Field(name), node) => node.field_mut(name),
+            _ => None,
+        })
+}
+
+fn remove(root: &mut Json, location: &[Key]) -> bool {
+    let (last, parent) = match location.split_last() {
+        Some(split) => split,
+        None => return false,
+    };
+    match (get_mut(root, parent), last) {
+        (Some(Json::Array(items)), Key::Index(i)) if *i < items.len() => {
+            items.remove(*i);
+            true
+        }
+        (Some(Json::Object(fields)), Key::Field(name)) => fields.remove(name),
+        _ => false,
+    }
+}
+
+fn invalid_path() -> Message {
+    Message::error("ERR invalid JSON path")
+}
+
+fn missing_path(path: &Path) -> Message {
+    Message::error(format!("ERR Path '{}' does not exist", path.text))
+}
+
+fn wrong_type(expected: &str, found: &Json) -> Message {
+    Message::error(format!(
+        "WRONGTYPE wrong type of path value - expected {} but found {}",
+        expected,
+        found.type_name()
+    ))
+}
+
+// Parsing bounds a document's depth, and a value stored `level` containers
+// down has to stay within the same bound.
+fn check_depth(level: usize, value_depth: usize) -> Result<(), Message> {
+    if level + value_depth > MAX_DEPTH {
+        return Err(Message::error("ERR nesting too deep"));
+    }
+    Ok(())
+}
+
+fn parse_value(arg: &Message) -> Result<Json, Message> {
+    match arg {
+        Bulk(bytes) => {
+            Json::parse(bytes).map_err(|err| Message::error(format!("ERR invalid JSON: {}", err)))
+        }
+        _ => Err(Message::error("ERR syntax error")),
+    }
+}
+
+fn parse_path_arg(arg: Option<&Message>) -> Result<Path, Message> {
+    match arg {
+        Some(Bulk(path)) => Path::parse(path),
+        Some(_) => Err(Message::error("ERR syntax error")),
+        None => Path::parse(b"."),
+    }
+}
+
+fn json_array_reply(values: Vec<Json>) -> Message {
+    Message::bulk(Json::Array(values).serialize().into_bytes())
+}
+
+pub fn json_set(args: Vec<Message>, jsons: &JsonMap) -> Message {
+    let (key, path, value, condition) = match args.as_slice() {
+        [Bulk(key), path, value, rest @ ..] if rest.len() <= 1 => (key, path, value, rest.first()),
+        _ => return Message::error("ERR wrong number of arguments for 'json.set' command"),
+    };
+    let (nx, xx) = match condition {
+        None => (false, false),
+        Some(Bulk(c)) if c.eq_ignore_ascii_case(b"NX") => (true, false),
+        Some(Bulk(c)) if c.eq_ignore_ascii_case(b"XX") => (false, true),
+        Some(_) => return Message::error("ERR syntax error"),
+    };
+    let path = match parse_path_arg(Some(path)) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    let value = match parse_value(value) {
+        Ok(value) => value,
+        Err(err) => return err,
+    };
+
+    let mut jsons = jsons.lock().unwrap();
+    let root = match jsons.get_mut(key) {
+        Some(root) => root,
+        None if !path.is_root() => {
+            return Message::error("ERR new objects must be created at the root")
+        }
+        None if xx => return Message::Null,
+        None => {
+            jsons.insert(key.clone(), value);
+            return Message::simple("OK");
+        }
+    };
+
+    let mut locations = path.resolve(root);
+    if path.legacy {
+        locations.truncate(1);
+    }
+    if !locations.is_empty() {
+        if nx {
+            return Message::Null;
+        }
+        let level = locations.iter().map(Vec::len).max().unwrap_or(0);
+        if let Err(err) = check_depth(level, value.depth()) {
+            return err;
+        }
+        for location in locations {
+            if let Some(target) = get_mut(root, &location) {
+                *target = value.clone();
+            }
+        }
+        return Message::simple("OK");
+    }
+    if xx {
+        return Message::Null;
+    }
+
+    // Only a missing member of an existing object can be created.
+    let (parent_steps, name) = match path.steps.split_last() {
+        Some((last, parent)) if !last.recursive => match last.selectors.as_slice() {
+            [Selector::Name(name)] => (parent, name),
+            _ => return Message::Null,
+        },
+        _ => return Message::Null,
+    };
+    let mut parents: Vec<Location> = resolve_steps(root, parent_steps)
+        .into_iter()
+        .filter(|location| matches!(get(root, location), Some(Json::Object(_))))
+        .collect();
+    if path.legacy {
+        parents.truncate(1);
+    }
+    if parents.is_empty() {
+        return if path.legacy {
+            missing_path(&path)
+        } else {
+            Message::Null
+        };
+    }
+    let level = parents.iter().map(Vec::len).max().unwrap_or(0) + 1;
+    if let Err(err) = check_depth(level, value.depth()) {
+        return err;
+    }
+    for parent in parents {
+        if let Some(Json::Object(fields)) = get_mut(root, &parent) {
+            fields.insert(name.clone(), value.clone());
+        }
+    }
+    Message::simple("OK")
+}
+
+pub fn json_get(args: Vec<Message>, jsons: &JsonMap) -> Message {
+    let (key, rest) = match args.as_slice() {
+        [Bulk(key), rest @ ..] => (key, rest),
+        _ => return Message::error("ERR wrong number of arguments for 'json.get' command"),
+    };
+    let mut format = Format::default();
+    let mut paths = Vec::new();
+    let mut i = 0;
+    while i < rest.len() {
+        let arg = match &rest[i] {
+            Bulk(arg) => arg,
+            _ => return Message::error("ERR syntax error"),
+        };
+        let option = match arg.to_ascii_uppercase().as_slice() {
+            b"INDENT" => Some(&mut format.indent),
+            b"NEWLINE" => Some(&mut format.newline),
+            b"SPACE" => Some(&mut format.space),
+            _ => None,
+        };
+        match (option, rest.get(i + 1)) {
+            (Some(target), Some(Bulk(value))) => {
+                *target = String::from_utf8_lossy(value).into_owned();
+                i += 2;
+            }
+            (Some(_), _) => return Message::error("ERR syntax error"),
+            (None, _) => {
+                match Path::parse(arg) {
+                    Ok(path) => paths.push(path),
+                    Err(err) => return err,
+                }
+                i += 1;
+            }
+        }
+    }
+    if paths.is_empty() {
+        paths.push(Path::parse(b".").unwrap());
+    }
+
+    let jsons = jsons.lock().unwrap();
+    let root = match jsons.get(key) {
+        Some(root) => root,
+        None => return Message::Null,
+    };
+    let legacy = paths.iter().all(|p| p.legacy);
+    let mut results = Vec::with_capacity(paths.len());
+    for path in &paths {
+        let matches: Vec<Json> = path
+            .resolve(root)
+            .iter()
+            .filter_map(|location| get(root, location).cloned())
+            .collect();
+        let result = if legacy {
+            match matches.into_iter().next() {
+                Some(value) => value,
+                None => return missing_path(path),
+            }
+        } else {
+            Json::Array(matches)
+        };
+        results.push((path.text.clone(), result));
+    }
+    let reply = if results.len() == 1 {
+        results.pop().unwrap().1
+    } else {
+        Json::Object(results.into_iter().collect())
+    };
+    let mut out = String::new();
+    reply.write(&mut out, &format, 0);
+    Message::bulk(out.into_bytes())
+}
+
+pub fn json_mget(args: Vec<Message>, jsons: &JsonMap) -> Message {
+    let (keys, path) = match args.as_slice() {
+        [keys @ .., path] if !keys.is_empty() => (keys, path),
+        _ => return Message::error("ERR wrong number of arguments for 'json.mget' command"),
+    };
+    let path = match parse_path_arg(Some(path)) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    let jsons = jsons.lock().unwrap();
+    let replies = keys
+        .iter()
+        .map(|key| {
+            let root = match key {
+                Bulk(key) => jsons.get(key),
+                _ => None,
+            };
+            let root = match root {
+                Some(root) => root,
+                None => return Message::Null,
+            };
+            let mut matches = path
+                .resolve(root)
+                .into_iter()
+                .filter_map(|location| get(root, &location).cloned());
+            if path.legacy {
+                match matches.next() {
+                    Some(value) => Message::bulk(value.serialize().into_bytes()),
+                    None => Message::Null,
+                }
+            } else {
+                json_array_reply(matches.collect())
+            }
+        })
+        .collect();
+    Message::array(replies)
+}
+
+pub fn json_del(args: Vec<Message>, jsons: &JsonMap) -> Message {
+    let (key, path) = match args.as_slice() {
+        [Bulk(key), rest @ ..] if rest.len() <= 1 => (key, rest.first()),
+        _ => return Message::error("ERR wrong number of arguments for 'json.del' command"),
+    };
+    let path = match parse_path_arg(path) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    let mut jsons = jsons.lock().unwrap();
+    if path.is_root() {
+        return Message::integer(jsons.remove(key).is_some() as i64);
+    }
+    let root = match jsons.get_mut(key) {
+        Some(root) => root,
+        None => return Message::integer(0),
+    };
+    let mut locations = path.resolve(root);
+    // Remove from the back so earlier array indexes stay valid.
+    locations.sort();
+    locations.dedup();
+    let deleted = locations
+        .iter()
+        .rev()
+        .filter(|location| remove(root, location))
+        .count();
+    Message::integer(deleted as i64)
+}
+
+pub fn json_type(args: Vec<Message>, jsons: &JsonMap) -> Message {
+    let (key, path) = match args.as_slice() {
+        [Bulk(key), rest @ ..] if rest.len() <= 1 => (key, rest.first()),
+        _ => return Message::error("ERR wrong number of arguments for 'json.type' command"),
+    };
+    let path = match parse_path_arg(path) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    let jsons = jsons.lock().unwrap();
+    let root = match jsons.get(key) {
+        Some(root) => root,
+        None => return Message::Null,
+    };
+    let types: Vec<Message> = path
+        .resolve(root)
+        .iter()
+        .filter_map(|location| get(root, location))
+        .map(|value| Message::bulk(value.type_name().as_bytes().to_vec()))
+        .collect();
+    if path.legacy {
+        types.into_iter().next().unwrap_or(Message::Null)
+    } else {
+        Message::array(types)
+    }
+}
+
+pub fn json_numincrby(args: Vec<Message>, jsons: &JsonMap) -> Message {
+    let (key, path, increment) = match args.as_slice() {
+        [Bulk(key), path, increment] => (key, path, increment),
+        _ => return Message::error("ERR wrong number of arguments for 'json.numincrby' command"),
+    };
+    let path = match parse_path_arg(Some(path)) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    let increment = match parse_value(increment) {
+        Ok(n @ (Json::Int(_) | Json::Float(_))) => n,
+        Ok(other) => return wrong_type("a number", &other),
+        Err(err) => return err,
+    };
+    let mut jsons = jsons.lock().unwrap();
+    let root = match jsons.get_mut(key) {
+        Some(root) => root,
+        None => {
+            return Message::error(
+                "ERR could not perform this operation on a key that doesn't exist",
+            )
+        }
+    };
+    let mut locations = path.resolve(root);
+    if path.legacy {
+        match locations.first().and_then(|l| get(root, l)) {
+            None => return missing_path(&path),
+            Some(Json::Int(_) | Json::Float(_)) => locations.truncate(1),
+            Some(other) => return wrong_type("a number", other),
+        }
+    }
+    // Every sum is checked before any is stored, so a failure leaves the
+    // document as it was, matching what the AOF and replicas see.
+    let mut results = Vec::with_capacity(locations.len());
+    for location in &locations {
+        let result = match (get(root, location), &increment) {
+            (Some(Json::Int(a)), Json::Int(b)) => match a.checked_add(*b) {
+                Some(sum) => Json::Int(sum),
+                None => Json::Float(*a as f64 + *b as f64),
+            },
+            (Some(Json::Int(a)), Json::Float(b)) => Json::Float(*a as f64 + b),
+            (Some(Json::Float(a)), Json::Int(b)) => Json::Float(a + *b as f64),
+            (Some(Json::Float(a)), Json::Float(b)) => Json::Float(a + b),
+            _ => Json::Null,
+        };
+        if matches!(result, Json::Float(f) if !f.is_finite()) {
+            return Message::error("ERR result is not a finite number");
+        }
+        results.push(result);
+    }
+    for (location, result) in locations.iter().zip(&results) {
+        if !matches!(result, Json::Null) {
+            *get_mut(root, location).unwrap() = result.clone();
+        }
+    }
+    if path.legacy {
+        Message::bulk(results[0].serialize().into_bytes())
+    } else {
+        json_array_reply(results)
+    }
+}
+
+pub fn json_arrappend(args: Vec<Message>, jsons: &JsonMap) -> Message {
+    let (key, path, values) = match args.as_slice() {
+        [Bulk(key), path, values @ ..] if !values.is_empty() => (key, path, values),
+        _ => return Message::error("ERR wrong number of arguments for 'json.arrappend' command"),
+    };
+    let path = match parse_path_arg(Some(path)) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    let values = match values
+        .iter()
+        .map(parse_value)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(values) => values,
+        Err(err) => return err,
+    };
+    let mut jsons = jsons.lock().unwrap();
+    let root = match jsons.get_mut(key) {
+        Some(root) => root,
+        None => {
+            return Message::error(
+                "ERR could not perform this operation on a key that doesn't exist",
+            )
+        }
+    };
+    let mut locations = path.resolve(root);
+    if path.legacy {
+        match locations.first().and_then(|l| get(root, l)) {
+            None => return missing_path(&path),
+            Some(Json::Array(_)) => locations.truncate(1),
+            Some(other) => return wrong_type("an array", other),
+        }
+    }
+    let level = locations
+        .iter()
+        .filter(|location| matches!(get(root, location), Some(Json::Array(_))))
+        .map(Vec::len)
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let value_depth = values.iter().map(Json::depth).max().unwrap_or(0);
+    if let Err(err) = check_depth(level, value_depth) {
+        return err;
+    }
+    let lengths: Vec<Message> = locations
+        .iter()
+        .map(|location| match get_mut(root, location) {
+            Some(Json::Array(items)) => {
+                items.extend(values.iter().cloned());
+                Message::integer(items.len() as i64)
+            }
+            _ => Message::Null,
+        })
+        .collect();
+    if path.legacy {
+        lengths.into_iter().next().unwrap()
+    } else {
+        Message::array(lengths)
+    }
+}
+
+pub fn json_arrlen(args: Vec<Message>, jsons: &JsonMap) -> Message {
+    let (key, path) = match args.as_slice() {
+        [Bulk(key), rest @ ..] if rest.len() <= 1 => (key, rest.first()),
+        _ => return Message::error("ERR wrong number of arguments for 'json.arrlen' command"),
+    };
+    inspect(key, path, jsons, "an array", |value| match value {
+        Json::Array(items) => Some(Message::integer(items.len() as i64)),
+        _ => None,
+    })
+}
+
+pub fn json_objkeys(args: Vec<Message>, jsons: &JsonMap) -> Message {
+    let (key, path) = match args.as_slice() {
+        [Bulk(key), rest @ ..] if rest.len() <= 1 => (key, rest.first()),
+        _ => return Message::error("ERR wrong number of arguments for 'json.objkeys' command"),
+    };
+    inspect(key, path, jsons, "an object", |value| match value {
+        Json::Object(fields) => Some(Message::array(
+            fields
+                .iter()
+                .map(|(name, _)| Message::bulk(name.as_bytes().to_vec()))
+                .collect(),
+        )),
+        _ => None,
+    })
+}
+
+// Shared shape of the read-only array/object inspection commands: one reply
+// per JSONPath match (nil where `f` does not apply) or the first legacy match.
+fn inspect<F>(key: &[u8], path: Option<&Message>, jsons: &JsonMap, expected: &str, f: F) -> Message
+where
+    F: Fn(&Json) -> Option<Message>,
+{
+    let path = match parse_path_arg(path) {
+        Ok(path) => path,
+        Err(err) => return err,
+    };
+    let jsons = jsons.lock().unwrap();
+    let root = match jsons.get(key) {
+        Some(root) => root,
+        None => return Message::Null,
+    };
+    let matches: Vec<&Json> = path
+        .resolve(root)
+        .iter()
+        .filter_map(|location| get(root, location))
+        .collect();
+    if path.legacy {
+        return match matches.first() {
+            None => missing_path(&path),
+            Some(value) => f(value).unwrap_or_else(|| wrong_type(expected, value)),
+        };
+    }
+    Message::array(
+        matches
+            .into_iter()
+            .map(|value| f(value).unwrap_or(Message::Null))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    fn bulk(s: &str) -> Message {
+        Message::bulk(s.as_bytes().to_vec())
+    }
+
+    fn store(doc: &str) -> JsonMap {
+        let jsons = Mutex::new(HashMap::new());
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$", doc]), &jsons),
+            Message::simple("OK")
+        );
+        jsons
+    }
+
+    #[test]
+    fn test_parse_and_serialize_round_trip() {
+        let text = r#"{"a":[1,-2.5,true,null],"b":{"c":"x\"y\né"},"d":1.0e3}"#;
+        let json = Json::parse(text.as_bytes()).unwrap();
+        assert_eq!(
+            json.serialize(),
+            "{\"a\":[1,-2.5,true,null],\"b\":{\"c\":\"x\\\"y\\né\"},\"d\":1000.0}"
+        );
+    }
+
+    #[test]
+    fn test_parse_surrogate_pair() {
+        let json = Json::parse(br#""\ud83d\ude00""#).unwrap();
+        assert_eq!(json, Json::Str("😀".to_string()));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Json::parse(b"{\"a\":}").is_err());
+        assert!(Json::parse(b"[1,]").is_err());
+        assert!(Json::parse(b"01").is_err());
+        assert!(Json::parse(b"\"abc").is_err());
+        assert!(Json::parse(b"1 2").is_err());
+        assert!(Json::parse(&[b'['; 200]).is_err());
+    }
+
+    #[test]
+    fn test_object_duplicates_and_removal() {
+        let doc: Vec<String> = (0..1000)
+            .map(|i| format!("\"k{}\":{}", i % 500, i))
+            .collect();
+        let jsons = store(&format!("{{{}}}", doc.join(",")));
+        assert_eq!(
+            json_get(Message::bulks(&["doc", ".k3"]), &jsons),
+            bulk("503")
+        );
+        assert_eq!(
+            json_del(Message::bulks(&["doc", "$.k1"]), &jsons),
+            Message::integer(1)
+        );
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$.k2", "true"]), &jsons),
+            Message::simple("OK")
+        );
+        let Bulk(serialized) = json_get(Message::bulks(&["doc"]), &jsons) else {
+            panic!("expected bulk");
+        };
+        assert!(serialized.starts_with(br#"{"k0":500,"k2":true,"k3":503,"#));
+        assert_eq!(
+            json_get(Message::bulks(&["doc", ".k499"]), &jsons),
+            bulk("999")
+        );
+    }
+
+    #[test]
+    fn test_set_checks_combined_depth() {
+        let arrays = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        let jsons = store(&format!(
+            "{}{{}}{}",
+            r#"{"a":"#.repeat(100),
+            "}".repeat(100)
+        ));
+        let deep = format!("${}", ".a".repeat(100));
+        for path in [deep.clone(), format!("{}.b", deep)] {
+            assert_eq!(
+                json_set(Message::bulks(&["doc", &path, &arrays(40)]), &jsons),
+                Message::error("ERR nesting too deep")
+            );
+        }
+        assert_eq!(
+            json_set(
+                Message::bulks(&["doc", &format!("{}.b", deep), "[]"]),
+                &jsons
+            ),
+            Message::simple("OK")
+        );
+        assert_eq!(
+            json_arrappend(
+                Message::bulks(&["doc", &format!("{}.b", deep), &arrays(40)]),
+                &jsons
+            ),
+            Message::error("ERR nesting too deep")
+        );
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$.b", &arrays(128)]), &jsons),
+            Message::simple("OK")
+        );
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$.c", &arrays(129)]), &jsons),
+            Message::error("ERR nesting too deep")
+        );
+    }
+
+    #[test]
+    fn test_path_parse() {
+        let path = Path::parse(b"$..a[0,'b c'][1:3].*").unwrap();
+        assert!(!path.legacy);
+        assert_eq!(path.steps.len(), 4);
+        assert!(path.steps[0].recursive);
+        assert_eq!(
+            path.steps[1].selectors,
+            vec![Selector::Index(0), Selector::Name("b c".to_string())]
+        );
+        assert_eq!(
+            path.steps[2].selectors,
+            vec![Selector::Slice(Some(1), Some(3))]
+        );
+        assert!(Path::parse(b"a.b").unwrap().legacy);
+        assert!(Path::parse(b".").unwrap().is_root());
+        assert!(Path::parse(b"$[").is_err());
+    }
+
+    #[test]
+    fn test_set_requires_root_for_new_key() {
+        let jsons = Mutex::new(HashMap::new());
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$.a", "1"]), &jsons),
+            Message::error("ERR new objects must be created at the root")
+        );
+    }
+
+    #[test]
+    fn test_set_and_get_paths() {
+        let jsons = store(r#"{"a":{"b":1},"c":[{"b":2},{"b":3}]}"#);
+        assert_eq!(
+            json_get(Message::bulks(&["doc", "$..b"]), &jsons),
+            bulk("[1,2,3]")
+        );
+        assert_eq!(
+            json_get(Message::bulks(&["doc", ".a.b"]), &jsons),
+            bulk("1")
+        );
+        assert_eq!(
+            json_get(Message::bulks(&["doc", "c[-1].b"]), &jsons),
+            bulk("3")
+        );
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$.c[*].b", "0"]), &jsons),
+            Message::simple("OK")
+        );
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$.a.new", "\"x\""]), &jsons),
+            Message::simple("OK")
+        );
+        assert_eq!(
+            json_get(Message::bulks(&["doc"]), &jsons),
+            bulk(r#"{"a":{"b":1,"new":"x"},"c":[{"b":0},{"b":0}]}"#)
+        );
+    }
+
+    #[test]
+    fn test_set_nx_xx() {
+        let jsons = store(r#"{"a":1}"#);
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$.a", "2", "NX"]), &jsons),
+            Message::Null
+        );
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$.b", "2", "XX"]), &jsons),
+            Message::Null
+        );
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$.a", "2", "XX"]), &jsons),
+            Message::simple("OK")
+        );
+        assert_eq!(
+            json_get(Message::bulks(&["doc"]), &jsons),
+            bulk(r#"{"a":2}"#)
+        );
+    }
+
+    #[test]
+    fn test_set_missing_legacy_parent() {
+        let jsons = store(r#"{"a":1}"#);
+        assert_eq!(
+            json_set(Message::bulks(&["doc", ".x.y", "1"]), &jsons),
+            Message::error("ERR Path '.x.y' does not exist")
+        );
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$.x.y", "1"]), &jsons),
+            Message::Null
+        );
+    }
+
+    #[test]
+    fn test_set_invalid_json() {
+        let jsons = Mutex::new(HashMap::new());
+        assert_eq!(
+            json_set(Message::bulks(&["doc", "$", "{"]), &jsons),
+            Message::error("ERR invalid JSON: expected string key at offset 1")
+        );
+    }
+
+    #[test]
+    fn test_get_formatting_and_multiple_paths() {
+        let jsons = store(r#"{"a":[1,2],"b":"x"}"#);
+        assert_eq!(
+            json_get(
+                Message::bulks(&["doc", "INDENT", "  ", "NEWLINE", "\n", "SPACE", " "]),
+                &jsons
+            ),
+            bulk("{\n  \"a\": [\n    1,\n    2\n  ],\n  \"b\": \"x\"\n}")
+        );
+        assert_eq!(
+            json_get(Message::bulks(&["doc", "$.a", "$.b"]), &jsons),
+            bulk(r#"{"$.a":[[1,2]],"$.b":["x"]}"#)
+        );
+        assert_eq!(
+            json_get(Message::bulks(&["doc", ".nope"]), &jsons),
+            Message::error("ERR Path '.nope' does not exist")
+        );
+        assert_eq!(
+            json_get(Message::bulks(&["missing"]), &jsons),
+            Message::Null
+        );
+    }
+
+    #[test]
+    fn test_del() {
+        let jsons = store(r#"{"a":[1,2,3,4],"b":{"c":1}}"#);
+        assert_eq!(
+            json_del(Message::bulks(&["doc", "$.a[0,2]"]), &jsons),
+            Message::integer(2)
+        );
+        assert_eq!(
+            json_del(Message::bulks(&["doc", "$.b.c"]), &jsons),
+            Message::integer(1)
+        );
+        assert_eq!(
+            json_get(Message::bulks(&["doc"]), &jsons),
+            bulk(r#"{"a":[2,4],"b":{}}"#)
+        );
+        assert_eq!(
+            json_del(Message::bulks(&["doc"]), &jsons),
+            Message::integer(1)
+        );
+        assert!(jsons.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_type() {
+        let jsons = store(r#"{"a":1,"b":1.5,"c":[],"d":null}"#);
+        assert_eq!(
+            json_type(Message::bulks(&["doc", "$.*"]), &jsons),
+            Message::array(vec![
+                bulk("integer"),
+                bulk("number"),
+                bulk("array"),
+                bulk("null")
+            ])
+        );
+        assert_eq!(json_type(Message::bulks(&["doc"]), &jsons), bulk("object"));
+    }
+
+    #[test]
+    fn test_numincrby() {
+        let jsons = store(r#"{"a":1,"b":{"a":"x"},"c":{"a":2.5}}"#);
+        assert_eq!(
+            json_numincrby(Message::bulks(&["doc", "$..a", "2"]), &jsons),
+            bulk("[3,null,4.5]")
+        );
+        assert_eq!(
+            json_numincrby(Message::bulks(&["doc", ".a", "0.5"]), &jsons),
+            bulk("3.5")
+        );
+        assert_eq!(
+            json_numincrby(Message::bulks(&["doc", ".b", "1"]), &jsons),
+            Message::error(
+                "WRONGTYPE wrong type of path value - expected a number but found object"
+            )
+        );
+
+        // The second match overflows, so the first must not change either.
+        let jsons = store(r#"{"a":1,"b":{"a":1.5e308}}"#);
+        let before = json_get(Message::bulks(&["doc"]), &jsons);
+        assert_eq!(
+            json_numincrby(Message::bulks(&["doc", "$..a", "1e308"]), &jsons),
+            Message::error("ERR result is not a finite number")
+        );
+        assert_eq!(json_get(Message::bulks(&["doc"]), &jsons), before);
+    }
+
+    #[test]
+    fn test_arrappend_and_arrlen() {
+        let jsons = store(r#"{"a":[1],"b":{"a":[]},"c":"x"}"#);
+        assert_eq!(
+            json_arrappend(Message::bulks(&["doc", "$..a", "2", "{\"x\":1}"]), &jsons),
+            Message::array(vec![Message::integer(3), Message::integer(2)])
+        );
+        assert_eq!(
+            json_arrlen(Message::bulks(&["doc", "$.*"]), &jsons),
+            Message::array(vec![Message::integer(3), Message::Null, Message::Null])
+        );
+        assert_eq!(
+            json_arrlen(Message::bulks(&["doc", ".c"]), &jsons),
+            Message::error(
+                "WRONGTYPE wrong type of path value - expected an array but found string"
+            )
+        );
+        assert_eq!(
+            json_get(Message::bulks(&["doc", ".a"]), &jsons),
+            bulk(r#"[1,2,{"x":1}]"#)
+        );
+    }
+
+    #[test]
+    fn test_objkeys() {
+        let jsons = store(r#"{"b":1,"a":{"x":1,"y":2}}"#);
+        assert_eq!(
+            json_objkeys(Message::bulks(&["doc"]), &jsons),
+            Message::array(vec![bulk("b"), bulk("a")])
+        );
+        assert_eq!(
+            json_objkeys(Message::bulks(&["doc", "$.*"]), &jsons),
+            Message::array(vec![
+                Message::Null,
+                Message::array(vec![bulk("x"), bulk("y")])
+            ])
+        );
+    }
+
+    #[test]
+    fn test_mget() {
+        let jsons = store(r#"{"a":1}"#);
+        json_set(Message::bulks(&["doc2", "$", r#"{"a":2}"#]), &jsons);
+        assert_eq!(
+            json_mget(Message::bulks(&["doc", "doc2", "missing", "$.a"]), &jsons),
+            Message::array(vec![bulk("[1]"), bulk("[2]"), Message::Null])
+        );
+        assert_eq!(
+            json_mget(Message::bulks(&["doc", "doc2", ".a"]), &jsons),
+            Message::array(vec![bulk("1"), bulk("2")])
+        );
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::handlers::HANDLERS;
+use crate::message::Message;
+use crate::message::Message::*;
+use crate::metrics::Histogram;
+
+// Samples kept for each event, and the most LATENCY GRAPH draws.
+const HISTORY_LEN: usize = 160;
+const GRAPH_COLUMNS: usize = 80;
+const GRAPH_ROWS: usize = 4;
+
+// Spikes of one event, at most one per second: a second spike in the same
+// second only raises that second's sample.
+#[derive(Default)]
+struct Series {
+    // (unix seconds, milliseconds), oldest first.
+    samples: VecDeque<(u64, u64)>,
+    max: u64,
+}
+
+impl Series {
+    fn add(&mut self, time: u64, ms: u64) {
+        self.max = self.max.max(ms);
+        match self.samples.back_mut() {
+            Some((last, latency)) if *last == time => *latency = (*latency).max(ms),
+            _ => {
+                if self.samples.len() == HISTORY_LEN {
+                    self.samples.pop_front();
+                }
+                self.samples.push_back((time, ms));
+            }
+        }
+    }
+}
+
+static EVENTS: Mutex<BTreeMap<&'static str, Series>> = Mutex::new(BTreeMap::new());
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Records `event` when it took at least latency-monitor-threshold
+// milliseconds. A threshold of 0 disables monitoring.
+pub fn sample(event: &'static str, elapsed: Duration) {
+    let threshold = config::latency_monitor_threshold();
+    let ms = elapsed.as_millis() as u64;
+    if threshold == 0 || ms < threshold {
+        return;
+    }
+    EVENTS
+        .lock()
+        .unwrap()
+        .entry(event)
+        .or_default()
+        .add(now(), ms);
+}
+
+// Seconds, minutes, hours or days ago, in as few characters as possible.
+fn age(seconds: u64) -> String {
+    match seconds {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86400 => format!("{}h", s / 3600),
+        s => format!("{}d", s / 86400),
+    }
+}
+
+// An ASCII chart of the samples, scaled between the lowest and highest, with
+// each column's age written vertically under it.
+fn graph(event: &str, series: &Series, now: u64) -> String {
+    let start = series.samples.len().saturating_sub(GRAPH_COLUMNS);
+    let samples: Vec<(u64, u64)> = series.samples.iter().skip(start).copied().collect();
+    let high = samples.iter().map(|&(_, ms)| ms).max().unwrap_or(0);
+    let low = samples.iter().map(|&(_, ms)| ms).min().unwrap_or(0);
+    let mut out = format!(
+        "{} - high {} ms, low {} ms (all time high {} ms)\n{}\n",
+        event,
+        high,
+        low,
+        series.max,
+        "-".repeat(GRAPH_COLUMNS)
+    );
+    // Each row holds two steps: '_' for the lower half, '#' or '|' when full.
+    let steps = (GRAPH_ROWS * 2) as u64;
+    let levels: Vec<u64> = samples
+        .iter()
+        .map(|&(_, ms)| match high - low {
+            0 => steps,
+            range => 1 + (ms - low) * (steps - 1) / range,
+        })
+        .collect();
+    for row in (0..GRAPH_ROWS as u64).rev() {
+        for &level in &levels {
+            out.push(match level.saturating_sub(row * 2) {
+                0 => ' ',
+                1 => '_',
+                2 => '#',
+                _ => '|',
+            });
+        }
+        out.push('\n');
+    }
+    out.push('\n');
+    let labels: Vec<Vec<u8>> = samples
+        .iter()
+        .map(|&(time, _)| age(now.saturating_sub(time)).into_bytes())
+        .collect();
+    let height = labels.iter().map(Vec::len).max().unwrap_or(0);
+    for row in 0..height {
+        for label in &labels {
+            out.push(label.get(row).map_or(' ', |&b| b as char));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// What each event usually means, for LATENCY DOCTOR.
+fn advice(event: &str) -> &'static str {
+    match event {
+        "aof-fsync" => {
+            "The disk is slow to fsync the AOF. Check for other processes doing I/O on \
+             the same disk, or consider 'appendfsync everysec' if it is 'always'."
+        }
+        "aof-write" => {
+            "Writes to the AOF are slow. The disk may be busy or the file system may be \
+             blocking on an fsync in progress."
+        }
+        "fork" => {
+            "Copying the keyspace for a snapshot or AOF rewrite is slow. This grows with \
+             the dataset, so consider saving less often."
+        }
+        "command" | "fast-command" => {
+            "Some commands are slow to run. Use SLOWLOG GET to find which ones, and \
+             LATENCY HISTOGRAM for how their run times spread."
+        }
+        _ => "No advice is available for this event.",
+    }
+}
+
+fn doctor(events: &BTreeMap<&'static str, Series>) -> String {
+    if config::latency_monitor_threshold() == 0 && events.is_empty() {
+        return "Latency monitoring is disabled. Start the server with \
+                --latency-monitor-threshold <milliseconds> to enable it.\n"
+            .to_string();
+    }
+    if events.is_empty() {
+        return "No latency spike was observed during the lifetime of this instance.\n".to_string();
+    }
+    let mut out = String::from("Latency spikes were observed for these events:\n\n");
+    for (i, (event, series)) in events.iter().enumerate() {
+        let count = series.samples.len() as u64;
+        let sum: u64 = series.samples.iter().map(|&(_, ms)| ms).sum();
+        let average = sum / count.max(1);
+        let deviation = series
+            .samples
+            .iter()
+            .map(|&(_, ms)| ms.abs_diff(average))
+            .sum::<u64>()
+            / count.max(1);
+        let period = match (series.samples.front(), series.samples.back()) {
+            (Some(first), Some(last)) if count > 1 => (last.0 - first.0) / (count - 1),
+            _ => 0,
+        };
+        _ = writeln!(
+            out,
+            "{}. {}: {} latency spikes (average {}ms, mean deviation {}ms, period {} sec). \
+             Worst all time event {}ms.",
+            i + 1,
+            event,
+            count,
+            average,
+            deviation,
+            period,
+            series.max
+        );
+    }
+    out.push_str("\nAdvice:\n\n");
+    for event in events.keys() {
+        _ = writeln!(out, "- {}: {}", event, advice(event));
+    }
+    out
+}
+
+// Redis' reply for one command: its calls, then the calls up to each
+// power of two microseconds, for the buckets that have any.
+fn histogram_reply(name: &str, histogram: &Histogram) -> Vec<Message> {
+    let mut buckets = Vec::new();
+    let mut previous = 0;
+    for (usec, count) in histogram.cumulative() {
+        if count > previous {
+            buckets.push(Message::integer(usec as i64));
+            buckets.push(Message::integer(count as i64));
+            previous = count;
+        }
+    }
+    vec![
+        Message::bulk(name.to_lowercase().into_bytes()),
+        Message::array(vec![
+            Message::bulk(b"calls".to_vec()),
+            Message::integer(histogram.count() as i64),
+            Message::bulk(b"histogram_usec".to_vec()),
+            Message::array(buckets),
+        ]),
+    ]
+}
+
+fn histogram(names: &[Vec<u8>]) -> Message {
+    let mut commands: Vec<_> = match names {
+        [] => HANDLERS.values().collect(),
+        names => names
+            .iter()
+            .filter_map(|name| {
+                let name = String::from_utf8_lossy(name).to_uppercase();
+                HANDLERS.get(name.as_str())
+            })
+            .collect(),
+    };
+    commands.sort_by_key(|command| command.name);
+    commands.dedup_by_key(|command| command.name);
+    Message::array(
+        commands
+            .into_iter()
+            .filter(|command| command.latency.count() > 0)
+            .flat_map(|command| histogram_reply(command.name, &command.latency))
+            .collect(),
+    )
+}
+
+pub fn latency(args: Vec<Message>) -> Message {
+    let mut parts = Vec::new();
+    for arg in args {
+        let Bulk(bytes) = arg else {
+            return Message::error("ERR syntax error");
+        };
+        parts.push(bytes);
+    }
+    let Some((subcommand, rest)) = parts.split_first() else {
+        return Message::error("ERR wrong number of arguments for 'latency' command");
+    };
+    let subcommand = String::from_utf8_lossy(subcommand).to_lowercase();
+    if subcommand == "histogram" {
+        return histogram(rest);
+    }
+    let mut events = EVENTS.lock().unwrap();
+    let event = |name: &[u8]| String::from_utf8_lossy(name).into_owned();
+    match (subcommand.as_str(), rest) {
+        ("latest", []) => Message::array(
+            events
+                .iter()
+                .filter_map(|(event, series)| {
+                    let &(time, ms) = series.samples.back()?;
+                    Some(Message::array(vec![
+                        Message::bulk(event.as_bytes().to_vec()),
+                        Message::integer(time as i64),
+                        Message::integer(ms as i64),
+                        Message::integer(series.max as i64),
+                    ]))
+                })
+                .collect(),
+        ),
+        ("history", [name]) => match events.get(event(name).as_str()) {
+            Some(series) => Message::array(
+                series
+                    .samples
+                    .iter()
+                    .map(|&(time, ms)| {
+                        Message::array(vec![
+                            Message::integer(time as i64),
+                            Message::integer(ms as i64),
+                        ])
+                    })
+                    .collect(),
+            ),
+            None => Message::array(vec![]),
+        },
+        ("reset", []) => {
+            let count = events.len();
+            events.clear();
+            Message::integer(count as i64)
+        }
+        ("reset", names) => {
+            let before = events.len();
+            for name in names {
+                events.remove(event(name).as_str());
+            }
+            Message::integer((before - events.len()) as i64)
+        }
+        ("graph", [name]) => match events.get(event(name).as_str()) {
+            Some(series) => Message::bulk(graph(&event(name), series, now()).into_bytes()),
+            None => Message::error(format!(
+                "ERR No samples available for event '{}'",
+                event(name)
+            )),
+        },
+        ("doctor", []) => Message::bulk(doctor(&events).into_bytes()),
+        _ => Message::error(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'. Try LATENCY HELP.",
+            subcommand
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_series_keeps_one_sample_per_second() {
+        let mut series = Series::default();
+        series.add(100, 5);
+        series.add(100, 20);
+        series.add(100, 7);
+        series.add(101, 3);
+        assert_eq!(series.samples, [(100, 20), (101, 3)]);
+        assert_eq!(series.max, 20);
+        for time in 0..HISTORY_LEN as u64 {
+            series.add(200 + time, 1);
+        }
+        assert_eq!(series.samples.len(), HISTORY_LEN);
+        assert_eq!(series.samples[0], (200, 1));
+        assert_eq!(series.max, 20);
+    }
+
+    #[test]
+    fn test_graph() {
+        let mut series = Series::default();
+        for (time, ms) in [(80, 10), (90, 40), (95, 100), (100, 100)] {
+            series.add(time, ms);
+        }
+        let expected = concat!(
+            "fork - high 100 ms, low 10 ms (all time high 100 ms)\n",
+            "--------------------------------------------------------------------------------\n",
+            "  ##\n",
+            "  ||\n",
+            " _||\n",
+            "_|||\n",
+            "\n",
+            "2150\n",
+            "00ss\n",
+            "ss  \n",
+        );
+        assert_eq!(graph("fork", &series, 100), expected);
+    }
+
+    #[test]
+    fn test_age() {
+        assert_eq!(age(0), "0s");
+        assert_eq!(age(59), "59s");
+        assert_eq!(age(600), "10m");
+        assert_eq!(age(7200), "2h");
+        assert_eq!(age(86400 * 3), "3d");
+    }
+
+    #[test]
+    fn test_histogram_reply() {
+        let histogram = Histogram::new();
+        for usec in [1, 1, 3, 100] {
+            histogram.record(Duration::from_micros(usec));
+        }
+        let int = |n| Message::integer(n);
+        assert_eq!(
+            histogram_reply("GET", &histogram),
+            vec![
+                Message::bulk(b"get".to_vec()),
+                Message::array(vec![
+                    Message::bulk(b"calls".to_vec()),
+                    int(4),
+                    Message::bulk(b"histogram_usec".to_vec()),
+                    Message::array(vec![int(1), int(2), int(4), int(3), int(128), int(4)]),
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_doctor() {
+        let mut events = BTreeMap::new();
+        let mut series = Series::default();
+        series.add(10, 30);
+        series.add(20, 10);
+        events.insert("aof-fsync", series);
+        let report = doctor(&events);
+        assert!(report.contains(
+            "1. aof-fsync: 2 latency spikes (average 20ms, mean deviation 10ms, \
+             period 10 sec). Worst all time event 30ms."
+        ));
+        assert!(report.contains("- aof-fsync: The disk is slow to fsync the AOF."));
+    }
+}
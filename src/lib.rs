@@ -0,0 +1,26 @@
+// The server, shared by the rustis binary and the tools next to it.
+
+pub mod aof;
+pub mod bitmap;
+pub mod cluster;
+pub mod command;
+pub mod config;
+pub mod expire;
+pub mod geo;
+pub mod handlers;
+pub mod hyperloglog;
+pub mod info;
+pub mod json;
+pub mod latency;
+pub mod list;
+pub mod manifest;
+pub mod message;
+pub mod metrics;
+pub mod monitor;
+pub mod rdb;
+pub mod replication;
+pub mod resp;
+pub mod set;
+pub mod slowlog;
+pub mod tcp_handler;
+pub mod zset;
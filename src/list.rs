@@ -0,0 +1,119 @@
+use std::collections::VecDeque;
+
+use crate::handlers::{parse_int, ListMap};
+use crate::message::Message;
+use crate::message::Message::*;
+
+pub fn rpush(args: Vec<Message>, lists: &ListMap) -> Message {
+    match args.as_slice() {
+        [Bulk(key), elements @ ..] if !elements.is_empty() => {
+            let mut pushed = Vec::new();
+            for element in elements {
+                match element {
+                    Bulk(element) => pushed.push(element.clone()),
+                    _ => return Message::error("ERR syntax error"),
+                }
+            }
+            let mut lists = lists.lock().unwrap();
+            let list = lists.entry(key.clone()).or_default();
+            list.extend(pushed);
+            Message::integer(list.len() as i64)
+        }
+        _ => Message::error("ERR wrong number of arguments for 'rpush' command"),
+    }
+}
+
+pub fn llen(args: Vec<Message>, lists: &ListMap) -> Message {
+    match args.as_slice() {
+        [Bulk(key)] => {
+            let lists = lists.lock().unwrap();
+            Message::integer(lists.get(key).map(VecDeque::len).unwrap_or(0) as i64)
+        }
+        _ => Message::error("ERR wrong number of arguments for 'llen' command"),
+    }
+}
+
+// Negative indexes count from the end, and out of range ones are clamped.
+pub fn lrange(args: Vec<Message>, lists: &ListMap) -> Message {
+    let [Bulk(key), Bulk(start), Bulk(stop)] = args.as_slice() else {
+        return Message::error("ERR wrong number of arguments for 'lrange' command");
+    };
+    let (Some(start), Some(stop)) = (parse_int(start), parse_int(stop)) else {
+        return Message::error("ERR value is not an integer or out of range");
+    };
+    let lists = lists.lock().unwrap();
+    let Some(list) = lists.get(key) else {
+        return Message::array(Vec::new());
+    };
+    let len = list.len() as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop {
+        return Message::array(Vec::new());
+    }
+    Message::array(
+        list.range(start as usize..=stop as usize)
+            .map(|element| Message::bulk(element.clone()))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_rpush_appends() {
+        let lists = Mutex::new(HashMap::new());
+        assert_eq!(
+            rpush(Message::bulks(&["l", "a", "b"]), &lists),
+            Message::integer(2)
+        );
+        assert_eq!(
+            rpush(Message::bulks(&["l", "c"]), &lists),
+            Message::integer(3)
+        );
+        assert_eq!(llen(Message::bulks(&["l"]), &lists), Message::integer(3));
+        assert_eq!(
+            llen(Message::bulks(&["nosuch"]), &lists),
+            Message::integer(0)
+        );
+    }
+
+    #[test]
+    fn test_lrange() {
+        let lists = Mutex::new(HashMap::new());
+        rpush(Message::bulks(&["l", "a", "b", "c", "d"]), &lists);
+        let cases: &[(&str, &str, &[&str])] = &[
+            ("0", "-1", &["a", "b", "c", "d"]),
+            ("1", "2", &["b", "c"]),
+            ("-2", "100", &["c", "d"]),
+            ("-100", "0", &["a"]),
+            ("3", "1", &[]),
+            ("5", "10", &[]),
+        ];
+        for (start, stop, expected) in cases {
+            assert_eq!(
+                lrange(Message::bulks(&["l", start, stop]), &lists),
+                Message::array(Message::bulks(expected)),
+                "LRANGE l {} {}",
+                start,
+                stop
+            );
+        }
+        assert_eq!(
+            lrange(Message::bulks(&["l", "x", "1"]), &lists),
+            Message::error("ERR value is not an integer or out of range")
+        );
+    }
+}
@@ -1,31 +1,71 @@
-use std::fs::File;
-use std::net::{TcpListener, TcpStream};
+use std::io::{Error, ErrorKind};
+use std::net::TcpListener;
+use std::path::Path;
+use std::thread::spawn;
 
-mod aof;
-mod handlers;
-mod message;
-mod resp;
-mod tcp_handler;
-
-use crate::aof::Aof;
-use crate::tcp_handler::{callback, handle_client};
+use rustis::aof::{self, Aof};
+use rustis::config::{Config, CONFIG};
+use rustis::tcp_handler::{callback, handle_client};
+use rustis::{cluster, expire, info, metrics, rdb, replication};
 
 fn main() -> std::io::Result<()> {
-    let file = match File::options()
-        .create(true)
-        .append(true)
-        .read(true)
-        .open("database.aof")
-    {
-        Ok(f) => f,
-        _ => panic!("Could not open or create file"),
-    };
-    let mut aof = Aof::new(file);
-    let _ = aof.read(callback);
-    let mut handler = |stream: TcpStream| handle_client(&mut aof, stream);
-    let listener = TcpListener::bind("127.0.0.1:6379")?;
+    let config = Config::from_args(std::env::args().skip(1))
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let (appenddirname, appendfilename) =
+        (config.appenddirname.clone(), config.appendfilename.clone());
+    let (appendonly, appendfsync) = (config.appendonly, config.appendfsync);
+    let (aof_timestamps, aof_load_truncated) =
+        (config.aof_timestamp_enabled, config.aof_load_truncated);
+    let dbfilename = config.dbfilename.clone();
+    let (port, replicaof) = (config.port, config.replicaof.clone());
+    let metrics_port = config.metrics_port;
+    let cluster_config_file = config
+        .cluster_enabled
+        .then(|| config.cluster_config_file.clone());
+    *CONFIG.lock().unwrap() = config;
+    info::init();
+
+    // Like Redis, the AOF is the source of truth when it is enabled.
+    let mut aof = None;
+    if appendonly {
+        let opened = Aof::open(appenddirname, &appendfilename, appendfsync, aof_timestamps)?;
+        aof::register(opened.clone());
+        if let Err(err) = opened.read(callback, aof_load_truncated) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        aof = Some(opened);
+    } else {
+        match rdb::load(Path::new(&dbfilename)) {
+            Ok(keys) => println!("DB loaded from disk: {keys} keys"),
+            Err(err) => {
+                eprintln!("Fatal error loading the DB: {err}");
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = cluster_config_file {
+        if let Err(err) = cluster::init(Path::new(&path)) {
+            eprintln!("Fatal error loading the cluster config {path}: {err}");
+            std::process::exit(1);
+        }
+        cluster::spawn_bus()?;
+    }
+    if metrics_port != 0 {
+        metrics::spawn_listener(metrics_port)?;
+    }
+    rdb::spawn_save_cron();
+    expire::spawn_expire_cron();
+    replication::spawn_replica_pings();
+    if let Some((host, port)) = replicaof {
+        replication::start_replication(host, port);
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
     for stream in listener.incoming() {
-        handler(stream?);
+        let stream = stream?;
+        let mut aof = aof.clone();
+        spawn(move || handle_client(aof.as_mut(), stream));
     }
     Ok(())
 }
@@ -0,0 +1,96 @@
+// One line of the manifest: a base or incremental file and its sequence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub seq: u64,
+}
+
+// Redis 7 style manifest listing the files to replay, base first.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Manifest {
+    pub base: Option<ManifestEntry>,
+    pub incrs: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    // Lines look like `file database.aof.1.incr.aof seq 1 type i`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut manifest = Manifest::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || format!("invalid AOF manifest line {}: '{}'", number + 1, line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if !words.len().is_multiple_of(2) {
+                return Err(invalid());
+            }
+            let field = |key: &str| {
+                words
+                    .chunks(2)
+                    .find(|pair| pair[0] == key)
+                    .map(|pair| pair[1])
+            };
+            let (Some(name), Some(seq), Some(kind)) = (field("file"), field("seq"), field("type"))
+            else {
+                return Err(invalid());
+            };
+            let entry = ManifestEntry {
+                name: name.to_string(),
+                seq: seq.parse().map_err(|_| invalid())?,
+            };
+            match kind {
+                "b" if manifest.base.is_none() => manifest.base = Some(entry),
+                "i" => manifest.incrs.push(entry),
+                // History files are only kept around for deletion.
+                "h" => {}
+                _ => return Err(invalid()),
+            }
+        }
+        manifest.incrs.sort_by_key(|entry| entry.seq);
+        Ok(manifest)
+    }
+
+    pub fn serialize(&self) -> String {
+        let mut text = String::new();
+        for (entry, kind) in self
+            .base
+            .iter()
+            .map(|e| (e, 'b'))
+            .chain(self.incrs.iter().map(|e| (e, 'i')))
+        {
+            text.push_str(&format!(
+                "file {} seq {} type {}\n",
+                entry.name, entry.seq, kind
+            ));
+        }
+        text
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.base.iter().chain(self.incrs.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let text = "file a.aof.2.base.aof seq 2 type b\n\
+                    file a.aof.4.incr.aof seq 4 type i\n\
+                    file a.aof.3.incr.aof seq 3 type i\n";
+        let manifest = Manifest::parse(text).unwrap();
+        assert_eq!(manifest.base.as_ref().unwrap().seq, 2);
+        assert_eq!(
+            manifest.incrs.iter().map(|e| e.seq).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert_eq!(Manifest::parse(&manifest.serialize()), Ok(manifest));
+        assert!(Manifest::parse("file a seq x type i").is_err());
+        assert!(Manifest::parse("file a seq 1 type z").is_err());
+        assert!(Manifest::parse("file a seq 1").is_err());
+    }
+}
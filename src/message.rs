@@ -2,10 +2,12 @@
 pub enum Message {
     Simple(String),
     Error(String),
-    // Number(u64),
+    Integer(i64),
     Bulk(Vec<u8>),
     Array(Vec<Message>),
     Null,
+    // A `#` line, which the AOF uses for `#TS:<unix seconds>` timestamps.
+    Annotation(String),
 }
 
 impl Message {
@@ -17,6 +19,10 @@ impl Message {
         Message::Error(s.into())
     }
 
+    pub fn integer(i: i64) -> Self {
+        Message::Integer(i)
+    }
+
     pub fn bulk(v: Vec<u8>) -> Self {
         Message::Bulk(v)
     }
@@ -25,13 +31,38 @@ impl Message {
         Message::Array(v)
     }
 
+    // An array of bulk strings, the way clients send commands.
+    pub fn command(parts: &[&str]) -> Self {
+        Message::array(
+            parts
+                .iter()
+                .map(|part| Message::bulk(part.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+
+    // The arguments of a command, as handlers get them.
+    #[cfg(test)]
+    pub fn bulks(parts: &[&str]) -> Vec<Self> {
+        parts
+            .iter()
+            .map(|part| Message::bulk(part.as_bytes().to_vec()))
+            .collect()
+    }
+
+    pub fn timestamp(secs: u64) -> Self {
+        Message::Annotation(format!("TS:{}", secs))
+    }
+
     pub fn marshal(&self) -> Vec<u8> {
         match self {
             array @ Message::Array(_) => array.marshal_array(),
             bulk @ Message::Bulk(_) => bulk.marshal_bulk(),
             string @ Message::Simple(_) => string.marshal_string(),
             error @ Message::Error(_) => error.marshal_error(),
+            integer @ Message::Integer(_) => integer.marshal_integer(),
             null @ Message::Null => null.marshal_null(),
+            Message::Annotation(text) => format!("#{}\r\n", text).into_bytes(),
             // _ => Vec::new(),
         }
     }
@@ -82,6 +113,16 @@ impl Message {
         bytes
     }
 
+    fn marshal_integer(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(b':');
+        if let Message::Integer(i) = self {
+            bytes.extend_from_slice(i.to_string().as_bytes());
+        }
+        bytes.extend_from_slice(b"\r\n");
+        bytes
+    }
+
     fn marshal_null(&self) -> Vec<u8> {
         b"$-1\r\n".to_vec()
     }
@@ -109,6 +150,18 @@ mod tests {
         assert_eq!(msg.marshal(), b"$6\r\nfoobar\r\n");
     }
 
+    #[test]
+    fn test_marshal_integer() {
+        let msg = Message::integer(-42);
+        assert_eq!(msg.marshal(), b":-42\r\n");
+    }
+
+    #[test]
+    fn test_timestamp_annotation() {
+        let msg = Message::timestamp(1628217470);
+        assert_eq!(msg.marshal(), b"#TS:1628217470\r\n");
+    }
+
     #[test]
     fn test_marshal_null() {
         let msg = Message::Null;
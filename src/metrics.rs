@@ -0,0 +1,248 @@
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::spawn;
+use std::time::Duration;
+
+use crate::aof;
+use crate::handlers::{self, HANDLERS};
+use crate::info;
+
+const BUCKETS: usize = 25;
+
+// Latencies in power of two microsecond buckets: bucket i counts those up
+// to 2^i µs. Slower ones are only in the count, which is the +Inf bucket.
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_usec: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            count: AtomicU64::new(0),
+            sum_usec: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let usec = elapsed.as_micros() as u64;
+        let bucket = match usec {
+            0 | 1 => 0,
+            usec => (u64::BITS - (usec - 1).leading_zeros()) as usize,
+        };
+        if let Some(bucket) = self.buckets.get(bucket) {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_usec.fetch_add(usec, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum_usec(&self) -> u64 {
+        self.sum_usec.load(Ordering::Relaxed)
+    }
+
+    // (upper bound in µs, calls up to it) for each bucket.
+    pub fn cumulative(&self) -> Vec<(u64, u64)> {
+        let mut total = 0;
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, bucket)| {
+                total += bucket.load(Ordering::Relaxed);
+                (1 << i, total)
+            })
+            .collect()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A histogram in the text exposition format, with the bounds in seconds.
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    let sep = if labels.is_empty() { "" } else { "," };
+    for (usec, count) in histogram.cumulative() {
+        let le = usec as f64 / 1e6;
+        _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{le}\"}} {count}");
+    }
+    let count = histogram.count();
+    _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {count}");
+    let sum = histogram.sum_usec() as f64 / 1e6;
+    let braces = |labels: &str| match labels {
+        "" => String::new(),
+        labels => format!("{{{labels}}}"),
+    };
+    _ = writeln!(out, "{name}_sum{} {sum}", braces(labels));
+    _ = writeln!(out, "{name}_count{} {count}", braces(labels));
+}
+
+// Everything /metrics reports.
+pub fn render() -> String {
+    let mut out = String::new();
+    let mut commands: Vec<_> = HANDLERS
+        .values()
+        .filter(|command| command.latency.count() > 0)
+        .collect();
+    commands.sort_by_key(|command| command.name);
+
+    out.push_str("# HELP rustis_commands_total Calls of each command.\n");
+    out.push_str("# TYPE rustis_commands_total counter\n");
+    for command in &commands {
+        let cmd = command.name.to_lowercase();
+        _ = writeln!(
+            out,
+            "rustis_commands_total{{cmd=\"{}\"}} {}",
+            cmd,
+            command.latency.count()
+        );
+    }
+    out.push_str("# HELP rustis_command_duration_seconds Time spent running each command.\n");
+    out.push_str("# TYPE rustis_command_duration_seconds histogram\n");
+    for command in &commands {
+        let labels = format!("cmd=\"{}\"", command.name.to_lowercase());
+        write_histogram(
+            &mut out,
+            "rustis_command_duration_seconds",
+            &labels,
+            &command.latency,
+        );
+    }
+
+    out.push_str("# HELP rustis_connected_clients Client connections open.\n");
+    out.push_str("# TYPE rustis_connected_clients gauge\n");
+    _ = writeln!(
+        out,
+        "rustis_connected_clients {}",
+        info::CONNECTED_CLIENTS.load(Ordering::Relaxed)
+    );
+    out.push_str("# HELP rustis_memory_used_bytes Bytes allocated by the server.\n");
+    out.push_str("# TYPE rustis_memory_used_bytes gauge\n");
+    _ = writeln!(out, "rustis_memory_used_bytes {}", info::used_memory());
+    out.push_str("# HELP rustis_db_keys Keys in each database.\n");
+    out.push_str("# TYPE rustis_db_keys gauge\n");
+    _ = writeln!(
+        out,
+        "rustis_db_keys{{db=\"db0\"}} {}",
+        handlers::key_count()
+    );
+
+    if let Some(aof) = aof::registered() {
+        out.push_str("# HELP rustis_aof_size_bytes Size of the append only file.\n");
+        out.push_str("# TYPE rustis_aof_size_bytes gauge\n");
+        _ = writeln!(out, "rustis_aof_size_bytes {}", aof.size());
+    }
+    out.push_str("# HELP rustis_aof_fsync_duration_seconds Time spent in each AOF fsync.\n");
+    out.push_str("# TYPE rustis_aof_fsync_duration_seconds histogram\n");
+    write_histogram(
+        &mut out,
+        "rustis_aof_fsync_duration_seconds",
+        "",
+        &aof::FSYNC_LATENCY,
+    );
+    out
+}
+
+// Serves GET /metrics on `port`, one connection at a time.
+pub fn spawn_listener(port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(err) = serve(stream) {
+                println!("metrics request failed: {err}");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers are not needed, but are read so the client sees the
+    // whole request consumed.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = Histogram::new();
+        for usec in [0, 1, 2, 3, 900, 1 << 30] {
+            histogram.record(Duration::from_micros(usec));
+        }
+        let cumulative = histogram.cumulative();
+        assert_eq!(cumulative[0], (1, 2));
+        assert_eq!(cumulative[1], (2, 3));
+        assert_eq!(cumulative[2], (4, 4));
+        assert_eq!(cumulative[9], (512, 4));
+        assert_eq!(cumulative[10], (1024, 5));
+        assert_eq!(cumulative[BUCKETS - 1], (1 << 24, 5));
+        assert_eq!(histogram.count(), 6);
+        assert_eq!(histogram.sum_usec(), 906 + (1 << 30));
+    }
+
+    #[test]
+    fn test_write_histogram() {
+        let histogram = Histogram::new();
+        histogram.record(Duration::from_micros(3));
+        let mut out = String::new();
+        write_histogram(&mut out, "h", "cmd=\"get\"", &histogram);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "h_bucket{cmd=\"get\",le=\"0.000001\"} 0");
+        assert_eq!(lines[2], "h_bucket{cmd=\"get\",le=\"0.000004\"} 1");
+        assert_eq!(lines[BUCKETS], "h_bucket{cmd=\"get\",le=\"+Inf\"} 1");
+        assert_eq!(lines[BUCKETS + 1], "h_sum{cmd=\"get\"} 0.000003");
+        assert_eq!(lines[BUCKETS + 2], "h_count{cmd=\"get\"} 1");
+
+        let mut out = String::new();
+        write_histogram(&mut out, "h", "", &histogram);
+        assert!(out.starts_with("h_bucket{le=\"0.000001\"} 0\n"));
+        assert!(out.ends_with("h_sum 0.000003\nh_count 1\n"));
+    }
+
+    #[test]
+    fn test_render() {
+        HANDLERS["PING"].latency.record(Duration::from_micros(5));
+        let text = render();
+        assert!(text.contains("\nrustis_commands_total{cmd=\"ping\"} "));
+        assert!(
+            text.contains("\nrustis_command_duration_seconds_bucket{cmd=\"ping\",le=\"+Inf\"} ")
+        );
+        assert!(text.contains("\n# TYPE rustis_connected_clients gauge\nrustis_connected_clients "));
+        assert!(text.contains("\nrustis_memory_used_bytes "));
+        assert!(text.contains("\nrustis_db_keys{db=\"db0\"} "));
+        assert!(text.contains("\nrustis_aof_fsync_duration_seconds_count "));
+    }
+}
@@ -0,0 +1,241 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::spawn;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::message::Message;
+use crate::message::Message::*;
+use crate::replication::ReplicaStream;
+
+type Monitor = (u64, SyncSender<Arc<Vec<u8>>>);
+
+// Connections that ran MONITOR, each fed by its own writer thread so a slow
+// one cannot hold up commands.
+static MONITORS: Mutex<Vec<Monitor>> = Mutex::new(Vec::new());
+// MONITORS.len(), read without the lock so commands skip formatting while
+// nobody is watching.
+static ATTACHED: AtomicUsize = AtomicUsize::new(0);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+// Lines a monitor may fall behind by before it is disconnected, as Redis
+// does once a client's output buffer grows past its limit.
+const MAX_PENDING: usize = 10_000;
+
+// Streams every command fed from now on to `stream`, until it fails or
+// `detach` is called with the returned id.
+pub fn attach(mut stream: Box<dyn ReplicaStream>) -> u64 {
+    let (sender, receiver) = sync_channel::<Arc<Vec<u8>>>(MAX_PENDING);
+    let id = register(sender);
+    spawn(move || {
+        for line in receiver {
+            if stream.write_all(&line).is_err() {
+                break;
+            }
+        }
+        stream.close();
+        detach(id);
+    });
+    id
+}
+
+fn register(sender: SyncSender<Arc<Vec<u8>>>) -> u64 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut monitors = MONITORS.lock().unwrap();
+    monitors.push((id, sender));
+    ATTACHED.store(monitors.len(), Ordering::Relaxed);
+    id
+}
+
+pub fn detach(id: u64) {
+    let mut monitors = MONITORS.lock().unwrap();
+    monitors.retain(|(monitor, _)| *monitor != id);
+    ATTACHED.store(monitors.len(), Ordering::Relaxed);
+}
+
+// Sends `argv`, run by the client at `addr`, to every monitor. One that
+// is MAX_PENDING lines behind is dropped, which ends its stream once the
+// writer catches up.
+pub fn feed(argv: &[Message], addr: &str) {
+    if ATTACHED.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let line = Arc::new(format_line(now.as_secs(), now.subsec_micros(), addr, argv).into_bytes());
+    let mut monitors = MONITORS.lock().unwrap();
+    monitors.retain(|(_, sender)| sender.try_send(line.clone()).is_ok());
+    ATTACHED.store(monitors.len(), Ordering::Relaxed);
+}
+
+// `+1339518083.107412 [0 127.0.0.1:60866] "SET" "k" "v"`, like Redis.
+fn format_line(secs: u64, micros: u32, addr: &str, argv: &[Message]) -> String {
+    let mut line = format!("+{}.{:06} [0 {}]", secs, micros, addr);
+    for arg in redacted(argv) {
+        line.push(' ');
+        line.push_str(&repr(arg));
+    }
+    line.push_str("\r\n");
+    line
+}
+
+// The arguments with passwords replaced, the way Redis hides them from
+// MONITOR.
+fn redacted(argv: &[Message]) -> Vec<&[u8]> {
+    let mut args: Vec<&[u8]> = argv
+        .iter()
+        .map(|arg| match arg {
+            Bulk(bytes) => bytes.as_slice(),
+            _ => b"",
+        })
+        .collect();
+    let Some(name) = args.first().map(|name| name.to_ascii_uppercase()) else {
+        return args;
+    };
+    let secret = |args: &mut [&[u8]], from: usize, count: usize| {
+        for arg in args.iter_mut().skip(from).take(count) {
+            *arg = b"(redacted)";
+        }
+    };
+    match name.as_slice() {
+        b"AUTH" => secret(&mut args, 1, usize::MAX),
+        // Options follow the protocol version, and MIGRATE's five positional
+        // arguments.
+        b"HELLO" | b"MIGRATE" => {
+            let mut i = if name == b"HELLO" { 2 } else { 6 };
+            while i < args.len() {
+                let count = match args[i].to_ascii_uppercase().as_slice() {
+                    b"AUTH" if name == b"HELLO" => 2,
+                    b"AUTH" => 1,
+                    b"AUTH2" => 2,
+                    _ => 0,
+                };
+                secret(&mut args, i + 1, count);
+                i += count + 1;
+            }
+        }
+        _ => {}
+    }
+    args
+}
+
+// A quoted string with non printable bytes escaped, as redis-cli prints it.
+fn repr(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            7 => out.push_str("\\a"),
+            8 => out.push_str("\\b"),
+            b' '..=b'~' => out.push(b as char),
+            b => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        let argv = vec![
+            Message::bulk(b"SET".to_vec()),
+            Message::bulk(b"k".to_vec()),
+            Message::bulk(b"a \"b\"\r\n\x00\xff".to_vec()),
+        ];
+        assert_eq!(
+            format_line(1339518083, 107412, "127.0.0.1:60866", &argv),
+            "+1339518083.107412 [0 127.0.0.1:60866] \"SET\" \"k\" \"a \\\"b\\\"\\r\\n\\x00\\xff\"\r\n"
+        );
+        assert_eq!(
+            format_line(1, 5, "a:1", &Message::bulks(&["PING"])),
+            "+1.000005 [0 a:1] \"PING\"\r\n"
+        );
+    }
+
+    #[test]
+    fn test_slow_monitor_is_dropped() {
+        // Keeps commands run by other tests out of the count.
+        let _execution = crate::handlers::EXECUTION.lock().unwrap();
+        let (sender, receiver) = sync_channel(MAX_PENDING);
+        let id = register(sender);
+        let attached = |id| MONITORS.lock().unwrap().iter().any(|m| m.0 == id);
+        for _ in 0..MAX_PENDING {
+            feed(&Message::bulks(&["PING"]), "a:1");
+        }
+        assert!(attached(id));
+        feed(&Message::bulks(&["PING"]), "a:1");
+        assert!(!attached(id));
+        assert_eq!(receiver.try_iter().count(), MAX_PENDING);
+    }
+
+    #[test]
+    fn test_redacted() {
+        let cases: &[(&[&str], &[&str])] = &[
+            (&["auth", "pw"], &["auth", "(redacted)"]),
+            (
+                &["AUTH", "user", "pw"],
+                &["AUTH", "(redacted)", "(redacted)"],
+            ),
+            (
+                &["HELLO", "3", "AUTH", "user", "pw", "SETNAME", "app"],
+                &[
+                    "HELLO",
+                    "3",
+                    "AUTH",
+                    "(redacted)",
+                    "(redacted)",
+                    "SETNAME",
+                    "app",
+                ],
+            ),
+            (
+                &[
+                    "MIGRATE", "h", "1", "k", "0", "5", "AUTH", "pw", "KEYS", "a",
+                ],
+                &[
+                    "MIGRATE",
+                    "h",
+                    "1",
+                    "k",
+                    "0",
+                    "5",
+                    "AUTH",
+                    "(redacted)",
+                    "KEYS",
+                    "a",
+                ],
+            ),
+            (
+                &["MIGRATE", "h", "1", "", "0", "5", "AUTH2", "u", "pw"],
+                &[
+                    "MIGRATE",
+                    "h",
+                    "1",
+                    "",
+                    "0",
+                    "5",
+                    "AUTH2",
+                    "(redacted)",
+                    "(redacted)",
+                ],
+            ),
+            (&["SET", "auth", "pw"], &["SET", "auth", "pw"]),
+            (
+                &["MIGRATE", "h", "1", "AUTH", "0", "5"],
+                &["MIGRATE", "h", "1", "AUTH", "0", "5"],
+            ),
+        ];
+        for (argv, expected) in cases {
+            let expected: Vec<&[u8]> = expected.iter().map(|a| a.as_bytes()).collect();
+            assert_eq!(redacted(&Message::bulks(argv)), expected, "{:?}", argv);
+        }
+    }
+}
@@ -0,0 +1,1585 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::expire::now_ms;
+use crate::handlers::{self, parse_int, Snapshot, EXECUTION};
+use crate::json::Json;
+use crate::message::Message;
+use crate::message::Message::*;
+use crate::resp::FIRST_READ;
+use crate::zset::SortedSet;
+
+// Snapshots are written as RDB version 9, which Redis 5.0 and later load.
+// Reading goes up to version 12, written by Redis 7.4.
+const RDB_VERSION: u32 = 9;
+const RDB_MAX_READ_VERSION: u32 = 12;
+
+// Value types and opcodes, numbered as in Redis' rdb.h.
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+const OPCODE_SLOT_INFO: u8 = 0xf4;
+const OPCODE_FUNCTION2: u8 = 0xf5;
+const OPCODE_MODULE_AUX: u8 = 0xf7;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+// Special string encodings flagged by the top two bits of a length.
+const ENC_INT8: u64 = 0;
+const ENC_INT16: u64 = 1;
+const ENC_INT32: u64 = 2;
+const ENC_LZF: u64 = 3;
+
+// Module values are a sequence of typed fields ending in EOF.
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_SINT: u64 = 1;
+const MODULE_OPCODE_UINT: u64 = 2;
+const MODULE_OPCODE_FLOAT: u64 = 3;
+const MODULE_OPCODE_DOUBLE: u64 = 4;
+const MODULE_OPCODE_STRING: u64 = 5;
+
+// Quicklist 2 nodes hold either one large element or a listpack.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+// JSON values are stored like RedisJSON does: type "ReJSON-RL", encoding
+// version 3, holding the serialized document as one string.
+const JSON_MODULE_ID: u64 = module_id(b"ReJSON-RL", 3);
+
+// Unix time of the last successful save, and writes since then.
+pub static LAST_SAVE: AtomicU64 = AtomicU64::new(0);
+pub static DIRTY: AtomicU64 = AtomicU64::new(0);
+static BGSAVE_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+static LAST_BGSAVE_OK: AtomicBool = AtomicBool::new(true);
+
+// After a failed background save the next automatic one waits this long.
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+// Module type ids pack the 9 character name, 6 bits per character, above
+// a 10 bit encoding version.
+const fn module_id(name: &[u8; 9], encver: u64) -> u64 {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut id = 0;
+    let mut i = 0;
+    while i < name.len() {
+        let mut index = 0;
+        while CHARSET[index] != name[i] {
+            index += 1;
+        }
+        id = (id << 6) | index as u64;
+        i += 1;
+    }
+    (id << 10) | encver
+}
+
+// CRC-64/Jones, reflected, as used by Redis for the RDB checksum.
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x95ac9329ac4bc9b5
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc64(crc: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(crc, |crc, &b| {
+        CRC64_TABLE[((crc ^ b as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+struct RdbWriter<W> {
+    out: W,
+    crc: u64,
+}
+
+impl<W: Write> RdbWriter<W> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        self.crc = crc64(self.crc, bytes);
+        self.out.write_all(bytes)
+    }
+
+    fn byte(&mut self, b: u8) -> Result<(), io::Error> {
+        self.bytes(&[b])
+    }
+
+    fn len(&mut self, len: u64) -> Result<(), io::Error> {
+        match len {
+            0..0x40 => self.byte(len as u8),
+            0x40..0x4000 => self.bytes(&[0x40 | (len >> 8) as u8, len as u8]),
+            0x4000..=0xffff_ffff => {
+                self.byte(0x80)?;
+                self.bytes(&(len as u32).to_be_bytes())
+            }
+            _ => {
+                self.byte(0x81)?;
+                self.bytes(&len.to_be_bytes())
+            }
+        }
+    }
+
+    // Integers that fit 32 bits and print back the same are stored as
+    // integers, everything else as length-prefixed bytes.
+    fn string(&mut self, s: &[u8]) -> Result<(), io::Error> {
+        let int = std::str::from_utf8(s)
+            .ok()
+            .filter(|text| text.len() <= 11)
+            .and_then(|text| text.parse::<i32>().ok().filter(|i| i.to_string() == text));
+        match int {
+            Some(i) if i8::try_from(i).is_ok() => self.bytes(&[0xc0, i as i8 as u8]),
+            Some(i) if i16::try_from(i).is_ok() => {
+                self.byte(0xc1)?;
+                self.bytes(&(i as i16).to_le_bytes())
+            }
+            Some(i) => {
+                self.byte(0xc2)?;
+                self.bytes(&i.to_le_bytes())
+            }
+            None => {
+                self.len(s.len() as u64)?;
+                self.bytes(s)
+            }
+        }
+    }
+
+    fn aux(&mut self, key: &str, value: &str) -> Result<(), io::Error> {
+        self.byte(OPCODE_AUX)?;
+        self.string(key.as_bytes())?;
+        self.string(value.as_bytes())
+    }
+
+    fn hash(&mut self, fields: &HashMap<Vec<u8>, Vec<u8>>) -> Result<(), io::Error> {
+        self.len(fields.len() as u64)?;
+        for (field, value) in fields {
+            self.string(field)?;
+            self.string(value)?;
+        }
+        Ok(())
+    }
+
+    fn zset(&mut self, zset: &SortedSet) -> Result<(), io::Error> {
+        self.len(zset.len() as u64)?;
+        for (score, member) in zset.iter() {
+            self.string(member)?;
+            self.bytes(&score.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Lists and sets as plain string sequences, which every Redis version
+    // loads whatever encoding it then picks.
+    fn strings<'a>(
+        &mut self,
+        len: usize,
+        strings: impl Iterator<Item = &'a Vec<u8>>,
+    ) -> Result<(), io::Error> {
+        self.len(len as u64)?;
+        for s in strings {
+            self.string(s)?;
+        }
+        Ok(())
+    }
+
+    // Written before a key that expires.
+    fn expire(&mut self, at: Option<&u64>) -> Result<(), io::Error> {
+        match at {
+            Some(at) => {
+                self.byte(OPCODE_EXPIRETIME_MS)?;
+                self.bytes(&at.to_le_bytes())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn json(&mut self, json: &Json) -> Result<(), io::Error> {
+        self.len(JSON_MODULE_ID)?;
+        self.len(MODULE_OPCODE_STRING)?;
+        self.string(json.serialize().as_bytes())?;
+        self.len(MODULE_OPCODE_EOF)
+    }
+}
+
+// Writes `snapshot` as an RDB file. `aof_base` marks it as the preamble of
+// an AOF rewrite.
+pub fn write_rdb<W: Write>(snapshot: &Snapshot, out: W, aof_base: bool) -> Result<(), io::Error> {
+    let mut rdb = RdbWriter { out, crc: 0 };
+    rdb.bytes(format!("REDIS{:04}", RDB_VERSION).as_bytes())?;
+    rdb.aux("redis-bits", &(usize::BITS).to_string())?;
+    rdb.aux("ctime", &unix_time().to_string())?;
+    rdb.aux("rustis-ver", env!("CARGO_PKG_VERSION"))?;
+    rdb.aux("aof-base", if aof_base { "1" } else { "0" })?;
+    rdb.byte(OPCODE_SELECTDB)?;
+    rdb.len(0)?;
+    rdb.byte(OPCODE_RESIZEDB)?;
+    rdb.len(snapshot.len() as u64)?;
+    rdb.len(snapshot.expires.len() as u64)?;
+
+    for (key, value) in &snapshot.sets {
+        rdb.expire(snapshot.expires.get(key))?;
+        rdb.byte(TYPE_STRING)?;
+        rdb.string(key)?;
+        rdb.string(value)?;
+    }
+    for (key, fields) in &snapshot.hsets {
+        rdb.expire(snapshot.expires.get(key))?;
+        rdb.byte(TYPE_HASH)?;
+        rdb.string(key)?;
+        rdb.hash(fields)?;
+    }
+    for (key, zset) in &snapshot.zsets {
+        rdb.expire(snapshot.expires.get(key))?;
+        rdb.byte(TYPE_ZSET_2)?;
+        rdb.string(key)?;
+        rdb.zset(zset)?;
+    }
+    for (key, json) in &snapshot.jsons {
+        rdb.expire(snapshot.expires.get(key))?;
+        rdb.byte(TYPE_MODULE_2)?;
+        rdb.string(key)?;
+        rdb.json(json)?;
+    }
+    for (key, list) in &snapshot.lists {
+        rdb.expire(snapshot.expires.get(key))?;
+        rdb.byte(TYPE_LIST)?;
+        rdb.string(key)?;
+        rdb.strings(list.len(), list.iter())?;
+    }
+    for (key, set) in &snapshot.ssets {
+        rdb.expire(snapshot.expires.get(key))?;
+        rdb.byte(TYPE_SET)?;
+        rdb.string(key)?;
+        rdb.strings(set.len(), set.iter())?;
+    }
+
+    rdb.byte(OPCODE_EOF)?;
+    let crc = rdb.crc;
+    rdb.out.write_all(&crc.to_le_bytes())?;
+    rdb.out.flush()
+}
+
+struct RdbReader<R> {
+    input: R,
+    crc: u64,
+    // Longest string accepted, checked before reading it.
+    max_len: usize,
+}
+
+// A length, or the special encoding a string is stored with.
+enum Length {
+    Plain(u64),
+    Encoded(u64),
+}
+
+impl<R: Read> RdbReader<R> {
+    // Grows the buffer as bytes arrive, so a length the input made up
+    // cannot allocate memory.
+    fn exact(&mut self, n: usize) -> Result<Vec<u8>, io::Error> {
+        if n > self.max_len {
+            return Err(invalid(format!("string of {} bytes is too long", n)));
+        }
+        let mut buf = Vec::with_capacity(n.min(FIRST_READ));
+        (&mut self.input).take(n as u64).read_to_end(&mut buf)?;
+        if buf.len() < n {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.crc = crc64(self.crc, &buf);
+        Ok(buf)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], io::Error> {
+        let mut buf = [0; N];
+        self.input.read_exact(&mut buf)?;
+        self.crc = crc64(self.crc, &buf);
+        Ok(buf)
+    }
+
+    fn byte(&mut self) -> Result<u8, io::Error> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn length(&mut self) -> Result<Length, io::Error> {
+        let first = self.byte()?;
+        Ok(match first >> 6 {
+            0 => Length::Plain((first & 0x3f) as u64),
+            1 => Length::Plain((((first & 0x3f) as u64) << 8) | self.byte()? as u64),
+            2 if first == 0x80 => Length::Plain(u32::from_be_bytes(self.array()?) as u64),
+            2 if first == 0x81 => Length::Plain(u64::from_be_bytes(self.array()?)),
+            2 => return Err(invalid(format!("unknown length encoding {:#x}", first))),
+            _ => Length::Encoded((first & 0x3f) as u64),
+        })
+    }
+
+    fn len(&mut self) -> Result<u64, io::Error> {
+        match self.length()? {
+            Length::Plain(len) => Ok(len),
+            Length::Encoded(_) => Err(invalid("expected a length, found an encoded string")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, io::Error> {
+        match self.length()? {
+            Length::Plain(len) => self.exact(len as usize),
+            Length::Encoded(ENC_INT8) => Ok((self.byte()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENC_INT16) => {
+                Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(ENC_INT32) => {
+                Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes())
+            }
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.len()? as usize;
+                let len = self.len()? as usize;
+                if len > self.max_len {
+                    return Err(invalid(format!("string of {} bytes is too long", len)));
+                }
+                let compressed = self.exact(compressed_len)?;
+                lzf_decompress(&compressed, len)
+            }
+            Length::Encoded(encoding) => {
+                Err(invalid(format!("unknown string encoding {}", encoding)))
+            }
+        }
+    }
+
+    // Scores of the old sorted set type: text after a length byte, with
+    // 253 to 255 standing for NaN and the infinities.
+    fn double_string(&mut self) -> Result<f64, io::Error> {
+        match self.byte()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => {
+                let text = self.exact(len as usize)?;
+                std::str::from_utf8(&text)
+                    .ok()
+                    .and_then(|text| text.parse().ok())
+                    .ok_or_else(|| invalid("invalid score in sorted set"))
+            }
+        }
+    }
+}
+
+// LZF as used by Redis: literal runs and back references, see lzf_d.c.
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, io::Error> {
+    let corrupt = || invalid("corrupt LZF string");
+    let mut out = Vec::with_capacity(len.min(FIRST_READ));
+    let mut i = 0;
+    while i < input.len() {
+        if out.len() > len {
+            return Err(corrupt());
+        }
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let run = input.get(i..i + ctrl + 1).ok_or_else(corrupt)?;
+            out.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            let mut copy = ctrl >> 5;
+            if copy == 7 {
+                copy += *input.get(i).ok_or_else(corrupt)? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).ok_or_else(corrupt)? as usize;
+            i += 1;
+            let back = ((ctrl & 0x1f) << 8) + low + 1;
+            let start = out.len().checked_sub(back).ok_or_else(corrupt)?;
+            // The source may overlap what is being written, so copy bytewise.
+            for k in 0..copy + 2 {
+                out.push(out[start + k]);
+            }
+        }
+    }
+    if out.len() != len {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
+
+// A decoded value.
+enum Value {
+    String(Vec<u8>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    ZSet(SortedSet),
+    Json(Json),
+    List(VecDeque<Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+}
+
+impl Value {
+    fn insert_into(self, snapshot: &mut Snapshot, key: Vec<u8>) {
+        match self {
+            Value::String(value) => _ = snapshot.sets.insert(key, value),
+            Value::Hash(fields) => _ = snapshot.hsets.insert(key, fields),
+            Value::ZSet(zset) => _ = snapshot.zsets.insert(key, zset),
+            Value::Json(json) => _ = snapshot.jsons.insert(key, json),
+            Value::List(list) => _ = snapshot.lists.insert(key, list),
+            Value::Set(set) => _ = snapshot.ssets.insert(key, set),
+        }
+    }
+}
+
+// Reads an RDB file written by rustis or by Redis into a snapshot. Keys
+// whose expire time has passed are skipped. rustis has no functions, so a
+// file holding them is refused unless rdb-load-lossy is set.
+pub fn read_rdb<R: Read>(input: R) -> Result<Snapshot, io::Error> {
+    read_rdb_with(input, config::rdb_load_lossy())
+}
+
+// With `lossy`, what rustis cannot keep is dropped with a warning, which
+// for now is only functions.
+fn read_rdb_with<R: Read>(input: R, lossy: bool) -> Result<Snapshot, io::Error> {
+    let mut rdb = RdbReader {
+        input,
+        crc: 0,
+        max_len: usize::MAX,
+    };
+    let header = rdb.exact(9)?;
+    let version = std::str::from_utf8(&header[5..])
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok());
+    let version = match (&header[..5], version) {
+        (b"REDIS", Some(version)) if version <= RDB_MAX_READ_VERSION => version,
+        (b"REDIS", Some(version)) => {
+            return Err(invalid(format!("unsupported RDB version {}", version)))
+        }
+        _ => return Err(invalid("not an RDB file")),
+    };
+
+    let mut snapshot = Snapshot::default();
+    let mut expires_at: Option<u64> = None;
+    let now_ms = now_ms();
+    let mut skipped: BTreeMap<&'static str, u64> = BTreeMap::new();
+    loop {
+        let kind = rdb.byte()?;
+        match kind {
+            OPCODE_AUX => {
+                rdb.string()?;
+                rdb.string()?;
+            }
+            OPCODE_RESIZEDB => {
+                rdb.len()?;
+                rdb.len()?;
+            }
+            OPCODE_SELECTDB => {
+                let db = rdb.len()?;
+                if db != 0 {
+                    return Err(invalid(format!(
+                        "only database 0 is supported, found {}",
+                        db
+                    )));
+                }
+            }
+            OPCODE_SLOT_INFO => {
+                rdb.len()?;
+                rdb.len()?;
+                rdb.len()?;
+            }
+            OPCODE_FUNCTION2 => {
+                rdb.string()?;
+                *skipped.entry("function libraries").or_default() += 1;
+            }
+            OPCODE_MODULE_AUX => {
+                // Module id, when opcode and when, then the module's data.
+                rdb.len()?;
+                rdb.len()?;
+                rdb.len()?;
+                skip_module_value(&mut rdb)?;
+            }
+            OPCODE_IDLE => {
+                rdb.len()?;
+            }
+            OPCODE_FREQ => {
+                rdb.byte()?;
+            }
+            OPCODE_EXPIRETIME_MS => expires_at = Some(u64::from_le_bytes(rdb.array()?)),
+            OPCODE_EXPIRETIME => expires_at = Some(u32::from_le_bytes(rdb.array()?) as u64 * 1000),
+            OPCODE_EOF => break,
+            _ => {
+                let key = rdb.string()?;
+                let value = read_value(&mut rdb, kind)?;
+                match expires_at.take() {
+                    Some(at) if at <= now_ms => continue,
+                    Some(at) => _ = snapshot.expires.insert(key.clone(), at),
+                    None => {}
+                }
+                value.insert_into(&mut snapshot, key);
+            }
+        }
+    }
+
+    let crc = rdb.crc;
+    if version >= 5 {
+        let expected = u64::from_le_bytes(rdb.array()?);
+        // A zero checksum means the writer had checksums turned off.
+        if expected != 0 && expected != crc {
+            return Err(invalid("RDB checksum mismatch"));
+        }
+    }
+    if !skipped.is_empty() && !lossy {
+        let losses: Vec<String> = skipped
+            .iter()
+            .map(|(kind, count)| format!("{} {}", count, kind))
+            .collect();
+        return Err(invalid(format!(
+            "the RDB file holds data rustis cannot keep ({}); \
+             start with --rdb-load-lossy yes to load the rest anyway",
+            losses.join(", ")
+        )));
+    }
+    for (kind, count) in skipped {
+        println!(
+            "!!! Warning: dropped {} {} from the RDB file, rustis does not support them",
+            count, kind
+        );
+    }
+    Ok(snapshot)
+}
+
+fn read_value<R: Read>(rdb: &mut RdbReader<R>, kind: u8) -> Result<Value, io::Error> {
+    Ok(match kind {
+        TYPE_STRING => Value::String(rdb.string()?),
+        TYPE_LIST => Value::List(
+            (0..rdb.len()?)
+                .map(|_| rdb.string())
+                .collect::<Result<_, _>>()?,
+        ),
+        TYPE_SET => Value::Set(
+            (0..rdb.len()?)
+                .map(|_| rdb.string())
+                .collect::<Result<_, _>>()?,
+        ),
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let mut zset = SortedSet::new();
+            for _ in 0..rdb.len()? {
+                let member = rdb.string()?;
+                let score = match kind {
+                    TYPE_ZSET => rdb.double_string()?,
+                    _ => f64::from_le_bytes(rdb.array()?),
+                };
+                if score.is_nan() {
+                    return Err(invalid("NaN score in sorted set"));
+                }
+                zset.insert(member, score);
+            }
+            Value::ZSet(zset)
+        }
+        TYPE_HASH => {
+            let mut fields = HashMap::new();
+            for _ in 0..rdb.len()? {
+                let field = rdb.string()?;
+                fields.insert(field, rdb.string()?);
+            }
+            Value::Hash(fields)
+        }
+        TYPE_MODULE_2 => {
+            let id = rdb.len()?;
+            if id >> 10 != JSON_MODULE_ID >> 10 {
+                return Err(invalid(format!("unsupported module type {:#x}", id)));
+            }
+            if rdb.len()? != MODULE_OPCODE_STRING {
+                return Err(invalid("unexpected JSON module value"));
+            }
+            let text = rdb.string()?;
+            if rdb.len()? != MODULE_OPCODE_EOF {
+                return Err(invalid("unexpected JSON module value"));
+            }
+            Value::Json(Json::parse(&text).map_err(invalid)?)
+        }
+        TYPE_HASH_ZIPMAP => Value::Hash(pairs(zipmap_entries(&rdb.string()?)?)?.collect()),
+        TYPE_HASH_ZIPLIST => Value::Hash(pairs(ziplist_entries(&rdb.string()?)?)?.collect()),
+        TYPE_HASH_LISTPACK => Value::Hash(pairs(listpack_entries(&rdb.string()?)?)?.collect()),
+        TYPE_ZSET_ZIPLIST => zset_value(ziplist_entries(&rdb.string()?)?)?,
+        TYPE_ZSET_LISTPACK => zset_value(listpack_entries(&rdb.string()?)?)?,
+        TYPE_LIST_ZIPLIST => Value::List(ziplist_entries(&rdb.string()?)?.into()),
+        TYPE_LIST_QUICKLIST => {
+            let mut list = VecDeque::new();
+            for _ in 0..rdb.len()? {
+                list.extend(ziplist_entries(&rdb.string()?)?);
+            }
+            Value::List(list)
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let mut list = VecDeque::new();
+            for _ in 0..rdb.len()? {
+                let container = rdb.len()?;
+                let node = rdb.string()?;
+                match container {
+                    QUICKLIST_NODE_PLAIN => list.push_back(node),
+                    QUICKLIST_NODE_PACKED => list.extend(listpack_entries(&node)?),
+                    _ => return Err(invalid(format!("unknown quicklist node {}", container))),
+                }
+            }
+            Value::List(list)
+        }
+        TYPE_SET_INTSET => Value::Set(intset_entries(&rdb.string()?)?.into_iter().collect()),
+        TYPE_SET_LISTPACK => Value::Set(listpack_entries(&rdb.string()?)?.into_iter().collect()),
+        _ => return Err(invalid(format!("unsupported value type {}", kind))),
+    })
+}
+
+// Steps over module data without the module, using the field opcodes.
+fn skip_module_value<R: Read>(rdb: &mut RdbReader<R>) -> Result<(), io::Error> {
+    loop {
+        match rdb.len()? {
+            MODULE_OPCODE_EOF => return Ok(()),
+            MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => _ = rdb.len()?,
+            MODULE_OPCODE_FLOAT => _ = rdb.array::<4>()?,
+            MODULE_OPCODE_DOUBLE => _ = rdb.array::<8>()?,
+            MODULE_OPCODE_STRING => _ = rdb.string()?,
+            opcode => return Err(invalid(format!("unknown module opcode {}", opcode))),
+        }
+    }
+}
+
+// Splits the flat field, value, field, value... layout of small hashes.
+fn pairs(entries: Vec<Vec<u8>>) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>, io::Error> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(invalid("odd number of entries in a hash or sorted set"));
+    }
+    let mut entries = entries.into_iter();
+    Ok(std::iter::from_fn(move || {
+        Some((entries.next()?, entries.next()?))
+    }))
+}
+
+// Small sorted sets store member, score pairs with scores as text.
+fn zset_value(entries: Vec<Vec<u8>>) -> Result<Value, io::Error> {
+    let mut zset = SortedSet::new();
+    for (member, score) in pairs(entries)? {
+        let score = std::str::from_utf8(&score)
+            .ok()
+            .and_then(|s| s.parse::<f64>().ok())
+            .filter(|score| !score.is_nan())
+            .ok_or_else(|| invalid("invalid score in sorted set"))?;
+        zset.insert(member, score);
+    }
+    Ok(Value::ZSet(zset))
+}
+
+// A cursor over one of the compact encodings Redis stores as a string.
+struct Blob<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    what: &'static str,
+}
+
+impl<'a> Blob<'a> {
+    fn new(bytes: &'a [u8], what: &'static str) -> Self {
+        Blob {
+            bytes,
+            pos: 0,
+            what,
+        }
+    }
+
+    fn corrupt(&self) -> io::Error {
+        invalid(format!("corrupt {} at offset {}", self.what, self.pos))
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], io::Error> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(n))
+            .ok_or_else(|| self.corrupt())?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, io::Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn le(&mut self, n: usize) -> Result<u64, io::Error> {
+        Ok(self
+            .take(n)?
+            .iter()
+            .rev()
+            .fold(0, |value, &b| (value << 8) | b as u64))
+    }
+
+    // A little endian two's complement integer of `n` bytes.
+    fn int(&mut self, n: usize) -> Result<Vec<u8>, io::Error> {
+        let shift = 64 - 8 * n as u32;
+        let value = ((self.le(n)? << shift) as i64) >> shift;
+        Ok(value.to_string().into_bytes())
+    }
+
+    // Checks the entry count from the header and that nothing trails the end.
+    fn finish(&self, count: u64, entries: &[Vec<u8>], unknown: u64) -> Result<(), io::Error> {
+        if self.pos != self.bytes.len() || (count != unknown && count as usize != entries.len()) {
+            return Err(self.corrupt());
+        }
+        Ok(())
+    }
+}
+
+// Ziplists, used by Redis before 7.0: a header, then entries each led by
+// the previous entry's length and an encoding byte.
+fn ziplist_entries(bytes: &[u8]) -> Result<Vec<Vec<u8>>, io::Error> {
+    let mut zl = Blob::new(bytes, "ziplist");
+    if zl.le(4)? != bytes.len() as u64 {
+        return Err(zl.corrupt());
+    }
+    zl.le(4)?;
+    let count = zl.le(2)?;
+    let mut entries = Vec::new();
+    loop {
+        match zl.byte()? {
+            0xff => break,
+            0xfe => _ = zl.take(4)?,
+            _ => {}
+        }
+        let encoding = zl.byte()?;
+        let entry = match encoding {
+            0x00..=0x3f => zl.take(encoding as usize)?.to_vec(),
+            0x40..=0x7f => {
+                let len = ((encoding as usize & 0x3f) << 8) | zl.byte()? as usize;
+                zl.take(len)?.to_vec()
+            }
+            0x80 => {
+                let len = u32::from_be_bytes(zl.take(4)?.try_into().unwrap());
+                zl.take(len as usize)?.to_vec()
+            }
+            0xc0 => zl.int(2)?,
+            0xd0 => zl.int(4)?,
+            0xe0 => zl.int(8)?,
+            0xf0 => zl.int(3)?,
+            0xfe => zl.int(1)?,
+            0xf1..=0xfd => (encoding as i64 - 0xf1).to_string().into_bytes(),
+            _ => return Err(zl.corrupt()),
+        };
+        entries.push(entry);
+    }
+    zl.finish(count, &entries, 0xffff)?;
+    Ok(entries)
+}
+
+// Listpacks, used by Redis 7.0 on: entries are an encoding, the data, and
+// the entry's length written backwards for reverse iteration.
+fn listpack_entries(bytes: &[u8]) -> Result<Vec<Vec<u8>>, io::Error> {
+    let mut lp = Blob::new(bytes, "listpack");
+    if lp.le(4)? != bytes.len() as u64 {
+        return Err(lp.corrupt());
+    }
+    let count = lp.le(2)?;
+    let mut entries = Vec::new();
+    loop {
+        let start = lp.pos;
+        let encoding = lp.byte()?;
+        let entry = match encoding {
+            0x00..=0x7f => encoding.to_string().into_bytes(),
+            0x80..=0xbf => lp.take(encoding as usize & 0x3f)?.to_vec(),
+            0xc0..=0xdf => {
+                let value = ((encoding as i64 & 0x1f) << 8) | lp.byte()? as i64;
+                ((value << 51) >> 51).to_string().into_bytes()
+            }
+            0xe0..=0xef => {
+                let len = ((encoding as usize & 0x0f) << 8) | lp.byte()? as usize;
+                lp.take(len)?.to_vec()
+            }
+            0xf0 => {
+                let len = lp.le(4)?;
+                lp.take(len as usize)?.to_vec()
+            }
+            0xf1 => lp.int(2)?,
+            0xf2 => lp.int(3)?,
+            0xf3 => lp.int(4)?,
+            0xf4 => lp.int(8)?,
+            0xff => break,
+            _ => return Err(lp.corrupt()),
+        };
+        let backlen = match lp.pos - start {
+            0..=127 => 1,
+            128..16383 => 2,
+            16383..2097151 => 3,
+            2097151..268435455 => 4,
+            _ => 5,
+        };
+        lp.take(backlen)?;
+        entries.push(entry);
+    }
+    lp.finish(count, &entries, 0xffff)?;
+    Ok(entries)
+}
+
+// Intsets: the integer width, the count, then the sorted integers.
+fn intset_entries(bytes: &[u8]) -> Result<Vec<Vec<u8>>, io::Error> {
+    let mut is = Blob::new(bytes, "intset");
+    let width = is.le(4)? as usize;
+    let count = is.le(4)?;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(is.corrupt());
+    }
+    let entries = (0..count)
+        .map(|_| is.int(width))
+        .collect::<Result<Vec<_>, _>>()?;
+    is.finish(count, &entries, u64::MAX)?;
+    Ok(entries)
+}
+
+// Zipmaps, the small hash encoding of Redis 2.x: length-prefixed keys and
+// values, each value followed by unused padding.
+fn zipmap_entries(bytes: &[u8]) -> Result<Vec<Vec<u8>>, io::Error> {
+    let mut zm = Blob::new(bytes, "zipmap");
+    let count = zm.byte()? as u64;
+    let mut entries = Vec::new();
+    loop {
+        let key_len = match zm.byte()? {
+            0xff => break,
+            0xfe => zm.le(4)?,
+            len => len as u64,
+        };
+        entries.push(zm.take(key_len as usize)?.to_vec());
+        let value_len = match zm.byte()? {
+            0xfe => zm.le(4)?,
+            0xff => return Err(zm.corrupt()),
+            len => len as u64,
+        };
+        let free = zm.byte()? as usize;
+        entries.push(zm.take(value_len as usize)?.to_vec());
+        zm.take(free)?;
+    }
+    // The count saturates at 254 for larger maps.
+    zm.finish(count * 2, &entries, 254 * 2)?;
+    Ok(entries)
+}
+
+// Writes a snapshot next to `path` and renames it into place.
+fn write_file(path: &Path, snapshot: &Snapshot) -> Result<(), io::Error> {
+    let temp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    let result = File::create(&temp).and_then(|file| {
+        write_rdb(snapshot, BufWriter::new(&file), false)?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+// Loads `path` into the stores; a missing file is an empty dataset.
+pub fn load(path: &Path) -> Result<usize, io::Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let snapshot = read_rdb(BufReader::new(file))
+        .map_err(|err| invalid(format!("{}: {}", path.display(), err)))?;
+    let keys = snapshot.len();
+    snapshot.restore();
+    Ok(keys)
+}
+
+fn saved(dirty_at_snapshot: u64) {
+    DIRTY.fetch_sub(dirty_at_snapshot, Ordering::Relaxed);
+    LAST_SAVE.store(unix_time(), Ordering::Relaxed);
+}
+
+pub fn save(args: Vec<Message>) -> Message {
+    if !args.is_empty() {
+        return Message::error("ERR wrong number of arguments for 'save' command");
+    }
+    if BGSAVE_IN_PROGRESS.load(Ordering::Relaxed) {
+        return Message::error("ERR Background save already in progress");
+    }
+    let dirty = DIRTY.load(Ordering::Relaxed);
+    match write_file(Path::new(&config::dbfilename()), &Snapshot::take()) {
+        Ok(()) => {
+            saved(dirty);
+            Message::simple("OK")
+        }
+        Err(err) => Message::error(format!("ERR {}", err)),
+    }
+}
+
+pub fn bgsave(args: Vec<Message>) -> Message {
+    if !args.is_empty() {
+        return Message::error("ERR wrong number of arguments for 'bgsave' command");
+    }
+    match start_bgsave() {
+        true => Message::simple("Background saving started"),
+        false => Message::error("ERR Background save already in progress"),
+    }
+}
+
+// Copies the keyspace now and writes it out on another thread. Callers
+// hold EXECUTION, so no command lands between the copy and reading DIRTY.
+fn start_bgsave() -> bool {
+    if BGSAVE_IN_PROGRESS.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    let dirty = DIRTY.load(Ordering::Relaxed);
+    let snapshot = Snapshot::take();
+    let path = config::dbfilename();
+    spawn(move || {
+        let result = write_file(Path::new(&path), &snapshot);
+        match &result {
+            Ok(()) => saved(dirty),
+            Err(err) => println!("Background saving error: {err}"),
+        }
+        LAST_BGSAVE_OK.store(result.is_ok(), Ordering::Relaxed);
+        BGSAVE_IN_PROGRESS.store(false, Ordering::Relaxed);
+    });
+    true
+}
+
+#[cfg(test)]
+pub fn wait_for_bgsave() {
+    while BGSAVE_IN_PROGRESS.load(Ordering::Relaxed) {
+        sleep(Duration::from_millis(1));
+    }
+}
+
+// The RDB fields of the persistence section of INFO.
+pub fn info() -> String {
+    let status = if LAST_BGSAVE_OK.load(Ordering::Relaxed) {
+        "ok"
+    } else {
+        "err"
+    };
+    [
+        format!(
+            "rdb_changes_since_last_save:{}",
+            DIRTY.load(Ordering::Relaxed)
+        ),
+        format!(
+            "rdb_bgsave_in_progress:{}",
+            BGSAVE_IN_PROGRESS.load(Ordering::Relaxed) as u8
+        ),
+        format!("rdb_last_save_time:{}", LAST_SAVE.load(Ordering::Relaxed)),
+        format!("rdb_last_bgsave_status:{}", status),
+    ]
+    .join("\r\n")
+}
+
+pub fn lastsave(args: Vec<Message>) -> Message {
+    if !args.is_empty() {
+        return Message::error("ERR wrong number of arguments for 'lastsave' command");
+    }
+    Message::integer(LAST_SAVE.load(Ordering::Relaxed) as i64)
+}
+
+// The value of `key` serialized as Redis' DUMP does: its type and value as
+// in an RDB file, then the RDB version as two bytes and a CRC64 of all
+// that. None when there is no such key.
+pub fn dump_payload(key: &[u8]) -> Result<Option<Vec<u8>>, io::Error> {
+    let snapshot = Snapshot::of(&[key.to_vec()]);
+    let mut rdb = RdbWriter {
+        out: Vec::new(),
+        crc: 0,
+    };
+    if let Some(value) = snapshot.sets.get(key) {
+        rdb.byte(TYPE_STRING)?;
+        rdb.string(value)?;
+    } else if let Some(fields) = snapshot.hsets.get(key) {
+        rdb.byte(TYPE_HASH)?;
+        rdb.hash(fields)?;
+    } else if let Some(zset) = snapshot.zsets.get(key) {
+        rdb.byte(TYPE_ZSET_2)?;
+        rdb.zset(zset)?;
+    } else if let Some(json) = snapshot.jsons.get(key) {
+        rdb.byte(TYPE_MODULE_2)?;
+        rdb.json(json)?;
+    } else if let Some(list) = snapshot.lists.get(key) {
+        rdb.byte(TYPE_LIST)?;
+        rdb.strings(list.len(), list.iter())?;
+    } else if let Some(set) = snapshot.ssets.get(key) {
+        rdb.byte(TYPE_SET)?;
+        rdb.strings(set.len(), set.iter())?;
+    } else {
+        return Ok(None);
+    }
+    rdb.bytes(&(RDB_VERSION as u16).to_le_bytes())?;
+    let crc = rdb.crc;
+    rdb.out.extend_from_slice(&crc.to_le_bytes());
+    Ok(Some(rdb.out))
+}
+
+// The value in a payload written by DUMP, here or in Redis. Errors carry
+// the reply RESTORE gives.
+fn read_payload(payload: &[u8]) -> Result<Value, Message> {
+    let wrong = || Message::error("ERR DUMP payload version or checksum are wrong");
+    let Some(split) = payload.len().checked_sub(10) else {
+        return Err(wrong());
+    };
+    let (value, footer) = payload.split_at(split);
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    let crc = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    if version as u32 > RDB_MAX_READ_VERSION || crc != crc64(0, &payload[..split + 2]) {
+        return Err(wrong());
+    }
+    let mut rdb = RdbReader {
+        input: value,
+        crc: 0,
+        max_len: config::proto_max_bulk_len(),
+    };
+    let bad = |_| Message::error("ERR Bad data format");
+    let kind = rdb.byte().map_err(bad)?;
+    let value = read_value(&mut rdb, kind).map_err(bad)?;
+    if !rdb.input.is_empty() {
+        return Err(Message::error("ERR Bad data format"));
+    }
+    Ok(value)
+}
+
+pub fn dump(args: Vec<Message>) -> Message {
+    let [Bulk(key)] = args.as_slice() else {
+        return Message::error("ERR wrong number of arguments for 'dump' command");
+    };
+    match dump_payload(key) {
+        Ok(Some(payload)) => Message::bulk(payload),
+        Ok(None) => Message::Null,
+        Err(err) => Message::error(format!("ERR {}", err)),
+    }
+}
+
+// RESTORE key ttl payload [REPLACE] [ABSTTL]. A ttl of 0 means no expire
+// time, and with ABSTTL it is a Unix time in milliseconds. rustis keeps no
+// access statistics, so IDLETIME and FREQ are refused rather than dropped.
+pub fn restore(args: Vec<Message>) -> Message {
+    let [Bulk(key), Bulk(ttl), Bulk(payload), options @ ..] = args.as_slice() else {
+        return Message::error("ERR wrong number of arguments for 'restore' command");
+    };
+    let (mut replace, mut absttl) = (false, false);
+    for option in options {
+        let Bulk(option) = option else {
+            return Message::error("ERR syntax error");
+        };
+        match option.to_ascii_uppercase().as_slice() {
+            b"REPLACE" => replace = true,
+            b"ABSTTL" => absttl = true,
+            name @ (b"IDLETIME" | b"FREQ") => {
+                return Message::error(format!(
+                    "ERR RESTORE {} is not supported",
+                    String::from_utf8_lossy(name)
+                ))
+            }
+            _ => return Message::error("ERR syntax error"),
+        }
+    }
+    let expires_at = match parse_int(ttl) {
+        Some(0) => None,
+        Some(ttl) if ttl > 0 && absttl => Some(ttl as u64),
+        Some(ttl) if ttl > 0 => Some(now_ms().saturating_add(ttl as u64)),
+        _ => return Message::error("ERR Invalid TTL value, must be >= 0"),
+    };
+    let value = match read_payload(payload) {
+        Ok(value) => value,
+        Err(err) => return err,
+    };
+    let mut snapshot = Snapshot::default();
+    value.insert_into(&mut snapshot, key.clone());
+    if !replace && handlers::exists(key) {
+        return Message::error("BUSYKEY Target key name already exists.");
+    }
+    handlers::remove(key);
+    match expires_at {
+        // Already expired: like Redis, the key is only deleted.
+        Some(at) if at <= now_ms() => return Message::simple("OK"),
+        Some(at) => _ = snapshot.expires.insert(key.clone(), at),
+        None => {}
+    }
+    snapshot.restore();
+    Message::simple("OK")
+}
+
+// A `save <seconds> <changes>` rule matches once at least `changes` writes
+// happened and `seconds` passed since the last save.
+fn save_due(rules: &[(u64, u64)], dirty: u64, elapsed: u64) -> bool {
+    rules
+        .iter()
+        .any(|&(seconds, changes)| dirty >= changes && elapsed >= seconds)
+}
+
+// Checks the save rules ten times a second, like Redis' serverCron.
+pub fn spawn_save_cron() {
+    LAST_SAVE.store(unix_time(), Ordering::Relaxed);
+    spawn(|| {
+        let mut last_try = 0;
+        loop {
+            sleep(Duration::from_millis(100));
+            let now = unix_time();
+            let elapsed = now.saturating_sub(LAST_SAVE.load(Ordering::Relaxed));
+            let retry_ok = LAST_BGSAVE_OK.load(Ordering::Relaxed)
+                || now.saturating_sub(last_try) >= BGSAVE_RETRY_DELAY;
+            if retry_ok
+                && save_due(
+                    &config::save_rules(),
+                    DIRTY.load(Ordering::Relaxed),
+                    elapsed,
+                )
+            {
+                let _execution = EXECUTION.lock().unwrap();
+                if start_bgsave() {
+                    last_try = now;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        snapshot.sets.insert(b"s".to_vec(), b"hello".to_vec());
+        snapshot.sets.insert(b"n".to_vec(), b"-300".to_vec());
+        snapshot.sets.insert(b"padded".to_vec(), b"007".to_vec());
+        snapshot.sets.insert(vec![0xff; 100], vec![0; 20000]);
+        let mut fields = HashMap::new();
+        fields.insert(b"f".to_vec(), b"v".to_vec());
+        snapshot.hsets.insert(b"h".to_vec(), fields);
+        let mut zset = SortedSet::new();
+        zset.insert(b"a".to_vec(), 1.5);
+        zset.insert(b"b".to_vec(), f64::NEG_INFINITY);
+        snapshot.zsets.insert(b"z".to_vec(), zset);
+        snapshot
+            .jsons
+            .insert(b"j".to_vec(), Json::parse(br#"{"a":[1,2.5,"x"]}"#).unwrap());
+        snapshot.lists.insert(
+            b"l".to_vec(),
+            VecDeque::from([b"x".to_vec(), b"12".to_vec(), b"x".to_vec()]),
+        );
+        snapshot.ssets.insert(
+            b"m".to_vec(),
+            HashSet::from([b"a".to_vec(), b"-5".to_vec(), vec![0; 100]]),
+        );
+        snapshot.expires.insert(b"s".to_vec(), 4102444800000);
+        snapshot.expires.insert(b"l".to_vec(), 4102444800001);
+        snapshot
+    }
+
+    fn round_trip(snapshot: &Snapshot) -> Snapshot {
+        let mut bytes = Vec::new();
+        write_rdb(snapshot, &mut bytes, false).unwrap();
+        read_rdb(&bytes[..]).unwrap()
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn test_json_module_id() {
+        // The name decodes from the top 54 bits, as Redis' moduleTypeNameByID does.
+        assert_eq!(JSON_MODULE_ID & 0x3ff, 3);
+        assert_eq!(JSON_MODULE_ID >> 58, 17); // 'R'
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let snapshot = sample();
+        let loaded = round_trip(&snapshot);
+        assert_eq!(loaded.sets, snapshot.sets);
+        assert_eq!(loaded.hsets, snapshot.hsets);
+        assert_eq!(loaded.zsets, snapshot.zsets);
+        assert_eq!(loaded.jsons, snapshot.jsons);
+        assert_eq!(loaded.lists, snapshot.lists);
+        assert_eq!(loaded.ssets, snapshot.ssets);
+        assert_eq!(loaded.expires, snapshot.expires);
+    }
+
+    #[test]
+    fn test_string_encodings() {
+        let mut rdb = RdbWriter {
+            out: Vec::new(),
+            crc: 0,
+        };
+        for s in [&b"12"[..], b"-200", b"70000", b"007", b"abc"] {
+            rdb.string(s).unwrap();
+        }
+        assert_eq!(
+            rdb.out,
+            [
+                &[0xc0, 12][..],
+                &[0xc1, 0x38, 0xff],
+                &[0xc2, 0x70, 0x11, 0x01, 0x00],
+                &[3, b'0', b'0', b'7'],
+                &[3, b'a', b'b', b'c'],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_restore_expiry() {
+        let mut snapshot = Snapshot::default();
+        snapshot
+            .sets
+            .insert(b"rdb-restore-ttl".to_vec(), b"v".to_vec());
+        snapshot.restore();
+        let payload = dump_payload(b"rdb-restore-ttl").unwrap().unwrap();
+        handlers::remove(b"rdb-restore-ttl");
+        let restore_with = |ttl: &str, options: &[&str]| {
+            let mut args = Message::bulks(&["rdb-restore-ttl", ttl]);
+            args.push(Message::bulk(payload.clone()));
+            args.extend(Message::bulks(options));
+            restore(args)
+        };
+        let ttl = |key: &[u8]| handlers::EXPIRES.lock().unwrap().get(key).copied();
+        let start = now_ms();
+        assert_eq!(restore_with("100000", &[]), Message::simple("OK"));
+        let at = ttl(b"rdb-restore-ttl").unwrap();
+        assert!((start + 100_000..=now_ms() + 100_000).contains(&at));
+        assert_eq!(
+            restore_with("4102444800000", &["REPLACE", "ABSTTL"]),
+            Message::simple("OK")
+        );
+        assert_eq!(ttl(b"rdb-restore-ttl"), Some(4102444800000));
+        // A deadline already past leaves no key behind.
+        assert_eq!(
+            restore_with("1000", &["REPLACE", "ABSTTL"]),
+            Message::simple("OK")
+        );
+        assert!(!handlers::exists(b"rdb-restore-ttl"));
+        assert_eq!(ttl(b"rdb-restore-ttl"), None);
+        assert_eq!(
+            restore_with("0", &["idletime", "5"]),
+            Message::error("ERR RESTORE IDLETIME is not supported")
+        );
+        assert_eq!(
+            restore_with("-1", &[]),
+            Message::error("ERR Invalid TTL value, must be >= 0")
+        );
+        assert!(!handlers::exists(b"rdb-restore-ttl"));
+        assert_eq!(restore_with("0", &[]), Message::simple("OK"));
+        assert_eq!(ttl(b"rdb-restore-ttl"), None);
+        handlers::remove(b"rdb-restore-ttl");
+    }
+
+    #[test]
+    fn test_restore_does_not_trust_declared_lengths() {
+        let payload = |value: &[u8]| {
+            let mut payload = value.to_vec();
+            payload.extend_from_slice(&(RDB_VERSION as u16).to_le_bytes());
+            let crc = crc64(0, &payload);
+            payload.extend_from_slice(&crc.to_le_bytes());
+            payload
+        };
+        let huge = (1u64 << 62).to_be_bytes();
+        for value in [
+            // A string claiming 2^62 bytes.
+            [&[TYPE_STRING, 0x81][..], &huge, b"ab"].concat(),
+            // An LZF string claiming to decompress to 2^62 bytes.
+            [&[TYPE_STRING, 0xc3, 1, 0x81][..], &huge, &[0, b'a']].concat(),
+            // A hash field over proto-max-bulk-len.
+            [&[TYPE_HASH, 1, 0x80][..], &u32::MAX.to_be_bytes()].concat(),
+        ] {
+            let reply = restore(vec![
+                Message::bulk(b"rdb-restore-huge".to_vec()),
+                Message::bulk(b"0".to_vec()),
+                Message::bulk(payload(&value)),
+            ]);
+            assert_eq!(reply, Message::error("ERR Bad data format"));
+        }
+        assert!(!handlers::exists(b"rdb-restore-huge"));
+    }
+
+    #[test]
+    fn test_dump_payload() {
+        // What Redis 5 replies to DUMP for a key holding "10".
+        let redis = b"\x00\xc0\n\t\x00\xbem\x06\x89Z(\x00\n";
+        assert!(matches!(read_payload(redis), Ok(Value::String(v)) if v == b"10"));
+        let mut snapshot = Snapshot::default();
+        snapshot.sets.insert(b"rdb-dump".to_vec(), b"10".to_vec());
+        snapshot.restore();
+        assert_eq!(dump_payload(b"rdb-dump").unwrap().unwrap(), redis);
+        assert_eq!(dump_payload(b"rdb-dump-missing").unwrap(), None);
+
+        let mut payload = redis.to_vec();
+        payload[1] = 0xc1;
+        assert_eq!(
+            read_payload(&payload).err(),
+            Some(Message::error(
+                "ERR DUMP payload version or checksum are wrong"
+            ))
+        );
+        let mut rdb = RdbWriter {
+            out: Vec::new(),
+            crc: 0,
+        };
+        rdb.bytes(&[TYPE_LIST, 0, 9, 0]).unwrap();
+        let crc = rdb.crc;
+        rdb.out.extend_from_slice(&crc.to_le_bytes());
+        assert!(matches!(
+            read_payload(&rdb.out),
+            Ok(Value::List(list)) if list.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        // "aaaaaaaaaa": one literal "a", then a back reference copying 9 bytes.
+        let compressed = [0x00, b'a', 0xe0, 0x00, 0x00];
+        assert_eq!(lzf_decompress(&compressed, 10).unwrap(), b"aaaaaaaaaa");
+        assert!(lzf_decompress(&compressed, 11).is_err());
+        assert!(lzf_decompress(&[0x20, 0x05], 3).is_err());
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut bytes = Vec::new();
+        write_rdb(&sample(), &mut bytes, false).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let err = read_rdb(&bytes[..]).err().unwrap();
+        assert_eq!(err.to_string(), "RDB checksum mismatch");
+    }
+
+    #[test]
+    fn test_expired_keys_are_skipped() {
+        let mut rdb = RdbWriter {
+            out: Vec::new(),
+            crc: 0,
+        };
+        rdb.bytes(b"REDIS0009").unwrap();
+        rdb.byte(OPCODE_EXPIRETIME_MS).unwrap();
+        rdb.bytes(&1000u64.to_le_bytes()).unwrap();
+        rdb.byte(TYPE_STRING).unwrap();
+        rdb.string(b"old").unwrap();
+        rdb.string(b"v").unwrap();
+        rdb.byte(TYPE_STRING).unwrap();
+        rdb.string(b"kept").unwrap();
+        rdb.string(b"v").unwrap();
+        rdb.byte(OPCODE_EOF).unwrap();
+        rdb.out.extend_from_slice(&[0; 8]);
+        let loaded = read_rdb(&rdb.out[..]).unwrap();
+        assert_eq!(
+            loaded.sets.keys().collect::<Vec<_>>(),
+            vec![&b"kept".to_vec()]
+        );
+    }
+
+    // Files built by tests/fixtures/rdb/generate.py, not dumped by Redis.
+    fn fixture(name: &str) -> Snapshot {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/rdb")
+            .join(name);
+        read_rdb_with(BufReader::new(File::open(path).unwrap()), true).unwrap()
+    }
+
+    fn strings(pairs: &[(&str, &str)]) -> HashMap<Vec<u8>, Vec<u8>> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    fn zset(pairs: &[(&str, f64)]) -> SortedSet {
+        let mut zset = SortedSet::new();
+        for (member, score) in pairs {
+            zset.insert(member.as_bytes().to_vec(), *score);
+        }
+        zset
+    }
+
+    fn list(elements: &[&str]) -> VecDeque<Vec<u8>> {
+        elements.iter().map(|e| e.as_bytes().to_vec()).collect()
+    }
+
+    fn set(members: &[&str]) -> HashSet<Vec<u8>> {
+        members.iter().map(|m| m.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn test_synthetic_rdb9_ziplists() {
+        let loaded = fixture("synthetic-rdb9-ziplists.rdb");
+        let lzf = "rustis ".repeat(30);
+        assert_eq!(
+            loaded.sets,
+            strings(&[
+                ("str", "hello"),
+                ("int", "12345"),
+                ("lzf", &lzf),
+                ("ttl", "later")
+            ])
+        );
+        assert_eq!(
+            loaded.expires,
+            HashMap::from([(b"ttl".to_vec(), 4102444800000)])
+        );
+        assert_eq!(loaded.hsets.len(), 2);
+        assert_eq!(
+            loaded.hsets[&b"hash:zl".to_vec()],
+            strings(&[
+                ("name", "rustis"),
+                ("count", "7"),
+                ("neg", "-1234567"),
+                ("big", "5000000000")
+            ])
+        );
+        assert_eq!(
+            loaded.hsets[&b"hash:ht".to_vec()],
+            strings(&[("a", "1"), ("b", "two")])
+        );
+        assert_eq!(loaded.zsets.len(), 2);
+        assert_eq!(
+            loaded.zsets[&b"zset:zl".to_vec()],
+            zset(&[("a", 1.0), ("b", 2.5), ("c", f64::NEG_INFINITY)])
+        );
+        assert_eq!(
+            loaded.zsets[&b"zset:sl".to_vec()],
+            zset(&[("m1", 0.5), ("m2", 100.0)])
+        );
+        assert!(loaded.jsons.is_empty());
+        assert_eq!(loaded.lists[&b"list:ql".to_vec()], list(&["x", "42", "y"]));
+        assert_eq!(loaded.ssets.len(), 2);
+        assert_eq!(
+            loaded.ssets[&b"set:is".to_vec()],
+            set(&["1", "2", "300000"])
+        );
+        assert_eq!(loaded.ssets[&b"set:ht".to_vec()], set(&["a", "b"]));
+    }
+
+    #[test]
+    fn test_synthetic_rdb11_listpacks() {
+        let loaded = fixture("synthetic-rdb11-listpacks.rdb");
+        assert_eq!(loaded.sets, strings(&[("ttl", "later"), ("str", "hello")]));
+        let long = "x".repeat(100);
+        assert_eq!(
+            loaded.hsets[&b"hash:lp".to_vec()],
+            strings(&[
+                ("f1", "v1"),
+                ("small", "7"),
+                ("neg", "-100"),
+                ("mid", "70000"),
+                ("long", &long)
+            ])
+        );
+        assert_eq!(
+            loaded.zsets[&b"zset:lp".to_vec()],
+            zset(&[("a", 1.0), ("b", 1.5), ("c", f64::INFINITY)])
+        );
+        // A packed quicklist node and a plain one.
+        assert_eq!(
+            loaded.lists[&b"list:ql2".to_vec()],
+            list(&["a", "1", "plainvalue"])
+        );
+        assert_eq!(loaded.ssets[&b"set:lp".to_vec()], set(&["x", "5"]));
+        assert_eq!(
+            loaded.ssets[&b"set:is".to_vec()],
+            set(&["-1", "5000000000"])
+        );
+        assert_eq!(loaded.len(), 7);
+    }
+
+    #[test]
+    fn test_synthetic_rdb6_zipmap() {
+        let loaded = fixture("synthetic-rdb6-zipmap.rdb");
+        assert_eq!(
+            loaded.hsets[&b"hash:zm".to_vec()],
+            strings(&[("f", "v"), ("name", "rustis")])
+        );
+        assert_eq!(loaded.sets, strings(&[("str", "hello")]));
+    }
+
+    #[test]
+    fn test_data_rustis_cannot_keep_is_refused() {
+        // A function library and a string, with checksums turned off.
+        let mut bytes = b"REDIS0010".to_vec();
+        bytes.extend([OPCODE_FUNCTION2, 4]);
+        bytes.extend(b"code");
+        bytes.extend([TYPE_STRING, 1, b's', 1, b'v', OPCODE_EOF]);
+        bytes.extend([0; 8]);
+        let err = read_rdb_with(&bytes[..], false).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "the RDB file holds data rustis cannot keep (1 function libraries); \
+             start with --rdb-load-lossy yes to load the rest anyway"
+        );
+        let loaded = read_rdb_with(&bytes[..], true).unwrap();
+        assert_eq!(loaded.sets, strings(&[("s", "v")]));
+    }
+
+    #[test]
+    fn test_compact_encodings_reject_corruption() {
+        // A listpack holding "a" and 1, then with its total length off by one.
+        let listpack = [12, 0, 0, 0, 2, 0, 0x81, b'a', 2, 1, 1, 0xff];
+        assert_eq!(
+            listpack_entries(&listpack).unwrap(),
+            vec![b"a".to_vec(), b"1".to_vec()]
+        );
+        let mut wrong_total = listpack;
+        wrong_total[0] += 1;
+        assert!(listpack_entries(&wrong_total).is_err());
+        assert!(listpack_entries(&listpack[..listpack.len() - 1]).is_err());
+
+        let ziplist = [13, 0, 0, 0, 10, 0, 0, 0, 1, 0, 0, 0xf2, 0xff];
+        assert_eq!(ziplist_entries(&ziplist).unwrap(), vec![b"1".to_vec()]);
+        let mut wrong_count = ziplist;
+        wrong_count[8] = 2;
+        assert!(ziplist_entries(&wrong_count).is_err());
+
+        assert!(intset_entries(&[3, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0]).is_err());
+        assert!(pairs(vec![b"field".to_vec()]).is_err());
+    }
+
+    #[test]
+    fn test_rejects_newer_versions() {
+        assert!(read_rdb(&b"REDIS0099\xff"[..]).is_err());
+        assert!(read_rdb(&b"NOTRDB009"[..]).is_err());
+    }
+
+    #[test]
+    fn test_save_due() {
+        let rules = [(3600, 1), (300, 100)];
+        assert!(!save_due(&rules, 0, 4000));
+        assert!(save_due(&rules, 1, 3600));
+        assert!(!save_due(&rules, 99, 300));
+        assert!(save_due(&rules, 100, 300));
+        assert!(!save_due(&[], 1000, 10000));
+    }
+}
@@ -0,0 +1,1077 @@
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, LazyLock, Mutex};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use crate::aof::{self, Aof};
+use crate::config;
+use crate::handlers::{Snapshot, EXECUTION, HANDLERS};
+use crate::message::Message;
+use crate::message::Message::*;
+use crate::rdb::{self, read_rdb, write_rdb};
+use crate::resp::Resp;
+use crate::tcp_handler::Client;
+
+// A replica drops the link after this long without hearing from its
+// primary, which pings every REPL_PING_PERIOD while replicas are attached.
+const REPL_TIMEOUT: Duration = Duration::from_secs(60);
+const REPL_PING_PERIOD: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// Replicas acknowledge what they applied this often, and on REPLCONF GETACK.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+// Local fsyncs wake no one, so WAITAOF checks on them this often.
+const WAIT_POLL: Duration = Duration::from_millis(10);
+// Writes a replica may fall behind by before it is disconnected, like
+// Redis's client-output-buffer-limit for replicas. It then resumes with
+// PSYNC, from the backlog if that still covers its offset.
+const REPLICA_MAX_PENDING: usize = 100_000;
+
+// The write half of a replica's connection.
+pub trait ReplicaStream: Write + Send {
+    // Closes the whole connection, so the replica notices and reconnects.
+    fn close(&self);
+}
+
+impl ReplicaStream for TcpStream {
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+// The last `size` bytes of the replication stream.
+struct Backlog {
+    data: VecDeque<u8>,
+    size: usize,
+    // Offset of the stream's last byte, i.e. bytes fed since the start.
+    offset: u64,
+}
+
+impl Backlog {
+    fn new(size: usize, offset: u64) -> Self {
+        Backlog {
+            data: VecDeque::new(),
+            size,
+            offset,
+        }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        self.data.extend(bytes);
+        self.offset += bytes.len() as u64;
+        if self.data.len() > self.size {
+            self.data.drain(..self.data.len() - self.size);
+        }
+    }
+
+    // The stream after `offset`, if the backlog still holds all of it.
+    fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        let start = self.offset - self.data.len() as u64;
+        if offset < start || offset > self.offset {
+            return None;
+        }
+        Some(
+            self.data
+                .range((offset - start) as usize..)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+struct Replica {
+    id: u64,
+    // Address the replica's clients would use: its IP and listening port.
+    addr: String,
+    port: u16,
+    stream: SyncSender<Arc<Vec<u8>>>,
+    // From REPLCONF ACK: how far the replica applied the stream, and how
+    // far its AOF is fsynced.
+    ack_offset: u64,
+    fsynced_offset: u64,
+    last_ack: Instant,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LinkState {
+    Connect,
+    Connecting,
+    Sync,
+    Connected,
+}
+
+impl LinkState {
+    fn name(self) -> &'static str {
+        match self {
+            LinkState::Connect => "connect",
+            LinkState::Connecting => "connecting",
+            LinkState::Sync => "sync",
+            LinkState::Connected => "connected",
+        }
+    }
+}
+
+// Set while this server replicates from a primary.
+struct PrimaryLink {
+    host: String,
+    port: u16,
+    state: LinkState,
+    generation: u64,
+    socket: Option<TcpStream>,
+}
+
+struct Replication {
+    replid: String,
+    // The id this server replicated under before its last promotion, and
+    // the offset up to which it is valid, so replicas of the old primary
+    // can continue from here.
+    replid2: String,
+    replid2_offset: u64,
+    backlog: Backlog,
+    replicas: Vec<Replica>,
+    primary: Option<PrimaryLink>,
+    // As a replica, for commands from the primary whose AOF write may not
+    // be fsynced yet: the AOF offset after the write, and the stream
+    // offset before the command.
+    unsynced: VecDeque<(u64, u64)>,
+}
+
+static REPLICATION: LazyLock<Mutex<Replication>> = LazyLock::new(|| {
+    Mutex::new(Replication {
+        replid: new_replid(),
+        replid2: "0".repeat(40),
+        replid2_offset: 0,
+        backlog: Backlog::new(config::repl_backlog_size(), 0),
+        replicas: Vec::new(),
+        primary: None,
+        unsynced: VecDeque::new(),
+    })
+});
+
+static NEXT_REPLICA_ID: AtomicU64 = AtomicU64::new(1);
+// Bumped whenever the primary changes, telling old link threads to stop.
+static LINK_GENERATION: AtomicU64 = AtomicU64::new(0);
+// Signalled on every acknowledgement from a replica.
+static ACKS: Condvar = Condvar::new();
+
+// 40 random hex characters.
+pub fn new_replid() -> String {
+    let state = RandomState::new();
+    (0..3)
+        .map(|i| format!("{:016x}", state.hash_one(i)))
+        .collect::<String>()[..40]
+        .to_string()
+}
+
+// Adds a message to the replication stream: the backlog, and every
+// attached replica. A replica REPLICA_MAX_PENDING writes behind is
+// dropped, which ends its stream once the writer catches up. Returns the
+// stream's offset after it.
+pub fn feed(msg: &Message) -> u64 {
+    feed_locked(&mut REPLICATION.lock().unwrap(), msg)
+}
+
+fn feed_locked(state: &mut Replication, msg: &Message) -> u64 {
+    let bytes = Arc::new(msg.marshal());
+    state.backlog.feed(&bytes);
+    state.replicas.retain(|replica| {
+        let sent = replica.stream.try_send(bytes.clone());
+        if let Err(TrySendError::Full(_)) = sent {
+            println!(
+                "Replica {}:{} is {} writes behind, disconnecting it",
+                replica.addr, replica.port, REPLICA_MAX_PENDING
+            );
+        }
+        sent.is_ok()
+    });
+    state.backlog.offset
+}
+
+// Bytes fed to the replication stream so far.
+pub fn offset() -> u64 {
+    REPLICATION.lock().unwrap().backlog.offset
+}
+
+// Whether this server follows a primary.
+pub fn is_replica() -> bool {
+    REPLICATION.lock().unwrap().primary.is_some()
+}
+
+// Whether writes from clients must be refused.
+pub fn read_only_replica() -> bool {
+    REPLICATION.lock().unwrap().primary.is_some() && config::replica_read_only()
+}
+
+pub fn replconf(args: &[Message], client: &mut Client) -> Option<Message> {
+    if !args.len().is_multiple_of(2) || args.is_empty() {
+        return Some(Message::error("ERR syntax error"));
+    }
+    let mut ack = None;
+    let mut fack = 0;
+    for pair in args.chunks(2) {
+        let (Bulk(option), Bulk(value)) = (&pair[0], &pair[1]) else {
+            return Some(Message::error("ERR syntax error"));
+        };
+        let value = String::from_utf8_lossy(value);
+        match String::from_utf8_lossy(option).to_lowercase().as_str() {
+            "listening-port" => match config::parse_port(&value) {
+                Ok(port) => client.listening_port = Some(port),
+                Err(_) => return Some(Message::error("ERR value is not a valid port")),
+            },
+            "capa" | "ip-address" => {}
+            "ack" | "fack" if client.replica.is_some() => {
+                let Ok(offset) = value.parse::<u64>() else {
+                    return None;
+                };
+                if option.eq_ignore_ascii_case(b"ack") {
+                    ack = Some(offset);
+                } else {
+                    fack = offset;
+                }
+            }
+            option => {
+                return Some(Message::error(format!(
+                    "ERR Unrecognized REPLCONF option: {}",
+                    option
+                )))
+            }
+        }
+    }
+    // Acknowledgements from replicas get no reply.
+    if let (Some(ack), Some(id)) = (ack, client.replica) {
+        let mut state = REPLICATION.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.ack_offset = replica.ack_offset.max(ack);
+            replica.fsynced_offset = replica.fsynced_offset.max(fack);
+            replica.last_ack = Instant::now();
+        }
+        ACKS.notify_all();
+        return None;
+    }
+    Some(Message::simple("OK"))
+}
+
+pub fn wait(args: &[Message], client: &Client) -> Message {
+    if REPLICATION.lock().unwrap().primary.is_some() {
+        return Message::error("ERR WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated.");
+    }
+    let [numreplicas, timeout] = args else {
+        return Message::error("ERR wrong number of arguments for 'wait' command");
+    };
+    let numreplicas = match parse_count(numreplicas) {
+        Ok(numreplicas) => numreplicas,
+        Err(err) => return err,
+    };
+    let timeout = match parse_timeout(timeout) {
+        Ok(timeout) => timeout,
+        Err(err) => return err,
+    };
+    let (_, replicas) = wait_for_acks(client, None, 0, numreplicas, timeout, false);
+    Message::integer(replicas as i64)
+}
+
+pub fn waitaof(args: &[Message], client: &Client, aof: Option<&Aof>) -> Message {
+    if REPLICATION.lock().unwrap().primary.is_some() {
+        return Message::error("ERR WAITAOF cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated.");
+    }
+    let [numlocal, numreplicas, timeout] = args else {
+        return Message::error("ERR wrong number of arguments for 'waitaof' command");
+    };
+    let (numlocal, numreplicas, timeout) = match (
+        parse_count(numlocal),
+        parse_count(numreplicas),
+        parse_timeout(timeout),
+    ) {
+        (Ok(numlocal), Ok(numreplicas), Ok(timeout)) => (numlocal, numreplicas, timeout),
+        (Err(err), _, _) | (_, Err(err), _) | (_, _, Err(err)) => return err,
+    };
+    if numlocal > 0 && aof.is_none() {
+        return Message::error(
+            "ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled.",
+        );
+    }
+    let (local, replicas) = wait_for_acks(client, aof, numlocal, numreplicas, timeout, true);
+    Message::array(vec![
+        Message::integer(local as i64),
+        Message::integer(replicas as i64),
+    ])
+}
+
+fn parse_count(value: &Message) -> Result<u64, Message> {
+    let Bulk(value) = value else {
+        return Err(Message::error(
+            "ERR value is not an integer or out of range",
+        ));
+    };
+    match String::from_utf8_lossy(value).parse::<i64>() {
+        Ok(count) if count >= 0 => Ok(count as u64),
+        Ok(_) => Err(Message::error(
+            "ERR value is out of range, must be positive",
+        )),
+        Err(_) => Err(Message::error(
+            "ERR value is not an integer or out of range",
+        )),
+    }
+}
+
+// A timeout in milliseconds; 0 waits forever.
+fn parse_timeout(value: &Message) -> Result<Option<Duration>, Message> {
+    let Bulk(value) = value else {
+        return Err(Message::error(
+            "ERR timeout is not an integer or out of range",
+        ));
+    };
+    match String::from_utf8_lossy(value).parse::<i64>() {
+        Ok(0) => Ok(None),
+        Ok(ms) if ms > 0 => Ok(Some(Duration::from_millis(ms as u64))),
+        Ok(_) => Err(Message::error("ERR timeout is negative")),
+        Err(_) => Err(Message::error(
+            "ERR timeout is not an integer or out of range",
+        )),
+    }
+}
+
+// Blocks the calling client until its last write is fsynced locally
+// `numlocal` times (0 or 1) and acknowledged by `numreplicas` replicas, or
+// the timeout passes. Acknowledgements count once the replica applied the
+// write, or with `fsynced` once its AOF has it on disk. Returns how many
+// of each there were.
+fn wait_for_acks(
+    client: &Client,
+    aof: Option<&Aof>,
+    numlocal: u64,
+    numreplicas: u64,
+    timeout: Option<Duration>,
+    fsynced: bool,
+) -> (u64, u64) {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut asked = false;
+    let mut state = REPLICATION.lock().unwrap();
+    loop {
+        let local = aof.is_some_and(|aof| aof.synced_offset() >= client.aof_offset) as u64;
+        let replicas = state
+            .replicas
+            .iter()
+            .filter(|replica| {
+                let acked = if fsynced {
+                    replica.fsynced_offset
+                } else {
+                    replica.ack_offset
+                };
+                acked >= client.repl_offset
+            })
+            .count() as u64;
+        let now = Instant::now();
+        if (local >= numlocal && replicas >= numreplicas)
+            || deadline.is_some_and(|deadline| now >= deadline)
+        {
+            return (local, replicas);
+        }
+        // Rather than wait for the next periodic acknowledgement.
+        if !asked && replicas < numreplicas {
+            feed_locked(&mut state, &Message::command(&["REPLCONF", "GETACK", "*"]));
+            asked = true;
+        }
+        let poll = deadline.map_or(WAIT_POLL, |deadline| (deadline - now).min(WAIT_POLL));
+        state = ACKS.wait_timeout(state, poll).unwrap().0;
+    }
+}
+
+// Answers PSYNC <replid> <offset> from a replica: +CONTINUE and the part
+// of the stream it missed when the backlog still has it, +FULLRESYNC and a
+// snapshot otherwise. From then on another thread writes the replication
+// stream to `stream`, so the connection gets no more replies. Must be
+// called under the execution lock, which keeps the snapshot and the offset
+// in step.
+pub fn psync(
+    args: &[Message],
+    client: &mut Client,
+    stream: Box<dyn ReplicaStream>,
+) -> Option<Message> {
+    let [Bulk(replid), Bulk(offset)] = args else {
+        return Some(Message::error(
+            "ERR wrong number of arguments for 'psync' command",
+        ));
+    };
+    let replid = String::from_utf8_lossy(replid).into_owned();
+    // Replicas ask for the first byte they are missing.
+    let wanted = String::from_utf8_lossy(offset)
+        .parse::<i64>()
+        .map_or(None, |offset| u64::try_from(offset - 1).ok());
+
+    let mut state = REPLICATION.lock().unwrap();
+    let continues = wanted.filter(|&wanted| {
+        replid == state.replid || (replid == state.replid2 && wanted <= state.replid2_offset)
+    });
+    let (first, snapshot) = match continues.and_then(|wanted| state.backlog.since(wanted)) {
+        Some(missed) => {
+            let mut first = format!("+CONTINUE {}\r\n", state.replid).into_bytes();
+            first.extend(missed);
+            (first, None)
+        }
+        None => (
+            format!("+FULLRESYNC {} {}\r\n", state.replid, state.backlog.offset).into_bytes(),
+            Some(Snapshot::take()),
+        ),
+    };
+    let id = NEXT_REPLICA_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = sync_channel::<Arc<Vec<u8>>>(REPLICA_MAX_PENDING);
+    let ip = client.addr.rsplit_once(':').map_or("", |(ip, _)| ip);
+    state.replicas.push(Replica {
+        id,
+        addr: ip.to_string(),
+        port: client.listening_port.unwrap_or(0),
+        stream: sender,
+        ack_offset: 0,
+        fsynced_offset: 0,
+        last_ack: Instant::now(),
+    });
+    drop(state);
+    client.replica = Some(id);
+    println!(
+        "Replica {} asks for synchronization, {}",
+        client.addr,
+        if snapshot.is_some() {
+            "starting a full resync"
+        } else {
+            "continuing from the backlog"
+        }
+    );
+
+    spawn(move || {
+        let mut stream = stream;
+        let result = (|| -> Result<(), io::Error> {
+            stream.write_all(&first)?;
+            if let Some(snapshot) = snapshot {
+                let mut rdb = Vec::new();
+                write_rdb(&snapshot, &mut rdb, false)?;
+                stream.write_all(format!("${}\r\n", rdb.len()).as_bytes())?;
+                stream.write_all(&rdb)?;
+            }
+            for bytes in receiver {
+                stream.write_all(&bytes)?;
+            }
+            Ok(())
+        })();
+        if let Err(err) = result {
+            println!("Connection with replica lost: {err}");
+        }
+        stream.close();
+        replica_disconnected(id);
+    });
+    None
+}
+
+pub fn replica_disconnected(id: u64) {
+    REPLICATION
+        .lock()
+        .unwrap()
+        .replicas
+        .retain(|replica| replica.id != id);
+}
+
+pub fn replicaof(args: Vec<Message>) -> Message {
+    let [Bulk(host), Bulk(port)] = args.as_slice() else {
+        return Message::error("ERR wrong number of arguments for 'replicaof' command");
+    };
+    let host = String::from_utf8_lossy(host).into_owned();
+    let port = String::from_utf8_lossy(port).into_owned();
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        promote();
+        return Message::simple("OK");
+    }
+    let Ok(port) = config::parse_port(&port) else {
+        return Message::error("ERR Invalid master port");
+    };
+    let state = REPLICATION.lock().unwrap();
+    if let Some(primary) = &state.primary {
+        if primary.host == host && primary.port == port {
+            return Message::simple("OK Already connected to specified master");
+        }
+    }
+    drop(state);
+    start_replication(host, port);
+    Message::simple("OK")
+}
+
+// Stops replicating. The current id becomes the secondary one, so replicas
+// that shared the old primary can continue with a partial resync.
+fn promote() {
+    let mut state = REPLICATION.lock().unwrap();
+    let Some(primary) = state.primary.take() else {
+        return;
+    };
+    LINK_GENERATION.fetch_add(1, Ordering::Relaxed);
+    if let Some(socket) = primary.socket {
+        let _ = socket.shutdown(Shutdown::Both);
+    }
+    state.replid2 = std::mem::replace(&mut state.replid, new_replid());
+    state.replid2_offset = state.backlog.offset;
+    println!("Primary mode enabled, new replication id {}", state.replid);
+}
+
+pub fn start_replication(host: String, port: u16) {
+    let mut state = REPLICATION.lock().unwrap();
+    let generation = LINK_GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    if let Some(socket) = state.primary.take().and_then(|primary| primary.socket) {
+        let _ = socket.shutdown(Shutdown::Both);
+    }
+    state.primary = Some(PrimaryLink {
+        host: host.clone(),
+        port,
+        state: LinkState::Connect,
+        generation,
+        socket: None,
+    });
+    drop(state);
+    println!("Connecting to primary {host}:{port}");
+    spawn(move || run_link(host, port, generation));
+}
+
+// Updates the link state, returning false once the link is stale.
+fn set_link_state(generation: u64, link_state: LinkState, socket: Option<&TcpStream>) -> bool {
+    let mut state = REPLICATION.lock().unwrap();
+    match &mut state.primary {
+        Some(primary) if primary.generation == generation => {
+            primary.state = link_state;
+            if let Some(socket) = socket {
+                primary.socket = socket.try_clone().ok();
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+fn run_link(host: String, port: u16, generation: u64) {
+    while set_link_state(generation, LinkState::Connecting, None) {
+        let result = sync_with_primary(&host, port, generation);
+        // A replaced link is shut down on purpose, which is no failure.
+        if !set_link_state(generation, LinkState::Connect, None) {
+            break;
+        }
+        if let Err(err) = result {
+            println!("Replication with {host}:{port} failed: {err}");
+        }
+        sleep(RECONNECT_DELAY);
+    }
+}
+
+fn request<R: io::Read>(
+    writer: &mut TcpStream,
+    resp: &mut Resp<R>,
+    parts: &[&str],
+) -> Result<Message, io::Error> {
+    writer.write_all(&Message::command(parts).marshal())?;
+    match resp.read()? {
+        Error(err) => Err(io::Error::other(err)),
+        reply => Ok(reply),
+    }
+}
+
+fn sync_with_primary(host: &str, port: u16, generation: u64) -> Result<(), io::Error> {
+    let socket = TcpStream::connect((host, port))?;
+    socket.set_read_timeout(Some(REPL_TIMEOUT))?;
+    if !set_link_state(generation, LinkState::Connecting, Some(&socket)) {
+        return Ok(());
+    }
+    let mut writer = socket.try_clone()?;
+    let mut resp = Resp::new(BufReader::new(socket));
+
+    request(&mut writer, &mut resp, &["PING"])?;
+    let listening_port = config::port().to_string();
+    request(
+        &mut writer,
+        &mut resp,
+        &["REPLCONF", "listening-port", &listening_port],
+    )?;
+    request(&mut writer, &mut resp, &["REPLCONF", "capa", "psync2"])?;
+
+    let (replid, offset) = {
+        let state = REPLICATION.lock().unwrap();
+        (state.replid.clone(), state.backlog.offset)
+    };
+    set_link_state(generation, LinkState::Sync, None);
+    let reply = request(
+        &mut writer,
+        &mut resp,
+        &["PSYNC", &replid, &(offset + 1).to_string()],
+    )?;
+    let Simple(reply) = reply else {
+        return Err(io::Error::other("unexpected reply to PSYNC"));
+    };
+    match reply.split(' ').collect::<Vec<_>>().as_slice() {
+        ["FULLRESYNC", replid, offset] => {
+            let offset = offset
+                .parse()
+                .map_err(|_| io::Error::other("invalid FULLRESYNC offset"))?;
+            let payload = resp.read_payload()?;
+            let snapshot = read_rdb(&payload[..])?;
+            let _execution = EXECUTION.lock().unwrap();
+            if !set_link_state(generation, LinkState::Connected, None) {
+                return Ok(());
+            }
+            let keys = snapshot.len();
+            snapshot.replace_all();
+            let mut state = REPLICATION.lock().unwrap();
+            state.replid = replid.to_string();
+            state.replid2 = "0".repeat(40);
+            state.replid2_offset = 0;
+            state.backlog = Backlog::new(config::repl_backlog_size(), offset);
+            state.unsynced.clear();
+            // Replicas of this server hold the old dataset and must resync.
+            for replica in state.replicas.drain(..) {
+                drop(replica.stream);
+            }
+            drop(state);
+            // The AOF still describes the old dataset until rewritten.
+            if let Some(aof) = aof::registered() {
+                if let Err(err) = aof.rewrite() {
+                    println!("AOF rewrite after sync failed: {err}");
+                }
+            }
+            println!("Full resync from {host}:{port} done, {keys} keys loaded");
+        }
+        ["CONTINUE", new_replid @ ..] => {
+            // A primary that was promoted since continues under a new id.
+            let mut state = REPLICATION.lock().unwrap();
+            if let [new_replid] = new_replid {
+                if *new_replid != state.replid {
+                    state.replid2 = std::mem::replace(&mut state.replid, new_replid.to_string());
+                    state.replid2_offset = state.backlog.offset;
+                }
+            }
+            drop(state);
+            if !set_link_state(generation, LinkState::Connected, None) {
+                return Ok(());
+            }
+            println!("Partial resync from {host}:{port} accepted");
+        }
+        _ => {
+            return Err(io::Error::other(format!(
+                "unexpected PSYNC reply {}",
+                reply
+            )))
+        }
+    }
+
+    let writer = Arc::new(Mutex::new(writer));
+    let acks = writer.clone();
+    spawn(move || {
+        while LINK_GENERATION.load(Ordering::Relaxed) == generation {
+            sleep(ACK_PERIOD);
+            if send_ack(&acks).is_err() {
+                break;
+            }
+        }
+    });
+    let result = follow_primary(&mut resp, &writer, generation);
+    // Also stops the thread sending acknowledgements.
+    let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+    result
+}
+
+fn follow_primary<R: io::Read>(
+    resp: &mut Resp<R>,
+    writer: &Mutex<TcpStream>,
+    generation: u64,
+) -> Result<(), io::Error> {
+    // One handle for the whole link, so writes share it like clients do.
+    let mut aof = aof::registered();
+    loop {
+        let msg = resp.read()?;
+        let execution = EXECUTION.lock().unwrap();
+        if LINK_GENERATION.load(Ordering::Relaxed) != generation {
+            return Ok(());
+        }
+        if is_getack(&msg) {
+            feed(&msg);
+            drop(execution);
+            send_ack(writer)?;
+            continue;
+        }
+        let appended = apply(&msg, aof.as_mut())?;
+        let mut state = REPLICATION.lock().unwrap();
+        if let Some(aof_offset) = appended {
+            let offset = state.backlog.offset;
+            state.unsynced.push_back((aof_offset, offset));
+        }
+        feed_locked(&mut state, &msg);
+        drop(state);
+        drop(execution);
+        // With appendfsync always, the fsync waits outside the lock as it
+        // does for clients.
+        if let (Some(aof), Some(offset)) = (&aof, appended) {
+            aof.commit(offset)?;
+        }
+    }
+}
+
+fn is_getack(msg: &Message) -> bool {
+    matches!(msg, Array(parts) if matches!(
+        parts.as_slice(),
+        [Bulk(name), Bulk(option), ..]
+            if name.eq_ignore_ascii_case(b"REPLCONF") && option.eq_ignore_ascii_case(b"GETACK")
+    ))
+}
+
+// Tells the primary how far this replica applied the stream, and how far
+// the AOF has it on disk; that is 0 without an AOF.
+fn send_ack(writer: &Mutex<TcpStream>) -> Result<(), io::Error> {
+    let (offset, fsynced) = {
+        let mut state = REPLICATION.lock().unwrap();
+        let offset = state.backlog.offset;
+        let fsynced = match aof::registered() {
+            Some(aof) => {
+                let synced = aof.synced_offset();
+                while state
+                    .unsynced
+                    .front()
+                    .is_some_and(|&(aof_offset, _)| aof_offset <= synced)
+                {
+                    state.unsynced.pop_front();
+                }
+                state.unsynced.front().map_or(offset, |&(_, before)| before)
+            }
+            None => 0,
+        };
+        (offset, fsynced)
+    };
+    let ack = Message::command(&[
+        "REPLCONF",
+        "ACK",
+        &offset.to_string(),
+        "FACK",
+        &fsynced.to_string(),
+    ]);
+    writer.lock().unwrap().write_all(&ack.marshal())
+}
+
+// Runs a command from the primary's stream, appending writes to the AOF.
+// Returns the AOF offset to commit after the write, if there was one.
+fn apply(msg: &Message, aof: Option<&mut Aof>) -> Result<Option<u64>, io::Error> {
+    let Array(parts) = msg else {
+        return Err(io::Error::other("expected a command from the primary"));
+    };
+    let Some(Bulk(name)) = parts.first() else {
+        return Err(io::Error::other("expected a command from the primary"));
+    };
+    let name = String::from_utf8_lossy(name).to_uppercase();
+    let Some(handler) = HANDLERS.get(name.as_str()) else {
+        println!("Unknown command {name} from the primary");
+        return Ok(None);
+    };
+    if matches!(handler.call(parts[1..].to_vec()), Error(_)) || !handler.is_write() {
+        return Ok(None);
+    }
+    rdb::DIRTY.fetch_add(1, Ordering::Relaxed);
+    match aof {
+        Some(aof) => Ok(Some(aof.append_message(msg)?)),
+        None => Ok(None),
+    }
+}
+
+// Pings attached replicas so they can tell a quiet primary from a dead one.
+pub fn spawn_replica_pings() {
+    spawn(|| loop {
+        sleep(REPL_PING_PERIOD);
+        let attached = {
+            let state = REPLICATION.lock().unwrap();
+            state.primary.is_none() && !state.replicas.is_empty()
+        };
+        if attached {
+            feed(&Message::command(&["PING"]));
+        }
+    });
+}
+
+pub fn role(args: Vec<Message>) -> Message {
+    if !args.is_empty() {
+        return Message::error("ERR wrong number of arguments for 'role' command");
+    }
+    let state = REPLICATION.lock().unwrap();
+    match &state.primary {
+        None => Message::array(vec![
+            Message::bulk(b"master".to_vec()),
+            Message::integer(state.backlog.offset as i64),
+            Message::array(
+                state
+                    .replicas
+                    .iter()
+                    .map(|replica| {
+                        Message::array(vec![
+                            Message::bulk(replica.addr.clone().into_bytes()),
+                            Message::bulk(replica.port.to_string().into_bytes()),
+                            Message::bulk(replica.ack_offset.to_string().into_bytes()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ]),
+        Some(primary) => Message::array(vec![
+            Message::bulk(b"slave".to_vec()),
+            Message::bulk(primary.host.clone().into_bytes()),
+            Message::integer(primary.port as i64),
+            Message::bulk(primary.state.name().as_bytes().to_vec()),
+            Message::integer(state.backlog.offset as i64),
+        ]),
+    }
+}
+
+// The replication section of INFO.
+pub fn info() -> String {
+    let state = REPLICATION.lock().unwrap();
+    let mut lines = Vec::new();
+    match &state.primary {
+        None => {
+            lines.push("role:master".to_string());
+            lines.push(format!("connected_slaves:{}", state.replicas.len()));
+            for (i, replica) in state.replicas.iter().enumerate() {
+                lines.push(format!(
+                    "slave{}:ip={},port={},state=online,offset={},lag={}",
+                    i,
+                    replica.addr,
+                    replica.port,
+                    replica.ack_offset,
+                    replica.last_ack.elapsed().as_secs()
+                ));
+            }
+        }
+        Some(primary) => {
+            lines.push("role:slave".to_string());
+            lines.push(format!("master_host:{}", primary.host));
+            lines.push(format!("master_port:{}", primary.port));
+            let up = primary.state == LinkState::Connected;
+            lines.push(format!(
+                "master_link_status:{}",
+                if up { "up" } else { "down" }
+            ));
+            lines.push(format!(
+                "master_sync_in_progress:{}",
+                (primary.state == LinkState::Sync) as u8
+            ));
+            lines.push(format!("slave_repl_offset:{}", state.backlog.offset));
+            lines.push(format!("slave_priority:{}", config::replica_priority()));
+            lines.push(format!(
+                "slave_read_only:{}",
+                config::replica_read_only() as u8
+            ));
+            lines.push(format!("connected_slaves:{}", state.replicas.len()));
+        }
+    }
+    let backlog = &state.backlog;
+    lines.push(format!("master_replid:{}", state.replid));
+    lines.push(format!("master_replid2:{}", state.replid2));
+    lines.push(format!("master_repl_offset:{}", backlog.offset));
+    lines.push(format!("second_repl_offset:{}", state.replid2_offset + 1));
+    lines.push(format!("repl_backlog_size:{}", backlog.size));
+    lines.push(format!(
+        "repl_backlog_first_byte_offset:{}",
+        backlog.offset - backlog.data.len() as u64 + 1
+    ));
+    lines.push(format!("repl_backlog_histlen:{}", backlog.data.len()));
+    lines.join("\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog_since() {
+        let mut backlog = Backlog::new(8, 100);
+        assert_eq!(backlog.since(100), Some(vec![]));
+        backlog.feed(b"abcdef");
+        assert_eq!(backlog.since(102), Some(b"cdef".to_vec()));
+        backlog.feed(b"ghij");
+        assert_eq!(backlog.offset, 110);
+        // Only the last 8 bytes are kept.
+        assert_eq!(backlog.since(101), None);
+        assert_eq!(backlog.since(102), Some(b"cdefghij".to_vec()));
+        assert_eq!(backlog.since(110), Some(vec![]));
+        assert_eq!(backlog.since(111), None);
+    }
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ReplicaStream for Captured {
+        fn close(&self) {}
+    }
+
+    fn wait_for(captured: &Captured, expected: &[u8]) -> Vec<u8> {
+        for _ in 0..1000 {
+            let bytes = captured.0.lock().unwrap().clone();
+            if bytes.windows(expected.len()).any(|w| w == expected) {
+                return bytes;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        panic!("{:?} never arrived", String::from_utf8_lossy(expected));
+    }
+
+    #[test]
+    fn test_psync_continues_from_backlog() {
+        let (replid, offset) = {
+            let state = REPLICATION.lock().unwrap();
+            (state.replid.clone(), state.backlog.offset)
+        };
+        let missed = Message::command(&["SET", "{psync}k", "v"]);
+        feed(&missed);
+
+        let captured = Captured::default();
+        let mut client = Client::default();
+        let args = [
+            Message::bulk(replid.clone().into_bytes()),
+            Message::bulk((offset + 1).to_string().into_bytes()),
+        ];
+        assert_eq!(psync(&args, &mut client, Box::new(captured.clone())), None);
+        let bytes = wait_for(&captured, &missed.marshal());
+        assert!(bytes.starts_with(format!("+CONTINUE {}\r\n", replid).as_bytes()));
+
+        let live = Message::command(&["SET", "{psync}k", "w"]);
+        feed(&live);
+        wait_for(&captured, &live.marshal());
+        replica_disconnected(client.replica.unwrap());
+    }
+
+    #[test]
+    fn test_psync_unknown_replid_gets_full_resync() {
+        let captured = Captured::default();
+        let mut client = Client::default();
+        let args = [Message::bulk(b"?".to_vec()), Message::bulk(b"-1".to_vec())];
+        assert_eq!(psync(&args, &mut client, Box::new(captured.clone())), None);
+        let bytes = wait_for(&captured, b"REDIS0009");
+        assert!(bytes.starts_with(b"+FULLRESYNC "));
+        replica_disconnected(client.replica.unwrap());
+    }
+
+    #[test]
+    fn test_slow_replica_is_dropped() {
+        let mut state = Replication {
+            replid: new_replid(),
+            replid2: "0".repeat(40),
+            replid2_offset: 0,
+            backlog: Backlog::new(1024, 0),
+            replicas: Vec::new(),
+            primary: None,
+            unsynced: VecDeque::new(),
+        };
+        let (sender, receiver) = sync_channel(REPLICA_MAX_PENDING);
+        state.replicas.push(Replica {
+            id: 0,
+            addr: "127.0.0.1".to_string(),
+            port: 0,
+            stream: sender,
+            ack_offset: 0,
+            fsynced_offset: 0,
+            last_ack: Instant::now(),
+        });
+        let ping = Message::command(&["PING"]);
+        for _ in 0..REPLICA_MAX_PENDING {
+            feed_locked(&mut state, &ping);
+        }
+        assert_eq!(state.replicas.len(), 1);
+        feed_locked(&mut state, &ping);
+        assert!(state.replicas.is_empty());
+        // What was queued still reaches the replica before its stream ends.
+        assert_eq!(receiver.iter().count(), REPLICA_MAX_PENDING);
+    }
+
+    #[test]
+    fn test_wait_counts_acknowledged_writes() {
+        let captured = Captured::default();
+        let mut replica = Client::default();
+        let args = Message::bulks(&["?", "-1"]);
+        assert_eq!(psync(&args, &mut replica, Box::new(captured.clone())), None);
+
+        let writer = Client {
+            repl_offset: feed(&Message::command(&["SET", "{wait}k", "v"])),
+            ..Client::default()
+        };
+        // Nothing acknowledged yet.
+        assert_eq!(
+            wait(&Message::bulks(&["1", "20"]), &writer),
+            Message::integer(0)
+        );
+
+        let acked = writer.repl_offset.to_string();
+        let acker = spawn(move || {
+            wait_for(&captured, b"GETACK");
+            let ack = Message::bulks(&["ACK", &acked, "FACK", "0"]);
+            assert_eq!(replconf(&ack, &mut replica), None);
+            replica
+        });
+        assert_eq!(
+            wait(&Message::bulks(&["1", "0"]), &writer),
+            Message::integer(1)
+        );
+        // The replica applied the write but has no AOF to fsync it.
+        assert_eq!(
+            waitaof(&Message::bulks(&["0", "1", "20"]), &writer, None),
+            Message::array(vec![Message::integer(0), Message::integer(0)])
+        );
+        replica_disconnected(acker.join().unwrap().replica.unwrap());
+    }
+
+    #[test]
+    fn test_waitaof_local() {
+        let client = Client::default();
+        assert_eq!(
+            waitaof(&Message::bulks(&["1", "0", "0"]), &client, None),
+            Message::error(
+                "ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled."
+            )
+        );
+        assert_eq!(
+            wait(&Message::bulks(&["1", "-1"]), &client),
+            Message::error("ERR timeout is negative")
+        );
+
+        let path = std::env::temp_dir().join(format!("rustis-waitaof-{}.aof", std::process::id()));
+        let mut aof = Aof::from_file(
+            std::fs::File::create(&path).unwrap(),
+            aof::AppendFsync::Always,
+        );
+        let client = Client {
+            aof_offset: aof
+                .append_message(&Message::command(&["SET", "k", "v"]))
+                .unwrap(),
+            ..Client::default()
+        };
+        assert_eq!(
+            waitaof(&Message::bulks(&["1", "0", "10"]), &client, Some(&aof)),
+            Message::array(vec![Message::integer(0), Message::integer(0)])
+        );
+        aof.commit(client.aof_offset).unwrap();
+        assert_eq!(
+            waitaof(&Message::bulks(&["1", "0", "0"]), &client, Some(&aof)),
+            Message::array(vec![Message::integer(1), Message::integer(0)])
+        );
+        drop(aof);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_new_replid() {
+        let id = new_replid();
+        assert_eq!(id.len(), 40);
+        assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(id, new_replid());
+    }
+}
@@ -3,41 +3,92 @@ use std::io::{Error, ErrorKind, Read, Result, Write};
 use crate::message::Message;
 use crate::message::Message::*;
 
+// Bulks are read into a buffer this big at first, which grows as the
+// bytes arrive, so a length the peer made up cannot allocate memory.
+pub const FIRST_READ: usize = 64 * 1024;
+
 pub struct Resp<R> {
     rw: R,
+    offset: u64,
+    max_bulk_len: usize,
 }
 
-impl<R: Read + Write> Resp<R> {
+impl<R> Resp<R> {
     pub fn new(rw: R) -> Resp<R> {
-        Resp { rw }
+        Resp {
+            rw,
+            offset: 0,
+            max_bulk_len: usize::MAX,
+        }
+    }
+
+    // Refuses bulks longer than `max`, like Redis' proto-max-bulk-len.
+    pub fn limit_bulk_len(&mut self, max: usize) {
+        self.max_bulk_len = max;
+    }
+
+    // Bytes consumed so far, i.e. where the next message starts.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.rw
     }
+}
+
+impl<R: Write> Resp<R> {
+    pub fn write(&mut self, message: Message) -> Result<usize> {
+        let bytes = message.marshal();
+        self.rw.write(&bytes)
+    }
+}
 
+impl<R: Read> Resp<R> {
+    // Running out of input before a message starts is reported as "no
+    // bytes"; running out in the middle of one is `UnexpectedEof`.
     pub fn read(&mut self) -> Result<Message> {
         let read_result = self.read_byte();
         match read_result {
-            Ok(b) => match b {
-                b'*' => self.read_array(),
-                b'$' => self.read_bulk(),
-                t => {
-                    println!("Unknown type: {}", String::from_utf8_lossy(&[t]));
-                    Ok(Null)
-                }
-            },
+            Ok(b) => self.read_value(b),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                Err(Error::new(ErrorKind::InvalidInput, "no bytes"))
+            }
             Err(err) => Err(err),
         }
     }
 
-    pub fn write(&mut self, message: Message) -> Result<usize> {
-        let bytes = message.marshal();
-        self.rw.write(&bytes)
+    fn read_value(&mut self, b: u8) -> Result<Message> {
+        match b {
+            b'*' => self.read_array(),
+            b'$' => self.read_bulk(),
+            b'#' => Ok(Annotation(self.read_text()?)),
+            b'+' => Ok(Simple(self.read_text()?)),
+            b'-' => Ok(Error(self.read_text()?)),
+            b':' => self
+                .read_text()?
+                .parse()
+                .map(Integer)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid integer")),
+            t => {
+                println!("Unknown type: {}", String::from_utf8_lossy(&[t]));
+                Ok(Null)
+            }
+        }
     }
 
     fn read_byte(&mut self) -> Result<u8> {
         let mut buf = [0u8; 1];
 
         match self.rw.read(&mut buf) {
-            Ok(0) => Err(Error::new(ErrorKind::InvalidInput, "no bytes")),
-            Ok(_) => Ok(buf[0]),
+            Ok(0) => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "unexpected end of input",
+            )),
+            Ok(_) => {
+                self.offset += 1;
+                Ok(buf[0])
+            }
             Err(err) => Err(err),
         }
     }
@@ -45,24 +96,61 @@ impl<R: Read + Write> Resp<R> {
     fn read_array(&mut self) -> Result<Message> {
         let (_, r) = self.read_integer();
         let array_length = r?;
-        let mut array = Vec::with_capacity(array_length);
+        let mut array = Vec::with_capacity(array_length.min(1024));
         for _ in 0..array_length {
-            let val = match self.read() {
-                Ok(msg) => msg,
-                Err(error) => Message::error(error.to_string()),
-            };
-            array.push(val);
+            let b = self.read_byte()?;
+            array.push(self.read_value(b)?);
         }
         Ok(Message::array(array))
     }
 
-    fn read_bulk(&mut self) -> Result<Message> {
+    // Replication sends the snapshot as `$<len>\r\n` and the bytes with no
+    // CRLF after them.
+    pub fn read_payload(&mut self) -> Result<Vec<u8>> {
+        if self.read_byte()? != b'$' {
+            return Err(Error::new(ErrorKind::InvalidData, "expected '$'"));
+        }
         let (_, r) = self.read_integer();
-        let array_length = r?;
-        let mut bulk = vec![0u8; array_length];
+        self.read_exact(r?)
+    }
+
+    fn read_exact(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(len.min(FIRST_READ));
+        (&mut self.rw).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() < len {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "unexpected end of input",
+            ));
+        }
+        self.offset += len as u64;
+        Ok(bytes)
+    }
+
+    fn read_text(&mut self) -> Result<String> {
+        let mut buf = vec![];
+        self.read_line(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
 
-        let _ = self.rw.read(&mut bulk);
-        let _ = self.read_line(&mut vec![0; 2]); // eat trailing CLRF
+    fn read_bulk(&mut self) -> Result<Message> {
+        let text = self.read_text()?;
+        if text == "-1" {
+            return Ok(Null);
+        }
+        let len = text
+            .parse::<usize>()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid integer"))?;
+        if len > self.max_bulk_len {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid bulk length"));
+        }
+        let bulk = self.read_exact(len)?;
+        if self.read_byte()? != b'\r' || self.read_byte()? != b'\n' {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "missing CRLF after bulk",
+            ));
+        }
         Ok(Message::bulk(bulk))
     }
 
@@ -151,6 +239,81 @@ mod tests {
         assert_eq!(message, Null);
     }
 
+    #[test]
+    fn test_read_replies() {
+        let input = b"+OK\r\n-ERR nope\r\n:-42\r\n$-1\r\n$3\r\nrdb";
+        let mut resp = Resp::new(Cursor::new(input.to_vec()));
+        assert_eq!(resp.read().unwrap(), Message::simple("OK"));
+        assert_eq!(resp.read().unwrap(), Message::error("ERR nope"));
+        assert_eq!(resp.read().unwrap(), Message::integer(-42));
+        assert_eq!(resp.read().unwrap(), Null);
+        assert_eq!(resp.read_payload().unwrap(), b"rdb");
+        assert_eq!(resp.offset(), input.len() as u64);
+    }
+
+    #[test]
+    fn test_read_tracks_offset() {
+        let input = b"$3\r\nfoo\r\n*1\r\n$1\r\nx\r\n";
+        let mut resp = Resp::new(Cursor::new(input.to_vec()));
+        resp.read().unwrap();
+        assert_eq!(resp.offset(), 9);
+        resp.read().unwrap();
+        assert_eq!(resp.offset(), input.len() as u64);
+    }
+
+    #[test]
+    fn test_read_annotation() {
+        let mut resp = Resp::new(Cursor::new(b"#TS:42\r\n$1\r\nx\r\n".to_vec()));
+        assert_eq!(resp.read().unwrap(), Message::timestamp(42));
+        assert_eq!(resp.offset(), 8);
+        assert_eq!(resp.read().unwrap(), Message::bulk(b"x".to_vec()));
+    }
+
+    #[test]
+    fn test_read_end_of_input() {
+        let mut resp = Resp::new(Cursor::new(Vec::new()));
+        let err = resp.read().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "no bytes");
+    }
+
+    #[test]
+    fn test_read_truncated_message() {
+        for input in [
+            &b"*2\r\n$3\r\nfoo\r\n"[..],
+            b"$5\r\nhel",
+            b"$3\r\nfoo\r",
+            b"*1\r",
+        ] {
+            let mut resp = Resp::new(Cursor::new(input.to_vec()));
+            let err = resp.read().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_read_bulk_length_is_not_trusted() {
+        // Claims a terabyte and ends: nothing that size is allocated.
+        let mut resp = Resp::new(Cursor::new(b"$1000000000000\r\nab".to_vec()));
+        assert_eq!(resp.read().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        let mut resp = Resp::new(Cursor::new(b"$4\r\nabcd\r\n$3\r\nabc\r\n".to_vec()));
+        resp.limit_bulk_len(3);
+        let err = resp.read().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "invalid bulk length");
+
+        let mut resp = Resp::new(Cursor::new(b"$3\r\nabc\r\n".to_vec()));
+        resp.limit_bulk_len(3);
+        assert_eq!(resp.read().unwrap(), Message::bulk(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn test_read_bulk_without_crlf() {
+        let mut resp = Resp::new(Cursor::new(b"$3\r\nfooxx".to_vec()));
+        assert_eq!(resp.read().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
     #[test]
     fn test_read_byte_empty() {
         let cursor = Cursor::new(Vec::new());
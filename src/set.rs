@@ -0,0 +1,92 @@
+use std::collections::HashSet;
+
+use crate::handlers::SSetMap;
+use crate::message::Message;
+use crate::message::Message::*;
+
+pub fn sadd(args: Vec<Message>, ssets: &SSetMap) -> Message {
+    match args.as_slice() {
+        [Bulk(key), members @ ..] if !members.is_empty() => {
+            let mut added = Vec::new();
+            for member in members {
+                match member {
+                    Bulk(member) => added.push(member.clone()),
+                    _ => return Message::error("ERR syntax error"),
+                }
+            }
+            let mut ssets = ssets.lock().unwrap();
+            let set = ssets.entry(key.clone()).or_default();
+            let added = added.into_iter().filter(|m| set.insert(m.clone())).count();
+            Message::integer(added as i64)
+        }
+        _ => Message::error("ERR wrong number of arguments for 'sadd' command"),
+    }
+}
+
+pub fn scard(args: Vec<Message>, ssets: &SSetMap) -> Message {
+    match args.as_slice() {
+        [Bulk(key)] => {
+            let ssets = ssets.lock().unwrap();
+            Message::integer(ssets.get(key).map(HashSet::len).unwrap_or(0) as i64)
+        }
+        _ => Message::error("ERR wrong number of arguments for 'scard' command"),
+    }
+}
+
+// In no particular order, as in Redis.
+pub fn smembers(args: Vec<Message>, ssets: &SSetMap) -> Message {
+    match args.as_slice() {
+        [Bulk(key)] => {
+            let ssets = ssets.lock().unwrap();
+            Message::array(
+                ssets
+                    .get(key)
+                    .into_iter()
+                    .flatten()
+                    .map(|member| Message::bulk(member.clone()))
+                    .collect(),
+            )
+        }
+        _ => Message::error("ERR wrong number of arguments for 'smembers' command"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_sadd_counts_new_members() {
+        let ssets = Mutex::new(HashMap::new());
+        assert_eq!(
+            sadd(Message::bulks(&["s", "a", "b", "a"]), &ssets),
+            Message::integer(2)
+        );
+        assert_eq!(
+            sadd(Message::bulks(&["s", "b", "c"]), &ssets),
+            Message::integer(1)
+        );
+        assert_eq!(scard(Message::bulks(&["s"]), &ssets), Message::integer(3));
+        assert_eq!(
+            scard(Message::bulks(&["nosuch"]), &ssets),
+            Message::integer(0)
+        );
+    }
+
+    #[test]
+    fn test_smembers() {
+        let ssets = Mutex::new(HashMap::new());
+        sadd(Message::bulks(&["s", "a", "b"]), &ssets);
+        let Array(mut members) = smembers(Message::bulks(&["s"]), &ssets) else {
+            panic!("expected array");
+        };
+        members.sort_by_key(|member| format!("{:?}", member));
+        assert_eq!(members, Message::bulks(&["a", "b"]));
+        assert_eq!(
+            smembers(Message::bulks(&["nosuch"]), &ssets),
+            Message::array(Vec::new())
+        );
+    }
+}
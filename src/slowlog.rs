@@ -0,0 +1,212 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::handlers::parse_int;
+use crate::message::Message;
+use crate::message::Message::*;
+
+// Like Redis, entries keep at most this many arguments of at most this many
+// bytes each, so a huge command cannot pin memory in the log.
+const MAX_ARGC: usize = 32;
+const MAX_STRING: usize = 128;
+
+struct Entry {
+    id: u64,
+    // Unix seconds the command was logged at.
+    time: u64,
+    duration: Duration,
+    argv: Vec<Vec<u8>>,
+    addr: String,
+    name: String,
+}
+
+struct Slowlog {
+    // Newest first.
+    entries: VecDeque<Entry>,
+    next_id: u64,
+}
+
+static SLOWLOG: Mutex<Slowlog> = Mutex::new(Slowlog::new());
+
+fn truncate(argv: &[Message]) -> Vec<Vec<u8>> {
+    let mut kept = Vec::new();
+    for (i, arg) in argv.iter().enumerate() {
+        if i == MAX_ARGC - 1 && argv.len() > MAX_ARGC {
+            let more = argv.len() - i;
+            kept.push(format!("... ({} more arguments)", more).into_bytes());
+            break;
+        }
+        let Bulk(bytes) = arg else {
+            continue;
+        };
+        if bytes.len() > MAX_STRING {
+            let more = format!("... ({} more bytes)", bytes.len() - MAX_STRING);
+            let mut bytes = bytes[..MAX_STRING].to_vec();
+            bytes.extend_from_slice(more.as_bytes());
+            kept.push(bytes);
+        } else {
+            kept.push(bytes.clone());
+        }
+    }
+    kept
+}
+
+impl Slowlog {
+    const fn new() -> Self {
+        Slowlog {
+            entries: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    // Keeps at most `max_len` entries, dropping the oldest.
+    fn push(&mut self, entry: (&[Message], Duration, &str, &str), max_len: usize) {
+        let (argv, duration, addr, name) = entry;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.entries.push_front(Entry {
+            id: self.next_id,
+            time,
+            duration,
+            argv: truncate(argv),
+            addr: addr.to_string(),
+            name: name.to_string(),
+        });
+        self.next_id += 1;
+        self.entries.truncate(max_len);
+    }
+
+    fn command(&mut self, subcommand: &str, rest: &[Vec<u8>]) -> Message {
+        match (subcommand, rest) {
+            ("get", [] | [_]) => {
+                let count = match rest.first().map(|count| parse_int(count)) {
+                    None => 10,
+                    Some(Some(-1)) => self.entries.len(),
+                    Some(Some(count)) if count >= 0 => count as usize,
+                    Some(_) => {
+                        return Message::error("ERR count should be greater than or equal to -1")
+                    }
+                };
+                Message::array(self.entries.iter().take(count).map(entry_reply).collect())
+            }
+            ("len", []) => Message::integer(self.entries.len() as i64),
+            ("reset", []) => {
+                self.entries.clear();
+                Message::simple("OK")
+            }
+            _ => Message::error(format!(
+                "ERR unknown subcommand or wrong number of arguments for '{}'. Try SLOWLOG HELP.",
+                subcommand
+            )),
+        }
+    }
+}
+
+fn entry_reply(entry: &Entry) -> Message {
+    Message::array(vec![
+        Message::integer(entry.id as i64),
+        Message::integer(entry.time as i64),
+        Message::integer(entry.duration.as_micros() as i64),
+        Message::array(entry.argv.iter().cloned().map(Message::bulk).collect()),
+        Message::bulk(entry.addr.clone().into_bytes()),
+        Message::bulk(entry.name.clone().into_bytes()),
+    ])
+}
+
+// Logs `argv` when it ran for at least slowlog-log-slower-than
+// microseconds. A negative threshold disables the log.
+pub fn record(argv: &[Message], duration: Duration, addr: &str, name: &str) {
+    let threshold = config::slowlog_log_slower_than();
+    if threshold >= 0 && duration.as_micros() >= threshold as u128 {
+        let max_len = config::slowlog_max_len();
+        SLOWLOG
+            .lock()
+            .unwrap()
+            .push((argv, duration, addr, name), max_len);
+    }
+}
+
+pub fn slowlog(args: Vec<Message>) -> Message {
+    let mut parts = Vec::new();
+    for arg in args {
+        let Bulk(bytes) = arg else {
+            return Message::error("ERR syntax error");
+        };
+        parts.push(bytes);
+    }
+    let Some((subcommand, rest)) = parts.split_first() else {
+        return Message::error("ERR wrong number of arguments for 'slowlog' command");
+    };
+    let subcommand = String::from_utf8_lossy(subcommand).to_lowercase();
+    SLOWLOG.lock().unwrap().command(&subcommand, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        let long = "x".repeat(MAX_STRING + 5);
+        let kept = truncate(&Message::bulks(&["SET", "k", &long]));
+        assert_eq!(kept[0], b"SET");
+        assert_eq!(kept[2].len(), MAX_STRING + "... (5 more bytes)".len());
+        assert!(kept[2].ends_with(b"x... (5 more bytes)"));
+
+        let many: Vec<String> = (0..40).map(|i| i.to_string()).collect();
+        let many: Vec<&str> = many.iter().map(String::as_str).collect();
+        let kept = truncate(&Message::bulks(&many));
+        assert_eq!(kept.len(), MAX_ARGC);
+        assert_eq!(kept[MAX_ARGC - 2], b"30");
+        assert_eq!(kept[MAX_ARGC - 1], b"... (9 more arguments)");
+    }
+
+    fn command(slowlog: &mut Slowlog, args: &[&str]) -> Message {
+        let args: Vec<Vec<u8>> = args.iter().map(|a| a.as_bytes().to_vec()).collect();
+        slowlog.command(&String::from_utf8_lossy(&args[0]), &args[1..])
+    }
+
+    #[test]
+    fn test_slowlog() {
+        let mut slowlog = Slowlog::new();
+        for key in ["a", "b", "c"] {
+            let argv = Message::bulks(&["GET", key]);
+            slowlog.push((&argv, Duration::from_millis(2), "a:1", "app"), 2);
+        }
+        assert_eq!(command(&mut slowlog, &["len"]), Message::integer(2));
+
+        let Array(entries) = command(&mut slowlog, &["get"]) else {
+            panic!("SLOWLOG GET should reply with an array");
+        };
+        assert_eq!(entries.len(), 2);
+        let Array(newest) = &entries[0] else {
+            panic!("entries should be arrays");
+        };
+        assert_eq!(newest[0], Message::integer(2));
+        assert_eq!(newest[2], Message::integer(2000));
+        assert_eq!(newest[3], Message::array(Message::bulks(&["GET", "c"])));
+        assert_eq!(newest[4], Message::bulk(b"a:1".to_vec()));
+        assert_eq!(newest[5], Message::bulk(b"app".to_vec()));
+        let Array(oldest) = &entries[1] else {
+            panic!("entries should be arrays");
+        };
+        assert_eq!(oldest[0], Message::integer(1));
+
+        assert_eq!(
+            command(&mut slowlog, &["get", "1"]),
+            Message::array(vec![entries[0].clone()])
+        );
+        assert_eq!(
+            command(&mut slowlog, &["get", "-1"]),
+            Message::array(entries)
+        );
+        assert!(matches!(command(&mut slowlog, &["get", "-2"]), Error(_)));
+        assert!(matches!(command(&mut slowlog, &["nope"]), Error(_)));
+        assert_eq!(command(&mut slowlog, &["reset"]), Message::simple("OK"));
+        assert_eq!(command(&mut slowlog, &["len"]), Message::integer(0));
+    }
+}
@@ -1,10 +1,71 @@
-use std::io::{ErrorKind, Read, Write};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 use crate::aof::Aof;
-use crate::handlers::HANDLERS;
+use crate::cluster;
+use crate::config;
+use crate::expire;
+use crate::handlers::{self, Command, EXECUTION, HANDLERS};
+use crate::info;
+use crate::latency;
 use crate::message::Message;
 use crate::message::Message::*;
+use crate::monitor;
+use crate::rdb;
+use crate::replication::{self, ReplicaStream};
 use crate::resp::Resp;
+use crate::slowlog;
+
+// What handle_client needs from a connection besides reading and writing.
+pub trait Connection: Read + Write {
+    fn peer_addr(&self) -> String;
+    // A second handle on the connection, for streaming writes to a replica
+    // or a monitor from another thread.
+    fn replica_stream(&self) -> io::Result<Box<dyn ReplicaStream>>;
+}
+
+impl Connection for TcpStream {
+    fn peer_addr(&self) -> String {
+        TcpStream::peer_addr(self).map_or_else(|_| "?:0".to_string(), |addr| addr.to_string())
+    }
+
+    fn replica_stream(&self) -> io::Result<Box<dyn ReplicaStream>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+impl<C: Connection> Connection for &mut C {
+    fn peer_addr(&self) -> String {
+        (**self).peer_addr()
+    }
+
+    fn replica_stream(&self) -> io::Result<Box<dyn ReplicaStream>> {
+        (**self).replica_stream()
+    }
+}
+
+// State kept for each connection.
+#[derive(Default)]
+pub struct Client {
+    pub addr: String,
+    // The port a replica on this connection accepts clients on, from
+    // REPLCONF listening-port.
+    pub listening_port: Option<u16>,
+    // Set once PSYNC has turned the connection into a replication link.
+    pub replica: Option<u64>,
+    // Replication and AOF offsets right after this client's last write,
+    // for WAIT and WAITAOF.
+    pub repl_offset: u64,
+    pub aof_offset: u64,
+    // Set by ASKING for the next command only.
+    pub asking: bool,
+    // Set by CLIENT SETNAME.
+    pub name: String,
+    // Set once MONITOR has turned the connection into a feed of commands.
+    pub monitor: Option<u64>,
+}
 
 pub fn callback(msg: Message) {
     if let Array(array) = &msg {
@@ -21,8 +82,86 @@ pub fn callback(msg: Message) {
     }
 }
 
-pub fn handle_client<R: Read + Write>(aof: &mut Aof, stream: R) {
+// Keyspace hits and misses of a read command, counted before it runs.
+fn count_lookups(handler: &Command, argv: &[Message]) {
+    for i in handler.key_indexes(argv.len()) {
+        if let Bulk(key) = &argv[i] {
+            let counter = match handlers::exists(key) {
+                true => &info::KEYSPACE_HITS,
+                false => &info::KEYSPACE_MISSES,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Logs a write to the AOF and the replication stream. Returns the AOF
+// offset to commit once the execution lock is released.
+pub fn propagate(
+    msg: &Message,
+    client: &mut Client,
+    aof: Option<&mut Aof>,
+) -> Result<Option<u64>, Message> {
+    rdb::DIRTY.fetch_add(1, Ordering::Relaxed);
+    let appended = match aof.map(|aof| aof.append_message(msg)) {
+        Some(Ok(offset)) => {
+            client.aof_offset = offset;
+            Ok(Some(offset))
+        }
+        Some(Err(err)) => Err(Message::error(format!(
+            "ERR failed to write to the AOF: {}",
+            err
+        ))),
+        None => Ok(None),
+    };
+    client.repl_offset = replication::feed(msg);
+    appended
+}
+
+// CLIENT SETNAME and GETNAME, which only touch this connection.
+fn client_command(args: &[Message], client: &mut Client) -> Message {
+    let subcommand = match args.first() {
+        Some(Bulk(subcommand)) => String::from_utf8_lossy(subcommand).to_lowercase(),
+        _ => return Message::error("ERR wrong number of arguments for 'client' command"),
+    };
+    match (subcommand.as_str(), &args[1..]) {
+        ("setname", [Bulk(name)]) => {
+            if name.iter().any(|&b| !(b'!'..=b'~').contains(&b)) {
+                return Message::error(
+                    "ERR Client names cannot contain spaces, newlines or special characters.",
+                );
+            }
+            client.name = String::from_utf8_lossy(name).into_owned();
+            Message::simple("OK")
+        }
+        ("getname", []) if client.name.is_empty() => Null,
+        ("getname", []) => Message::bulk(client.name.clone().into_bytes()),
+        _ => Message::error(format!(
+            "ERR unknown subcommand or wrong number of arguments for '{}'",
+            subcommand
+        )),
+    }
+}
+
+// With appendfsync always this returns after the fsync.
+pub fn commit(aof: Option<&Aof>, appended: Option<u64>) -> Result<(), Message> {
+    match (aof, appended) {
+        (Some(aof), Some(offset)) => aof
+            .commit(offset)
+            .map_err(|err| Message::error(format!("ERR failed to write to the AOF: {}", err))),
+        _ => Ok(()),
+    }
+}
+
+pub fn handle_client<C: Connection>(mut aof: Option<&mut Aof>, stream: C) {
+    let mut client = Client {
+        addr: stream.peer_addr(),
+        ..Client::default()
+    };
     let mut resp = Resp::new(stream);
+    resp.limit_bulk_len(config::proto_max_bulk_len());
+    info::CONNECTED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+    info::TOTAL_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
 
     loop {
         let read = resp.read();
@@ -36,6 +175,9 @@ pub fn handle_client<R: Read + Write>(aof: &mut Aof, stream: R) {
             }
             Err(err) => {
                 println!("error reading from client: {err}");
+                if err.kind() == ErrorKind::InvalidData {
+                    _ = resp.write(Message::error(format!("ERR Protocol error: {err}")));
+                }
                 break;
             }
         };
@@ -49,14 +191,136 @@ pub fn handle_client<R: Read + Write>(aof: &mut Aof, stream: R) {
                 if let Ok(cmd_str) = std::str::from_utf8(command) {
                     let cmd = cmd_str.to_uppercase();
                     let args = &array[1..];
+                    let asking = std::mem::take(&mut client.asking);
+                    info::TOTAL_COMMANDS.fetch_add(1, Ordering::Relaxed);
 
+                    // A replication link only sends acknowledgements, and
+                    // its replies would corrupt the stream.
+                    if client.replica.is_some() && cmd != "REPLCONF" {
+                        continue;
+                    }
+                    // Same for a monitor, whose replies would mix with the
+                    // feed.
+                    if client.monitor.is_some() {
+                        continue;
+                    }
                     match HANDLERS.get(cmd.as_str()) {
+                        _ if cmd == "REPLCONF" => {
+                            if let Some(reply) = replication::replconf(args, &mut client) {
+                                _ = resp.write(reply);
+                            }
+                        }
+                        _ if cmd == "PSYNC" => {
+                            let _execution = EXECUTION.lock().unwrap();
+                            let reply = match resp.get_ref().replica_stream() {
+                                Ok(stream) => replication::psync(args, &mut client, stream),
+                                Err(err) => Some(Message::error(format!("ERR {}", err))),
+                            };
+                            if let Some(reply) = reply {
+                                _ = resp.write(reply);
+                            }
+                        }
+                        // These block only this connection's thread, so they
+                        // run without the execution lock.
+                        _ if cmd == "WAIT" => {
+                            _ = resp.write(replication::wait(args, &client));
+                        }
+                        _ if cmd == "WAITAOF" => {
+                            _ = resp.write(replication::waitaof(args, &client, aof.as_deref()));
+                        }
+                        _ if cmd == "MONITOR" => match resp.get_ref().replica_stream() {
+                            Ok(stream) => {
+                                _ = resp.write(Message::simple("OK"));
+                                client.monitor = Some(monitor::attach(stream));
+                            }
+                            Err(err) => _ = resp.write(Message::error(format!("ERR {}", err))),
+                        },
+                        _ if cmd == "CLIENT" => {
+                            _ = resp.write(client_command(args, &mut client));
+                        }
+                        _ if cmd == "ASKING" => {
+                            let reply = cluster::asking();
+                            client.asking = matches!(reply, Simple(_));
+                            _ = resp.write(reply);
+                        }
+                        // Talks to the target with the execution lock held,
+                        // so the keys cannot change while they move. Keys
+                        // moved away are propagated as a DEL.
+                        _ if cmd == "MIGRATE" => {
+                            let execution = EXECUTION.lock().unwrap();
+                            monitor::feed(array, &client.addr);
+                            let (mut reply, del) = cluster::migrate(args);
+                            let mut appended = None;
+                            if let Some(del) = del {
+                                match propagate(&del, &mut client, aof.as_deref_mut()) {
+                                    Ok(offset) => appended = offset,
+                                    Err(err) => reply = err,
+                                }
+                            }
+                            drop(execution);
+                            if let Err(err) = commit(aof.as_deref(), appended) {
+                                reply = err;
+                            }
+                            _ = resp.write(reply);
+                        }
+                        Some(handler) if !handler.check_arity(array.len()) => {
+                            _ = resp.write(handler.arity_error());
+                        }
+                        Some(handler)
+                            if let Some(reply) = cluster::redirect(handler, array, asking) =>
+                        {
+                            _ = resp.write(reply);
+                        }
+                        Some(handler) if handler.is_write() && replication::read_only_replica() => {
+                            _ = resp.write(Message::error(
+                                "READONLY You can't write against a read only replica.",
+                            ));
+                        }
                         Some(handler) => {
-                            if cmd == "SET" || cmd == "HSET" {
-                                let _ = aof.write_message(&msg);
+                            let execution = EXECUTION.lock().unwrap();
+                            // Expired keys the command names go first, as a
+                            // DEL of their own.
+                            let mut appended = None;
+                            let keys = handler.key_indexes(array.len()).into_iter();
+                            let keys = keys.filter_map(|i| match &array[i] {
+                                Bulk(key) => Some(key.clone()),
+                                _ => None,
+                            });
+                            if let Some(del) = expire::expire_due(keys) {
+                                match propagate(&del, &mut client, aof.as_deref_mut()) {
+                                    Ok(offset) => appended = offset,
+                                    Err(err) => {
+                                        drop(execution);
+                                        _ = resp.write(err);
+                                        continue;
+                                    }
+                                }
+                            }
+                            if !handler.is_write() {
+                                count_lookups(handler, array);
+                            }
+                            monitor::feed(array, &client.addr);
+                            let start = Instant::now();
+                            let mut result_msg = handler.call(args.to_vec());
+                            let elapsed = start.elapsed();
+                            handler.latency.record(elapsed);
+                            slowlog::record(array, elapsed, &client.addr, &client.name);
+                            let event = match handler.flags & handlers::FAST {
+                                0 => "command",
+                                _ => "fast-command",
+                            };
+                            latency::sample(event, elapsed);
+                            info::sample_peak_memory();
+                            if handler.is_write() && !matches!(result_msg, Error(_)) {
+                                match propagate(&msg, &mut client, aof.as_deref_mut()) {
+                                    Ok(offset) => appended = offset,
+                                    Err(err) => result_msg = err,
+                                }
+                            }
+                            drop(execution);
+                            if let Err(err) = commit(aof.as_deref(), appended) {
+                                result_msg = err;
                             }
-
-                            let result_msg = handler.call(args.to_vec());
                             _ = resp.write(result_msg);
                         }
                         None => {
@@ -74,11 +338,19 @@ pub fn handle_client<R: Read + Write>(aof: &mut Aof, stream: R) {
             continue;
         }
     }
+    if let Some(id) = client.replica {
+        replication::replica_disconnected(id);
+    }
+    if let Some(id) = client.monitor {
+        monitor::detach(id);
+    }
+    info::CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::aof::AppendFsync;
     use std::fs::File;
     use std::io::{self, Read, Write};
 
@@ -113,6 +385,16 @@ mod tests {
         }
     }
 
+    impl Connection for MockStream {
+        fn peer_addr(&self) -> String {
+            "127.0.0.1:50000".to_string()
+        }
+
+        fn replica_stream(&self) -> io::Result<Box<dyn ReplicaStream>> {
+            Err(io::Error::other("mock streams cannot be cloned"))
+        }
+    }
+
     impl Write for MockStream {
         fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
             self.write_data.extend_from_slice(buf);
@@ -130,8 +412,8 @@ mod tests {
         let input = b"*1\r\n$4\r\nPING\r\n".to_vec();
         let mut mock_stream = MockStream::new(input);
         let dev_null = File::open("/dev/null").unwrap();
-        let mut aof = Aof::new(dev_null);
-        let mut handler = |stream: &mut MockStream| handle_client(&mut aof, stream);
+        let mut aof = Aof::from_file(dev_null, AppendFsync::No);
+        let mut handler = |stream: &mut MockStream| handle_client(Some(&mut aof), stream);
 
         handler(&mut mock_stream);
 
@@ -146,8 +428,8 @@ mod tests {
         let input = b"*1\r\n$7\r\nUNKNOWN\r\n".to_vec();
         let mut mock_stream = MockStream::new(input);
         let dev_null = File::open("/dev/null").unwrap();
-        let mut aof = Aof::new(dev_null);
-        let mut handler = |stream: &mut MockStream| handle_client(&mut aof, stream);
+        let mut aof = Aof::from_file(dev_null, AppendFsync::No);
+        let mut handler = |stream: &mut MockStream| handle_client(Some(&mut aof), stream);
 
         handler(&mut mock_stream);
 
@@ -156,14 +438,152 @@ mod tests {
         assert_eq!(&mock_stream.write_data, expected_output);
     }
 
+    #[test]
+    fn test_handle_client_wrong_arity() {
+        let input = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n*1\r\n$3\r\nGET\r\n".to_vec();
+        let mut mock_stream = MockStream::new(input);
+        let dev_null = File::open("/dev/null").unwrap();
+        let mut aof = Aof::from_file(dev_null, AppendFsync::No);
+        let mut handler = |stream: &mut MockStream| handle_client(Some(&mut aof), stream);
+
+        handler(&mut mock_stream);
+
+        let expected_output = b"$-1\r\n-ERR wrong number of arguments for 'get' command\r\n";
+        assert_eq!(&mock_stream.write_data, expected_output);
+    }
+
+    #[test]
+    fn test_handle_client_setname() {
+        let input = b"*2\r\n$6\r\nCLIENT\r\n$7\r\nGETNAME\r\n\
+                      *3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$3\r\napp\r\n\
+                      *2\r\n$6\r\nclient\r\n$7\r\ngetname\r\n\
+                      *3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$3\r\na b\r\n"
+            .to_vec();
+        let mut mock_stream = MockStream::new(input);
+        handle_client(None, &mut mock_stream);
+
+        let expected_output = b"$-1\r\n+OK\r\n$3\r\napp\r\n\
+              -ERR Client names cannot contain spaces, newlines or special characters.\r\n";
+        assert_eq!(&mock_stream.write_data, expected_output);
+    }
+
+    #[test]
+    fn test_handle_client_appends_only_writes_to_aof() {
+        let input = b"*3\r\n$3\r\nSET\r\n$9\r\naof:write\r\n$1\r\nv\r\n\
+                      *2\r\n$3\r\nGET\r\n$9\r\naof:write\r\n"
+            .to_vec();
+        let path = std::env::temp_dir().join(format!("rustis-aof-{}.aof", std::process::id()));
+        let file = File::options()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut aof = Aof::from_file(file, AppendFsync::Always);
+        let mut mock_stream = MockStream::new(input);
+
+        handle_client(Some(&mut aof), &mut mock_stream);
+
+        let logged = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(&mock_stream.write_data, b"+OK\r\n$1\r\nv\r\n");
+        assert_eq!(logged, b"*3\r\n$3\r\nSET\r\n$9\r\naof:write\r\n$1\r\nv\r\n");
+    }
+
+    fn encode(commands: &[&[&str]]) -> Vec<u8> {
+        commands
+            .iter()
+            .flat_map(|args| Message::command(args).marshal())
+            .collect()
+    }
+
+    // Runs `commands` through handle_client with an AOF, returning the
+    // replies and what was logged.
+    fn run_logged(name: &str, commands: &[&[&str]]) -> (Vec<u8>, Vec<u8>) {
+        let input = encode(commands);
+        let path = std::env::temp_dir().join(format!("rustis-{}-{}.aof", name, std::process::id()));
+        let file = File::options()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let mut aof = Aof::from_file(file, AppendFsync::Always);
+        let mut mock_stream = MockStream::new(input);
+        handle_client(Some(&mut aof), &mut mock_stream);
+        let logged = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        (mock_stream.write_data, logged)
+    }
+
+    #[test]
+    fn test_handle_client_logs_hyperloglog_writes() {
+        let pfadd: &[&str] = &["PFADD", "{aof-hll}a", "x", "y"];
+        let pfmerge: &[&str] = &["PFMERGE", "{aof-hll}b", "{aof-hll}a"];
+        let (replies, logged) = run_logged("hll", &[pfadd, &["PFCOUNT", "{aof-hll}a"], pfmerge]);
+        assert_eq!(replies, b":1\r\n:2\r\n+OK\r\n");
+        assert_eq!(logged, encode(&[pfadd, pfmerge]));
+    }
+
+    #[test]
+    fn test_handle_client_logs_geo_writes() {
+        let geoadd: &[&str] = &[
+            "GEOADD",
+            "{aof-geo}a",
+            "13.361389",
+            "38.115556",
+            "p",
+            "15.087269",
+            "37.502669",
+            "c",
+        ];
+        let store: &[&str] = &[
+            "GEOSEARCHSTORE",
+            "{aof-geo}b",
+            "{aof-geo}a",
+            "FROMMEMBER",
+            "p",
+            "BYRADIUS",
+            "500",
+            "km",
+        ];
+        let zrem: &[&str] = &["ZREM", "{aof-geo}a", "c"];
+        let (replies, logged) = run_logged(
+            "geo",
+            &[geoadd, &["GEODIST", "{aof-geo}a", "p", "c"], store, zrem],
+        );
+        assert!(replies.starts_with(b":2\r\n$"));
+        assert!(replies.ends_with(b":2\r\n:1\r\n"));
+        assert_eq!(logged, encode(&[geoadd, store, zrem]));
+    }
+
+    #[test]
+    fn test_handle_client_logs_json_writes() {
+        let set: &[&str] = &["JSON.SET", "{aof-json}a", "$", r#"{"n":1,"l":[]}"#];
+        let incr: &[&str] = &["JSON.NUMINCRBY", "{aof-json}a", "$.n", "2"];
+        let append: &[&str] = &["JSON.ARRAPPEND", "{aof-json}a", "$.l", "1"];
+        let del: &[&str] = &["JSON.DEL", "{aof-json}a", "$.l"];
+        let (replies, logged) = run_logged(
+            "json",
+            &[set, incr, &["JSON.GET", "{aof-json}a", "$.n"], append, del],
+        );
+        assert_eq!(
+            replies,
+            b"+OK\r\n$3\r\n[3]\r\n$3\r\n[3]\r\n*1\r\n:1\r\n:1\r\n"
+        );
+        assert_eq!(logged, encode(&[set, incr, append, del]));
+    }
+
     #[test]
     fn test_handle_client_non_array_message() {
         // Simulate a malformed message: $5\r\nhello\r\n
         let input = b"$5\r\nhello\r\n".to_vec();
         let mut mock_stream = MockStream::new(input);
         let dev_null = File::open("/dev/null").unwrap();
-        let mut aof = Aof::new(dev_null);
-        let mut handler = |stream: &mut MockStream| handle_client(&mut aof, stream);
+        let mut aof = Aof::from_file(dev_null, AppendFsync::No);
+        let mut handler = |stream: &mut MockStream| handle_client(Some(&mut aof), stream);
 
         handler(&mut mock_stream);
 
@@ -178,8 +598,8 @@ mod tests {
         let input = b"*1\r\n$1\r\n\xFF\r\n".to_vec();
         let mut mock_stream = MockStream::new(input);
         let dev_null = File::open("/dev/null").unwrap();
-        let mut aof = Aof::new(dev_null);
-        let mut handler = |stream: &mut MockStream| handle_client(&mut aof, stream);
+        let mut aof = Aof::from_file(dev_null, AppendFsync::No);
+        let mut handler = |stream: &mut MockStream| handle_client(Some(&mut aof), stream);
 
         handler(&mut mock_stream);
 
@@ -0,0 +1,250 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::handlers::{parse_float, ZSetMap};
+use crate::message::Message;
+use crate::message::Message::*;
+
+// f64 wrapper with a total order so scores can key a BTreeSet.
+#[derive(Clone, Copy, Debug)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// Members ordered by (score, member), with a side table for score lookups.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    ordered: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        SortedSet::default()
+    }
+
+    // Returns the previous score of the member, if any.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> Option<f64> {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(old) = previous {
+            self.ordered.remove(&(Score(old), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        previous
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.ordered.remove(&(Score(score), member.to_vec()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (f64, &Vec<u8>)> {
+        self.ordered.iter().map(|(score, member)| (score.0, member))
+    }
+
+    // Members with min <= score < max, in order.
+    pub fn range(&self, min: f64, max: f64) -> impl Iterator<Item = (f64, &Vec<u8>)> {
+        self.ordered
+            .range((Score(min), Vec::new())..(Score(max), Vec::new()))
+            .map(|(score, member)| (score.0, member))
+    }
+}
+
+pub fn format_score(score: f64) -> Vec<u8> {
+    match score {
+        f64::INFINITY => b"inf".to_vec(),
+        f64::NEG_INFINITY => b"-inf".to_vec(),
+        _ => score.to_string().into_bytes(),
+    }
+}
+
+pub fn zadd(args: Vec<Message>, zsets: &ZSetMap) -> Message {
+    match args.as_slice() {
+        [Bulk(key), pairs @ ..] if !pairs.is_empty() && pairs.len().is_multiple_of(2) => {
+            let mut entries = Vec::new();
+            for pair in pairs.chunks(2) {
+                match pair {
+                    [Bulk(score), Bulk(member)] => match parse_float(score) {
+                        Some(score) => entries.push((score, member.clone())),
+                        None => return Message::error("ERR value is not a valid float"),
+                    },
+                    _ => return Message::error("ERR syntax error"),
+                }
+            }
+            let mut zsets = zsets.lock().unwrap();
+            let zset = zsets.entry(key.clone()).or_default();
+            let added = entries
+                .into_iter()
+                .filter(|(score, member)| zset.insert(member.clone(), *score).is_none())
+                .count();
+            Message::integer(added as i64)
+        }
+        [Bulk(_), _, ..] => Message::error("ERR syntax error"),
+        _ => Message::error("ERR wrong number of arguments for 'zadd' command"),
+    }
+}
+
+pub fn zscore(args: Vec<Message>, zsets: &ZSetMap) -> Message {
+    match args.as_slice() {
+        [Bulk(key), Bulk(member)] => {
+            let zsets = zsets.lock().unwrap();
+            match zsets.get(key).and_then(|zset| zset.score(member)) {
+                Some(score) => Message::bulk(format_score(score)),
+                None => Message::Null,
+            }
+        }
+        _ => Message::error("ERR wrong number of arguments for 'zscore' command"),
+    }
+}
+
+pub fn zcard(args: Vec<Message>, zsets: &ZSetMap) -> Message {
+    match args.as_slice() {
+        [Bulk(key)] => {
+            let zsets = zsets.lock().unwrap();
+            Message::integer(zsets.get(key).map(SortedSet::len).unwrap_or(0) as i64)
+        }
+        _ => Message::error("ERR wrong number of arguments for 'zcard' command"),
+    }
+}
+
+pub fn zrem(args: Vec<Message>, zsets: &ZSetMap) -> Message {
+    match args.as_slice() {
+        [Bulk(key), members @ ..] if !members.is_empty() => {
+            let mut zsets = zsets.lock().unwrap();
+            let zset = match zsets.get_mut(key) {
+                Some(zset) => zset,
+                None => return Message::integer(0),
+            };
+            let removed = members
+                .iter()
+                .filter(|member| matches!(member, Bulk(m) if zset.remove(m)))
+                .count();
+            if zset.is_empty() {
+                zsets.remove(key);
+            }
+            Message::integer(removed as i64)
+        }
+        _ => Message::error("ERR wrong number of arguments for 'zrem' command"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn zsets_with(key: &str, entries: &[(&str, f64)]) -> ZSetMap {
+        let mut zset = SortedSet::new();
+        for (member, score) in entries {
+            zset.insert(member.as_bytes().to_vec(), *score);
+        }
+        let zsets = Mutex::new(HashMap::new());
+        zsets.lock().unwrap().insert(key.as_bytes().to_vec(), zset);
+        zsets
+    }
+
+    #[test]
+    fn test_insert_updates_order() {
+        let mut zset = SortedSet::new();
+        assert_eq!(zset.insert(b"a".to_vec(), 3.0), None);
+        zset.insert(b"b".to_vec(), 2.0);
+        assert_eq!(zset.insert(b"a".to_vec(), 1.0), Some(3.0));
+        let members: Vec<_> = zset.range(0.0, 10.0).map(|(_, m)| m.clone()).collect();
+        assert_eq!(members, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(zset.len(), 2);
+    }
+
+    #[test]
+    fn test_range_is_half_open() {
+        let mut zset = SortedSet::new();
+        for (i, member) in ["a", "b", "c", "d"].iter().enumerate() {
+            zset.insert(member.as_bytes().to_vec(), i as f64);
+        }
+        let members: Vec<_> = zset.range(1.0, 3.0).map(|(_, m)| m.clone()).collect();
+        assert_eq!(members, vec![b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn test_zadd() {
+        let zsets: ZSetMap = Mutex::new(HashMap::new());
+        assert_eq!(
+            zadd(Message::bulks(&["z", "1", "a", "inf", "b"]), &zsets),
+            Message::integer(2)
+        );
+        assert_eq!(
+            zadd(Message::bulks(&["z", "2", "a"]), &zsets),
+            Message::integer(0)
+        );
+        assert_eq!(zsets.lock().unwrap()[&b"z".to_vec()].score(b"a"), Some(2.0));
+        assert_eq!(
+            zadd(Message::bulks(&["z", "x", "a"]), &zsets),
+            Message::error("ERR value is not a valid float")
+        );
+        assert_eq!(
+            zadd(Message::bulks(&["z", "1"]), &zsets),
+            Message::error("ERR syntax error")
+        );
+    }
+
+    #[test]
+    fn test_zscore() {
+        let zsets = zsets_with("z", &[("a", 1.5)]);
+        assert_eq!(
+            zscore(Message::bulks(&["z", "a"]), &zsets),
+            Message::bulk(b"1.5".to_vec())
+        );
+        assert_eq!(zscore(Message::bulks(&["z", "b"]), &zsets), Message::Null);
+    }
+
+    #[test]
+    fn test_zcard() {
+        let zsets = zsets_with("z", &[("a", 1.0), ("b", 2.0)]);
+        assert_eq!(zcard(Message::bulks(&["z"]), &zsets), Message::integer(2));
+        assert_eq!(zcard(Message::bulks(&["y"]), &zsets), Message::integer(0));
+    }
+
+    #[test]
+    fn test_zrem_deletes_empty_key() {
+        let zsets = zsets_with("z", &[("a", 1.0), ("b", 2.0)]);
+        assert_eq!(
+            zrem(Message::bulks(&["z", "a", "b", "c"]), &zsets),
+            Message::integer(2)
+        );
+        assert!(zsets.lock().unwrap().is_empty());
+    }
+}